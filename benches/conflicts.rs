@@ -0,0 +1,86 @@
+//! Conflict detection against serve configs with thousands of handlers.
+//!
+//! `cargo bench --bench conflicts`
+
+use std::collections::HashMap;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use funnelctl::core::{detect_conflicts, ConflictIndex, HttpHandler, ServeConfig, WebServerConfig};
+
+const HOST_PORTS: [&str; 3] = [
+    "node.tail0000.ts.net:443",
+    "node.tail0000.ts.net:8443",
+    "node.tail0000.ts.net:10000",
+];
+const NEW_TARGET: &str = "http://127.0.0.1:3000";
+
+/// `handlers` routes per port, half prefix mounts, nested a few levels deep
+fn config_with(handlers: usize) -> ServeConfig {
+    let mut web = HashMap::new();
+    for host_port in HOST_PORTS {
+        let mut web_config = WebServerConfig::new();
+        web_config.handlers = Some(
+            (0..handlers)
+                .map(|i| {
+                    let path = if i % 2 == 0 {
+                        format!("/team{}/svc{}/", i % 17, i)
+                    } else {
+                        format!("/team{}/svc{}/hook", i % 17, i)
+                    };
+                    let target = format!("http://127.0.0.1:{}", 10000 + i % 50000);
+                    (path, HttpHandler::new_proxy(target))
+                })
+                .collect(),
+        );
+        web.insert(host_port.to_string(), web_config);
+    }
+    let mut config = ServeConfig::new();
+    config.web = Some(web);
+    config
+}
+
+fn bench_conflicts(c: &mut Criterion) {
+    let mut group = c.benchmark_group("conflicts");
+    for handlers in [100, 1_000, 5_000] {
+        let config = config_with(handlers);
+        let new_path = "/funnelctl/a7Xk9mPq";
+
+        group.bench_with_input(
+            BenchmarkId::new("detect_conflicts_per_port", handlers),
+            &config,
+            |b, config| {
+                b.iter(|| {
+                    for host_port in HOST_PORTS {
+                        black_box(detect_conflicts(
+                            config, host_port, new_path, NEW_TARGET, true,
+                        ))
+                        .ok();
+                    }
+                })
+            },
+        );
+
+        group.bench_with_input(
+            BenchmarkId::new("index_build", handlers),
+            &config,
+            |b, config| b.iter(|| black_box(ConflictIndex::new(config))),
+        );
+
+        let index = ConflictIndex::new(&config);
+        group.bench_with_input(
+            BenchmarkId::new("index_check_all_ports", handlers),
+            &index,
+            |b, index| {
+                b.iter(|| {
+                    for host_port in HOST_PORTS {
+                        black_box(index.check(host_port, new_path, NEW_TARGET, true)).ok();
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_conflicts);
+criterion_main!(benches);
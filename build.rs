@@ -0,0 +1,65 @@
+//! Build metadata for `funnelctl --version --json`: git commit, build time, and the
+//! enabled cargo features. Everything is optional; a source tarball builds without git.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-env-changed=FUNNELCTL_GIT_COMMIT");
+
+    let commit = std::env::var("FUNNELCTL_GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(git_commit);
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=FUNNELCTL_GIT_COMMIT={}", commit);
+    }
+
+    // Reproducible builds pin the date through SOURCE_DATE_EPOCH
+    let epoch = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<i64>().ok())
+        .or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .and_then(|now| i64::try_from(now.as_secs()).ok())
+        });
+    if let Some(epoch) = epoch {
+        println!("cargo:rustc-env=FUNNELCTL_BUILD_EPOCH={}", epoch);
+    }
+
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|name| name.to_ascii_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=FUNNELCTL_FEATURES={}", features.join(","));
+}
+
+fn git_commit() -> Option<String> {
+    let git_dir = Path::new(".git");
+    if !git_dir.exists() {
+        return None;
+    }
+    // A new commit moves the branch ref, not HEAD itself
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    if let Ok(head) = std::fs::read_to_string(git_dir.join("HEAD")) {
+        if let Some(reference) = head.trim().strip_prefix("ref: ") {
+            println!("cargo:rerun-if-changed=.git/{}", reference);
+        }
+    }
+    let output = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let commit = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    (!commit.is_empty()).then_some(commit)
+}
@@ -0,0 +1,56 @@
+#!/bin/sh
+# Captures the live ServeConfig of this node as a test fixture:
+#
+#   scripts/capture-serve-config.sh <scenario>
+#
+# writes tests/fixtures/serve-config/captured/v<version>/<scenario>.json, where
+# <version> is the running tailscaled's. Set the scenario up first with `tailscale
+# serve` / `tailscale funnel` (see the fixtures README for the list). The document is
+# taken from `GET /localapi/v0/serve-config` as is; only the node name and the
+# foreground session ids are replaced, so the key set and shape stay the daemon's.
+#
+# Needs curl and jq, and read access to the LocalAPI socket (usually root). Set
+# TAILSCALED_SOCKET when the socket is not at /var/run/tailscale/tailscaled.sock.
+
+set -eu
+
+scenario=${1:?usage: $0 <scenario>}
+socket=${TAILSCALED_SOCKET:-/var/run/tailscale/tailscaled.sock}
+root=$(cd "$(dirname "$0")/.." && pwd)
+
+localapi() {
+    curl --silent --show-error --fail --unix-socket "$socket" \
+        -H 'Sec-Tailscale: localapi' "http://local-tailscaled.sock/localapi/v0/$1"
+}
+
+status=$(localapi status)
+# "1.50.1-t0123abcd-g4567ef" -> "1.50.1"
+version=$(printf '%s' "$status" | jq -r '.Version' | cut -d- -f1)
+node=$(printf '%s' "$status" | jq -r '.Self.DNSName' | sed 's/\.$//')
+case $version in
+    [0-9]*.[0-9]*.[0-9]*) ;;
+    *) echo "unexpected tailscaled version '$version'" >&2; exit 1 ;;
+esac
+
+dir="$root/tests/fixtures/serve-config/captured/v$version"
+mkdir -p "$dir"
+out="$dir/$scenario.json"
+
+# Node name -> node.tail1234.ts.net everywhere (keys and values); session ids ->
+# session-1, session-2, ... in the daemon's order
+localapi serve-config | jq --arg node "$node" '
+    def redact: if type == "string" then split($node) | join("node.tail1234.ts.net") else . end;
+    def walk_redact:
+        if type == "object" then with_entries(.key |= redact | .value |= walk_redact)
+        elif type == "array" then map(walk_redact)
+        else redact end;
+    walk_redact
+    | if .Foreground then
+        .Foreground |= (to_entries
+            | to_entries
+            | map({key: "session-\(.key + 1)", value: .value.value})
+            | from_entries)
+      else . end
+' >"$out"
+
+echo "captured tailscaled $version into ${out#"$root"/}"
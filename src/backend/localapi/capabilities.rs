@@ -0,0 +1,135 @@
+//! Optional LocalAPI features of the connected tailscaled, probed once per daemon
+//! version and cached in `cache_dir()/capabilities.json`, so `open` does not
+//! re-discover a limitation through a failed request every time.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use super::client::LocalApiClient;
+use crate::dirs;
+use crate::net::LocalApiError;
+
+const CACHE_FILE: &str = "capabilities.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// GET serve-config returns an ETag, so writes can be guarded with If-Match
+    pub serve_config_etag: bool,
+    /// serve-config takes a JSON merge patch, so a write can send only what changed.
+    /// Absent from entries cached before it was probed.
+    #[serde(default)]
+    pub serve_config_patch: bool,
+}
+
+impl Capabilities {
+    /// Probes with read-only requests; nothing on the daemon changes
+    pub async fn probe(client: &LocalApiClient) -> Result<Self, LocalApiError> {
+        let serve_config = client.get_serve_config().await?;
+        let serve_config_patch = client.serve_config_accepts_patch().await?;
+        Ok(Self {
+            serve_config_etag: serve_config.etag.is_some(),
+            serve_config_patch,
+        })
+    }
+}
+
+/// Probe results keyed by tailscaled version; an upgrade is a cache miss
+pub struct CapabilityCache {
+    path: PathBuf,
+}
+
+impl CapabilityCache {
+    pub fn open() -> Option<Self> {
+        match dirs::cache_dir() {
+            Ok(dir) => Some(Self::at(dir.join(CACHE_FILE))),
+            Err(err) => {
+                tracing::debug!(error = %err, "capability cache unavailable");
+                None
+            }
+        }
+    }
+
+    pub fn at(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    pub fn get(&self, version: &str) -> Option<Capabilities> {
+        self.read().remove(version)
+    }
+
+    /// Best effort: a cache that cannot be written only costs a re-probe next time
+    pub fn put(&self, version: &str, capabilities: Capabilities) {
+        let mut entries = self.read();
+        entries.insert(version.to_string(), capabilities);
+        let result = serde_json::to_vec_pretty(&entries)
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| {
+                dirs::write_private(&self.path, &bytes).map_err(|err| err.to_string())
+            });
+        if let Err(err) = result {
+            tracing::debug!(error = %err, "failed to write capability cache");
+        }
+    }
+
+    /// A missing or unreadable cache is treated as empty
+    fn read(&self) -> BTreeMap<String, Capabilities> {
+        std::fs::read(&self.path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAPS: Capabilities = Capabilities {
+        serve_config_etag: true,
+        serve_config_patch: false,
+    };
+
+    #[test]
+    fn test_cache_keyed_by_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CapabilityCache::at(dir.path().join(CACHE_FILE));
+        assert_eq!(cache.get("1.60.0"), None);
+
+        cache.put("1.60.0", CAPS);
+        assert_eq!(cache.get("1.60.0"), Some(CAPS));
+        assert_eq!(cache.get("1.62.1"), None);
+
+        let upgraded = Capabilities {
+            serve_config_patch: true,
+            ..CAPS
+        };
+        cache.put("1.62.1", upgraded);
+        assert_eq!(cache.get("1.60.0"), Some(CAPS));
+        assert_eq!(cache.get("1.62.1"), Some(upgraded));
+    }
+
+    #[test]
+    fn test_entry_cached_before_patch_probe() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CACHE_FILE);
+        std::fs::write(
+            &path,
+            br#"{"1.60.0":{"serve_config_etag":true,"cert_endpoint":false}}"#,
+        )
+        .unwrap();
+        assert_eq!(CapabilityCache::at(path).get("1.60.0"), Some(CAPS));
+    }
+
+    #[test]
+    fn test_corrupt_cache_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CACHE_FILE);
+        std::fs::write(&path, b"not json").unwrap();
+        let cache = CapabilityCache::at(path);
+        assert_eq!(cache.get("1.60.0"), None);
+        cache.put("1.60.0", CAPS);
+        assert_eq!(cache.get("1.60.0"), Some(CAPS));
+    }
+}
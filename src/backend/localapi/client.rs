@@ -1,22 +1,28 @@
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use futures::{StreamExt, TryStreamExt};
 use http_body_util::BodyExt;
-use hyper::header::{HeaderValue, CONTENT_TYPE, ETAG, IF_MATCH};
+use hyper::header::{HeaderValue, ALLOW, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH};
 use hyper::{Method, Response, StatusCode};
 use serde_json::Value;
+use tokio::sync::Notify;
 use tokio::task::JoinHandle;
 use tokio_util::codec::{FramedRead, LinesCodec};
 use tokio_util::io::StreamReader;
 
-use crate::net::{LocalApiError, LocalApiTransport, TransportRequest};
+use super::redact;
+use crate::net::{http_dump, LocalApiError, LocalApiTransport, TransportRequest};
 
 const WATCH_MASK_INITIAL_STATE: u64 = 1 << 1;
 const MAX_WATCH_LINE: usize = 1024 * 1024;
 const JSON_CONTENT_TYPE: &str = "application/json";
+const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
 const STATUS_ENDPOINT: &str = "/localapi/v0/status";
 const WATCH_IPN_BUS_ENDPOINT: &str = "/localapi/v0/watch-ipn-bus";
 const SERVE_CONFIG_ENDPOINT: &str = "/localapi/v0/serve-config";
+const PREFS_ENDPOINT: &str = "/localapi/v0/prefs";
 
 pub struct LocalApiClient {
     transport: LocalApiTransport,
@@ -30,6 +36,8 @@ pub struct ServeConfigResponse {
 pub struct WatchIpnBus {
     session_id: String,
     drain_task: Option<JoinHandle<()>>,
+    events: Arc<Notify>,
+    ended: Arc<AtomicBool>,
 }
 
 impl WatchIpnBus {
@@ -37,6 +45,16 @@ impl WatchIpnBus {
         &self.session_id
     }
 
+    /// Signalled on every notification after the session id, and once when the stream ends
+    pub fn events(&self) -> Arc<Notify> {
+        Arc::clone(&self.events)
+    }
+
+    /// Whether tailscaled closed the stream; its foreground config is gone with it
+    pub fn ended(&self) -> bool {
+        self.ended.load(Ordering::Relaxed)
+    }
+
     pub fn close(&mut self) {
         if let Some(task) = self.drain_task.take() {
             task.abort();
@@ -55,31 +73,89 @@ impl LocalApiClient {
         Self { transport }
     }
 
+    pub fn transport(&self) -> &LocalApiTransport {
+        &self.transport
+    }
+
     pub async fn get_status(&self) -> Result<Value, LocalApiError> {
         let request = TransportRequest::new(Method::GET, STATUS_ENDPOINT);
         let response = self.send_ok(request).await?;
-        parse_json_response(response).await
+        parse_json_response(response, STATUS_ENDPOINT).await
+    }
+
+    /// Node preferences; any local user may read them
+    pub async fn get_prefs(&self) -> Result<Value, LocalApiError> {
+        let request = TransportRequest::new(Method::GET, PREFS_ENDPOINT);
+        let response = self.send_ok(request).await?;
+        parse_json_response(response, PREFS_ENDPOINT).await
     }
 
     pub async fn get_serve_config(&self) -> Result<ServeConfigResponse, LocalApiError> {
         let request = TransportRequest::new(Method::GET, SERVE_CONFIG_ENDPOINT);
         let response = self.send_ok(request).await?;
         let etag = header_to_string(response.headers(), ETAG)?;
-        let config = parse_json_response(response).await?;
+        let config = parse_json_response(response, SERVE_CONFIG_ENDPOINT).await?;
         Ok(ServeConfigResponse { etag, config })
     }
 
+    /// `None` when the config still has ETag `etag` (`304 Not Modified`). Daemons that
+    /// ignore If-None-Match answer `200` with the whole document, like a plain GET.
+    pub async fn get_serve_config_if_changed(
+        &self,
+        etag: &str,
+    ) -> Result<Option<ServeConfigResponse>, LocalApiError> {
+        let mut request = TransportRequest::new(Method::GET, SERVE_CONFIG_ENDPOINT);
+        let header_value =
+            HeaderValue::from_str(etag).map_err(|_| LocalApiError::InvalidHeaderValue {
+                name: "if-none-match",
+            })?;
+        request.headers.insert(IF_NONE_MATCH, header_value);
+        let response = self.transport.send(request).await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            // Drain the (empty) body so the connection can be reused.
+            let _ = response.into_body().collect().await;
+            return Ok(None);
+        }
+        let response =
+            ensure_status_ok(response, Method::GET, SERVE_CONFIG_ENDPOINT.to_string()).await?;
+        let etag = header_to_string(response.headers(), ETAG)?;
+        let config = parse_json_response(response, SERVE_CONFIG_ENDPOINT).await?;
+        Ok(Some(ServeConfigResponse { etag, config }))
+    }
+
     pub async fn set_serve_config(
         &self,
         config: &Value,
         etag: Option<&str>,
     ) -> Result<(), LocalApiError> {
-        let body = serde_json::to_vec(config)?;
-        let mut request =
-            TransportRequest::new(Method::POST, SERVE_CONFIG_ENDPOINT).with_body(body);
+        self.send_serve_config(Method::POST, JSON_CONTENT_TYPE, config, etag)
+            .await
+    }
+
+    /// Sends only what changed, as a JSON merge patch (RFC 7396), under the same
+    /// If-Match as a full write
+    pub async fn patch_serve_config(
+        &self,
+        delta: &Value,
+        etag: Option<&str>,
+    ) -> Result<(), LocalApiError> {
+        self.send_serve_config(Method::PATCH, MERGE_PATCH_CONTENT_TYPE, delta, etag)
+            .await
+    }
+
+    async fn send_serve_config(
+        &self,
+        method: Method,
+        content_type: &'static str,
+        document: &Value,
+        etag: Option<&str>,
+    ) -> Result<(), LocalApiError> {
+        dump_json('>', SERVE_CONFIG_ENDPOINT, document);
+        let body = serde_json::to_vec(document)?;
+        let mut request = TransportRequest::new(method, SERVE_CONFIG_ENDPOINT).with_body(body);
         request
             .headers
-            .insert(CONTENT_TYPE, HeaderValue::from_static(JSON_CONTENT_TYPE));
+            .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
         if let Some(etag_value) = etag {
             let header_value = HeaderValue::from_str(etag_value)
                 .map_err(|_| LocalApiError::InvalidHeaderValue { name: "if-match" })?;
@@ -89,6 +165,34 @@ impl LocalApiClient {
         Ok(())
     }
 
+    /// Whether serve-config takes `PATCH`, going by the `Allow` header of an `OPTIONS`
+    /// request, which changes nothing. Releases so far answer `405` without one.
+    pub async fn serve_config_accepts_patch(&self) -> Result<bool, LocalApiError> {
+        let request = TransportRequest::new(Method::OPTIONS, SERVE_CONFIG_ENDPOINT);
+        let response = self.transport.send(request).await?;
+        let status = response.status();
+        let allow = response
+            .headers()
+            .get(ALLOW)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        // Drain the body so the connection can be reused.
+        let _ = response.into_body().collect().await;
+        if status == StatusCode::UNAUTHORIZED {
+            return Err(LocalApiError::HttpStatus {
+                status,
+                method: Method::OPTIONS,
+                path: SERVE_CONFIG_ENDPOINT.to_string(),
+                body: String::new(),
+            });
+        }
+        Ok(allow.is_some_and(|allow| {
+            allow
+                .split(',')
+                .any(|method| method.trim().eq_ignore_ascii_case("PATCH"))
+        }))
+    }
+
     pub async fn watch_ipn_bus(&self) -> Result<WatchIpnBus, LocalApiError> {
         let path = format!("{WATCH_IPN_BUS_ENDPOINT}?mask={}", WATCH_MASK_INITIAL_STATE);
         let request = TransportRequest::new(Method::GET, path);
@@ -112,6 +216,7 @@ impl LocalApiClient {
             if line.trim().is_empty() {
                 continue;
             }
+            dump_line(&line);
             if let Some(id) = parse_session_id(&line)? {
                 session_id = Some(id);
                 break;
@@ -120,18 +225,33 @@ impl LocalApiClient {
 
         let session_id = session_id.ok_or(LocalApiError::MissingSessionId)?;
 
-        let drain_task = tokio::spawn(async move {
-            while let Some(result) = lines.next().await {
-                if let Err(err) = result {
-                    tracing::debug!(error = %err, "watch-ipn-bus stream ended with error");
-                    break;
+        let events = Arc::new(Notify::new());
+        let ended = Arc::new(AtomicBool::new(false));
+        let drain_task = {
+            let events = Arc::clone(&events);
+            let ended = Arc::clone(&ended);
+            tokio::spawn(async move {
+                while let Some(result) = lines.next().await {
+                    match result {
+                        Ok(line) => dump_line(&line),
+                        Err(err) => {
+                            tracing::debug!(error = %err, "watch-ipn-bus stream ended with error");
+                            break;
+                        }
+                    }
+                    events.notify_one();
                 }
-            }
-        });
+                tracing::debug!("watch-ipn-bus stream closed");
+                ended.store(true, Ordering::Relaxed);
+                events.notify_one();
+            })
+        };
 
         Ok(WatchIpnBus {
             session_id,
             drain_task: Some(drain_task),
+            events,
+            ended,
         })
     }
 
@@ -155,7 +275,7 @@ async fn ensure_status_ok(
         return Ok(response);
     }
     let status = response.status();
-    let body = read_body_string(response).await?;
+    let body = read_body_string(response, &path).await?;
     Err(LocalApiError::HttpStatus {
         status,
         method,
@@ -166,23 +286,66 @@ async fn ensure_status_ok(
 
 async fn parse_json_response(
     response: Response<hyper::body::Incoming>,
+    path: &str,
 ) -> Result<Value, LocalApiError> {
     let bytes = response.into_body().collect().await?.to_bytes();
     if bytes.is_empty() {
         return Ok(Value::Null);
     }
     let value = serde_json::from_slice(bytes.as_ref())?;
+    dump_json('<', path, &value);
     Ok(value)
 }
 
+/// Error and probe bodies: short plain-text messages from tailscaled
 async fn read_body_string(
     response: Response<hyper::body::Incoming>,
+    path: &str,
 ) -> Result<String, LocalApiError> {
+    let content_type = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
     let bytes = response.into_body().collect().await?.to_bytes();
     if bytes.is_empty() {
         return Ok("<empty>".to_string());
     }
-    Ok(String::from_utf8_lossy(bytes.as_ref()).to_string())
+    let body = String::from_utf8_lossy(bytes.as_ref()).to_string();
+    if http_dump::enabled() {
+        match serde_json::from_str::<Value>(&body) {
+            Ok(value) => dump_json('<', path, &value),
+            // Error text can quote whatever tailscaled was handed, and has no structure
+            // to redact
+            Err(_) => http_dump::omitted_body('<', path, content_type.as_deref(), bytes.len()),
+        }
+    }
+    Ok(body)
+}
+
+/// `--debug-http`: a LocalAPI document with keys and identities masked
+fn dump_json(direction: char, path: &str, value: &Value) {
+    if !http_dump::enabled() {
+        return;
+    }
+    let redacted = match path {
+        STATUS_ENDPOINT => redact::redact_status(value),
+        SERVE_CONFIG_ENDPOINT => redact::redact_serve_config(value),
+        PREFS_ENDPOINT => redact::redact_prefs(value),
+        _ => redact::redact_value(value),
+    };
+    http_dump::body(direction, path, &redacted.to_string());
+}
+
+/// One watch-ipn-bus notification; netmaps carry the same keys as status
+fn dump_line(line: &str) {
+    if !http_dump::enabled() {
+        return;
+    }
+    match serde_json::from_str::<Value>(line) {
+        Ok(value) => dump_json('<', WATCH_IPN_BUS_ENDPOINT, &value),
+        Err(_) => http_dump::body('<', WATCH_IPN_BUS_ENDPOINT, "[unparsable line omitted]"),
+    }
 }
 
 fn parse_session_id(line: &str) -> Result<Option<String>, LocalApiError> {
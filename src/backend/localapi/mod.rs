@@ -1,33 +1,100 @@
+mod capabilities;
 mod client;
+mod redact;
+mod status_cache;
 
+use std::collections::{BTreeMap, HashSet};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use chrono::Utc;
+use rand::Rng;
 use serde_json::Value;
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 use tokio::time::{timeout, Duration};
 
-use crate::backend::{Backend, BackendStatus};
+use crate::backend::{
+    funnel_port_denied, operator, Backend, BackendStatus, FUNNEL_DISABLED, PREVIEW_SESSION,
+};
 use crate::core::{
-    apply_patch, detect_conflicts, LocalTarget, ServeConfig, TunnelResult, TunnelSpec,
+    alternative_paths, apply_background_patch, apply_patch, foreground_route_state, list_routes,
+    remove_routes, restore_snapshot, BackupStore, ConflictIndex, LocalTarget, RestoreOutcome,
+    Route, RouteState, ServeConfig, TunnelResult, TunnelSpec, ALLOWED_HTTPS_PORTS,
 };
-use crate::error::{FunnelError, Result};
+use crate::error::{ConflictEntry, FunnelError, Result};
+use crate::lock::ApplyMutex;
 use crate::net::{LocalApiError, LocalApiTransport};
+use crate::progress::{NoProgress, Phase, Progress};
+use capabilities::{Capabilities, CapabilityCache};
+use status_cache::StatusCache;
+
+pub use status_cache::DEFAULT_TTL as DEFAULT_STATUS_CACHE_TTL;
 
 pub use client::{LocalApiClient, WatchIpnBus};
 
-const MIN_SUPPORTED_VERSION: (u32, u32, u32) = (1, 50, 0);
+pub const MIN_SUPPORTED_VERSION: (u32, u32, u32) = (1, 50, 0);
+/// How long an apply or serve-config transaction may take before it is abandoned
+pub const DEFAULT_APPLY_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long undoing an abandoned write may take; the daemon is already misbehaving
+const UNDO_TIMEOUT: Duration = Duration::from_secs(5);
+/// Raised once per run by the first write without an ETag under `--no-etag`
+const NO_ETAG_WARNING: &str = "tailscaled sends no ServeConfig ETag; writing without If-Match, so a concurrent serve change may be overwritten.";
 const SOCKET_CANDIDATES: &[&str] = &[
     "/var/run/tailscale/tailscaled.sock",
     "/run/tailscale/tailscaled.sock",
 ];
+/// Where other tailscaled setups listen: the container image's `TS_SOCKET` default and
+/// the open-source macOS daemon. Never picked automatically; doctor looks for a second
+/// daemon here.
+const OTHER_SOCKET_CANDIDATES: &[&str] = &["/tmp/tailscaled.sock", "/var/run/tailscaled.socket"];
+/// Node attribute that limits Funnel to some ports; the list follows `ports=`
+const FUNNEL_PORTS_CAP: &str = "https://tailscale.com/cap/funnel-ports?ports=";
 
 pub struct LocalApiBackend {
     client: LocalApiClient,
     watch: Mutex<Option<WatchIpnBus>>,
+    applied: Mutex<Option<AppliedRoute>>,
     force: bool,
+    etag_fallback: bool,
+    /// False when the target is on another node, so this machine cannot connect to it
+    check_target: bool,
+    /// `--skip-checks`: only the serve config write gates an apply
+    skip_checks: bool,
+    status_cache: Option<StatusCache>,
+    /// The last ServeConfig read with an ETag, reused while tailscaled answers `304`
+    last_config: std::sync::Mutex<Option<(String, ServeConfig)>>,
+    warned_no_etag: AtomicBool,
+    /// Raised by the backend, handed out by `take_warnings`
+    warnings: std::sync::Mutex<Vec<String>>,
+    /// Set when the capability probe found that serve-config takes `PATCH`; writes
+    /// after that send only the delta
+    delta_writes: AtomicBool,
+    apply_timeout: Duration,
+    /// A ServeConfig write sent but not yet answered; undone if the deadline passes
+    pending_write: std::sync::Mutex<Option<PendingWrite>>,
+}
+
+struct PendingWrite {
+    previous: ServeConfig,
+    written: ServeConfig,
+}
+
+/// What became of a write abandoned at the deadline
+enum Undo {
+    NotApplied,
+    RolledBack,
+    /// Neither what was there before nor what was written; someone else changed it
+    ChangedSince,
+}
+
+/// What `apply` wrote for the foreground session, kept to check and restore it later
+#[derive(Clone)]
+struct AppliedRoute {
+    spec: TunnelSpec,
+    session_id: String,
+    host_ports: Vec<String>,
 }
 
 impl LocalApiBackend {
@@ -35,10 +102,61 @@ impl LocalApiBackend {
         Self {
             client: LocalApiClient::new(transport),
             watch: Mutex::new(None),
+            applied: Mutex::new(None),
             force,
+            etag_fallback: false,
+            check_target: true,
+            skip_checks: false,
+            status_cache: None,
+            last_config: std::sync::Mutex::new(None),
+            warned_no_etag: AtomicBool::new(false),
+            warnings: std::sync::Mutex::new(Vec::new()),
+            delta_writes: AtomicBool::new(false),
+            apply_timeout: DEFAULT_APPLY_TIMEOUT,
+            pending_write: std::sync::Mutex::new(None),
         }
     }
 
+    /// Deadline for one apply or serve-config transaction, so a wedged tailscaled cannot
+    /// keep the locks held forever
+    pub fn with_apply_timeout(mut self, apply_timeout: Duration) -> Self {
+        self.apply_timeout = apply_timeout;
+        self
+    }
+
+    /// Lets ServeConfig writes go ahead without If-Match on daemons that send no ETag
+    /// (`--no-etag`), accepting that a concurrent edit between read and write is lost
+    pub fn with_etag_fallback(mut self, enabled: bool) -> Self {
+        self.etag_fallback = enabled;
+        self
+    }
+
+    /// Applies without the target, version, capability, HTTPS, Funnel and CertDomains
+    /// checks, for daemons that work but report themselves oddly
+    pub fn with_skip_checks(mut self, skip: bool) -> Self {
+        self.skip_checks = skip;
+        self
+    }
+
+    /// Reuses node status fetched by earlier runs within `ttl`; zero turns this off
+    pub fn with_status_cache(mut self, ttl: Duration) -> Self {
+        self.status_cache = StatusCache::open(self.client.transport().cache_key(), ttl);
+        self
+    }
+
+    /// For a remote node's tailscaled: the target port is on that node, out of reach of
+    /// the liveness check before applying
+    pub fn for_remote_node(mut self) -> Self {
+        self.check_target = false;
+        self
+    }
+
+    /// `false` leaves the target port alone, for checks run where the targets are not up
+    pub fn with_target_check(mut self, check: bool) -> Self {
+        self.check_target = self.check_target && check;
+        self
+    }
+
     pub fn build_transport(
         socket: Option<PathBuf>,
         localapi_port: Option<u16>,
@@ -75,7 +193,48 @@ impl LocalApiBackend {
         })
     }
 
+    /// Transport to `node`'s relay. A bare node name gets the tailnet suffix of the local
+    /// node's DNS name, since the relay's certificate is for the full name.
+    pub async fn build_remote_transport(
+        node: &str,
+        port: u16,
+        token_file: PathBuf,
+        local: Option<&dyn Backend>,
+    ) -> Result<LocalApiTransport> {
+        let host = remote_host(node, local).await?;
+        LocalApiTransport::remote(host, port, token_file).map_err(map_transport_error)
+    }
+
+    /// The socket used when `--socket` is not given
+    pub fn default_socket() -> Option<PathBuf> {
+        find_first_socket()
+    }
+
+    /// Every existing socket a tailscaled might listen on, starting with `explicit` and
+    /// `$TS_SOCKET`. Paths that resolve to the same file are listed once.
+    pub fn socket_candidates(explicit: Option<&Path>) -> Vec<PathBuf> {
+        let mut seen = HashSet::new();
+        explicit
+            .map(Path::to_path_buf)
+            .into_iter()
+            .chain(std::env::var_os("TS_SOCKET").map(PathBuf::from))
+            .chain(
+                SOCKET_CANDIDATES
+                    .iter()
+                    .chain(OTHER_SOCKET_CANDIDATES)
+                    .map(PathBuf::from),
+            )
+            .filter(|path| path.exists())
+            .filter(|path| {
+                seen.insert(std::fs::canonicalize(path).unwrap_or_else(|_| path.clone()))
+            })
+            .collect()
+    }
+
     async fn check_port_liveness(&self, target: &LocalTarget) -> Result<()> {
+        if !self.check_target || self.skip_checks {
+            return Ok(());
+        }
         let addr = resolve_socket_addr(target).await?;
         let result = timeout(Duration::from_secs(2), TcpStream::connect(addr)).await;
         match result {
@@ -91,7 +250,18 @@ impl LocalApiBackend {
         }
     }
 
-    async fn fetch_status(&self) -> Result<BackendStatus> {
+    /// Node status alone, from the status cache when it is fresh. Unlike `status`, it
+    /// neither reads the serve config nor probes write access.
+    pub async fn fetch_status(&self) -> Result<BackendStatus> {
+        if let Some(status) = self
+            .status_cache
+            .as_ref()
+            .and_then(|cache| cache.get(Utc::now()))
+        {
+            tracing::debug!("using cached node status");
+            return Ok(status);
+        }
+
         let value = self
             .client
             .get_status()
@@ -104,38 +274,148 @@ impl LocalApiBackend {
             .map(str::to_string);
 
         let dns_name = parse_dns_name(&value);
+        let cert_domains = parse_cert_domains(&value);
         let https_enabled = parse_https_enabled(&value);
-        let funnel_enabled = parse_funnel_enabled(&value);
+        let funnel_ports = parse_funnel_ports(&value);
+        let magic_dns_enabled = value
+            .pointer("/CurrentTailnet/MagicDNSEnabled")
+            .and_then(Value::as_bool);
+        let tags = value
+            .pointer("/Self/Tags")
+            .and_then(Value::as_array)
+            .map(|tags| {
+                tags.iter()
+                    .filter_map(Value::as_str)
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let login_name = parse_login_name(&value);
 
-        Ok(BackendStatus {
+        let status = BackendStatus {
             dns_name,
             version,
             https_enabled,
-            funnel_enabled,
+            funnel_ports,
             permissions_ok: None,
-        })
+            serve_config_readable: None,
+            operator_user: None,
+            magic_dns_enabled,
+            cert_domains,
+            tags,
+            login_name,
+        };
+        if let Some(cache) = &self.status_cache {
+            cache.put(&status, Utc::now());
+        }
+        Ok(status)
     }
-}
 
-#[async_trait::async_trait]
-impl Backend for LocalApiBackend {
-    async fn apply(&self, spec: &TunnelSpec) -> Result<TunnelResult> {
-        let watch = self
-            .client
-            .watch_ipn_bus()
+    /// Optional features of this daemon, from the cache when `version` was probed before
+    async fn capabilities(&self, version: &str) -> Result<Capabilities> {
+        let cache = CapabilityCache::open();
+        if let Some(capabilities) = cache.as_ref().and_then(|cache| cache.get(version)) {
+            tracing::debug!(version, ?capabilities, "using cached LocalAPI capabilities");
+            self.delta_writes
+                .store(capabilities.serve_config_patch, Ordering::Relaxed);
+            return Ok(capabilities);
+        }
+
+        let capabilities = Capabilities::probe(&self.client)
             .await
             .map_err(map_transport_error)?;
-        let session_id = watch.session_id().to_string();
+        tracing::debug!(version, ?capabilities, "probed LocalAPI capabilities");
+        if let Some(cache) = cache {
+            cache.put(version, capabilities);
+        }
+        self.delta_writes
+            .store(capabilities.serve_config_patch, Ordering::Relaxed);
+        Ok(capabilities)
+    }
 
-        self.check_port_liveness(&spec.local_target).await?;
+    /// Patches every `host:port` the spec is served on; conflicts on any of them fail the
+    /// whole write, all reported together with the first `alternative_paths` entry that
+    /// is free on every port. The background config and each foreground session are indexed
+    /// once for all ports.
+    fn patch_all(
+        &self,
+        config: &mut ServeConfig,
+        spec: &TunnelSpec,
+        session_id: &str,
+        host_ports: &[String],
+    ) -> Result<bool> {
+        if !self.force {
+            let background = ConflictIndex::new(config);
+            let sessions = config
+                .foreground
+                .iter()
+                .flatten()
+                // Our own session only matters when restoring, and then it is overwritten.
+                .filter(|(session, _)| *session != session_id)
+                .map(|(session, value)| {
+                    Ok((
+                        session.as_str(),
+                        ConflictIndex::new(&value_to_config(value.clone())?),
+                    ))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let conflicts: Vec<_> = host_ports
+                .iter()
+                .flat_map(|host_port| find_conflicts(&background, &sessions, spec, host_port))
+                .collect();
+            if !conflicts.is_empty() {
+                let suggested_path = alternative_paths(&spec.path).find(|path| {
+                    let mut candidate = spec.clone();
+                    candidate.path = path.clone();
+                    host_ports.iter().all(|host_port| {
+                        find_conflicts(&background, &sessions, &candidate, host_port).is_empty()
+                    })
+                });
+                return Err(FunnelError::Conflicts {
+                    conflicts,
+                    suggested_path,
+                });
+            }
+        }
 
-        let status = self.fetch_status().await?;
-        ensure_version_supported(status.version.as_deref())?;
+        let target = spec.serve_target().to_string();
+        for host_port in host_ports {
+            if spec.detached {
+                apply_background_patch(config, host_port, &spec.path, &target, spec.funnel);
+            } else {
+                apply_patch(
+                    config,
+                    session_id,
+                    host_port,
+                    &spec.path,
+                    &target,
+                    spec.funnel,
+                )?;
+            }
+        }
+        Ok(true)
+    }
 
-        let dns_name = status.dns_name.ok_or_else(|| FunnelError::Prerequisites {
-            source: None,
-            context: "Node not yet assigned DNS name".to_string(),
-        })?;
+    /// The node name `spec` is served under, once HTTPS (and Funnel, when the tunnel is
+    /// public) is usable
+    fn tunnel_dns_name(&self, spec: &TunnelSpec, status: BackendStatus) -> Result<String> {
+        let dns_name = match spec.dns_name.as_deref() {
+            Some(name) => check_dns_name_override(
+                name,
+                status.cert_domains.as_deref(),
+                self.force || self.skip_checks,
+            )?,
+            None => status
+                .dns_name
+                .clone()
+                .ok_or_else(|| FunnelError::Prerequisites {
+                    source: None,
+                    context: "Node not yet assigned DNS name".to_string(),
+                })?,
+        };
+        if self.skip_checks {
+            return Ok(dns_name);
+        }
 
         if status.https_enabled != Some(true) {
             return Err(FunnelError::Prerequisites {
@@ -144,107 +424,205 @@ impl Backend for LocalApiBackend {
             });
         }
 
-        if status.funnel_enabled != Some(true) {
-            return Err(FunnelError::Prerequisites {
+        // Tailnet-only tunnels are plain serve handlers and need no Funnel permission
+        if spec.funnel {
+            if status.funnel_enabled() != Some(true) {
+                return Err(FunnelError::Prerequisites {
+                    source: None,
+                    context: FUNNEL_DISABLED.to_string(),
+                });
+            }
+            let permitted = status.funnel_permitted().unwrap_or_default();
+            if let Some(port) = spec.https_ports().find(|port| !permitted.contains(port)) {
+                return Err(FunnelError::Prerequisites {
+                    source: None,
+                    context: funnel_port_denied(port, &permitted),
+                });
+            }
+        }
+
+        Ok(dns_name)
+    }
+
+    async fn applied_route(&self, lease_id: &str) -> Result<AppliedRoute> {
+        self.applied
+            .lock()
+            .await
+            .clone()
+            .filter(|route| route.session_id == lease_id)
+            .ok_or_else(|| FunnelError::Other(format!("No route applied for lease {}", lease_id)))
+    }
+
+    async fn session_ended(&self) -> bool {
+        self.watch
+            .lock()
+            .await
+            .as_ref()
+            .map_or(true, WatchIpnBus::ended)
+    }
+
+    /// Fails unless `--no-etag` allows unguarded writes; raises a warning once when it does
+    fn ensure_etag_fallback(&self) -> Result<()> {
+        if !self.etag_fallback {
+            return Err(FunnelError::VersionTooOld {
                 source: None,
-                context: "Funnel not enabled in tailnet policy".to_string(),
+                context: "ServeConfig ETag missing; LocalAPI too old (pass --no-etag to write without it)"
+                    .to_string(),
             });
         }
+        if !self.warned_no_etag.swap(true, Ordering::Relaxed) {
+            self.warnings
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .push(NO_ETAG_WARNING.to_string());
+        }
+        Ok(())
+    }
 
-        let host_port = format!("{}:{}", dns_name, spec.https_port);
-
-        let mut attempt = 0u8;
-        loop {
-            attempt += 1;
-            let response = self
+    /// The live ServeConfig and its ETag. Once a read has returned an ETag, the next one
+    /// asks with If-None-Match and reuses the parsed copy on `304`, so polling an
+    /// unchanged config with hundreds of routes neither downloads nor parses it again.
+    async fn read_serve_config(&self) -> Result<(Option<String>, ServeConfig)> {
+        let cached = self
+            .last_config
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone();
+        let response = match cached {
+            Some((etag, config)) => {
+                match self
+                    .client
+                    .get_serve_config_if_changed(&etag)
+                    .await
+                    .map_err(map_transport_error)?
+                {
+                    Some(response) => response,
+                    None => return Ok((Some(etag), config)),
+                }
+            }
+            None => self
                 .client
                 .get_serve_config()
                 .await
-                .map_err(map_transport_error)?;
+                .map_err(map_transport_error)?,
+        };
+        let config = value_to_config(response.config)?;
+        *self
+            .last_config
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) =
+            response.etag.clone().map(|etag| (etag, config.clone()));
+        Ok((response.etag, config))
+    }
 
-            let etag = response.etag.ok_or_else(|| FunnelError::VersionTooOld {
-                source: None,
-                context: "ServeConfig ETag missing; LocalAPI too old".to_string(),
-            })?;
+    /// The `OperatorUser` pref; `None` when unset or unreadable
+    async fn operator_user(&self) -> Option<String> {
+        self.operator_pref().await.flatten()
+    }
 
-            let mut config = value_to_config(response.config)?;
+    /// The `OperatorUser` pref, `Some(None)` when unset; `None` when the prefs are
+    /// unreadable
+    async fn operator_pref(&self) -> Option<Option<String>> {
+        let prefs = match self.client.get_prefs().await {
+            Ok(prefs) => prefs,
+            Err(err) => {
+                tracing::debug!(error = %err, "cannot read prefs for the operator");
+                return None;
+            }
+        };
+        Some(
+            prefs
+                .get("OperatorUser")
+                .and_then(Value::as_str)
+                .filter(|user| !user.is_empty())
+                .map(str::to_string),
+        )
+    }
 
-            match detect_conflicts(
-                &config,
-                &host_port,
-                &spec.path,
-                &spec.local_target.to_string(),
-                spec.funnel,
-            ) {
-                Ok(Some(true)) => {
-                    // Idempotent; no conflict. Still proceed to set foreground config.
-                }
-                Ok(Some(false)) => {}
-                Ok(None) => {}
-                Err(conflict) => {
-                    if !self.force {
-                        return Err(FunnelError::Conflict {
-                            source: None,
-                            context: conflict.describe(),
-                        });
-                    }
-                }
+    /// The error for a refused ServeConfig write, saying who the operator is
+    async fn write_denied(&self) -> FunnelError {
+        let user = operator::current_user();
+        let operator = self.operator_user().await;
+        let detail = match (&operator, &user) {
+            (Some(operator), Some(user)) if operator == user => {
+                format!(", although {} is the tailscale operator", user)
             }
+            (Some(operator), _) => format!(" (the tailscale operator is {})", operator),
+            (None, _) => " (no tailscale operator is set)".to_string(),
+        };
+        FunnelError::Permission {
+            source: None,
+            context: format!(
+                "LocalAPI allows this user to read the serve config but not to change it{}",
+                detail
+            ),
+        }
+    }
 
-            if let Some(foreground) = &config.foreground {
-                for (session, value) in foreground {
-                    let session_config = value_to_config(value.clone())?;
-                    match detect_conflicts(
-                        &session_config,
-                        &host_port,
-                        &spec.path,
-                        &spec.local_target.to_string(),
-                        spec.funnel,
-                    ) {
-                        Ok(None) => continue,
-                        Ok(Some(_)) => {
-                            if !self.force {
-                                return Err(FunnelError::Conflict {
-                                    source: None,
-                                    context: format!(
-                                        "Path {} already in use by foreground session {}",
-                                        spec.path, session
-                                    ),
-                                });
-                            }
-                        }
-                        Err(conflict) => {
-                            if !self.force {
-                                return Err(FunnelError::Conflict {
-                                    source: None,
-                                    context: format!(
-                                        "{} (session {})",
-                                        conflict.describe(),
-                                        session
-                                    ),
-                                });
-                            }
-                        }
-                    }
-                }
+    /// Reads the ServeConfig, lets `mutate` edit it, and writes it back under
+    /// If-Match (bare, with `--no-etag` on a daemon without ETags), retrying when
+    /// another writer got there first. `mutate` returns false when there is nothing
+    /// to write.
+    ///
+    /// Once the capability probe has seen `PATCH` support, only the delta is sent;
+    /// otherwise, or when the daemon refuses the patch, the full config; see SPEC 7.5.
+    async fn update_serve_config<F>(&self, progress: &dyn Progress, mutate: F) -> Result<bool>
+    where
+        F: FnMut(&mut ServeConfig) -> Result<bool> + Send,
+    {
+        match timeout(
+            self.apply_timeout,
+            self.write_serve_config(progress, mutate),
+        )
+        .await
+        {
+            Ok(result) => result,
+            Err(_) => Err(self.abandon_write().await),
+        }
+    }
+
+    async fn write_serve_config<F>(&self, progress: &dyn Progress, mut mutate: F) -> Result<bool>
+    where
+        F: FnMut(&mut ServeConfig) -> Result<bool> + Send,
+    {
+        // Held across read, mutate and write, so other funnelctl processes on this host
+        // wait their turn instead of invalidating our ETag
+        let _mutex = ApplyMutex::acquire(ApplyMutex::WAIT).await;
+        let mut attempt = 0u8;
+        loop {
+            attempt += 1;
+            progress.phase(Phase::ApplyingServeConfig { attempt });
+            let (etag, mut config) = self.read_serve_config().await?;
+            if etag.is_none() {
+                self.ensure_etag_fallback()?;
             }
 
-            apply_patch(
-                &mut config,
-                &session_id,
-                &host_port,
-                &spec.path,
-                &spec.local_target.to_string(),
-                spec.funnel,
-            )?;
+            let previous = config.clone();
+            if !mutate(&mut config)? {
+                return Ok(false);
+            }
+            backup_before_write(&previous);
 
-            let value = serde_json::to_value(config).map_err(|err| FunnelError::ApplyFailed {
+            let value = serde_json::to_value(&config).map_err(|err| FunnelError::ApplyFailed {
                 source: Some(Box::new(err)),
                 context: "Failed to serialize ServeConfig".to_string(),
             })?;
 
-            match self.client.set_serve_config(&value, Some(&etag)).await {
-                Ok(()) => break,
+            let delta = if self.delta_writes.load(Ordering::Relaxed) {
+                serde_json::to_value(&previous)
+                    .ok()
+                    .and_then(|previous| merge_patch(&previous, &value))
+            } else {
+                None
+            };
+            self.set_pending_write(Some(PendingWrite {
+                previous,
+                written: config,
+            }));
+            let result = self.send_write(&value, delta, etag.as_deref()).await;
+            self.set_pending_write(None);
+            match result {
+                Ok(()) => return Ok(true),
                 Err(LocalApiError::HttpStatus { status, .. })
                     if status == hyper::StatusCode::PRECONDITION_FAILED
                         || status == hyper::StatusCode::CONFLICT =>
@@ -257,24 +635,194 @@ impl Backend for LocalApiBackend {
                     }
                     continue;
                 }
+                Err(err) if is_denied(&err) => return Err(self.write_denied().await),
                 Err(err) => return Err(map_transport_error(err)),
             }
         }
+    }
 
-        let mut guard = self.watch.lock().await;
-        *guard = Some(watch);
+    /// Sends `delta` when there is one, falling back to the full `config` if the daemon
+    /// turns the patch down after all
+    async fn send_write(
+        &self,
+        config: &Value,
+        delta: Option<Value>,
+        etag: Option<&str>,
+    ) -> std::result::Result<(), LocalApiError> {
+        let Some(delta) = delta else {
+            return self.client.set_serve_config(config, etag).await;
+        };
+        match self.client.patch_serve_config(&delta, etag).await {
+            Err(LocalApiError::HttpStatus { status, .. })
+                if matches!(
+                    status,
+                    hyper::StatusCode::NOT_FOUND
+                        | hyper::StatusCode::METHOD_NOT_ALLOWED
+                        | hyper::StatusCode::UNSUPPORTED_MEDIA_TYPE
+                        | hyper::StatusCode::NOT_IMPLEMENTED
+                ) =>
+            {
+                tracing::debug!(%status, "serve-config PATCH refused; writing the full config");
+                self.delta_writes.store(false, Ordering::Relaxed);
+                self.client.set_serve_config(config, etag).await
+            }
+            result => result,
+        }
+    }
+
+    fn set_pending_write(&self, write: Option<PendingWrite>) {
+        *self
+            .pending_write
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = write;
+    }
+
+    /// The error for a transaction that ran past its deadline. A write still in flight may
+    /// have landed, so it is undone when tailscaled answers quickly enough to tell.
+    async fn abandon_write(&self) -> FunnelError {
+        let pending = self
+            .pending_write
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        let outcome = match pending {
+            None => "no serve config change was sent",
+            Some(write) => match timeout(UNDO_TIMEOUT, self.undo_write(write)).await {
+                Ok(Ok(Undo::NotApplied)) => "the serve config write did not take effect",
+                Ok(Ok(Undo::RolledBack)) => "the serve config write had taken effect and was rolled back",
+                Ok(Ok(Undo::ChangedSince)) => {
+                    "the serve config has changed since and was left alone; check it with `funnelctl audit`"
+                }
+                Ok(Err(_)) | Err(_) => {
+                    "whether the serve config write took effect is unknown; once tailscaled answers, `funnelctl rollback` restores the config saved before it"
+                }
+            },
+        };
+        tracing::warn!(timeout = ?self.apply_timeout, outcome, "abandoned serve config transaction");
+        FunnelError::ApplyTimeout {
+            source: None,
+            context: format!(
+                "tailscaled did not finish within {}; {}",
+                humantime::format_duration(self.apply_timeout),
+                outcome
+            ),
+        }
+    }
+
+    async fn undo_write(&self, write: PendingWrite) -> Result<Undo> {
+        let response = self
+            .client
+            .get_serve_config()
+            .await
+            .map_err(map_transport_error)?;
+        let current = value_to_config(response.config)?;
+        if current == write.previous {
+            return Ok(Undo::NotApplied);
+        }
+        if current != write.written {
+            return Ok(Undo::ChangedSince);
+        }
+        let value = serde_json::to_value(&write.previous)
+            .map_err(|err| FunnelError::Other(err.to_string()))?;
+        self.client
+            .set_serve_config(&value, response.etag.as_deref())
+            .await
+            .map_err(map_transport_error)?;
+        Ok(Undo::RolledBack)
+    }
+
+    async fn apply_spec(&self, spec: &TunnelSpec, progress: &dyn Progress) -> Result<TunnelResult> {
+        // Detached tunnels live in the background config, so no session ties them to us.
+        let watch = if spec.detached {
+            None
+        } else {
+            progress.phase(Phase::ConnectingWatchBus);
+            Some(
+                self.client
+                    .watch_ipn_bus()
+                    .await
+                    .map_err(map_transport_error)?,
+            )
+        };
+        let session_id = match &watch {
+            Some(watch) => watch.session_id().to_string(),
+            None => generate_detached_id(),
+        };
+
+        if !self.skip_checks {
+            progress.phase(Phase::CheckingTarget);
+            self.check_port_liveness(&spec.local_target).await?;
+        }
+
+        progress.phase(Phase::CheckingPrerequisites);
+        let status = self.fetch_status().await?;
+        if !self.skip_checks {
+            let version = ensure_version_supported(status.version.as_deref())?;
+            let capabilities = self.capabilities(version).await?;
+            if !capabilities.serve_config_etag {
+                self.ensure_etag_fallback()?;
+            }
+        }
+
+        let dns_name = self.tunnel_dns_name(spec, status)?;
+        let host_ports = host_ports(&dns_name, spec);
+
+        self.update_serve_config(progress, |config| {
+            self.patch_all(config, spec, &session_id, &host_ports)
+        })
+        .await?;
+
+        if let Some(watch) = watch {
+            let mut guard = self.watch.lock().await;
+            *guard = Some(watch);
+            *self.applied.lock().await = Some(AppliedRoute {
+                spec: spec.clone(),
+                session_id: session_id.clone(),
+                host_ports,
+            });
+        }
 
         let url = build_url(&dns_name, spec.https_port, &spec.path)?;
+        let mirror_urls = spec
+            .mirror_ports
+            .iter()
+            .map(|port| build_url(&dns_name, *port, &spec.path))
+            .collect::<Result<_>>()?;
 
         Ok(TunnelResult {
             url,
             lease_id: session_id,
             applied_at: Utc::now(),
             expires_at: None,
+            mirror_urls,
         })
     }
+}
+
+#[async_trait::async_trait]
+impl Backend for LocalApiBackend {
+    async fn apply(&self, spec: &TunnelSpec, progress: &dyn Progress) -> Result<TunnelResult> {
+        // One deadline for the checks and the write together; the write's own deadline
+        // starts later, so this one fires first and does the undo
+        match timeout(self.apply_timeout, self.apply_spec(spec, progress)).await {
+            Ok(result) => result,
+            Err(_) => Err(self.abandon_write().await),
+        }
+    }
+
+    async fn preview(&self, spec: &TunnelSpec, config: &mut ServeConfig) -> Result<url::Url> {
+        self.check_port_liveness(&spec.local_target).await?;
+        let status = self.fetch_status().await?;
+        if !self.skip_checks {
+            ensure_version_supported(status.version.as_deref())?;
+        }
+        let dns_name = self.tunnel_dns_name(spec, status)?;
+        self.patch_all(config, spec, PREVIEW_SESSION, &host_ports(&dns_name, spec))?;
+        build_url(&dns_name, spec.https_port, &spec.path)
+    }
 
     async fn remove(&self, _lease_id: &str) -> Result<()> {
+        *self.applied.lock().await = None;
         let mut guard = self.watch.lock().await;
         if let Some(mut watch) = guard.take() {
             watch.close();
@@ -282,24 +830,248 @@ impl Backend for LocalApiBackend {
         Ok(())
     }
 
+    async fn node_status(&self) -> Result<BackendStatus> {
+        self.fetch_status().await
+    }
+
     async fn status(&self) -> Result<BackendStatus> {
         let mut status = self.fetch_status().await?;
 
+        // tailscaled lets any local user read, but only root and the operator write.
+        // Nothing is written to find out: a refused write shows up as a 403 on the
+        // command that makes it.
         match self.client.get_serve_config().await {
-            Ok(_) => status.permissions_ok = Some(true),
-            Err(LocalApiError::HttpStatus { status: code, .. })
-                if code == hyper::StatusCode::FORBIDDEN
-                    || code == hyper::StatusCode::UNAUTHORIZED =>
-            {
+            Ok(_) => {
+                status.serve_config_readable = Some(true);
+                let pref = self.operator_pref().await;
+                status.permissions_ok = may_write(
+                    operator::is_root(),
+                    pref.as_ref().map(Option::as_deref),
+                    operator::current_user().as_deref(),
+                );
+                status.operator_user = pref.flatten();
+            }
+            Err(err) if is_denied(&err) => {
+                status.serve_config_readable = Some(false);
                 status.permissions_ok = Some(false);
+                status.operator_user = self.operator_user().await;
             }
             Err(_) => {
                 status.permissions_ok = None;
+                status.operator_user = self.operator_user().await;
             }
         }
 
         Ok(status)
     }
+
+    async fn serve_config(&self) -> Result<ServeConfig> {
+        Ok(self.read_serve_config().await?.1)
+    }
+
+    async fn routes(&self) -> Result<Vec<Route>> {
+        list_routes(&self.read_serve_config().await?.1)
+    }
+
+    async fn remove_routes(&self, routes: &[Route]) -> Result<usize> {
+        let mut removed = 0;
+        self.update_serve_config(&NoProgress, |config| {
+            removed = remove_routes(config, routes)?;
+            Ok(removed > 0)
+        })
+        .await?;
+        Ok(removed)
+    }
+
+    async fn replace_serve_config(&self, config: &ServeConfig) -> Result<()> {
+        self.update_serve_config(&NoProgress, |live| {
+            if live == config {
+                return Ok(false);
+            }
+            *live = config.clone();
+            Ok(true)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn restore_snapshot(&self, snapshot: &ServeConfig) -> Result<Vec<RestoreOutcome>> {
+        let mut outcomes = Vec::new();
+        self.update_serve_config(&NoProgress, |config| {
+            outcomes = restore_snapshot(config, snapshot, self.force)?;
+            Ok(outcomes
+                .iter()
+                .any(|outcome| matches!(outcome, RestoreOutcome::Restored(_))))
+        })
+        .await?;
+        Ok(outcomes)
+    }
+
+    async fn diagnostics(&self) -> Result<Vec<(String, Value)>> {
+        let status = self
+            .client
+            .get_status()
+            .await
+            .map_err(map_transport_error)?;
+        let serve_config = self
+            .client
+            .get_serve_config()
+            .await
+            .map_err(map_transport_error)?;
+        let mut entries = vec![
+            (
+                "/localapi/v0/status".to_string(),
+                redact::redact_status(&status),
+            ),
+            (
+                "/localapi/v0/serve-config".to_string(),
+                redact::redact_serve_config(&serve_config.config),
+            ),
+        ];
+        if let Some(version) = status.get("Version").and_then(Value::as_str) {
+            let capabilities = self.capabilities(version).await?;
+            entries.push((
+                "capabilities".to_string(),
+                serde_json::to_value(capabilities).unwrap_or(Value::Null),
+            ));
+        }
+        Ok(entries)
+    }
+
+    async fn route_event(&self) {
+        let events = self.watch.lock().await.as_ref().map(WatchIpnBus::events);
+        match events {
+            Some(events) => events.notified().await,
+            None => futures::future::pending().await,
+        }
+    }
+
+    async fn route_state(&self, lease_id: &str) -> Result<RouteState> {
+        let route = self.applied_route(lease_id).await?;
+        if self.session_ended().await {
+            return Ok(RouteState::SessionEnded);
+        }
+        let (_, config) = self.read_serve_config().await?;
+        // With mirror ports, the first one that changed speaks for the route
+        for host_port in &route.host_ports {
+            let state = foreground_route_state(
+                &config,
+                &route.session_id,
+                host_port,
+                &route.spec.path,
+                &route.spec.serve_target().to_string(),
+                route.spec.funnel,
+            )?;
+            if state != RouteState::Intact {
+                return Ok(state);
+            }
+        }
+        Ok(RouteState::Intact)
+    }
+
+    async fn restore_route(&self, lease_id: &str) -> Result<()> {
+        let route = self.applied_route(lease_id).await?;
+        if self.session_ended().await {
+            return Err(FunnelError::ApplyFailed {
+                source: None,
+                context: "tailscaled closed the watch session; restart funnelctl open".to_string(),
+            });
+        }
+        self.update_serve_config(&NoProgress, |config| {
+            self.patch_all(config, &route.spec, &route.session_id, &route.host_ports)
+        })
+        .await?;
+        Ok(())
+    }
+
+    async fn set_route_funnel(&self, lease_id: &str, funnel: bool) -> Result<()> {
+        let mut route = self.applied_route(lease_id).await?;
+        if self.session_ended().await {
+            return Err(FunnelError::ApplyFailed {
+                source: None,
+                context: "tailscaled closed the watch session; restart funnelctl open".to_string(),
+            });
+        }
+        route.spec.funnel = funnel;
+        self.update_serve_config(&NoProgress, |config| {
+            self.patch_all(config, &route.spec, &route.session_id, &route.host_ports)
+        })
+        .await?;
+        // Later route checks and restores go by the route as it is now served
+        if let Some(applied) = self.applied.lock().await.as_mut() {
+            applied.spec.funnel = funnel;
+        }
+        Ok(())
+    }
+
+    fn take_warnings(&self) -> Vec<String> {
+        std::mem::take(
+            &mut *self
+                .warnings
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        )
+    }
+}
+
+/// Conflicts of `spec` on `host_port` with background handlers, and with any handler of
+/// another foreground session, even an identical one
+fn find_conflicts(
+    background: &ConflictIndex,
+    sessions: &[(&str, ConflictIndex)],
+    spec: &TunnelSpec,
+    host_port: &str,
+) -> Vec<ConflictEntry> {
+    let target = spec.serve_target().to_string();
+    let mut found = Vec::new();
+    if let Err(conflicts) = background.check(host_port, &spec.path, &target, spec.funnel) {
+        found.extend(conflicts.iter().map(|c| c.entry(host_port, None)));
+    }
+    for (session, index) in sessions {
+        match index.check(host_port, &spec.path, &target, spec.funnel) {
+            Ok(None) => {}
+            Ok(Some(_)) => found.push(ConflictEntry {
+                kind: "in_use".to_string(),
+                host_port: host_port.to_string(),
+                path: spec.path.clone(),
+                existing_path: spec.path.clone(),
+                existing_target: target.clone(),
+                session: Some(session.to_string()),
+                message: format!(
+                    "Path {} already in use by foreground session {}",
+                    spec.path, session
+                ),
+            }),
+            Err(conflicts) => {
+                found.extend(conflicts.iter().map(|c| c.entry(host_port, Some(session))))
+            }
+        }
+    }
+    found
+}
+
+/// Saves the config about to be replaced for `funnelctl rollback`. Best effort: a broken
+/// state dir must not block the write.
+fn backup_before_write(config: &ServeConfig) {
+    if let Err(err) = BackupStore::open().and_then(|store| store.save(config, Utc::now())) {
+        tracing::warn!(error = %err, "failed to back up serve config");
+    }
+}
+
+/// Lease id for a detached tunnel; there is no WatchIPNBus session to borrow one from
+fn generate_detached_id() -> String {
+    let token: String = rand::thread_rng()
+        .sample_iter(&rand::distributions::Alphanumeric)
+        .take(16)
+        .map(|byte| char::from(byte).to_ascii_lowercase())
+        .collect();
+    format!("detached-{}", token)
+}
+
+fn host_ports(dns_name: &str, spec: &TunnelSpec) -> Vec<String> {
+    spec.https_ports()
+        .map(|port| format!("{}:{}", dns_name, port))
+        .collect()
 }
 
 fn find_first_socket() -> Option<PathBuf> {
@@ -309,6 +1081,41 @@ fn find_first_socket() -> Option<PathBuf> {
         .find(|path| path.exists())
 }
 
+/// The JSON merge patch (RFC 7396) that turns `old` into `new`. `None` when `new`
+/// holds a `null`, which a merge patch would read as "delete".
+fn merge_patch(old: &Value, new: &Value) -> Option<Value> {
+    fn has_null(value: &Value) -> bool {
+        match value {
+            Value::Null => true,
+            Value::Object(map) => map.values().any(has_null),
+            Value::Array(items) => items.iter().any(has_null),
+            _ => false,
+        }
+    }
+    fn diff(old: &Value, new: &Value) -> Value {
+        let (Value::Object(old), Value::Object(new)) = (old, new) else {
+            return new.clone();
+        };
+        let mut patch = serde_json::Map::new();
+        for key in old.keys().filter(|key| !new.contains_key(*key)) {
+            patch.insert(key.clone(), Value::Null);
+        }
+        for (key, value) in new {
+            match old.get(key) {
+                Some(previous) if previous == value => {}
+                Some(previous) => {
+                    patch.insert(key.clone(), diff(previous, value));
+                }
+                None => {
+                    patch.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        Value::Object(patch)
+    }
+    (!has_null(new)).then(|| diff(old, new))
+}
+
 fn value_to_config(value: Value) -> Result<ServeConfig> {
     if value.is_null() {
         return Ok(ServeConfig::new());
@@ -345,11 +1152,58 @@ fn parse_dns_name(value: &Value) -> Option<String> {
     }
 }
 
-fn parse_https_enabled(value: &Value) -> Option<bool> {
-    if let Some(domains) = value.pointer("/Self/CertDomains").and_then(Value::as_array) {
-        return Some(!domains.is_empty());
+/// `--dns-name` must be one of the node's certificate domains, or tailscaled has no
+/// certificate to serve it with; `--force` skips the check
+fn check_dns_name_override(
+    name: &str,
+    cert_domains: Option<&[String]>,
+    force: bool,
+) -> Result<String> {
+    let known = cert_domains.unwrap_or_default();
+    if force || known.iter().any(|domain| domain == name) {
+        return Ok(name.to_string());
     }
-    if let Some(domains) = value.get("CertDomains").and_then(Value::as_array) {
+    let listed = if known.is_empty() {
+        "none reported".to_string()
+    } else {
+        known.join(", ")
+    };
+    Err(FunnelError::Prerequisites {
+        source: None,
+        context: format!(
+            "DNS name '{}' is not in this node's CertDomains ({}); pass --force to use it anyway",
+            name, listed
+        ),
+    })
+}
+
+/// The owner's login from the `User` map; tagged nodes are owned by `tagged-devices`
+fn parse_login_name(value: &Value) -> Option<String> {
+    let user_id = value.pointer("/Self/UserID").and_then(Value::as_i64)?;
+    let login = value
+        .get("User")?
+        .get(user_id.to_string())?
+        .get("LoginName")?
+        .as_str()?;
+    (!login.is_empty() && login != "tagged-devices").then(|| login.to_string())
+}
+
+fn parse_cert_domains(value: &Value) -> Option<Vec<String>> {
+    let domains = value
+        .pointer("/Self/CertDomains")
+        .or_else(|| value.get("CertDomains"))
+        .and_then(Value::as_array)?;
+    Some(
+        domains
+            .iter()
+            .filter_map(Value::as_str)
+            .map(|domain| trim_trailing_dot(domain).to_ascii_lowercase())
+            .collect(),
+    )
+}
+
+fn parse_https_enabled(value: &Value) -> Option<bool> {
+    if let Some(domains) = parse_cert_domains(value) {
         return Some(!domains.is_empty());
     }
     value.pointer("/Self/HTTPS").and_then(Value::as_bool)
@@ -367,6 +1221,10 @@ fn parse_funnel_enabled(value: &Value) -> Option<bool> {
         return Some(enabled);
     }
 
+    if let Some(cap_map) = value.pointer("/Self/CapMap").and_then(Value::as_object) {
+        return Some(cap_map.contains_key("funnel"));
+    }
+
     if let Some(capabilities) = value
         .pointer("/Self/Capabilities")
         .and_then(Value::as_array)
@@ -392,11 +1250,72 @@ fn parse_funnel_enabled(value: &Value) -> Option<bool> {
     None
 }
 
+/// Funnel permission per HTTPS port: the `funnel` attribute turns it on, and a
+/// `funnel-ports` attribute, when present, narrows it to the ports it lists
+fn parse_funnel_ports(value: &Value) -> Option<BTreeMap<u16, bool>> {
+    let enabled = parse_funnel_enabled(value);
+    let restricted = parse_funnel_port_attr(value);
+    if enabled.is_none() && restricted.is_none() {
+        return None;
+    }
+    Some(
+        ALLOWED_HTTPS_PORTS
+            .iter()
+            .map(|port| {
+                let allowed = enabled != Some(false)
+                    && restricted
+                        .as_ref()
+                        .map_or(true, |ports| ports.contains(port));
+                (*port, allowed)
+            })
+            .collect(),
+    )
+}
+
+/// The ports in the `funnel-ports` node attribute (`...?ports=443,8443` or ranges such
+/// as `8000-9000`), narrowed to those Funnel accepts at all. It shows up as a `CapMap`
+/// key, or as a `Capabilities` entry on older daemons.
+fn parse_funnel_port_attr(value: &Value) -> Option<Vec<u16>> {
+    let cap_map = value
+        .pointer("/Self/CapMap")
+        .and_then(Value::as_object)
+        .into_iter()
+        .flat_map(|map| map.keys().map(String::as_str));
+    let capabilities = value
+        .pointer("/Self/Capabilities")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str);
+    let ports = cap_map
+        .chain(capabilities)
+        .find_map(|cap| cap.strip_prefix(FUNNEL_PORTS_CAP))?;
+
+    let ranges: Vec<(u16, u16)> = ports
+        .split(',')
+        .filter_map(|entry| {
+            let (low, high) = entry.split_once('-').unwrap_or((entry, entry));
+            Some((low.trim().parse().ok()?, high.trim().parse().ok()?))
+        })
+        .collect();
+    Some(
+        ALLOWED_HTTPS_PORTS
+            .iter()
+            .copied()
+            .filter(|port| {
+                ranges
+                    .iter()
+                    .any(|(low, high)| (*low..=*high).contains(port))
+            })
+            .collect(),
+    )
+}
+
 fn trim_trailing_dot(input: &str) -> &str {
     input.strip_suffix('.').unwrap_or(input)
 }
 
-fn ensure_version_supported(version: Option<&str>) -> Result<()> {
+fn ensure_version_supported(version: Option<&str>) -> Result<&str> {
     let version = version.ok_or_else(|| FunnelError::VersionTooOld {
         source: None,
         context: "tailscaled version missing".to_string(),
@@ -411,7 +1330,7 @@ fn ensure_version_supported(version: Option<&str>) -> Result<()> {
             context: format!("tailscaled version {} is not supported", version),
         });
     }
-    Ok(())
+    Ok(version)
 }
 
 fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
@@ -422,18 +1341,67 @@ fn parse_version(version: &str) -> Option<(u32, u32, u32)> {
     Some((major, minor, patch))
 }
 
+/// The public URL for a route. A unicode `dns_name` is converted to punycode and the path
+/// is percent-encoded, so the result can be pasted as is.
 fn build_url(dns_name: &str, https_port: u16, path: &str) -> Result<url::Url> {
-    let base = if https_port == 443 {
-        format!("https://{}", dns_name)
-    } else {
-        format!("https://{}:{}", dns_name, https_port)
+    let host = match url::Host::parse(dns_name) {
+        Ok(url::Host::Domain(host)) => host,
+        Ok(_) | Err(_) => {
+            return Err(FunnelError::Other(format!(
+                "Failed to build URL: '{}' is not a DNS name",
+                dns_name
+            )))
+        }
     };
-    let mut url = url::Url::parse(&base)
+    let mut url = url::Url::parse(&format!("https://{}", host))
         .map_err(|err| FunnelError::Other(format!("Failed to build URL: {}", err)))?;
-    url.set_path(path);
+    // 443 is the https default, which `set_port` leaves out
+    url.set_port(Some(https_port))
+        .map_err(|_| FunnelError::Other("Failed to build URL: cannot set port".to_string()))?;
+    url.set_path(&encode_path(path));
     Ok(url)
 }
 
+/// Percent-encodes everything but `/`, unreserved and sub-delim characters. `set_path`
+/// alone would keep `%` literal and turn `\` into `/`, so a `--lax-path` URL would not
+/// decode back to the served path.
+fn encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'/' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            b'!' | b'$' | b'&' | b'\'' | b'(' | b')' | b'*' | b'+' | b',' | b';' | b'=' | b':'
+            | b'@' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Whether tailscaled will take writes from this user: root always, otherwise only the
+/// operator. `operator` is the `OperatorUser` pref (`Some(None)` when unset, `None` when
+/// the prefs could not be read). Other platforms grant LocalAPI access differently, so
+/// there a non-operator is left undecided.
+fn may_write(root: bool, operator: Option<Option<&str>>, user: Option<&str>) -> Option<bool> {
+    if root {
+        return Some(true);
+    }
+    match (operator?, user) {
+        (Some(operator), Some(user)) if operator == user => Some(true),
+        _ if cfg!(target_os = "linux") => Some(false),
+        _ => None,
+    }
+}
+
+fn is_denied(err: &LocalApiError) -> bool {
+    matches!(
+        err,
+        LocalApiError::HttpStatus { status, .. }
+            if *status == hyper::StatusCode::UNAUTHORIZED || *status == hyper::StatusCode::FORBIDDEN
+    )
+}
+
 fn map_transport_error(err: LocalApiError) -> FunnelError {
     match err {
         LocalApiError::HttpStatus {
@@ -473,6 +1441,13 @@ fn map_transport_error(err: LocalApiError) -> FunnelError {
             "LocalAPI password file {} is empty",
             path.display()
         )),
+        LocalApiError::TokenFile { path, reason } => {
+            FunnelError::InvalidArgument(format!("Remote token file {} {}", path.display(), reason))
+        }
+        LocalApiError::Tls(message) => FunnelError::Unreachable {
+            source: None,
+            context: format!("TLS setup for the remote node failed: {}", message),
+        },
         LocalApiError::MissingSessionId => FunnelError::ApplyFailed {
             source: None,
             context: "watch-ipn-bus did not provide a session id".to_string(),
@@ -504,6 +1479,33 @@ fn map_transport_error(err: LocalApiError) -> FunnelError {
     }
 }
 
+/// The full MagicDNS name for `node`, taking the tailnet suffix from `local`'s DNS name
+async fn remote_host(node: &str, local: Option<&dyn Backend>) -> Result<String> {
+    let node = node.trim_end_matches('.');
+    if node.contains('.') {
+        return Ok(node.to_string());
+    }
+    let local = local.ok_or_else(|| {
+        FunnelError::InvalidArgument(format!(
+            "Give the full MagicDNS name of {} (e.g. {}.tailnet-name.ts.net)",
+            node, node
+        ))
+    })?;
+    let status = local.node_status().await?;
+    let suffix = status
+        .dns_name
+        .as_deref()
+        .and_then(|name| name.trim_end_matches('.').split_once('.'))
+        .map(|(_, suffix)| suffix.to_string())
+        .ok_or_else(|| {
+            FunnelError::InvalidArgument(format!(
+                "This node has no DNS name to take the tailnet suffix from; give the full name of {}",
+                node
+            ))
+        })?;
+    Ok(format!("{}.{}", node, suffix))
+}
+
 async fn resolve_socket_addr(target: &LocalTarget) -> Result<SocketAddr> {
     let host = target.bind.clone();
     let port = target.port;
@@ -520,3 +1522,416 @@ async fn resolve_socket_addr(target: &LocalTarget) -> Result<SocketAddr> {
             context: format!("No address resolved for {}", target),
         })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_may_write() {
+        assert_eq!(may_write(true, None, None), Some(true));
+        assert_eq!(
+            may_write(false, Some(Some("alice")), Some("alice")),
+            Some(true)
+        );
+        assert_eq!(may_write(false, None, Some("alice")), None);
+        let linux = cfg!(target_os = "linux").then_some(false);
+        assert_eq!(may_write(false, Some(Some("bob")), Some("alice")), linux);
+        assert_eq!(may_write(false, Some(None), Some("alice")), linux);
+    }
+
+    #[tokio::test]
+    async fn test_remote_host_takes_local_tailnet_suffix() {
+        let local = crate::backend::MockBackend::new();
+        assert_eq!(
+            remote_host("build-box", Some(&local)).await.unwrap(),
+            "build-box.tail0000.ts.net"
+        );
+        assert_eq!(
+            remote_host("build-box.other.ts.net.", None).await.unwrap(),
+            "build-box.other.ts.net"
+        );
+        assert!(remote_host("build-box", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_serve_config_is_not_downloaded_again() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("tailscaled.sock");
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let server = tokio::spawn(async move {
+            let body = r#"{"Web":{"node.ts.net:443":{"Handlers":{"/a":{"Proxy":"http://127.0.0.1:3000"}}}}}"#;
+            let mut requests = Vec::new();
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 4096];
+                let n = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                let response = if request.contains("if-none-match: \"v1\"") {
+                    "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\nContent-Length: 0\r\n\r\n".to_string()
+                } else {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                };
+                stream.write_all(response.as_bytes()).await.unwrap();
+                requests.push(request);
+            }
+            requests
+        });
+
+        let backend = LocalApiBackend::new(LocalApiTransport::unix_socket(socket), false);
+        assert_eq!(backend.routes().await.unwrap().len(), 1);
+        assert_eq!(backend.routes().await.unwrap().len(), 1);
+        let requests = server.await.unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+    }
+
+    #[tokio::test]
+    async fn test_write_without_etag_raises_one_warning() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        crate::dirs::set_state_dir(tempfile::tempdir().unwrap().keep()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("tailscaled.sock");
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let requests = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        tokio::spawn(async move {
+            // An old daemon: no ETag on reads, writes always accepted
+            let body = r#"{"Web":{"node.ts.net:443":{"Handlers":{"/a":{"Proxy":"http://127.0.0.1:3000"}}}}}"#;
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 8192];
+                let n = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+                let response = if request.starts_with("get ") {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                } else {
+                    "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n".to_string()
+                };
+                stream.write_all(response.as_bytes()).await.unwrap();
+                seen.lock().unwrap().push(request);
+            }
+        });
+        let written = |requests: &std::sync::Mutex<Vec<String>>| {
+            requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|request| !request.starts_with("get "))
+                .count()
+        };
+
+        let guarded = LocalApiBackend::new(LocalApiTransport::unix_socket(socket.clone()), false);
+        let err = guarded
+            .replace_serve_config(&ServeConfig::default())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, FunnelError::VersionTooOld { .. }),
+            "{:?}",
+            err
+        );
+        assert_eq!(written(&requests), 0);
+        assert!(guarded.take_warnings().is_empty());
+
+        let unguarded = LocalApiBackend::new(LocalApiTransport::unix_socket(socket), false)
+            .with_etag_fallback(true);
+        unguarded
+            .replace_serve_config(&ServeConfig::default())
+            .await
+            .unwrap();
+        unguarded
+            .replace_serve_config(&ServeConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(written(&requests), 2);
+        assert!(requests
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|request| !request.contains("if-match")));
+        // Handed to the caller once, not printed by the backend
+        assert_eq!(unguarded.take_warnings(), vec![NO_ETAG_WARNING.to_string()]);
+        assert!(unguarded.take_warnings().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_write_landing_after_deadline_is_rolled_back() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // The write is backed up first; other tests may still be using the state dir
+        crate::dirs::set_state_dir(tempfile::tempdir().unwrap().keep()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("tailscaled.sock");
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let server = tokio::spawn(async move {
+            let original = r#"{"Web":{"node.ts.net:443":{"Handlers":{"/a":{"Proxy":"http://127.0.0.1:3000"}}}}}"#;
+            let respond = |etag: &str, body: &str| {
+                format!(
+                    "HTTP/1.1 200 OK\r\nETag: \"{}\"\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                    etag,
+                    body.len(),
+                    body
+                )
+            };
+            let read = |mut stream: tokio::net::UnixStream| async move {
+                let mut buf = vec![0; 8192];
+                let n = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                let body = request.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+                (stream, body)
+            };
+
+            let (mut stream, _) = read(listener.accept().await.unwrap().0).await;
+            stream
+                .write_all(respond("v1", original).as_bytes())
+                .await
+                .unwrap();
+            // The write lands, but tailscaled never answers it
+            let (_hung, written) = read(listener.accept().await.unwrap().0).await;
+            let (mut stream, _) = read(listener.accept().await.unwrap().0).await;
+            stream
+                .write_all(respond("v2", &written).as_bytes())
+                .await
+                .unwrap();
+            let (mut stream, restored) = read(listener.accept().await.unwrap().0).await;
+            stream
+                .write_all(respond("v3", "").as_bytes())
+                .await
+                .unwrap();
+            (written, restored)
+        });
+
+        let backend = LocalApiBackend::new(LocalApiTransport::unix_socket(socket), false)
+            .with_apply_timeout(Duration::from_millis(300));
+        let route = Route {
+            host_port: "node.ts.net:443".to_string(),
+            https_port: Some(443),
+            path: "/a".to_string(),
+            target: "http://127.0.0.1:3000".to_string(),
+            funnel: false,
+            session: None,
+            owned: false,
+        };
+        let err = backend.remove_routes(&[route]).await.unwrap_err();
+        assert_eq!(err.code(), crate::error::ErrorCode::ApplyTimeout);
+        assert!(err.format_detailed(false).contains("was rolled back"));
+
+        let (written, restored) = server.await.unwrap();
+        assert!(!written.contains("/a"));
+        assert!(restored.contains("/a"));
+    }
+
+    #[test]
+    fn test_merge_patch_sends_only_changes() {
+        let old = serde_json::json!({
+            "Web": {"node.ts.net:443": {"Handlers": {"/a": {"Proxy": "3000"}}}},
+            "AllowFunnel": {"node.ts.net:443": true}
+        });
+        let new = serde_json::json!({
+            "Web": {"node.ts.net:443": {"Handlers": {"/b": {"Proxy": "4000"}}}},
+            "AllowFunnel": {"node.ts.net:443": true}
+        });
+        assert_eq!(
+            merge_patch(&old, &new),
+            Some(serde_json::json!({
+                "Web": {"node.ts.net:443": {"Handlers": {"/a": null, "/b": {"Proxy": "4000"}}}}
+            }))
+        );
+        assert_eq!(merge_patch(&old, &old), Some(serde_json::json!({})));
+        assert_eq!(merge_patch(&old, &serde_json::json!({"X": null})), None);
+    }
+
+    #[tokio::test]
+    async fn test_refused_patch_falls_back_to_full_write() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        crate::dirs::set_state_dir(tempfile::tempdir().unwrap().keep()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("tailscaled.sock");
+        let listener = tokio::net::UnixListener::bind(&socket).unwrap();
+        let server = tokio::spawn(async move {
+            let original = r#"{"Web":{"node.ts.net:443":{"Handlers":{"/a":{"Proxy":"http://127.0.0.1:3000"}}}}}"#;
+            let responses = [
+                format!(
+                    "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+                    original.len(),
+                    original
+                ),
+                "HTTP/1.1 405 Method Not Allowed\r\nConnection: close\r\nContent-Length: 0\r\n\r\n"
+                    .to_string(),
+                "HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n".to_string(),
+            ];
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 8192];
+                let n = stream.read(&mut buf).await.unwrap();
+                requests.push(String::from_utf8_lossy(&buf[..n]).to_string());
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+
+        let backend = LocalApiBackend::new(LocalApiTransport::unix_socket(socket), false);
+        backend.delta_writes.store(true, Ordering::Relaxed);
+        let route = Route {
+            host_port: "node.ts.net:443".to_string(),
+            https_port: Some(443),
+            path: "/a".to_string(),
+            target: "http://127.0.0.1:3000".to_string(),
+            funnel: false,
+            session: None,
+            owned: false,
+        };
+        backend.remove_routes(&[route]).await.unwrap();
+        assert!(!backend.delta_writes.load(Ordering::Relaxed));
+
+        let requests = server.await.unwrap();
+        let body = |request: &str| request.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+        assert!(requests[1].starts_with("PATCH /localapi/v0/serve-config"));
+        assert!(requests[1]
+            .to_lowercase()
+            .contains("content-type: application/merge-patch+json"));
+        assert!(requests[1].to_lowercase().contains("if-match: \"v1\""));
+        assert_eq!(body(&requests[1]), r#"{"Web":null}"#);
+        assert!(requests[2].starts_with("POST /localapi/v0/serve-config"));
+        assert_eq!(body(&requests[2]), "{}");
+    }
+
+    #[test]
+    fn test_parse_cert_domains() {
+        let value = serde_json::json!({
+            "Self": { "CertDomains": ["Node.tail1234.ts.net.", "hooks.example.com"] }
+        });
+        assert_eq!(
+            parse_cert_domains(&value),
+            Some(vec![
+                "node.tail1234.ts.net".to_string(),
+                "hooks.example.com".to_string()
+            ])
+        );
+        assert_eq!(parse_cert_domains(&serde_json::json!({})), None);
+    }
+
+    #[test]
+    fn test_parse_login_name() {
+        let value = serde_json::json!({
+            "Self": { "UserID": 42 },
+            "User": { "42": { "LoginName": "alice@example.com" } }
+        });
+        assert_eq!(
+            parse_login_name(&value).as_deref(),
+            Some("alice@example.com")
+        );
+        let tagged = serde_json::json!({
+            "Self": { "UserID": 7, "Tags": ["tag:server"] },
+            "User": { "7": { "LoginName": "tagged-devices" } }
+        });
+        assert_eq!(parse_login_name(&tagged), None);
+    }
+
+    #[test]
+    fn test_parse_funnel_ports() {
+        let value = serde_json::json!({
+            "Self": { "CapMap": {
+                "funnel": null,
+                "https://tailscale.com/cap/funnel-ports?ports=8443,9000-10100": null
+            } }
+        });
+        let ports =
+            |allowed: [bool; 3]| Some(ALLOWED_HTTPS_PORTS.iter().copied().zip(allowed).collect());
+        assert_eq!(parse_funnel_ports(&value), ports([false, true, true]));
+
+        let value = serde_json::json!({
+            "Self": { "Capabilities": ["funnel", "https://tailscale.com/cap/funnel-ports?ports=443"] }
+        });
+        assert_eq!(parse_funnel_ports(&value), ports([true, false, false]));
+
+        let value = serde_json::json!({ "Self": { "Capabilities": ["funnel"] } });
+        assert_eq!(parse_funnel_ports(&value), ports([true, true, true]));
+
+        let value = serde_json::json!({ "Self": { "CapMap": { "https": null } } });
+        assert_eq!(parse_funnel_ports(&value), ports([false, false, false]));
+        assert_eq!(parse_funnel_ports(&serde_json::json!({})), None);
+    }
+
+    #[test]
+    fn test_dns_name_override_checked_against_cert_domains() {
+        let domains = vec![
+            "node.tail1234.ts.net".to_string(),
+            "hooks.example.com".to_string(),
+        ];
+        assert_eq!(
+            check_dns_name_override("hooks.example.com", Some(&domains), false).unwrap(),
+            "hooks.example.com"
+        );
+        match check_dns_name_override("other.example.com", Some(&domains), false) {
+            Err(FunnelError::Prerequisites { context, .. }) => {
+                assert!(context.contains("hooks.example.com"))
+            }
+            other => panic!("expected Prerequisites, got {:?}", other),
+        }
+        assert!(check_dns_name_override("other.example.com", None, false).is_err());
+        assert!(check_dns_name_override("other.example.com", None, true).is_ok());
+    }
+
+    #[test]
+    fn test_build_url_plain_path() {
+        let url = build_url("node.tail1234.ts.net", 443, "/funnelctl/abc").unwrap();
+        assert_eq!(url.as_str(), "https://node.tail1234.ts.net/funnelctl/abc");
+        let url = build_url("node.tail1234.ts.net", 8443, "/hook/").unwrap();
+        assert_eq!(url.as_str(), "https://node.tail1234.ts.net:8443/hook/");
+    }
+
+    #[test]
+    fn test_build_url_alternate_ports_with_paths() {
+        let url = build_url("node.tail1234.ts.net", 8443, "/api/v1/hooks~1/a.b_c").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://node.tail1234.ts.net:8443/api/v1/hooks~1/a.b_c"
+        );
+        let url = build_url("node.tail1234.ts.net", 10000, "/a b/x;y=1/").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://node.tail1234.ts.net:10000/a%20b/x;y=1/"
+        );
+        assert_eq!(url.port(), Some(10000));
+    }
+
+    #[test]
+    fn test_build_url_punycode_host() {
+        let url = build_url("bücher.tail1234.ts.net", 443, "/hook").unwrap();
+        assert_eq!(url.as_str(), "https://xn--bcher-kva.tail1234.ts.net/hook");
+        let url = build_url("Node.Tail1234.ts.net", 10000, "/").unwrap();
+        assert_eq!(url.as_str(), "https://node.tail1234.ts.net:10000/");
+    }
+
+    #[test]
+    fn test_build_url_rejects_non_dns_hosts() {
+        assert!(build_url("100.64.0.1", 443, "/hook").is_err());
+        assert!(build_url("bad host", 443, "/hook").is_err());
+        assert!(build_url("", 443, "/hook").is_err());
+    }
+
+    #[test]
+    fn test_build_url_encodes_lax_path() {
+        let url = build_url("node.tail1234.ts.net", 443, "/my hook/100%/a\\b?#/données").unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://node.tail1234.ts.net/my%20hook/100%25/a%5Cb%3F%23/donn%C3%A9es"
+        );
+        assert_eq!(url.path_segments().unwrap().count(), 4);
+    }
+}
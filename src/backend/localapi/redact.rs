@@ -0,0 +1,138 @@
+//! Redaction of raw LocalAPI documents for `doctor -v` bug reports.
+//!
+//! Keys and secrets are masked wherever they appear, peers other than this node are
+//! reduced to a count, and user identities are dropped. The shape of the JSON (which
+//! keys exist, which types they hold) is preserved, since that is what parsing bugs
+//! depend on.
+
+use serde_json::{Map, Value};
+
+const REDACTED: &str = "[redacted]";
+
+/// Keys whose values are masked at any depth
+const SECRET_KEYS: &[&str] = &[
+    "PublicKey",
+    "PrivateKey",
+    "NodeKey",
+    "MachineKey",
+    "DiscoKey",
+    "AuthURL",
+    "Addrs",
+    "CurAddr",
+    "Endpoints",
+    "LoginName",
+    "DisplayName",
+    "ProfilePicURL",
+    "Text",
+];
+
+/// Redacts a `/localapi/v0/status` document
+pub fn redact_status(value: &Value) -> Value {
+    let mut value = redact_value(value);
+    if let Some(object) = value.as_object_mut() {
+        if let Some(peers) = object.get("Peer").and_then(Value::as_object) {
+            let summary = format!("{} peers omitted", peers.len());
+            object.insert("Peer".to_string(), Value::String(summary));
+        }
+        if let Some(users) = object.get("User").and_then(Value::as_object) {
+            let summary = format!("{} users omitted", users.len());
+            object.insert("User".to_string(), Value::String(summary));
+        }
+    }
+    value
+}
+
+/// Redacts a serve-config document
+pub fn redact_serve_config(value: &Value) -> Value {
+    redact_value(value)
+}
+
+/// Redacts a prefs document; `Config` holds the node's private keys and login
+pub fn redact_prefs(value: &Value) -> Value {
+    let mut value = redact_value(value);
+    if let Some(config) = value.get_mut("Config").filter(|config| !config.is_null()) {
+        *config = Value::String(REDACTED.to_string());
+    }
+    value
+}
+
+/// Masks secret keys at any depth, for documents without a dedicated redaction
+pub fn redact_value(value: &Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let redacted: Map<String, Value> = object
+                .iter()
+                .map(|(key, inner)| {
+                    let inner = if SECRET_KEYS.contains(&key.as_str()) && !inner.is_null() {
+                        Value::String(REDACTED.to_string())
+                    } else {
+                        redact_value(inner)
+                    };
+                    (key.clone(), inner)
+                })
+                .collect();
+            Value::Object(redacted)
+        }
+        Value::Array(items) => Value::Array(items.iter().map(redact_value).collect()),
+        other => other.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_redact_status_masks_keys_and_peers() {
+        let status = json!({
+            "Version": "1.66.0",
+            "Self": {
+                "DNSName": "node.tailnet.ts.net.",
+                "PublicKey": "nodekey:abc",
+                "Addrs": ["203.0.113.7:41641"],
+                "CertDomains": ["node.tailnet.ts.net"]
+            },
+            "Peer": {"nodekey:1": {"HostName": "laptop"}, "nodekey:2": {}},
+            "User": {"1": {"LoginName": "me@example.com"}},
+            "CurrentTailnet": {"MagicDNSEnabled": true}
+        });
+
+        let redacted = redact_status(&status);
+        assert_eq!(redacted["Version"], "1.66.0");
+        assert_eq!(redacted["Self"]["DNSName"], "node.tailnet.ts.net.");
+        assert_eq!(redacted["Self"]["PublicKey"], REDACTED);
+        assert_eq!(redacted["Self"]["Addrs"], REDACTED);
+        assert_eq!(redacted["Peer"], "2 peers omitted");
+        assert_eq!(redacted["User"], "1 users omitted");
+        assert_eq!(redacted["CurrentTailnet"]["MagicDNSEnabled"], true);
+        assert!(!redacted.to_string().contains("me@example.com"));
+    }
+
+    #[test]
+    fn test_redact_prefs_drops_persisted_keys() {
+        let prefs = json!({
+            "OperatorUser": "alice",
+            "Config": {"PrivateNodeKey": "privkey:abc", "UserProfile": {"LoginName": "a@b.c"}}
+        });
+
+        let redacted = redact_prefs(&prefs);
+        assert_eq!(redacted["OperatorUser"], "alice");
+        assert_eq!(redacted["Config"], REDACTED);
+    }
+
+    #[test]
+    fn test_redact_serve_config_keeps_shape() {
+        let config = json!({
+            "Web": {"node.ts.net:443": {"Handlers": {
+                "/": {"Proxy": "http://127.0.0.1:3000"},
+                "/motd": {"Text": "secret message"}
+            }}}
+        });
+
+        let redacted = redact_serve_config(&config);
+        let handlers = &redacted["Web"]["node.ts.net:443"]["Handlers"];
+        assert_eq!(handlers["/"]["Proxy"], "http://127.0.0.1:3000");
+        assert_eq!(handlers["/motd"]["Text"], REDACTED);
+    }
+}
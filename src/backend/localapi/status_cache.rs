@@ -0,0 +1,113 @@
+//! Node status shared between invocations for a few seconds, so a shell prompt that runs
+//! funnelctl on every line does not fetch `/localapi/v0/status` each time
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::backend::BackendStatus;
+use crate::dirs;
+
+const CACHE_FILE: &str = "status.json";
+pub const DEFAULT_TTL: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    fetched_at: DateTime<Utc>,
+    status: BackendStatus,
+}
+
+/// Statuses keyed by the daemon they came from (see `LocalApiTransport::cache_key`)
+pub struct StatusCache {
+    path: PathBuf,
+    key: String,
+    ttl: Duration,
+}
+
+impl StatusCache {
+    /// `None` when `ttl` is zero or there is no cache directory
+    pub fn open(key: String, ttl: Duration) -> Option<Self> {
+        if ttl.is_zero() {
+            return None;
+        }
+        match dirs::cache_dir() {
+            Ok(dir) => Some(Self::at(dir.join(CACHE_FILE), key, ttl)),
+            Err(err) => {
+                tracing::debug!(error = %err, "status cache unavailable");
+                None
+            }
+        }
+    }
+
+    pub fn at(path: PathBuf, key: String, ttl: Duration) -> Self {
+        Self { path, key, ttl }
+    }
+
+    /// The cached status, unless it is older than the TTL or from the future
+    pub fn get(&self, now: DateTime<Utc>) -> Option<BackendStatus> {
+        let entry = self.read().remove(&self.key)?;
+        let age = (now - entry.fetched_at).to_std().ok()?;
+        (age < self.ttl).then_some(entry.status)
+    }
+
+    /// Best effort, like the capability cache: a failed write only costs a fetch
+    pub fn put(&self, status: &BackendStatus, now: DateTime<Utc>) {
+        let mut entries = self.read();
+        entries.retain(|_, entry| {
+            (now - entry.fetched_at)
+                .to_std()
+                .is_ok_and(|age| age < self.ttl)
+        });
+        entries.insert(
+            self.key.clone(),
+            Entry {
+                fetched_at: now,
+                status: status.clone(),
+            },
+        );
+        let result = serde_json::to_vec(&entries)
+            .map_err(|err| err.to_string())
+            .and_then(|bytes| {
+                dirs::write_private(&self.path, &bytes).map_err(|err| err.to_string())
+            });
+        if let Err(err) = result {
+            tracing::debug!(error = %err, "failed to write status cache");
+        }
+    }
+
+    fn read(&self) -> BTreeMap<String, Entry> {
+        std::fs::read(&self.path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_expires_and_is_per_daemon() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(CACHE_FILE);
+        let cache = StatusCache::at(path.clone(), "unix:/a.sock".to_string(), DEFAULT_TTL);
+        let other = StatusCache::at(path, "unix:/b.sock".to_string(), DEFAULT_TTL);
+        let now = Utc::now();
+        let status = BackendStatus {
+            dns_name: Some("node.tail0000.ts.net".to_string()),
+            ..BackendStatus::default()
+        };
+
+        assert!(cache.get(now).is_none());
+        cache.put(&status, now);
+        let cached = cache.get(now + chrono::Duration::seconds(1)).unwrap();
+        assert_eq!(cached.dns_name, status.dns_name);
+        assert!(other.get(now).is_none());
+        assert!(cache.get(now + chrono::Duration::seconds(3)).is_none());
+        assert!(cache.get(now - chrono::Duration::seconds(1)).is_none());
+    }
+}
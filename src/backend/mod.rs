@@ -1,51 +1,366 @@
-use crate::core::{TunnelResult, TunnelSpec};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::core::{
+    apply_background_patch, apply_patch, foreground_route_state, list_routes, remove_routes,
+    restore_snapshot, RestoreOutcome, Route, RouteState, ServeConfig, TunnelResult, TunnelSpec,
+    ALLOWED_HTTPS_PORTS,
+};
 use crate::error::{FunnelError, Result};
+use crate::progress::{Phase, Progress};
 
 pub mod localapi;
+pub mod operator;
 use async_trait::async_trait;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use url::Url;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BackendStatus {
     pub dns_name: Option<String>,
     pub version: Option<String>,
     pub https_enabled: Option<bool>,
-    pub funnel_enabled: Option<bool>,
+    /// Whether Funnel may serve on each of 443, 8443 and 10000, from the `funnel` and
+    /// `funnel-ports` node attributes; `None` when the status says nothing about Funnel
+    #[serde(default)]
+    pub funnel_ports: Option<BTreeMap<u16, bool>>,
+    /// Whether this user may write the ServeConfig, which `open`, `close` and every
+    /// other route change need; `None` when it could not be tested
     pub permissions_ok: Option<bool>,
+    /// Whether this user may read the ServeConfig, which is all `audit` and `export` need
+    #[serde(default)]
+    pub serve_config_readable: Option<bool>,
+    /// The `OperatorUser` pref, read only when writes are refused; `None` when unset
+    #[serde(default)]
+    pub operator_user: Option<String>,
+    pub magic_dns_enabled: Option<bool>,
+    /// Names tailscaled can get certificates for; the first is the node's MagicDNS name
+    #[serde(default)]
+    pub cert_domains: Option<Vec<String>>,
+    /// ACL tags of this node (`Self.Tags`)
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Login of the node's owner; tagged nodes belong to no person
+    #[serde(default)]
+    pub login_name: Option<String>,
+}
+
+impl BackendStatus {
+    /// Funnel is usable on at least one port
+    pub fn funnel_enabled(&self) -> Option<bool> {
+        self.funnel_ports
+            .as_ref()
+            .map(|ports| ports.values().any(|allowed| *allowed))
+    }
+
+    pub fn funnel_allowed(&self, https_port: u16) -> Option<bool> {
+        self.funnel_ports
+            .as_ref()
+            .map(|ports| ports.get(&https_port).copied().unwrap_or(false))
+    }
+
+    /// The ports Funnel may serve on, in port order
+    pub fn funnel_permitted(&self) -> Option<Vec<u16>> {
+        self.funnel_ports.as_ref().map(|ports| {
+            ports
+                .iter()
+                .filter(|(_, allowed)| **allowed)
+                .map(|(port, _)| *port)
+                .collect()
+        })
+    }
+}
+
+/// Prerequisite failure when the tailnet policy grants this node no Funnel at all
+pub const FUNNEL_DISABLED: &str = "Funnel not enabled in tailnet policy; `funnelctl funnel-policy` shows the nodeAttrs entry to add";
+
+/// Why a Funnel route cannot go on `port`, pointing at a port that works
+pub fn funnel_port_denied(port: u16, permitted: &[u16]) -> String {
+    match permitted.first() {
+        Some(allowed) => format!(
+            "Funnel is not permitted on port {} for this node (tailnet policy allows {:?}); use --https-port {}",
+            port, permitted, allowed
+        ),
+        None => format!(
+            "Funnel is not permitted on port {} for this node; tailnet policy allows none of 443, 8443 or 10000 (`funnelctl funnel-policy` shows the nodeAttrs entry to add)",
+            port
+        ),
+    }
 }
 
 #[async_trait]
 pub trait Backend: Send + Sync {
-    async fn apply(&self, spec: &TunnelSpec) -> Result<TunnelResult>;
+    /// Applies `spec`, reporting each step to `progress`
+    async fn apply(&self, spec: &TunnelSpec, progress: &dyn Progress) -> Result<TunnelResult>;
+    /// Runs `apply`'s checks and patches `spec` into `config` without writing anything,
+    /// returning the URL the tunnel would get. Foreground routes go under `PREVIEW_SESSION`.
+    async fn preview(&self, spec: &TunnelSpec, config: &mut ServeConfig) -> Result<Url>;
     async fn remove(&self, lease_id: &str) -> Result<()>;
     async fn status(&self) -> Result<BackendStatus>;
+    /// The daemon's own status, without the serve-config and permission checks `status`
+    /// adds; enough for the node's name and version
+    async fn node_status(&self) -> Result<BackendStatus> {
+        self.status().await
+    }
+    /// The live serve config
+    async fn serve_config(&self) -> Result<ServeConfig>;
+    /// Lists every route in the live serve config, including foreign ones
+    async fn routes(&self) -> Result<Vec<Route>>;
+    /// Removes the given routes from the live serve config, returning how many were removed
+    async fn remove_routes(&self, routes: &[Route]) -> Result<usize>;
+    /// Replaces the whole live serve config with `config`, guarded like every other write
+    async fn replace_serve_config(&self, config: &ServeConfig) -> Result<()>;
+    /// Writes the background handlers of `snapshot` into the live serve config; see
+    /// `core::restore_snapshot`
+    async fn restore_snapshot(&self, snapshot: &ServeConfig) -> Result<Vec<RestoreOutcome>>;
+    /// Raw backend documents for bug reports, labelled and with secrets redacted
+    async fn diagnostics(&self) -> Result<Vec<(String, serde_json::Value)>>;
+    /// Resolves when the applied route may have changed. Backends without a change feed
+    /// never resolve; callers poll `route_state` as well.
+    async fn route_event(&self);
+    /// How the foreground route applied for `lease_id` looks in the live serve config
+    async fn route_state(&self, lease_id: &str) -> Result<RouteState>;
+    /// Writes the foreground route applied for `lease_id` back after someone removed it
+    async fn restore_route(&self, lease_id: &str) -> Result<()>;
+    /// Turns Funnel on or off for the foreground route applied for `lease_id`, keeping
+    /// its handler and session, so the URL stays the same
+    async fn set_route_funnel(&self, lease_id: &str, funnel: bool) -> Result<()>;
+    /// Warnings raised since the last call, for the caller to print or put in its JSON
+    /// events; backends never write to stderr themselves
+    fn take_warnings(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Stands in for the watch session a foreground tunnel gets only once it is applied
+pub const PREVIEW_SESSION: &str = "<new session>";
+
+/// DNS name the mock node reports and builds URLs with
+pub const MOCK_DNS_NAME: &str = "mock-node.tail0000.ts.net";
+
+/// In-memory backend: routes are patched into a `ServeConfig` held in memory with the
+/// same functions the LocalAPI backend uses, so CLI flows run without tailscaled
+#[derive(Default)]
+pub struct MockBackend {
+    state: Mutex<MockState>,
+}
+
+#[derive(Default)]
+struct MockState {
+    config: ServeConfig,
+    applied: HashMap<String, TunnelSpec>,
+}
+
+impl MockBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MockState> {
+        // A panic while patching leaves nothing half-written worth protecting
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn not_applied(lease_id: &str) -> FunnelError {
+        FunnelError::Other(format!("No mock tunnel with lease id {}", lease_id))
+    }
+}
+
+fn mock_host_port(https_port: u16) -> String {
+    format!("{}:{}", MOCK_DNS_NAME, https_port)
+}
+
+fn mock_url(spec: &TunnelSpec, https_port: u16) -> Result<Url> {
+    let mut url = Url::parse(&format!("https://{}", MOCK_DNS_NAME))
+        .map_err(|err| FunnelError::Other(format!("Failed to build URL: {}", err)))?;
+    url.set_port(Some(https_port))
+        .map_err(|_| FunnelError::Other("Failed to build URL: cannot set port".to_string()))?;
+    url.set_path(&spec.path);
+    Ok(url)
 }
 
-pub struct MockBackend;
+/// Writes the route on every port of `spec`, into the background config when detached
+fn mock_patch(config: &mut ServeConfig, spec: &TunnelSpec, session_id: &str) -> Result<()> {
+    let target = spec.serve_target().to_string();
+    for port in spec.https_ports() {
+        let host_port = mock_host_port(port);
+        if spec.detached {
+            apply_background_patch(config, &host_port, &spec.path, &target, spec.funnel);
+        } else {
+            apply_patch(
+                config,
+                session_id,
+                &host_port,
+                &spec.path,
+                &target,
+                spec.funnel,
+            )?;
+        }
+    }
+    Ok(())
+}
 
 #[async_trait]
 impl Backend for MockBackend {
-    async fn apply(&self, _spec: &TunnelSpec) -> Result<TunnelResult> {
-        Err(FunnelError::Other(
-            "MockBackend not implemented".to_string(),
-        ))
+    async fn apply(&self, spec: &TunnelSpec, progress: &dyn Progress) -> Result<TunnelResult> {
+        progress.phase(Phase::ApplyingServeConfig { attempt: 1 });
+        let token: String = rand::thread_rng()
+            .sample_iter(&rand::distributions::Alphanumeric)
+            .take(16)
+            .map(|byte| char::from(byte).to_ascii_lowercase())
+            .collect();
+        let lease_id = if spec.detached {
+            format!("detached-{}", token)
+        } else {
+            token
+        };
+        let mut state = self.state();
+        mock_patch(&mut state.config, spec, &lease_id)?;
+        state.applied.insert(lease_id.clone(), spec.clone());
+
+        Ok(TunnelResult {
+            url: mock_url(spec, spec.https_port)?,
+            lease_id,
+            applied_at: chrono::Utc::now(),
+            expires_at: None,
+            mirror_urls: spec
+                .mirror_ports
+                .iter()
+                .map(|port| mock_url(spec, *port))
+                .collect::<Result<_>>()?,
+        })
     }
 
-    async fn remove(&self, _lease_id: &str) -> Result<()> {
-        Err(FunnelError::Other(
-            "MockBackend not implemented".to_string(),
-        ))
+    async fn remove(&self, lease_id: &str) -> Result<()> {
+        let mut state = self.state();
+        let spec = state
+            .applied
+            .remove(lease_id)
+            .ok_or_else(|| Self::not_applied(lease_id))?;
+        if spec.detached {
+            let routes: Vec<Route> = list_routes(&state.config)?
+                .into_iter()
+                .filter(|route| {
+                    route.session.is_none()
+                        && spec
+                            .https_ports()
+                            .any(|port| route.host_port == mock_host_port(port))
+                        && route.path == spec.path
+                })
+                .collect();
+            remove_routes(&mut state.config, &routes)?;
+        } else if let Some(foreground) = state.config.foreground.as_mut() {
+            // Like closing the watch bus: tailscaled drops the whole session
+            foreground.remove(lease_id);
+            if foreground.is_empty() {
+                state.config.foreground = None;
+            }
+        }
+        Ok(())
+    }
+
+    async fn preview(&self, spec: &TunnelSpec, config: &mut ServeConfig) -> Result<Url> {
+        mock_patch(config, spec, PREVIEW_SESSION)?;
+        mock_url(spec, spec.https_port)
     }
 
     async fn status(&self) -> Result<BackendStatus> {
         Ok(BackendStatus {
-            dns_name: Some("mock-node".to_string()),
+            dns_name: Some(MOCK_DNS_NAME.to_string()),
             version: Some("1.50.0".to_string()),
             https_enabled: Some(true),
-            funnel_enabled: Some(true),
+            funnel_ports: Some(
+                ALLOWED_HTTPS_PORTS
+                    .iter()
+                    .map(|port| (*port, true))
+                    .collect(),
+            ),
             permissions_ok: Some(true),
+            serve_config_readable: Some(true),
+            operator_user: None,
+            magic_dns_enabled: Some(true),
+            cert_domains: Some(vec![MOCK_DNS_NAME.to_string()]),
+            tags: Vec::new(),
+            login_name: Some("dev@example.com".to_string()),
         })
     }
+
+    async fn serve_config(&self) -> Result<ServeConfig> {
+        Ok(self.state().config.clone())
+    }
+
+    async fn routes(&self) -> Result<Vec<Route>> {
+        list_routes(&self.state().config)
+    }
+
+    async fn remove_routes(&self, routes: &[Route]) -> Result<usize> {
+        remove_routes(&mut self.state().config, routes)
+    }
+
+    async fn replace_serve_config(&self, config: &ServeConfig) -> Result<()> {
+        self.state().config = config.clone();
+        Ok(())
+    }
+
+    async fn restore_snapshot(&self, snapshot: &ServeConfig) -> Result<Vec<RestoreOutcome>> {
+        restore_snapshot(&mut self.state().config, snapshot, false)
+    }
+
+    async fn diagnostics(&self) -> Result<Vec<(String, serde_json::Value)>> {
+        let config = serde_json::to_value(&self.state().config)
+            .map_err(|err| FunnelError::Other(err.to_string()))?;
+        Ok(vec![("serve-config".to_string(), config)])
+    }
+
+    async fn route_event(&self) {
+        futures::future::pending::<()>().await
+    }
+
+    async fn route_state(&self, lease_id: &str) -> Result<RouteState> {
+        let state = self.state();
+        let spec = state
+            .applied
+            .get(lease_id)
+            .ok_or_else(|| Self::not_applied(lease_id))?;
+        for port in spec.https_ports() {
+            let route_state = foreground_route_state(
+                &state.config,
+                lease_id,
+                &mock_host_port(port),
+                &spec.path,
+                &spec.serve_target().to_string(),
+                spec.funnel,
+            )?;
+            if route_state != RouteState::Intact {
+                return Ok(route_state);
+            }
+        }
+        Ok(RouteState::Intact)
+    }
+
+    async fn restore_route(&self, lease_id: &str) -> Result<()> {
+        let mut state = self.state();
+        let spec = state
+            .applied
+            .get(lease_id)
+            .cloned()
+            .ok_or_else(|| Self::not_applied(lease_id))?;
+        mock_patch(&mut state.config, &spec, lease_id)
+    }
+
+    async fn set_route_funnel(&self, lease_id: &str, funnel: bool) -> Result<()> {
+        let mut state = self.state();
+        let spec = state
+            .applied
+            .get_mut(lease_id)
+            .ok_or_else(|| Self::not_applied(lease_id))?;
+        spec.funnel = funnel;
+        let spec = spec.clone();
+        mock_patch(&mut state.config, &spec, lease_id)
+    }
 }
 
 pub struct UnreachableBackend {
@@ -62,7 +377,14 @@ impl UnreachableBackend {
 
 #[async_trait]
 impl Backend for UnreachableBackend {
-    async fn apply(&self, _spec: &TunnelSpec) -> Result<TunnelResult> {
+    async fn apply(&self, _spec: &TunnelSpec, _progress: &dyn Progress) -> Result<TunnelResult> {
+        Err(FunnelError::Unreachable {
+            source: None,
+            context: self.context.clone(),
+        })
+    }
+
+    async fn preview(&self, _spec: &TunnelSpec, _config: &mut ServeConfig) -> Result<Url> {
         Err(FunnelError::Unreachable {
             source: None,
             context: self.context.clone(),
@@ -82,4 +404,70 @@ impl Backend for UnreachableBackend {
             context: self.context.clone(),
         })
     }
+
+    async fn serve_config(&self) -> Result<ServeConfig> {
+        Err(FunnelError::Unreachable {
+            source: None,
+            context: self.context.clone(),
+        })
+    }
+
+    async fn routes(&self) -> Result<Vec<Route>> {
+        Err(FunnelError::Unreachable {
+            source: None,
+            context: self.context.clone(),
+        })
+    }
+
+    async fn remove_routes(&self, _routes: &[Route]) -> Result<usize> {
+        Err(FunnelError::Unreachable {
+            source: None,
+            context: self.context.clone(),
+        })
+    }
+
+    async fn replace_serve_config(&self, _config: &ServeConfig) -> Result<()> {
+        Err(FunnelError::Unreachable {
+            source: None,
+            context: self.context.clone(),
+        })
+    }
+
+    async fn restore_snapshot(&self, _snapshot: &ServeConfig) -> Result<Vec<RestoreOutcome>> {
+        Err(FunnelError::Unreachable {
+            source: None,
+            context: self.context.clone(),
+        })
+    }
+
+    async fn diagnostics(&self) -> Result<Vec<(String, serde_json::Value)>> {
+        Err(FunnelError::Unreachable {
+            source: None,
+            context: self.context.clone(),
+        })
+    }
+
+    async fn route_event(&self) {
+        futures::future::pending::<()>().await
+    }
+
+    async fn route_state(&self, _lease_id: &str) -> Result<RouteState> {
+        Err(FunnelError::Unreachable {
+            source: None,
+            context: self.context.clone(),
+        })
+    }
+
+    async fn restore_route(&self, _lease_id: &str) -> Result<()> {
+        Err(FunnelError::Unreachable {
+            source: None,
+            context: self.context.clone(),
+        })
+    }
+    async fn set_route_funnel(&self, _lease_id: &str, _funnel: bool) -> Result<()> {
+        Err(FunnelError::Unreachable {
+            source: None,
+            context: self.context.clone(),
+        })
+    }
 }
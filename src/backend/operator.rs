@@ -0,0 +1,89 @@
+//! Who may change tailscaled's config: root, or the one user set as its operator
+//! (`tailscale set --operator`). Everyone else gets read-only LocalAPI access.
+
+/// Name of the effective user, from the password database (so `sudo -E` does not lie),
+/// else `$USER`
+pub fn current_user() -> Option<String> {
+    #[cfg(unix)]
+    {
+        if let Some(name) = passwd_name(unsafe { libc::geteuid() }) {
+            return Some(name);
+        }
+        std::env::var("USER").ok().filter(|user| !user.is_empty())
+    }
+
+    #[cfg(not(unix))]
+    {
+        std::env::var("USERNAME")
+            .ok()
+            .filter(|user| !user.is_empty())
+    }
+}
+
+/// Root writes whatever the operator setting says
+pub fn is_root() -> bool {
+    #[cfg(unix)]
+    {
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    #[cfg(not(unix))]
+    {
+        false
+    }
+}
+
+/// The command that makes `user` the operator, or the current user when `None`
+pub fn set_operator_command(user: Option<&str>) -> String {
+    let user = user.map(str::to_string).or_else(current_user);
+    format!(
+        "sudo tailscale set --operator={}",
+        user.as_deref().unwrap_or("$USER")
+    )
+}
+
+/// `Fix:` line for refused LocalAPI access
+pub fn permission_fix() -> String {
+    format!(
+        "Run with sudo, or make your user the operator: {}",
+        set_operator_command(None)
+    )
+}
+
+#[cfg(unix)]
+fn passwd_name(uid: libc::uid_t) -> Option<String> {
+    let mut buf = vec![0u8; 1024];
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getpwuid_r(
+            uid,
+            &mut entry,
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+            &mut result,
+        )
+    };
+    if status != 0 || result.is_null() || entry.pw_name.is_null() {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(entry.pw_name) };
+    name.to_str()
+        .ok()
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_operator_command() {
+        assert_eq!(
+            set_operator_command(Some("alice")),
+            "sudo tailscale set --operator=alice"
+        );
+        assert!(permission_fix().contains("sudo tailscale set --operator="));
+    }
+}
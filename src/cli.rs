@@ -3,7 +3,11 @@ use std::path::PathBuf;
 use clap::{ArgAction, Args, Parser, Subcommand};
 use clap_complete::Shell;
 
-const EXAMPLES: &str = "EXAMPLES:\n    funnelctl open 8081                    # Quick tunnel with random path\n    funnelctl open 8081 --path /webhook    # Custom path\n    funnelctl open 8081 --ttl 30m          # Auto-expire after 30 minutes\n";
+use crate::cmd::DoctorCheck;
+use crate::error::FunnelError;
+use crate::template::Template;
+
+const EXAMPLES: &str = "EXAMPLES:\n    funnelctl open 8081                     # Quick tunnel with random path\n    funnelctl open 8081 --path /webhook     # Custom path\n    funnelctl open 8081 --ttl 30m           # Auto-expire after 30 minutes\n    funnelctl open 8081 --ttl 'until 18:00' # Auto-expire at 18:00 local time\n";
 
 #[derive(Parser, Debug)]
 #[command(
@@ -28,25 +32,131 @@ pub struct Cli {
         help = "Increase log verbosity (-v, -vv, -vvv)",
     )]
     pub verbose: u8,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Pure-ASCII output without color, one line per record"
+    )]
+    pub plain: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "Log every LocalAPI request and response, credentials and keys redacted"
+    )]
+    pub debug_http: bool,
+
+    #[arg(
+        long,
+        global = true,
+        value_name = "path",
+        env = "FUNNELCTL_STATE_DIR",
+        help = "Directory for leases, history, and the lock (default: XDG state dir)"
+    )]
+    pub state_dir: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     #[command(alias = "o", after_long_help = EXAMPLES)]
-    Open(OpenArgs),
+    Open(Box<OpenArgs>),
+    Apply(ApplyArgs),
+    Check(CheckArgs),
+    Up(UpArgs),
+    Down(DownArgs),
     #[command(alias = "doc")]
     Doctor(DoctorArgs),
     #[command(alias = "c")]
-    Close,
-    #[command(alias = "s")]
-    Status,
+    Close(CloseArgs),
+    Extend(ExtendArgs),
+    Pause(PauseArgs),
+    Resume(PauseArgs),
+    Health(HealthArgs),
+    Clean(CleanArgs),
+    Export(ExportArgs),
+    Audit(AuditArgs),
+    FunnelPolicy(FunnelPolicyArgs),
+    Import(ImportArgs),
+    Rollback(RollbackArgs),
+    Reaper(ReaperArgs),
+    Relay(RelayArgs),
+    Selftest(SelftestArgs),
+    Migrate(MigrateArgs),
+    #[command(alias = "s", visible_alias = "list")]
+    Status(StatusArgs),
+    Prompt(PromptArgs),
+    WatchRequests(WatchRequestsArgs),
+    History(HistoryArgs),
+    Errors(ErrorsArgs),
     Completions(CompletionsArgs),
 }
 
+impl Commands {
+    /// Whether `--json` was given, so errors go out as a JSON `error` event instead of text
+    pub fn json(&self) -> bool {
+        match self {
+            Commands::Open(args) => args.json,
+            Commands::Apply(args) => args.json,
+            Commands::Check(args) => args.json,
+            Commands::Doctor(args) => args.json,
+            Commands::Health(args) => args.json,
+            Commands::Audit(args) => args.json,
+            Commands::FunnelPolicy(args) => args.json,
+            Commands::Status(args) => args.json,
+            Commands::Prompt(args) => args.json,
+            Commands::WatchRequests(args) => args.json,
+            Commands::History(args) => args.json,
+            Commands::Errors(args) => args.json,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Args, Debug)]
 pub struct OpenArgs {
-    #[arg(value_name = "port", help = "Local port on loopback")]
-    pub port: u16,
+    #[arg(
+        value_name = "port",
+        required_unless_present_any = ["port_from_env", "port_file", "from_history"],
+        conflicts_with_all = ["port_from_env", "port_file"],
+        help = "Local port on loopback"
+    )]
+    pub port: Option<u16>,
+
+    #[arg(
+        long,
+        value_name = "n|id",
+        conflicts_with_all = ["port", "port_from_env", "port_file"],
+        help = "Open a tunnel from `funnelctl history` again with the same arguments: 1 is the newest, or give a name or lease ID; other flags override the recorded ones"
+    )]
+    pub from_history: Option<String>,
+
+    #[arg(
+        short,
+        long,
+        requires = "from_history",
+        help = "Reopen without asking for confirmation"
+    )]
+    pub yes: bool,
+
+    /// The arguments after `open`, as given; recorded in history for `--from-history`
+    #[arg(skip)]
+    pub raw_args: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "var",
+        conflicts_with = "port_file",
+        help = "Read the local port from this environment variable (e.g. PORT)"
+    )]
+    pub port_from_env: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "path",
+        help = "Read the local port from this file, waiting for it to appear"
+    )]
+    pub port_file: Option<PathBuf>,
 
     #[arg(
         long,
@@ -63,6 +173,19 @@ pub struct OpenArgs {
     )]
     pub path: Option<String>,
 
+    #[arg(
+        long,
+        conflicts_with = "path",
+        help = "Reuse the path of the last tunnel opened from this directory for the same ports"
+    )]
+    pub reuse_path: bool,
+
+    #[arg(
+        long,
+        help = "On a route conflict, take the suggested free path (e.g. <path>-2) instead of failing"
+    )]
+    pub auto_resolve: bool,
+
     #[arg(
         long,
         default_value = "443",
@@ -71,38 +194,310 @@ pub struct OpenArgs {
     )]
     pub https_port: u16,
 
+    #[arg(
+        long,
+        help = "Serve the path on all HTTPS ports (443, 8443, and 10000), --https-port first"
+    )]
+    pub all_https_ports: bool,
+
+    #[arg(
+        long,
+        help = "Fail instead of moving to another HTTPS port when Funnel is not permitted on --https-port"
+    )]
+    pub strict_port: bool,
+
     #[arg(
         long,
         value_name = "duration",
-        help = "Keep tunnel up for duration, then tear down"
+        help = "Keep tunnel up for a duration (30m), or until a deadline ('until 18:00', RFC 3339), then tear down"
     )]
     pub ttl: Option<String>,
 
-    #[arg(long, help = "Allow overwriting conflicting serve routes")]
+    #[arg(
+        long,
+        conflicts_with = "ttl",
+        help = "Run without a TTL even if the config sets open.ttl"
+    )]
+    pub no_ttl: bool,
+
+    #[arg(
+        long,
+        help = "Allow overwriting conflicting serve routes, and a --dns-name outside CertDomains"
+    )]
     pub force: bool,
 
+    #[arg(
+        long,
+        help = "Skip the target, version, HTTPS and Funnel checks and only write the serve config"
+    )]
+    pub skip_checks: bool,
+
+    #[arg(
+        long,
+        help = "Don't check over public DNS that the URL's host resolves to Funnel before printing it"
+    )]
+    pub no_dns_check: bool,
+
+    #[arg(
+        long,
+        value_name = "host",
+        help = "Host for the URL and serve config instead of the node's DNS name; must be in CertDomains"
+    )]
+    pub dns_name: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "url",
+        help = "Show URLs under this base (e.g. a CDN in front of Funnel); the serve config is unchanged"
+    )]
+    pub public_url_base: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "name",
+        help = "Tunnel name for close/extend/status (default: derived from current directory)"
+    )]
+    pub name: Option<String>,
+
+    #[arg(
+        long = "tag",
+        value_name = "key=value",
+        help = "Label the tunnel for status/close filters (repeatable)"
+    )]
+    pub tags: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "email|device",
+        help = "Record who or what the tunnel is for (a tailnet user, a device, or a note); shown by status, history and audit"
+    )]
+    pub expose_for: Option<String>,
+
     #[arg(long, help = "NDJSON output for scripting")]
     pub json: bool,
 
-    #[arg(long, value_name = "path", help = "Unix socket path override")]
-    pub socket: Option<PathBuf>,
+    #[arg(
+        long,
+        value_name = "fd",
+        value_parser = clap::value_parser!(u32).range(1..),
+        conflicts_with = "json_file",
+        help = "Also write the NDJSON events to this open file descriptor, e.g. 3"
+    )]
+    pub json_fd: Option<u32>,
 
-    #[arg(long, value_name = "port", help = "LocalAPI TCP port (macOS/Windows)")]
-    pub localapi_port: Option<u16>,
+    #[arg(
+        long,
+        value_name = "path",
+        help = "Also write the NDJSON events to this file or named pipe"
+    )]
+    pub json_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Print a ready-to-copy curl command after the URL (human output only)"
+    )]
+    pub curl_example: bool,
 
     #[arg(
         long,
         value_name = "path",
-        help = "File containing LocalAPI password (0600 permissions)"
+        help = "Path appended to the URL in the curl example (implies --curl-example)"
     )]
-    pub localapi_password_file: Option<PathBuf>,
+    pub example_path: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "template",
+        value_parser = parse_template,
+        conflicts_with_all = ["json", "curl_example", "example_path"],
+        help = "Print the started line from a template, e.g. '{url} (expires {expires_at})'"
+    )]
+    pub format: Option<Template>,
+
+    #[arg(
+        long,
+        value_name = "path",
+        help = "Write the URL to this file once the tunnel is up; removed when it stops"
+    )]
+    pub url_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        requires = "url_file",
+        help = "Write the started event as JSON to --url-file instead of the bare URL"
+    )]
+    pub url_file_json: bool,
+
+    #[arg(
+        long,
+        help = "Print only the URL: no Name/Local/Expires lines on stderr, no key=value fields with --plain"
+    )]
+    pub no_banner: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "format",
+        help = "Print the started banner on one line (default when stdout is not a terminal or the terminal is narrow)"
+    )]
+    pub compact: bool,
+
+    #[arg(
+        long,
+        help = "Drop advisory Warning:/Note: lines; problems with the running tunnel still go to stderr"
+    )]
+    pub quiet_warnings: bool,
+
+    #[arg(
+        long,
+        help = "Fail instead of warning about the path or TTL (except open.ignore_warnings)"
+    )]
+    pub deny_warnings: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
 
     #[arg(long, help = "Allow non-loopback bind addresses")]
     pub allow_non_loopback: bool,
+
+    #[arg(
+        long,
+        help = "Allow a --path under a reserved prefix (/.well-known, /metrics, /debug)"
+    )]
+    pub allow_reserved_path: bool,
+
+    #[arg(
+        long,
+        help = "Allow --path characters that need percent-encoding (spaces, unicode, %, ...)"
+    )]
+    pub lax_path: bool,
+
+    #[arg(
+        long,
+        value_name = "n",
+        help = "Limit in-flight requests via a local proxy; excess requests get 503"
+    )]
+    pub max_concurrency: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Route requests through a local proxy so `funnelctl watch-requests` can tail them"
+    )]
+    pub inspect: bool,
+
+    #[arg(
+        long,
+        help = "Serve a robots.txt disallowing the path and send X-Robots-Tag: noindex, via a local proxy, so crawlers skip the tunnel"
+    )]
+    pub noindex: bool,
+
+    #[arg(
+        long,
+        help = "While the target is down, have a local proxy answer with a \"service restarting\" page that reloads itself"
+    )]
+    pub holding_page: bool,
+
+    #[arg(
+        long,
+        value_name = "path",
+        help = "HTML file to serve as the holding page (implies --holding-page)"
+    )]
+    pub holding_page_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Print the serve config changes opening would make, then exit without making them"
+    )]
+    pub dry_run: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["max_concurrency", "inspect", "noindex", "holding_page", "holding_page_file"],
+        help = "Keep the tunnel after exiting; --ttl is enforced by later commands or `funnelctl reaper`"
+    )]
+    pub detach: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "detach",
+        help = "Put the route back when another tool removes or replaces it"
+    )]
+    pub self_heal: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "detach",
+        help = "Tear the tunnel down when the target stops accepting connections"
+    )]
+    pub stop_on_target_down: bool,
+
+    #[arg(
+        long,
+        value_name = "duration",
+        conflicts_with = "detach",
+        help = "When the target goes down, keep probing with backoff this long before reporting it (e.g. 30s)"
+    )]
+    pub target_retry: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "path",
+        help = "Before exposing the target, wait until GET <path> on it answers 2xx (e.g. /healthz)"
+    )]
+    pub health_path: Option<String>,
+
+    #[arg(
+        long,
+        default_value = "30s",
+        value_name = "duration",
+        requires = "health_path",
+        help = "How long --health-path may take to answer 2xx"
+    )]
+    pub health_timeout: String,
+
+    #[arg(
+        long,
+        value_name = "time",
+        help = "Wait until this time (HH:MM today, or RFC 3339) before opening; the TTL counts from then"
+    )]
+    pub at: Option<String>,
+
+    #[arg(
+        long,
+        help = "Remove routes left behind by crashed or expired tunnels without asking"
+    )]
+    pub auto_clean: bool,
+
+    #[arg(
+        long,
+        value_name = "node",
+        requires = "remote_token_file",
+        conflicts_with_all = ["detach", "max_concurrency", "inspect", "noindex", "holding_page", "holding_page_file", "stop_on_target_down", "target_retry", "health_path"],
+        help = "Open the tunnel on another node through its `funnelctl relay`; the port is on that node"
+    )]
+    pub remote_node: Option<String>,
+
+    #[arg(
+        long,
+        default_value = "4443",
+        value_name = "port",
+        requires = "remote_node",
+        help = "HTTPS port the remote node serves its relay on"
+    )]
+    pub remote_port: u16,
+
+    #[arg(
+        long,
+        value_name = "path",
+        requires = "remote_node",
+        help = "File containing the remote relay's token (0600 permissions)"
+    )]
+    pub remote_token_file: Option<PathBuf>,
 }
 
-#[derive(Args, Debug)]
-pub struct DoctorArgs {
+/// How to reach tailscaled's LocalAPI; shared by every command that talks to it
+#[derive(Args, Debug, Clone)]
+#[command(about = None, long_about = None)]
+pub struct LocalApiArgs {
     #[arg(long, value_name = "path", help = "Unix socket path override")]
     pub socket: Option<PathBuf>,
 
@@ -115,10 +510,589 @@ pub struct DoctorArgs {
         help = "File containing LocalAPI password (0600 permissions)"
     )]
     pub localapi_password_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Write ServeConfig without If-Match when tailscaled sends no ETag (concurrent edits may be lost)"
+    )]
+    pub no_etag: bool,
+
+    #[arg(
+        long,
+        help = "Fetch node status from tailscaled even if a recent run cached it"
+    )]
+    pub no_cache: bool,
+
+    #[arg(
+        long,
+        value_name = "duration",
+        help = "Give up on a serve config change tailscaled has not finished after this long (default 30s)"
+    )]
+    pub apply_timeout: Option<String>,
 }
 
 #[derive(Args, Debug)]
-pub struct CompletionsArgs {
-    #[arg(value_enum, help = "Shell to generate completions for")]
-    pub shell: Shell,
+pub struct DoctorArgs {
+    #[arg(
+        long,
+        help = "Also check that Funnel is reachable from the public internet (opens a temporary probe route)"
+    )]
+    pub external: bool,
+
+    #[arg(
+        long,
+        value_name = "check",
+        value_delimiter = ',',
+        conflicts_with = "skip",
+        help = "Run only these checks (comma-separated or repeatable)"
+    )]
+    pub only: Vec<DoctorCheck>,
+
+    #[arg(
+        long,
+        value_name = "check",
+        value_delimiter = ',',
+        help = "Skip these checks (comma-separated or repeatable)"
+    )]
+    pub skip: Vec<DoctorCheck>,
+
+    #[arg(
+        long,
+        default_value = "443",
+        value_name = "port",
+        help = "HTTPS port the port check looks at (443, 8443, or 10000)"
+    )]
+    pub https_port: u16,
+
+    #[arg(
+        long,
+        help = "When permissions fail, offer to make you the tailscale operator (runs sudo)"
+    )]
+    pub fix: bool,
+
+    #[arg(
+        long,
+        conflicts_with = "fix",
+        help = "One JSON object per check, then a summary with ready_for_funnel"
+    )]
+    pub json: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct SelftestArgs {
+    #[arg(
+        long,
+        help = "Use an in-memory backend instead of tailscaled and skip the public fetch"
+    )]
+    pub offline: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct CloseArgs {
+    #[arg(
+        value_name = "lease",
+        conflicts_with_all = ["path", "https_port", "tags", "all"],
+        help = "Tunnel name, lease id, or unique id prefix to close"
+    )]
+    pub lease: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "prefix",
+        help = "Only close routes whose path starts with prefix"
+    )]
+    pub path: Option<String>,
+
+    #[arg(
+        long = "port",
+        value_name = "port",
+        help = "Only close routes on this public HTTPS port"
+    )]
+    pub https_port: Option<u16>,
+
+    #[arg(
+        long = "tag",
+        value_name = "key=value",
+        conflicts_with = "all",
+        help = "Only close tunnels carrying this tag (repeatable; all must match)"
+    )]
+    pub tags: Vec<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "all",
+        help = "Only close tunnels created by funnelctl (default)"
+    )]
+    pub mine: bool,
+
+    #[arg(
+        long,
+        help = "Close every matching route, including foreign `tailscale serve` routes (requires --force)"
+    )]
+    pub all: bool,
+
+    #[arg(long, help = "Confirm teardown of routes not created by funnelctl")]
+    pub force: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct CleanArgs {
+    #[arg(long, help = "Show what would be removed without changing anything")]
+    pub dry_run: bool,
+
+    #[arg(short, long, help = "Remove without asking for confirmation")]
+    pub yes: bool,
+
+    #[arg(
+        long,
+        help = "Also remove background routes marked as funnelctl's that no lease records"
+    )]
+    pub orphans: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct ApplyArgs {
+    #[arg(
+        short,
+        long,
+        value_name = "path",
+        help = "YAML or JSON file listing the tunnels that should exist"
+    )]
+    pub file: PathBuf,
+
+    #[arg(
+        long,
+        help = "Print the serve config changes the file would make, then exit without making them"
+    )]
+    pub diff: bool,
+
+    #[arg(long, requires = "diff", help = "Print the diff as JSON")]
+    pub json: bool,
+
+    #[arg(long, help = "Allow overwriting conflicting serve routes")]
+    pub force: bool,
+
+    #[arg(
+        long,
+        help = "Skip the target, version, HTTPS and Funnel checks and only write the serve config"
+    )]
+    pub skip_checks: bool,
+
+    #[arg(
+        long = "node",
+        value_name = "node",
+        requires = "remote_token_file",
+        help = "Apply on this node through its `funnelctl relay` instead of here (repeatable)"
+    )]
+    pub nodes: Vec<String>,
+
+    #[arg(
+        long,
+        default_value = "4443",
+        value_name = "port",
+        requires = "nodes",
+        help = "HTTPS port the nodes serve their relay on"
+    )]
+    pub remote_port: u16,
+
+    #[arg(
+        long,
+        value_name = "path",
+        requires = "nodes",
+        help = "File containing the relays' token (0600 permissions)"
+    )]
+    pub remote_token_file: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct CheckArgs {
+    #[arg(
+        short,
+        long,
+        value_name = "path",
+        help = "YAML or JSON file listing the tunnels that should exist"
+    )]
+    pub file: PathBuf,
+
+    #[arg(long, help = "Print one JSON line per tunnel, then a summary line")]
+    pub json: bool,
+
+    #[arg(
+        long,
+        help = "Also check that each target port accepts connections (off by default, since CI runs without the targets)"
+    )]
+    pub check_targets: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct UpArgs {
+    #[arg(
+        short,
+        long,
+        value_name = "path",
+        default_value = "funnel.yaml",
+        help = "YAML or JSON file listing the tunnels to bring up"
+    )]
+    pub file: PathBuf,
+
+    #[arg(
+        long,
+        help = "Keep running and bring back tunnels that disappear or change"
+    )]
+    pub watch: bool,
+
+    #[arg(
+        long,
+        value_name = "duration",
+        default_value = "30s",
+        requires = "watch",
+        help = "Time between checks with --watch"
+    )]
+    pub interval: String,
+
+    #[arg(long, help = "Allow overwriting conflicting serve routes")]
+    pub force: bool,
+
+    #[arg(
+        long,
+        help = "Skip the target, version, HTTPS and Funnel checks and only write the serve config"
+    )]
+    pub skip_checks: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct DownArgs {
+    #[arg(
+        short,
+        long,
+        value_name = "path",
+        default_value = "funnel.yaml",
+        help = "Spec file whose tunnels to remove"
+    )]
+    pub file: PathBuf,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct MigrateArgs {
+    #[command(subcommand)]
+    pub source: MigrateSource,
+}
+
+/// Tools whose configuration `migrate` can translate into a spec file
+#[derive(Subcommand, Debug)]
+pub enum MigrateSource {
+    /// Translate an ngrok agent config (ngrok.yml)
+    Ngrok(MigrateNgrokArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct MigrateNgrokArgs {
+    #[arg(
+        value_name = "file",
+        help = "ngrok config, e.g. ~/.config/ngrok/ngrok.yml"
+    )]
+    pub file: PathBuf,
+
+    #[arg(
+        long,
+        value_name = "name",
+        default_value = "ngrok",
+        help = "Project of the generated spec file"
+    )]
+    pub project: String,
+}
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    #[arg(
+        long,
+        help = "Only export routes of active funnelctl tunnels, not the whole serve config"
+    )]
+    pub owned: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct AuditArgs {
+    #[arg(
+        long,
+        default_value = "7d",
+        value_name = "duration",
+        help = "Report Funnel routes that have been up longer than this (e.g. 24h, 30d)"
+    )]
+    pub max_age: String,
+
+    #[arg(
+        long,
+        default_value_t = 1000,
+        value_name = "count",
+        help = "Report tunnels whose proxy counted more requests per hour than this"
+    )]
+    pub max_requests_per_hour: u64,
+
+    #[arg(long, help = "One JSON object per finding")]
+    pub json: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct FunnelPolicyArgs {
+    #[arg(
+        long,
+        default_value = "443",
+        value_name = "port",
+        help = "HTTPS port the tunnel needs Funnel on (443, 8443, or 10000)"
+    )]
+    pub https_port: u16,
+
+    #[arg(long, help = "Print the report as one JSON object")]
+    pub json: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct ImportArgs {
+    #[arg(
+        value_name = "file",
+        help = "File written by `funnelctl export`, or a bare ServeConfig JSON"
+    )]
+    pub file: PathBuf,
+
+    #[arg(long, help = "Overwrite conflicting serve routes")]
+    pub force: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct RollbackArgs {
+    #[arg(
+        value_name = "backup",
+        help = "Backup id or unique prefix (default: the newest)"
+    )]
+    pub backup: Option<String>,
+
+    #[arg(long, conflicts_with_all = ["backup", "dry_run", "yes"], help = "List saved backups")]
+    pub list: bool,
+
+    #[arg(long, help = "Show what would change without changing anything")]
+    pub dry_run: bool,
+
+    #[arg(short, long, help = "Restore without asking for confirmation")]
+    pub yes: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct ReaperArgs {
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct RelayArgs {
+    #[arg(
+        long,
+        default_value = "4480",
+        value_name = "port",
+        help = "Loopback port to listen on; put it on the tailnet with `tailscale serve`"
+    )]
+    pub port: u16,
+
+    #[arg(
+        long,
+        value_name = "path",
+        help = "Token file clients must present; created with a new token (0600) if missing"
+    )]
+    pub token_file: PathBuf,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct ExtendArgs {
+    #[arg(
+        value_name = "lease",
+        help = "Tunnel name, lease id, or unique id prefix"
+    )]
+    pub lease: String,
+
+    #[arg(
+        value_name = "duration",
+        help = "Time to add to the TTL (e.g. 30m, 1h)"
+    )]
+    pub duration: String,
+}
+
+#[derive(Args, Debug)]
+pub struct PauseArgs {
+    #[arg(
+        value_name = "lease",
+        help = "Tunnel name, lease id, or unique id prefix"
+    )]
+    pub lease: String,
+}
+
+#[derive(Args, Debug)]
+pub struct HealthArgs {
+    #[arg(
+        value_name = "lease",
+        help = "Tunnel name, lease id, or unique id prefix"
+    )]
+    pub lease: String,
+
+    #[arg(long, help = "Print the checks as one JSON object")]
+    pub json: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct StatusArgs {
+    #[arg(
+        long = "tag",
+        value_name = "key=value",
+        help = "Only show tunnels carrying this tag (repeatable; all must match)"
+    )]
+    pub tags: Vec<String>,
+
+    #[arg(long, help = "One JSON object per tunnel")]
+    pub json: bool,
+
+    #[arg(
+        long,
+        help = "Keep running and redraw the list (or print it again with --json) until Ctrl-C"
+    )]
+    pub watch: bool,
+
+    #[arg(
+        long,
+        value_name = "duration",
+        default_value = "2s",
+        requires = "watch",
+        help = "Time between redraws with --watch"
+    )]
+    pub interval: String,
+}
+
+#[derive(Args, Debug)]
+pub struct PromptArgs {
+    #[arg(
+        long,
+        default_value = "10m",
+        value_name = "duration",
+        help = "Call out tunnels that expire within this long"
+    )]
+    pub expiring_within: String,
+
+    #[arg(long, help = "Never contact tailscaled; read only the lease store")]
+    pub offline: bool,
+
+    #[arg(long, help = "Print the summary as one JSON object")]
+    pub json: bool,
+
+    #[command(flatten)]
+    pub localapi: LocalApiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct WatchRequestsArgs {
+    #[arg(
+        value_name = "lease",
+        help = "Tunnel name, lease id, or unique id prefix"
+    )]
+    pub lease: String,
+
+    #[arg(
+        long,
+        value_name = "prefix",
+        help = "Only show requests whose path starts with this"
+    )]
+    pub path: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "code",
+        help = "Only show these statuses, e.g. 404 or 5xx (repeatable)"
+    )]
+    pub status: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "method",
+        help = "Only show requests with this method (repeatable)"
+    )]
+    pub method: Vec<String>,
+
+    #[arg(long, help = "One JSON object per request")]
+    pub json: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct HistoryArgs {
+    #[arg(
+        long,
+        default_value = "20",
+        value_name = "n",
+        help = "Show the n most recent tunnels"
+    )]
+    pub limit: usize,
+
+    #[arg(long, help = "One JSON object per tunnel")]
+    pub json: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ErrorsArgs {
+    #[arg(long, help = "One JSON object per exit code")]
+    pub json: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct CompletionsArgs {
+    #[arg(value_enum, help = "Shell to generate completions for")]
+    pub shell: Shell,
+}
+
+/// clap already names the flag, so drop the `Invalid argument:` prefix
+fn parse_template(raw: &str) -> Result<Template, String> {
+    Template::parse(raw).map_err(|err| match err {
+        FunnelError::InvalidArgument(message) => message,
+        other => other.to_string(),
+    })
 }
@@ -0,0 +1,386 @@
+//! `funnelctl apply -f funnel.yaml`: creates, updates, and removes detached tunnels until
+//! the ones tagged with the file's project match the file.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use chrono::Utc;
+
+use crate::backend::Backend;
+use crate::cli::ApplyArgs;
+use crate::cmd::close::supersede_displaced;
+use crate::cmd::open::{config_path_policy, resolve_ttl, warn_unchecked};
+use crate::cmd::reaper::reap_expired_from;
+use crate::config::OpenConfig;
+use crate::core::manifest::{plan, ApplyAction, DesiredTunnel, Manifest, PROJECT_TAG};
+use crate::core::{
+    diff_serve_configs, list_routes, remove_routes, Lease, LeaseStore, Route, ServeConfig,
+};
+use crate::error::{FunnelError, Result};
+use crate::lock::LockGuard;
+use crate::output::{render_serve_diff, Theme};
+use crate::progress::NoProgress;
+
+pub struct ApplyCommand {
+    args: ApplyArgs,
+    config: OpenConfig,
+    theme: Theme,
+}
+
+impl ApplyCommand {
+    pub fn new(args: ApplyArgs, config: OpenConfig, theme: Theme) -> Self {
+        Self {
+            args,
+            config,
+            theme,
+        }
+    }
+
+    pub async fn run(self, backend: Arc<dyn Backend>) -> Result<()> {
+        let (project, desired) = load_spec(&self.args.file, &self.config)?;
+        let project = project.as_str();
+
+        if self.args.diff {
+            let actions = plan(desired, &LeaseStore::open()?.list()?, project, Utc::now())?;
+            return self.print_diff(backend.as_ref(), &actions, None).await;
+        }
+
+        if self.args.skip_checks {
+            warn_unchecked();
+        }
+        // One lock for the whole run: the plan is only valid while nobody else edits
+        let _lock = LockGuard::acquire()?;
+        let store = LeaseStore::open()?;
+        let actions = plan(desired, &store.list()?, project, Utc::now())?;
+        let counts = apply_actions(backend.as_ref(), &store, actions, project, None).await?;
+        println!("{}: {}", project, counts);
+        Ok(())
+    }
+
+    /// `--node`: applies the file on each node in turn, through its relay. Each node
+    /// keeps its own lease records, and a node that fails does not stop the others.
+    pub async fn run_nodes(self, nodes: Vec<(String, Result<Arc<dyn Backend>>)>) -> Result<()> {
+        let (project, desired) = load_spec(&self.args.file, &self.config)?;
+        if self.args.skip_checks && !self.args.diff {
+            warn_unchecked();
+        }
+        let total = nodes.len();
+        let mut failures = Vec::new();
+        for (node, backend) in nodes {
+            let result = match backend {
+                Ok(backend) => {
+                    self.apply_node(backend.as_ref(), &node, &project, desired.clone())
+                        .await
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                eprintln!("{}: Failed: {}", node, err);
+                failures.push(err);
+            }
+        }
+        if !self.args.diff {
+            println!(
+                "{}: applied on {} of {} nodes",
+                project,
+                total - failures.len(),
+                total
+            );
+        }
+        failures.into_iter().next().map_or(Ok(()), Err)
+    }
+
+    async fn apply_node(
+        &self,
+        backend: &dyn Backend,
+        node: &str,
+        project: &str,
+        desired: Vec<DesiredTunnel>,
+    ) -> Result<()> {
+        let store = LeaseStore::for_node(node)?;
+        if self.args.diff {
+            let actions = plan(desired, &store.list()?, project, Utc::now())?;
+            return self.print_diff(backend, &actions, Some(node)).await;
+        }
+        // The node's own reaper has no record of these tunnels, so expired ones go here
+        for lease in reap_expired_from(backend, &store).await? {
+            println!("{}: Reaped {}", node, describe(&lease));
+        }
+        let _lock = LockGuard::acquire()?;
+        let actions = plan(desired, &store.list()?, project, Utc::now())?;
+        let counts = apply_actions(backend, &store, actions, project, Some(node)).await?;
+        println!("{}: {}: {}", node, project, counts);
+        Ok(())
+    }
+
+    /// Plays the plan against a copy of the serve config and prints what would change;
+    /// under `--node`, with the node's name
+    async fn print_diff(
+        &self,
+        backend: &dyn Backend,
+        actions: &[ApplyAction],
+        node: Option<&str>,
+    ) -> Result<()> {
+        let before = backend.serve_config().await?;
+        let mut after = before.clone();
+        for action in actions {
+            match action {
+                ApplyAction::Remove(lease) => remove_owned(&mut after, lease)?,
+                ApplyAction::Update { lease, desired } => {
+                    remove_owned(&mut after, lease)?;
+                    backend.preview(&desired.spec, &mut after).await?;
+                }
+                ApplyAction::Create(desired) => {
+                    backend.preview(&desired.spec, &mut after).await?;
+                }
+                ApplyAction::Unchanged(_) => {}
+            }
+        }
+        let diff = diff_serve_configs(&before, &after)?;
+
+        if self.args.json {
+            let json = match node {
+                Some(node) => serde_json::to_string(&serde_json::json!({
+                    "node": node,
+                    "diff": diff,
+                })),
+                None => serde_json::to_string(&diff),
+            }
+            .map_err(|err| FunnelError::Other(format!("JSON serialization failed: {}", err)))?;
+            println!("{}", json);
+            return Ok(());
+        }
+        if let Some(node) = node {
+            println!("{}:", node);
+        }
+        for action in actions {
+            let verb = match action {
+                ApplyAction::Create(_) => "create",
+                ApplyAction::Update { .. } => "update",
+                ApplyAction::Unchanged(_) => "keep",
+                ApplyAction::Remove(_) => "remove",
+            };
+            println!("Would {} {}", verb, action.name());
+        }
+        println!("{}", render_serve_diff(&diff, &self.theme));
+        Ok(())
+    }
+}
+
+/// What one apply did
+#[derive(Debug, Default)]
+struct Counts {
+    created: usize,
+    updated: usize,
+    unchanged: usize,
+    removed: usize,
+}
+
+impl std::fmt::Display for Counts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} created, {} updated, {} unchanged, {} removed",
+            self.created, self.updated, self.unchanged, self.removed
+        )
+    }
+}
+
+/// Carries out the plan, printing a line per tunnel, prefixed with `node` when the
+/// routes are on another node. Tunnels there share no routes with running `open`s
+/// here, so nothing is superseded.
+async fn apply_actions(
+    backend: &dyn Backend,
+    store: &LeaseStore,
+    actions: Vec<ApplyAction>,
+    project: &str,
+    node: Option<&str>,
+) -> Result<Counts> {
+    let prefix = node.map(|node| format!("{}: ", node)).unwrap_or_default();
+    let create_tunnel = |desired| async move {
+        match node {
+            Some(_) => record(backend, store, desired, project).await,
+            None => create(backend, store, desired, project).await,
+        }
+    };
+    let mut counts = Counts::default();
+    for action in actions {
+        match action {
+            ApplyAction::Remove(lease) => {
+                remove(backend, store, &lease).await?;
+                println!("{}Removed {}", prefix, describe(&lease));
+                counts.removed += 1;
+            }
+            ApplyAction::Update { lease, desired } => {
+                remove(backend, store, &lease).await?;
+                let lease = create_tunnel(desired).await?;
+                println!("{}Updated {}", prefix, describe(&lease));
+                counts.updated += 1;
+            }
+            ApplyAction::Create(desired) => {
+                let lease = create_tunnel(desired).await?;
+                println!("{}Created {}", prefix, describe(&lease));
+                counts.created += 1;
+            }
+            ApplyAction::Unchanged(lease) => {
+                println!("{}Unchanged {}", prefix, describe(&lease));
+                counts.unchanged += 1;
+            }
+        }
+    }
+    Ok(counts)
+}
+
+/// Reads a spec file into its project and the tunnels it wants, validated against the
+/// config's path policy
+pub(crate) fn load_spec(file: &Path, config: &OpenConfig) -> Result<(String, Vec<DesiredTunnel>)> {
+    let manifest = Manifest::from_path(file)?;
+    let project = manifest.project()?.to_string();
+    let policy = config_path_policy(config)?;
+    let mut desired = manifest.desired(&policy, chrono::Local::now())?;
+    for tunnel in &mut desired {
+        default_ttl(tunnel, config)?;
+    }
+    Ok((project, desired))
+}
+
+/// Tunnels without a `ttl` take `open.ttl` from the config, like `open` does
+pub(crate) fn default_ttl(tunnel: &mut DesiredTunnel, config: &OpenConfig) -> Result<()> {
+    if tunnel.ttl.is_some() {
+        return Ok(());
+    }
+    tunnel.ttl = resolve_ttl(
+        None,
+        false,
+        config.ttl.as_deref(),
+        false,
+        chrono::Local::now(),
+    )?;
+    if tunnel.ttl.is_none() && config.require_ttl {
+        return Err(FunnelError::PolicyViolation {
+            source: None,
+            context: format!(
+                "tunnel '{}': a TTL is required (open.require_ttl in config)",
+                tunnel.name
+            ),
+        });
+    }
+    Ok(())
+}
+
+/// Creates the tunnel, then stops running `open`s whose route it took over
+pub(crate) async fn create(
+    backend: &dyn Backend,
+    store: &LeaseStore,
+    desired: DesiredTunnel,
+    project: &str,
+) -> Result<Lease> {
+    let name = desired.name.clone();
+    let lease = record(backend, store, desired, project).await?;
+    for displaced in supersede_displaced(&lease.tunnel_spec, &lease.lease_id, &name).await {
+        tracing::info!(lease = %displaced.lease_id, "superseded running tunnel");
+    }
+    Ok(lease)
+}
+
+/// Applies the route, then records the lease; the route is rolled back if the lease
+/// cannot be saved, since nothing could close it otherwise
+async fn record(
+    backend: &dyn Backend,
+    store: &LeaseStore,
+    desired: DesiredTunnel,
+    project: &str,
+) -> Result<Lease> {
+    let result = backend.apply(&desired.spec, &NoProgress).await?;
+    let lease = Lease::new(
+        result.lease_id,
+        desired.spec.clone(),
+        desired.expires_at(result.applied_at),
+    )
+    .with_name(desired.name.as_str())
+    .with_tags([(PROJECT_TAG.to_string(), project.to_string())])
+    .with_url(result.url.as_str());
+    if let Err(err) = store.save(&lease) {
+        let routes = owned_routes(backend, &lease).await?;
+        if let Err(rollback) = backend.remove_routes(&routes).await {
+            tracing::warn!(error = %rollback, "failed to roll back detached tunnel");
+        }
+        return Err(err);
+    }
+    Ok(lease)
+}
+
+pub(crate) async fn remove(backend: &dyn Backend, store: &LeaseStore, lease: &Lease) -> Result<()> {
+    let routes = owned_routes(backend, lease).await?;
+    if !routes.is_empty() {
+        backend.remove_routes(&routes).await?;
+    }
+    store.remove(&lease.lease_id)?;
+    Ok(())
+}
+
+pub(crate) fn remove_owned(config: &mut ServeConfig, lease: &Lease) -> Result<()> {
+    let routes: Vec<Route> = list_routes(config)?
+        .into_iter()
+        .filter(|route| lease.owns(route))
+        .collect();
+    remove_routes(config, &routes)?;
+    Ok(())
+}
+
+pub(crate) async fn owned_routes(backend: &dyn Backend, lease: &Lease) -> Result<Vec<Route>> {
+    Ok(backend
+        .routes()
+        .await?
+        .into_iter()
+        .filter(|route| lease.owns(route))
+        .collect())
+}
+
+pub(crate) fn describe(lease: &Lease) -> String {
+    format!(
+        "{} {}",
+        lease.name.as_deref().unwrap_or(lease.lease_id.as_str()),
+        lease
+            .url
+            .as_deref()
+            .unwrap_or(lease.tunnel_spec.path.as_str())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MockBackend;
+    use crate::core::PathPolicy;
+
+    #[tokio::test]
+    async fn test_apply_actions_on_node() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LeaseStore::at(dir.path()).unwrap();
+        let backend = MockBackend::new();
+        let desired = |yaml: &str| {
+            Manifest::parse(yaml)
+                .unwrap()
+                .desired(&PathPolicy::default(), chrono::Local::now())
+                .unwrap()
+        };
+        let spec = "project: shop\ntunnels:\n  - name: stripe\n    port: 3000\n";
+
+        let actions = plan(desired(spec), &store.list().unwrap(), "shop", Utc::now()).unwrap();
+        let counts = apply_actions(&backend, &store, actions, "shop", Some("edge-2"))
+            .await
+            .unwrap();
+        assert_eq!(
+            counts.to_string(),
+            "1 created, 0 updated, 0 unchanged, 0 removed"
+        );
+        assert_eq!(backend.routes().await.unwrap().len(), 1);
+
+        let actions = plan(desired(spec), &store.list().unwrap(), "shop", Utc::now()).unwrap();
+        let counts = apply_actions(&backend, &store, actions, "shop", Some("edge-2"))
+            .await
+            .unwrap();
+        assert_eq!(counts.unchanged, 1);
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+
+use crate::backend::Backend;
+use crate::cli::AuditArgs;
+use crate::core::{audit_routes, HistoryLog, LeaseStore, Severity};
+use crate::error::{FunnelError, Result};
+use crate::output::render_table;
+
+pub struct AuditCommand {
+    args: AuditArgs,
+}
+
+impl AuditCommand {
+    pub fn new(args: AuditArgs) -> Self {
+        Self { args }
+    }
+
+    /// Reports risky Funnel routes in the live serve config, most severe first. Read-only;
+    /// takes no lock.
+    pub async fn run(self, backend: Arc<dyn Backend>) -> Result<()> {
+        let max_age = humantime::parse_duration(&self.args.max_age).map_err(|err| {
+            FunnelError::InvalidField {
+                field: "--max-age".to_string(),
+                value: self.args.max_age.clone(),
+                constraint: format!("Invalid --max-age '{}': {}", self.args.max_age, err),
+            }
+        })?;
+        let routes = backend.routes().await?;
+        let leases = LeaseStore::open()?.list()?;
+        let history = HistoryLog::open()?.read()?;
+        let findings = audit_routes(
+            &routes,
+            &leases,
+            &history,
+            max_age,
+            self.args.max_requests_per_hour,
+            Utc::now(),
+        );
+
+        if self.args.json {
+            for finding in &findings {
+                let line = serde_json::to_string(finding)
+                    .map_err(|err| FunnelError::Other(err.to_string()))?;
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+
+        let public = routes.iter().filter(|route| route.funnel).count();
+        if findings.is_empty() {
+            println!("No risky exposures in {} Funnel route(s)", public);
+            return Ok(());
+        }
+
+        let rows: Vec<Vec<String>> = findings
+            .iter()
+            .map(|finding| {
+                vec![
+                    finding.severity.as_str().to_uppercase(),
+                    finding.check.as_str().to_string(),
+                    format!("{}{}", finding.host_port, finding.path),
+                    finding.target.clone(),
+                    if finding.funnelctl {
+                        "funnelctl"
+                    } else {
+                        "other"
+                    }
+                    .to_string(),
+                    finding
+                        .expose_for
+                        .clone()
+                        .unwrap_or_else(|| "-".to_string()),
+                    finding.message.clone(),
+                ]
+            })
+            .collect();
+        println!(
+            "{}",
+            render_table(
+                &["SEVERITY", "CHECK", "ROUTE", "TARGET", "OWNER", "FOR", "DETAIL"],
+                &rows
+            )
+        );
+        let count = |severity: Severity| {
+            findings
+                .iter()
+                .filter(|finding| finding.severity == severity)
+                .count()
+        };
+        println!(
+            "{} finding(s) in {} Funnel route(s): {} high, {} medium, {} low",
+            findings.len(),
+            public,
+            count(Severity::High),
+            count(Severity::Medium),
+            count(Severity::Low)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,281 @@
+//! `funnelctl check -f funnel.yaml`: validates a spec file and plays it against the live
+//! serve config without writing anything, reporting each tunnel on its own so CI can
+//! gate a merge on the file applying cleanly.
+
+use std::sync::Arc;
+
+use chrono::{Local, Utc};
+use serde::Serialize;
+
+use crate::backend::Backend;
+use crate::cli::CheckArgs;
+use crate::cmd::apply::{default_ttl, remove_owned};
+use crate::cmd::open::config_path_policy;
+use crate::config::OpenConfig;
+use crate::core::manifest::{check_name, plan, ApplyAction, DesiredTunnel, Manifest};
+use crate::core::{LeaseStore, ServeConfig};
+use crate::error::{FunnelError, Result};
+use crate::output::Theme;
+
+pub struct CheckCommand {
+    args: CheckArgs,
+    config: OpenConfig,
+    theme: Theme,
+}
+
+/// One line of `check --json`
+#[derive(Debug, Serialize)]
+struct EntryReport {
+    name: String,
+    ok: bool,
+    /// What apply would do; absent when the entry itself is invalid
+    action: Option<&'static str>,
+    url: Option<String>,
+    error: Option<String>,
+    error_code: Option<&'static str>,
+    #[serde(skip)]
+    exit_code: i32,
+}
+
+impl EntryReport {
+    fn passed(name: &str, action: &'static str, url: Option<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            ok: true,
+            action: Some(action),
+            url,
+            error: None,
+            error_code: None,
+            exit_code: 0,
+        }
+    }
+
+    fn failed(name: &str, action: Option<&'static str>, err: &FunnelError) -> Self {
+        Self {
+            name: name.to_string(),
+            ok: false,
+            action,
+            url: None,
+            error: Some(err.to_string()),
+            error_code: Some(err.code().name()),
+            exit_code: err.exit_code(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record<'a> {
+    Entry(&'a EntryReport),
+    Summary {
+        project: &'a str,
+        total: usize,
+        passed: usize,
+        failed: usize,
+        exit_code: i32,
+    },
+}
+
+impl CheckCommand {
+    pub fn new(args: CheckArgs, config: OpenConfig, theme: Theme) -> Self {
+        Self {
+            args,
+            config,
+            theme,
+        }
+    }
+
+    /// Returns the exit code: 0 when every entry would apply, else the code of the first
+    /// failure. Errors are only for files that cannot be read at all.
+    pub async fn run(self, backend: Arc<dyn Backend>) -> Result<i32> {
+        let manifest = Manifest::from_path(&self.args.file)?;
+        let project = manifest.project()?.to_string();
+        let policy = config_path_policy(&self.config)?;
+        let leases = LeaseStore::open()?.list()?;
+        let now = Utc::now();
+
+        let mut reports = Vec::new();
+        let mut valid = Vec::new();
+        for (name, result) in manifest.check(&policy, Local::now())? {
+            let result = result.and_then(|mut tunnel| {
+                default_ttl(&mut tunnel, &self.config)?;
+                check_name(&tunnel.name, &leases, &project, now)?;
+                Ok(tunnel)
+            });
+            match result {
+                Ok(tunnel) => valid.push(tunnel),
+                Err(err) => reports.push(EntryReport::failed(&name, None, &err)),
+            }
+        }
+
+        let actions = plan(valid, &leases, &project, now)?;
+        let live = backend.serve_config().await?;
+        reports.extend(simulate(backend.as_ref(), live, &actions).await);
+        // File order, with the removals apply would make after the file's entries
+        let order = |report: &EntryReport| {
+            manifest
+                .tunnels
+                .iter()
+                .position(|tunnel| tunnel.name == report.name && report.action != Some("remove"))
+                .unwrap_or(usize::MAX)
+        };
+        reports.sort_by_key(order);
+
+        let failed = reports.iter().filter(|report| !report.ok).count();
+        let exit_code = reports
+            .iter()
+            .find(|report| !report.ok)
+            .map_or(0, |report| report.exit_code);
+        if self.args.json {
+            for report in &reports {
+                print_record(&Record::Entry(report))?;
+            }
+            print_record(&Record::Summary {
+                project: &project,
+                total: reports.len(),
+                passed: reports.len() - failed,
+                failed,
+                exit_code,
+            })?;
+        } else {
+            self.print_human(&project, &reports);
+        }
+        Ok(exit_code)
+    }
+
+    fn print_human(&self, project: &str, reports: &[EntryReport]) {
+        let (pass, fail) = self.theme.marks();
+        let width = reports
+            .iter()
+            .map(|report| report.name.len())
+            .max()
+            .unwrap_or(0);
+        for report in reports {
+            let mark = if report.ok { &pass } else { &fail };
+            let detail = match (&report.error, &report.url) {
+                (Some(error), _) => error.clone(),
+                (None, Some(url)) => url.clone(),
+                (None, None) => String::new(),
+            };
+            let line = format!(
+                "{} {:<width$}  {:<9}  {}",
+                mark,
+                report.name,
+                report.action.unwrap_or("invalid"),
+                detail,
+                width = width
+            );
+            println!("{}", self.theme.text(line.trim_end()));
+        }
+        let passed = reports.iter().filter(|report| report.ok).count();
+        println!(
+            "{}: {} of {} tunnels apply cleanly",
+            project,
+            passed,
+            reports.len()
+        );
+    }
+}
+
+/// Plays the plan against a copy of the live serve config. Each change is tried on its
+/// own copy, so a failed entry leaves nothing behind for the ones after it.
+async fn simulate(
+    backend: &dyn Backend,
+    mut config: ServeConfig,
+    actions: &[ApplyAction],
+) -> Vec<EntryReport> {
+    let mut reports = Vec::with_capacity(actions.len());
+    for action in actions {
+        let report = match action {
+            ApplyAction::Remove(lease) => match remove_owned(&mut config, lease) {
+                Ok(()) => EntryReport::passed(action.name(), "remove", lease.url.clone()),
+                Err(err) => EntryReport::failed(action.name(), Some("remove"), &err),
+            },
+            ApplyAction::Unchanged(lease) => {
+                EntryReport::passed(action.name(), "unchanged", lease.url.clone())
+            }
+            ApplyAction::Update { lease, desired } => {
+                let mut trial = config.clone();
+                let result = match remove_owned(&mut trial, lease) {
+                    Ok(()) => preview(backend, desired, &mut trial).await,
+                    Err(err) => Err(err),
+                };
+                settle(&mut config, trial, action.name(), "update", result)
+            }
+            ApplyAction::Create(desired) => {
+                let mut trial = config.clone();
+                let result = preview(backend, desired, &mut trial).await;
+                settle(&mut config, trial, action.name(), "create", result)
+            }
+        };
+        reports.push(report);
+    }
+    reports
+}
+
+async fn preview(
+    backend: &dyn Backend,
+    desired: &DesiredTunnel,
+    config: &mut ServeConfig,
+) -> Result<String> {
+    Ok(backend.preview(&desired.spec, config).await?.to_string())
+}
+
+/// Keeps the trial config only when the change went through
+fn settle(
+    config: &mut ServeConfig,
+    trial: ServeConfig,
+    name: &str,
+    action: &'static str,
+    result: Result<String>,
+) -> EntryReport {
+    match result {
+        Ok(url) => {
+            *config = trial;
+            EntryReport::passed(name, action, Some(url))
+        }
+        Err(err) => EntryReport::failed(name, Some(action), &err),
+    }
+}
+
+fn print_record(record: &Record) -> Result<()> {
+    let line = serde_json::to_string(record).map_err(|err| FunnelError::Other(err.to_string()))?;
+    println!("{}", line);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MockBackend;
+    use crate::core::{LocalTarget, TunnelSpec};
+
+    fn create(name: &str, path: &str) -> ApplyAction {
+        ApplyAction::Create(DesiredTunnel {
+            name: name.to_string(),
+            spec: TunnelSpec::new(
+                LocalTarget::new("127.0.0.1".to_string(), 3000),
+                443,
+                path.to_string(),
+                true,
+            )
+            .detached(),
+            ttl: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_simulate_reports_each_entry() {
+        let backend = MockBackend::new();
+        let config = backend.serve_config().await.unwrap();
+        let actions = vec![create("stripe", "/hooks/stripe"), create("docs", "/docs")];
+        let reports = simulate(&backend, config, &actions).await;
+        assert_eq!(reports.len(), 2);
+        assert!(reports.iter().all(|report| report.ok));
+        assert_eq!(reports[0].action, Some("create"));
+        assert!(reports[0]
+            .url
+            .as_deref()
+            .is_some_and(|url| url.ends_with("/hooks/stripe")));
+    }
+}
@@ -0,0 +1,169 @@
+use std::io::{BufRead, IsTerminal, Write};
+use std::sync::Arc;
+
+use chrono::Utc;
+
+use crate::backend::Backend;
+use crate::cli::CleanArgs;
+use crate::core::{Lease, LeaseStore, Route};
+use crate::error::{FunnelError, Result};
+use crate::lock::LockGuard;
+
+pub struct CleanCommand {
+    args: CleanArgs,
+}
+
+impl CleanCommand {
+    pub fn new(args: CleanArgs) -> Self {
+        Self { args }
+    }
+
+    pub async fn run(self, backend: Arc<dyn Backend>) -> Result<()> {
+        let store = LeaseStore::open()?;
+        let stale = store.collect_garbage(Utc::now())?;
+        let live = backend.routes().await?;
+        let leases = store.list()?;
+        let orphans = orphaned_routes(&live, &leases);
+        if stale.is_empty() && (orphans.is_empty() || !self.args.orphans) {
+            println!("No stale tunnels");
+            if !orphans.is_empty() {
+                println!(
+                    "{} route(s) marked as funnelctl's have no lease here; `funnelctl clean --orphans` removes them",
+                    orphans.len()
+                );
+            }
+            return Ok(());
+        }
+
+        let mut routes: Vec<Route> = live
+            .into_iter()
+            .filter(|route| stale.iter().any(|lease| lease.owns(route)))
+            .collect();
+
+        if !stale.is_empty() {
+            println!("Stale tunnels:");
+        }
+        for lease in &stale {
+            let leftover = if routes.iter().any(|route| lease.owns(route)) {
+                ", route left behind"
+            } else {
+                ""
+            };
+            println!(
+                "  {}  {}  ({}{})",
+                lease.name.as_deref().unwrap_or("-"),
+                lease
+                    .url
+                    .as_deref()
+                    .unwrap_or(lease.tunnel_spec.path.as_str()),
+                stale_reason(lease),
+                leftover
+            );
+        }
+        if self.args.orphans && !orphans.is_empty() {
+            println!("Routes marked as funnelctl's with no lease:");
+            for route in &orphans {
+                println!("  {}{}  ({})", route.host_port, route.path, route.target);
+            }
+            routes.extend(orphans);
+        } else if !orphans.is_empty() {
+            println!(
+                "{} route(s) marked as funnelctl's have no lease here; add --orphans to remove them",
+                orphans.len()
+            );
+        }
+
+        if self.args.dry_run {
+            return Ok(());
+        }
+        if !self.args.yes && !confirm(stale.len(), routes.len())? {
+            println!("Nothing removed");
+            return Ok(());
+        }
+
+        if !routes.is_empty() {
+            let _lock = LockGuard::acquire()?;
+            backend.remove_routes(&routes).await?;
+        }
+        for lease in &stale {
+            store.remove(&lease.lease_id)?;
+        }
+        println!(
+            "Removed {} stale lease record(s) and {} route(s)",
+            stale.len(),
+            routes.len()
+        );
+        Ok(())
+    }
+}
+
+/// Background routes carrying funnelctl's marker that no lease in this store owns, e.g.
+/// after the lease store was lost. Foreground routes are left out: tailscaled drops them
+/// with their session, so one still present belongs to a running process.
+fn orphaned_routes(routes: &[Route], leases: &[Lease]) -> Vec<Route> {
+    routes
+        .iter()
+        .filter(|route| route.owned && route.session.is_none())
+        .filter(|route| !leases.iter().any(|lease| lease.owns(route)))
+        .cloned()
+        .collect()
+}
+
+pub(crate) fn stale_reason(lease: &Lease) -> &'static str {
+    if lease.pid.is_some() {
+        "owner exited"
+    } else {
+        "expired"
+    }
+}
+
+fn confirm(leases: usize, routes: usize) -> Result<bool> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Err(FunnelError::InvalidArgument(
+            "clean needs --yes when stdin is not a terminal".to_string(),
+        ));
+    }
+
+    print!(
+        "Remove {} stale lease record(s) and {} route(s)? [y/N] ",
+        leases, routes
+    );
+    std::io::stdout()
+        .flush()
+        .map_err(|err| FunnelError::Other(err.to_string()))?;
+    let mut answer = String::new();
+    stdin
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|err| FunnelError::Other(err.to_string()))?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(path: &str, owned: bool, session: Option<&str>) -> Route {
+        Route {
+            host_port: "node.ts.net:443".to_string(),
+            https_port: Some(443),
+            path: path.to_string(),
+            target: "http://127.0.0.1:3000".to_string(),
+            funnel: true,
+            session: session.map(str::to_string),
+            owned,
+        }
+    }
+
+    #[test]
+    fn test_orphaned_routes_need_marker_and_no_lease() {
+        let routes = vec![
+            route("/funnelctl/lost", true, None),
+            route("/docs", false, None),
+            route("/funnelctl/live", true, Some("session1")),
+        ];
+        let orphans = orphaned_routes(&routes, &[]);
+        assert_eq!(orphans, vec![routes[0].clone()]);
+    }
+}
@@ -1,11 +1,185 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::backend::Backend;
+use crate::cli::CloseArgs;
+use crate::control::{self, ControlCommand};
+use crate::core::{
+    parse_tag, resolve_lease, validate_https_port, Lease, LeaseStore, Route, TunnelSpec,
+};
 use crate::error::{FunnelError, Result};
+use crate::lock::LockGuard;
+
+/// How long a superseded `open` gets to acknowledge; it tears down after replying
+const SUPERSEDE_TIMEOUT: Duration = Duration::from_secs(2);
 
-pub struct CloseCommand;
+pub struct CloseCommand {
+    args: CloseArgs,
+}
 
 impl CloseCommand {
-    pub async fn run() -> Result<()> {
-        Err(FunnelError::Other(
-            "close command not yet implemented (MVP uses foreground sessions only)".to_string(),
-        ))
+    pub fn new(args: CloseArgs) -> Self {
+        Self { args }
+    }
+
+    pub async fn run(self, backend: Arc<dyn Backend>) -> Result<()> {
+        if self.args.all && !self.args.force {
+            return Err(FunnelError::InvalidArgument(
+                "--all also removes routes not created by funnelctl; add --force to confirm"
+                    .to_string(),
+            ));
+        }
+        if let Some(port) = self.args.https_port {
+            validate_https_port(port)?;
+        }
+        let path = self.args.path.as_deref();
+        let https_port = self.args.https_port;
+        let tags = self
+            .args
+            .tags
+            .iter()
+            .map(|tag| parse_tag(tag))
+            .collect::<Result<Vec<_>>>()?;
+
+        let store = LeaseStore::open()?;
+        let all_leases = store.list()?;
+        let leases: Vec<Lease> = match self.args.lease.as_deref() {
+            Some(query) => vec![resolve_lease(&all_leases, query)?.clone()],
+            None => all_leases
+                .into_iter()
+                .filter(|lease| lease.matches(path, https_port) && lease.has_tags(&tags))
+                .collect(),
+        };
+
+        // Running owners tear down their own session and lease; ask them first.
+        let mut closed_by_owner = Vec::new();
+        let mut remaining = Vec::new();
+        for lease in leases {
+            if lease.owner_is_running() && close_via_owner(&lease).await {
+                closed_by_owner.push(lease);
+            } else {
+                remaining.push(lease);
+            }
+        }
+        let leases = remaining;
+
+        let routes: Vec<Route> = backend
+            .routes()
+            .await?
+            .into_iter()
+            .filter(|route| {
+                if self.args.all {
+                    route.matches(path, https_port)
+                } else {
+                    leases.iter().any(|lease| lease.owns(route))
+                }
+            })
+            .collect();
+
+        if routes.is_empty() && leases.is_empty() && closed_by_owner.is_empty() {
+            println!("No matching tunnels to close");
+            return Ok(());
+        }
+
+        if !routes.is_empty() {
+            let _lock = LockGuard::acquire()?;
+            backend.remove_routes(&routes).await?;
+        }
+
+        for lease in &leases {
+            if let Some(pid) = lease.pid.filter(|_| lease.owner_is_running()) {
+                terminate_owner(pid);
+            }
+            store.remove(&lease.lease_id)?;
+        }
+
+        for lease in &closed_by_owner {
+            println!("Closed {}", describe_lease(lease));
+        }
+        for route in &routes {
+            println!(
+                "Closed {}{} ({})",
+                route.host_port, route.path, route.target
+            );
+        }
+        let closed_without_route = leases
+            .iter()
+            .filter(|lease| !routes.iter().any(|route| lease.owns(route)));
+        for lease in closed_without_route {
+            println!("Closed {} (route already gone)", describe_lease(lease));
+        }
+
+        Ok(())
+    }
+}
+
+fn describe_lease(lease: &Lease) -> &str {
+    lease
+        .url
+        .as_deref()
+        .unwrap_or(lease.tunnel_spec.path.as_str())
+}
+
+/// Asks the running `open` to shut down over its control socket
+async fn close_via_owner(lease: &Lease) -> bool {
+    match control::send(&lease.lease_id, &ControlCommand::Close).await {
+        Ok(reply) if reply.ok => true,
+        Ok(reply) => {
+            tracing::warn!(lease = %lease.lease_id, error = ?reply.error, "owner refused close");
+            false
+        }
+        Err(err) => {
+            tracing::debug!(lease = %lease.lease_id, error = %err, "control socket unavailable");
+            false
+        }
+    }
+}
+
+/// Tells every running `open` whose route `spec` now serves that it was superseded, so
+/// it exits instead of believing it still owns the route. A successful apply can only
+/// overlap a live tunnel under `--force`. Returns the leases that acknowledged.
+pub(crate) async fn supersede_displaced(
+    spec: &TunnelSpec,
+    by_lease_id: &str,
+    by: &str,
+) -> Vec<Lease> {
+    let leases = match LeaseStore::open().and_then(|store| store.list()) {
+        Ok(leases) => leases,
+        Err(err) => {
+            tracing::debug!(error = %err, "cannot read leases to notify superseded tunnels");
+            return Vec::new();
+        }
+    };
+    let command = ControlCommand::Supersede { by: by.to_string() };
+    let mut superseded = Vec::new();
+    for lease in leases {
+        if lease.lease_id == by_lease_id || !lease.owner_is_running() || !lease.displaced_by(spec) {
+            continue;
+        }
+        match control::send_with_timeout(&lease.lease_id, &command, SUPERSEDE_TIMEOUT).await {
+            Ok(reply) if reply.ok => superseded.push(lease),
+            Ok(reply) => {
+                tracing::warn!(lease = %lease.lease_id, error = ?reply.error, "owner refused supersede");
+            }
+            Err(err) => {
+                tracing::warn!(lease = %lease.lease_id, error = %err, "cannot notify superseded tunnel");
+            }
+        }
+    }
+    superseded
+}
+
+/// Falls back to SIGTERM when the owner's control socket is unreachable
+fn terminate_owner(pid: u32) {
+    #[cfg(unix)]
+    unsafe {
+        if libc::kill(pid as i32, libc::SIGTERM) != 0 {
+            tracing::warn!(pid, error = %std::io::Error::last_os_error(), "failed to signal lease owner");
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tracing::warn!(pid, "cannot signal lease owner on this platform");
     }
 }
@@ -1,124 +1,414 @@
+use std::collections::HashSet;
+use std::io::{BufRead, IsTerminal, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
-use crate::backend::{Backend, BackendStatus};
-use crate::error::{FunnelError, Result};
-use crate::output::use_color;
+use clap::ValueEnum;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Serialize;
+
+use crate::backend::localapi::LocalApiBackend;
+use crate::backend::operator::{current_user, is_root, set_operator_command};
+use crate::backend::{funnel_port_denied, Backend, BackendStatus, FUNNEL_DISABLED};
+use crate::core::{detect_conflicts, validate_https_port, Conflict, LocalTarget, TunnelSpec};
+use crate::error::{ErrorCode, FunnelError, Result};
+use crate::lock::LockGuard;
+use crate::net::probe::{self, ProbeResponder};
+use crate::net::LocalApiTransport;
+use crate::output::Theme;
+use crate::progress::NoProgress;
 
 pub struct DoctorCommand;
 
+#[derive(Debug, Clone, Default)]
+pub struct DoctorOptions {
+    /// LocalAPI is reached over TCP with a password, so auth gets its own check
+    pub tcp_mode: bool,
+    /// Run the public reachability probe
+    pub external: bool,
+    /// Append redacted raw LocalAPI documents
+    pub dump_raw: bool,
+    pub theme: Theme,
+    /// Run only these checks (empty means all)
+    pub only: Vec<DoctorCheck>,
+    /// Never run these checks
+    pub skip: Vec<DoctorCheck>,
+    /// Sockets to look for other tailscaled instances at; `None` skips that check
+    pub daemon_sockets: Option<Vec<PathBuf>>,
+    /// The socket the other checks talk to; `None` in TCP mode
+    pub socket: Option<PathBuf>,
+    /// Port the `port` check looks at; 443 when unset
+    pub https_port: Option<u16>,
+    /// Offer to set the current user as operator when permissions fail
+    pub fix: bool,
+    /// One JSON object per check, then a summary; no human output or raw dump
+    pub json: bool,
+}
+
+impl DoctorOptions {
+    fn selects(&self, check: DoctorCheck) -> bool {
+        (self.only.is_empty() || self.only.contains(&check)) && !self.skip.contains(&check)
+    }
+
+    /// The probe opens a real route, so it needs `--external` or an explicit `--only external`
+    fn runs_external(&self) -> bool {
+        (self.external || self.only.contains(&DoctorCheck::External))
+            && self.selects(DoctorCheck::External)
+    }
+}
+
+/// Doctor checks, as named by `--only` and `--skip`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DoctorCheck {
+    Reachable,
+    Auth,
+    Version,
+    Permissions,
+    Https,
+    Funnel,
+    Dns,
+    MagicDns,
+    Port,
+    Daemons,
+    State,
+    External,
+}
+
+impl DoctorCheck {
+    /// Name as accepted by `--only` and `--skip`
+    pub fn name(self) -> &'static str {
+        match self {
+            DoctorCheck::Reachable => "reachable",
+            DoctorCheck::Auth => "auth",
+            DoctorCheck::Version => "version",
+            DoctorCheck::Permissions => "permissions",
+            DoctorCheck::Https => "https",
+            DoctorCheck::Funnel => "funnel",
+            DoctorCheck::Dns => "dns",
+            DoctorCheck::MagicDns => "magic-dns",
+            DoctorCheck::Port => "port",
+            DoctorCheck::Daemons => "daemons",
+            DoctorCheck::State => "state",
+            DoctorCheck::External => "external",
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            DoctorCheck::Reachable => "tailscaled reachable",
+            DoctorCheck::Auth => "LocalAPI auth",
+            DoctorCheck::Version => "tailscaled version",
+            DoctorCheck::Permissions => "Permissions",
+            DoctorCheck::Https => "HTTPS enabled",
+            DoctorCheck::Funnel => "Funnel capability",
+            DoctorCheck::Dns => "DNS name available",
+            DoctorCheck::MagicDns => "MagicDNS",
+            DoctorCheck::Port => "HTTPS port",
+            DoctorCheck::Daemons => "tailscaled instances",
+            DoctorCheck::State => "State directory",
+            DoctorCheck::External => "External reachability",
+        }
+    }
+}
+
 #[derive(Debug)]
-struct CheckResult {
-    name: String,
-    passed: bool,
-    message: String,
-    error_code: Option<i32>,
+pub struct CheckResult {
+    pub check: DoctorCheck,
+    pub passed: bool,
+    pub message: String,
+    /// Exit code this failure maps to; `None` for passes and skipped checks
+    pub error_code: Option<i32>,
+}
+
+/// Outcome of a doctor run; the caller decides whether to exit with `exit_code`
+#[derive(Debug)]
+pub struct DoctorReport {
+    pub checks: Vec<CheckResult>,
+    /// Most severe failure code among the executed checks, or 0
+    pub exit_code: i32,
+}
+
+/// Checks that must all run and pass before `open` can publish a Funnel route
+const READY_CHECKS: [DoctorCheck; 6] = [
+    DoctorCheck::Reachable,
+    DoctorCheck::Version,
+    DoctorCheck::Permissions,
+    DoctorCheck::Https,
+    DoctorCheck::Funnel,
+    DoctorCheck::Dns,
+];
+
+/// One line of `doctor --json`
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonRecord<'a> {
+    Check {
+        check: &'static str,
+        passed: bool,
+        message: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error_code: Option<i32>,
+    },
+    Summary(Summary),
+}
+
+/// Final line of `doctor --json`, for CI gates
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct Summary {
+    total: usize,
+    passed: usize,
+    failed: usize,
+    exit_code: i32,
+    ready_for_funnel: bool,
+    /// Why `ready_for_funnel` is false, in one line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+impl Summary {
+    fn new(report: &DoctorReport) -> Self {
+        let passed = report.checks.iter().filter(|check| check.passed).count();
+        let failure = report
+            .checks
+            .iter()
+            .filter(|check| !check.passed && check.error_code.is_some())
+            .find(|check| check.error_code == Some(report.exit_code))
+            .or_else(|| report.checks.iter().find(|check| !check.passed));
+        let unchecked: Vec<&str> = READY_CHECKS
+            .iter()
+            .filter(|ready| !report.checks.iter().any(|check| check.check == **ready))
+            .map(|ready| ready.name())
+            .collect();
+        let reason = match failure {
+            Some(check) => Some(format!("{}: {}", check.check.label(), check.message)),
+            None if !unchecked.is_empty() => Some(format!("not checked: {}", unchecked.join(", "))),
+            None => None,
+        };
+        Self {
+            total: report.checks.len(),
+            passed,
+            failed: report.checks.len() - passed,
+            exit_code: report.exit_code,
+            ready_for_funnel: reason.is_none(),
+            reason,
+        }
+    }
+}
+
+fn json_lines(report: &DoctorReport) -> Result<Vec<String>> {
+    let records = report
+        .checks
+        .iter()
+        .map(|check| JsonRecord::Check {
+            check: check.check.name(),
+            passed: check.passed,
+            message: &check.message,
+            error_code: check.error_code,
+        })
+        .chain(std::iter::once(JsonRecord::Summary(Summary::new(report))));
+    records
+        .map(|record| {
+            serde_json::to_string(&record).map_err(|err| FunnelError::Other(err.to_string()))
+        })
+        .collect()
 }
 
 impl DoctorCommand {
-    pub async fn run(backend: Arc<dyn Backend>, tcp_mode: bool) -> Result<()> {
-        let use_color = use_color();
+    /// Runs the checks, prints them (and the raw dump with `dump_raw`), and returns the report
+    pub async fn run(backend: Arc<dyn Backend>, options: DoctorOptions) -> Result<DoctorReport> {
+        if let Some(port) = options.https_port {
+            validate_https_port(port)?;
+        }
+        let mut report = Self::check(backend.as_ref(), &options).await;
+        if options.json {
+            for line in json_lines(&report)? {
+                println!("{}", line);
+            }
+            return Ok(report);
+        }
+        Self::print_results(&report.checks, &options.theme);
+        if options.fix {
+            if let Some(fixed) = offer_operator_fix(backend.as_ref(), &report).await? {
+                Self::print_results(std::slice::from_ref(&fixed), &options.theme);
+                if let Some(check) = report
+                    .checks
+                    .iter_mut()
+                    .find(|check| check.check == DoctorCheck::Permissions)
+                {
+                    *check = fixed;
+                }
+                report.exit_code = select_exit_code(&report.checks);
+            }
+        }
+        if options.dump_raw {
+            print_raw_dump(backend.as_ref()).await;
+        }
+        Ok(report)
+    }
+
+    /// Runs the selected checks without printing anything
+    pub async fn check(backend: &dyn Backend, options: &DoctorOptions) -> DoctorReport {
         let status_result = backend.status().await;
 
         let mut checks = Vec::new();
         checks.push(check_tailscaled_reachable(&status_result));
-        if tcp_mode {
+        if options.tcp_mode {
             checks.push(check_localapi_auth(&status_result));
         }
 
         match &status_result {
             Ok(status) => {
                 checks.push(check_version(status));
-                checks.push(check_permissions(status));
+                checks.push(check_permissions(status, current_user().as_deref()));
                 checks.push(check_https_enabled(status));
-                checks.push(check_funnel_capability(status));
+                checks.push(check_funnel_capability(
+                    status,
+                    options.https_port.unwrap_or(443),
+                ));
                 checks.push(check_dns_name(status));
+                checks.push(check_magic_dns(status));
+                checks.push(
+                    check_https_port(backend, status, options.https_port.unwrap_or(443)).await,
+                );
             }
             Err(FunnelError::Permission { .. }) => {
                 checks.push(CheckResult {
-                    name: "tailscaled version".to_string(),
+                    check: DoctorCheck::Version,
                     passed: false,
                     message: "Cannot check version (permission denied)".to_string(),
-                    error_code: Some(11),
+                    error_code: Some(ErrorCode::Permission.code()),
                 });
                 checks.push(CheckResult {
-                    name: "Permissions".to_string(),
+                    check: DoctorCheck::Permissions,
                     passed: false,
                     message: "Permission denied — need root or operator group".to_string(),
-                    error_code: Some(11),
+                    error_code: Some(ErrorCode::Permission.code()),
                 });
                 checks.push(CheckResult {
-                    name: "HTTPS enabled".to_string(),
+                    check: DoctorCheck::Https,
                     passed: false,
                     message: "Cannot check HTTPS (permission denied)".to_string(),
-                    error_code: Some(11),
+                    error_code: Some(ErrorCode::Permission.code()),
                 });
                 checks.push(CheckResult {
-                    name: "Funnel capability".to_string(),
+                    check: DoctorCheck::Funnel,
                     passed: false,
                     message: "Cannot check Funnel capability (permission denied)".to_string(),
-                    error_code: Some(11),
+                    error_code: Some(ErrorCode::Permission.code()),
                 });
                 checks.push(CheckResult {
-                    name: "DNS name available".to_string(),
+                    check: DoctorCheck::Dns,
                     passed: false,
                     message: "Cannot check DNS name (permission denied)".to_string(),
-                    error_code: Some(11),
+                    error_code: Some(ErrorCode::Permission.code()),
+                });
+                checks.push(CheckResult {
+                    check: DoctorCheck::MagicDns,
+                    passed: false,
+                    message: "Cannot check MagicDNS (permission denied)".to_string(),
+                    error_code: Some(ErrorCode::Permission.code()),
+                });
+                checks.push(CheckResult {
+                    check: DoctorCheck::Port,
+                    passed: false,
+                    message: "Cannot check HTTPS port (permission denied)".to_string(),
+                    error_code: Some(ErrorCode::Permission.code()),
                 });
             }
             Err(_) => {
                 checks.push(CheckResult {
-                    name: "tailscaled version".to_string(),
+                    check: DoctorCheck::Version,
                     passed: false,
                     message: "Cannot check version (tailscaled unreachable)".to_string(),
-                    error_code: Some(10),
+                    error_code: Some(ErrorCode::Unreachable.code()),
                 });
                 checks.push(CheckResult {
-                    name: "Permissions".to_string(),
+                    check: DoctorCheck::Permissions,
                     passed: false,
                     message: "Cannot check permissions (tailscaled unreachable)".to_string(),
-                    error_code: Some(10),
+                    error_code: Some(ErrorCode::Unreachable.code()),
                 });
                 checks.push(CheckResult {
-                    name: "HTTPS enabled".to_string(),
+                    check: DoctorCheck::Https,
                     passed: false,
                     message: "Cannot check HTTPS (tailscaled unreachable)".to_string(),
-                    error_code: Some(10),
+                    error_code: Some(ErrorCode::Unreachable.code()),
                 });
                 checks.push(CheckResult {
-                    name: "Funnel capability".to_string(),
+                    check: DoctorCheck::Funnel,
                     passed: false,
                     message: "Cannot check Funnel capability (tailscaled unreachable)".to_string(),
-                    error_code: Some(10),
+                    error_code: Some(ErrorCode::Unreachable.code()),
                 });
                 checks.push(CheckResult {
-                    name: "DNS name available".to_string(),
+                    check: DoctorCheck::Dns,
                     passed: false,
                     message: "Cannot check DNS name (tailscaled unreachable)".to_string(),
-                    error_code: Some(10),
+                    error_code: Some(ErrorCode::Unreachable.code()),
+                });
+                checks.push(CheckResult {
+                    check: DoctorCheck::MagicDns,
+                    passed: false,
+                    message: "Cannot check MagicDNS (tailscaled unreachable)".to_string(),
+                    error_code: Some(ErrorCode::Unreachable.code()),
+                });
+                checks.push(CheckResult {
+                    check: DoctorCheck::Port,
+                    passed: false,
+                    message: "Cannot check HTTPS port (tailscaled unreachable)".to_string(),
+                    error_code: Some(ErrorCode::Unreachable.code()),
                 });
             }
         }
 
-        Self::print_results(&checks, use_color);
+        if let Some(sockets) = &options.daemon_sockets {
+            if options.selects(DoctorCheck::Daemons) {
+                let daemons = survey_daemons(sockets).await;
+                checks.push(check_daemons(&daemons, options.socket.as_deref()));
+            }
+        }
 
-        let exit_code = select_exit_code(&checks);
-        if exit_code != 0 {
-            std::process::exit(exit_code);
+        if options.selects(DoctorCheck::State) {
+            checks.push(check_state_dir(crate::dirs::state_dir()));
+        }
+
+        checks.retain(|check| options.selects(check.check));
+
+        if options.runs_external() {
+            let check = if checks.iter().all(|check| check.passed) {
+                check_external_reachability(backend).await
+            } else {
+                CheckResult {
+                    check: DoctorCheck::External,
+                    passed: false,
+                    message: "Skipped (fix the failures above first)".to_string(),
+                    error_code: None,
+                }
+            };
+            checks.push(check);
         }
 
-        Ok(())
+        let exit_code = select_exit_code(&checks);
+        DoctorReport { checks, exit_code }
     }
 
-    fn print_results(checks: &[CheckResult], use_color: bool) {
-        let (pass_mark, fail_mark) = if use_color {
-            ("\x1b[1;32m✓\x1b[0m", "\x1b[1;31m✗\x1b[0m")
-        } else {
-            ("✓", "✗")
-        };
+    fn print_results(checks: &[CheckResult], theme: &Theme) {
+        let (pass_mark, fail_mark) = theme.marks();
 
+        if checks.is_empty() {
+            println!("No checks selected");
+        }
         for check in checks {
-            let mark = if check.passed { pass_mark } else { fail_mark };
-            println!("{} {}: {}", mark, check.name, check.message);
+            let mark = if check.passed { &pass_mark } else { &fail_mark };
+            println!(
+                "{} {}: {}",
+                mark,
+                check.check.label(),
+                theme.text(&check.message)
+            );
         }
     }
 }
@@ -126,28 +416,28 @@ impl DoctorCommand {
 fn check_tailscaled_reachable(status: &Result<BackendStatus>) -> CheckResult {
     match status {
         Ok(_) => CheckResult {
-            name: "tailscaled reachable".to_string(),
+            check: DoctorCheck::Reachable,
             passed: true,
             message: "Socket exists and responds".to_string(),
             error_code: None,
         },
         Err(FunnelError::Permission { .. }) => CheckResult {
-            name: "tailscaled reachable".to_string(),
+            check: DoctorCheck::Reachable,
             passed: true,
             message: "Socket exists and responds".to_string(),
             error_code: None,
         },
         Err(FunnelError::Unreachable { .. }) => CheckResult {
-            name: "tailscaled reachable".to_string(),
+            check: DoctorCheck::Reachable,
             passed: false,
             message: "tailscaled not running".to_string(),
-            error_code: Some(10),
+            error_code: Some(ErrorCode::Unreachable.code()),
         },
         Err(_) => CheckResult {
-            name: "tailscaled reachable".to_string(),
+            check: DoctorCheck::Reachable,
             passed: false,
             message: "tailscaled not running".to_string(),
-            error_code: Some(10),
+            error_code: Some(ErrorCode::Unreachable.code()),
         },
     }
 }
@@ -155,22 +445,22 @@ fn check_tailscaled_reachable(status: &Result<BackendStatus>) -> CheckResult {
 fn check_localapi_auth(status: &Result<BackendStatus>) -> CheckResult {
     match status {
         Ok(_) => CheckResult {
-            name: "LocalAPI auth".to_string(),
+            check: DoctorCheck::Auth,
             passed: true,
             message: "Password accepted".to_string(),
             error_code: None,
         },
         Err(FunnelError::Permission { .. }) => CheckResult {
-            name: "LocalAPI auth".to_string(),
+            check: DoctorCheck::Auth,
             passed: false,
             message: "Invalid LocalAPI password".to_string(),
-            error_code: Some(11),
+            error_code: Some(ErrorCode::Permission.code()),
         },
         Err(_) => CheckResult {
-            name: "LocalAPI auth".to_string(),
+            check: DoctorCheck::Auth,
             passed: false,
             message: "Cannot check (tailscaled unreachable)".to_string(),
-            error_code: Some(10),
+            error_code: Some(ErrorCode::Unreachable.code()),
         },
     }
 }
@@ -180,82 +470,186 @@ fn check_version(status: &BackendStatus) -> CheckResult {
         Some(version) => {
             if version_supported(version) {
                 CheckResult {
-                    name: "tailscaled version".to_string(),
+                    check: DoctorCheck::Version,
                     passed: true,
                     message: format!("Version {} (>= 1.50.0)", version),
                     error_code: None,
                 }
             } else {
                 CheckResult {
-                    name: "tailscaled version".to_string(),
+                    check: DoctorCheck::Version,
                     passed: false,
                     message: format!("tailscaled too old (got {}, need 1.50.0+)", version),
-                    error_code: Some(16),
+                    error_code: Some(ErrorCode::VersionTooOld.code()),
                 }
             }
         }
         None => CheckResult {
-            name: "tailscaled version".to_string(),
+            check: DoctorCheck::Version,
             passed: false,
             message: "Version unknown".to_string(),
-            error_code: Some(16),
+            error_code: Some(ErrorCode::VersionTooOld.code()),
         },
     }
 }
 
-fn check_permissions(status: &BackendStatus) -> CheckResult {
-    match status.permissions_ok {
-        Some(true) => CheckResult {
-            name: "Permissions".to_string(),
+/// `user` is who runs doctor; when it is not the operator, the failure names the exact
+/// `tailscale set --operator` command
+fn check_permissions(status: &BackendStatus, user: Option<&str>) -> CheckResult {
+    let is_operator = user.is_some() && status.operator_user.as_deref() == user;
+    let guidance = if is_operator {
+        format!(
+            " ({} is already the operator, yet writes are refused; try sudo)",
+            user.unwrap_or_default()
+        )
+    } else {
+        let current = status
+            .operator_user
+            .as_deref()
+            .map(|operator| format!("; the operator is {}", operator))
+            .unwrap_or_default();
+        format!(" — run: {}{}", set_operator_command(user), current)
+    };
+    match (status.permissions_ok, status.serve_config_readable) {
+        (Some(true), _) => CheckResult {
+            check: DoctorCheck::Permissions,
             passed: true,
             message: "Can read/write ServeConfig".to_string(),
             error_code: None,
         },
-        Some(false) => CheckResult {
-            name: "Permissions".to_string(),
+        (Some(false), Some(true)) => CheckResult {
+            check: DoctorCheck::Permissions,
             passed: false,
-            message: "Permission denied — need root or operator group".to_string(),
-            error_code: Some(11),
+            message: format!(
+                "Read-only access — status, audit and export work, but open and close need root or the operator{}",
+                guidance
+            ),
+            error_code: Some(ErrorCode::Permission.code()),
         },
-        None => CheckResult {
-            name: "Permissions".to_string(),
+        (Some(false), _) => CheckResult {
+            check: DoctorCheck::Permissions,
+            passed: false,
+            message: format!("Permission denied — need root or operator group{}", guidance),
+            error_code: Some(ErrorCode::Permission.code()),
+        },
+        (None, Some(true)) => CheckResult {
+            check: DoctorCheck::Permissions,
+            passed: true,
+            message: "Can read ServeConfig; write access not verified".to_string(),
+            error_code: None,
+        },
+        (None, _) => CheckResult {
+            check: DoctorCheck::Permissions,
             passed: false,
             message: "Permission check unavailable".to_string(),
-            error_code: Some(11),
+            error_code: Some(ErrorCode::Permission.code()),
         },
     }
 }
 
+/// `--fix`: offers to make the current user the operator when the permissions check
+/// failed for lack of it, then checks again. Returns the new permissions result if the
+/// command ran.
+async fn offer_operator_fix(
+    backend: &dyn Backend,
+    report: &DoctorReport,
+) -> Result<Option<CheckResult>> {
+    let denied = report.checks.iter().any(|check| {
+        check.check == DoctorCheck::Permissions
+            && check.error_code == Some(ErrorCode::Permission.code())
+    });
+    if !denied || is_root() || !cfg!(unix) {
+        return Ok(None);
+    }
+    let Some(user) = current_user() else {
+        return Ok(None);
+    };
+    let operator = backend
+        .status()
+        .await
+        .ok()
+        .and_then(|status| status.operator_user);
+    if operator.as_deref() == Some(user.as_str()) {
+        return Ok(None);
+    }
+
+    let command = set_operator_command(Some(&user));
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        println!("To fix permissions, run: {}", command);
+        return Ok(None);
+    }
+    print!("Run `{}` now? [y/N] ", command);
+    std::io::stdout()
+        .flush()
+        .map_err(|err| FunnelError::Other(err.to_string()))?;
+    let mut answer = String::new();
+    stdin
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|err| FunnelError::Other(err.to_string()))?;
+    if !matches!(answer.trim(), "y" | "Y" | "yes") {
+        return Ok(None);
+    }
+
+    let status = std::process::Command::new("sudo")
+        .args(["tailscale", "set", &format!("--operator={}", user)])
+        .status()
+        .map_err(|err| FunnelError::Other(format!("Failed to run sudo: {}", err)))?;
+    if !status.success() {
+        return Err(FunnelError::Other(format!(
+            "`{}` failed ({})",
+            command, status
+        )));
+    }
+    let status = backend.status().await?;
+    Ok(Some(check_permissions(&status, Some(&user))))
+}
+
 fn check_https_enabled(status: &BackendStatus) -> CheckResult {
     match status.https_enabled {
         Some(true) => CheckResult {
-            name: "HTTPS enabled".to_string(),
+            check: DoctorCheck::Https,
             passed: true,
             message: "Node has HTTPS cert".to_string(),
             error_code: None,
         },
         Some(false) | None => CheckResult {
-            name: "HTTPS enabled".to_string(),
+            check: DoctorCheck::Https,
             passed: false,
             message: "HTTPS not enabled. Run `tailscale cert`".to_string(),
-            error_code: Some(12),
+            error_code: Some(ErrorCode::Prerequisites.code()),
         },
     }
 }
 
-fn check_funnel_capability(status: &BackendStatus) -> CheckResult {
-    match status.funnel_enabled {
-        Some(true) => CheckResult {
-            name: "Funnel capability".to_string(),
+fn check_funnel_capability(status: &BackendStatus, https_port: u16) -> CheckResult {
+    let permitted = status.funnel_permitted().unwrap_or_default();
+    match (status.funnel_enabled(), status.funnel_allowed(https_port)) {
+        (Some(true), Some(true)) => CheckResult {
+            check: DoctorCheck::Funnel,
             passed: true,
-            message: "Tailnet allows Funnel".to_string(),
+            message: format!(
+                "Tailnet allows Funnel (ports {})",
+                permitted
+                    .iter()
+                    .map(u16::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
             error_code: None,
         },
-        Some(false) | None => CheckResult {
-            name: "Funnel capability".to_string(),
+        (Some(true), _) => CheckResult {
+            check: DoctorCheck::Funnel,
             passed: false,
-            message: "Funnel not enabled in tailnet policy".to_string(),
-            error_code: Some(12),
+            message: funnel_port_denied(https_port, &permitted),
+            error_code: Some(ErrorCode::Prerequisites.code()),
+        },
+        _ => CheckResult {
+            check: DoctorCheck::Funnel,
+            passed: false,
+            message: FUNNEL_DISABLED.to_string(),
+            error_code: Some(ErrorCode::Prerequisites.code()),
         },
     }
 }
@@ -263,31 +657,387 @@ fn check_funnel_capability(status: &BackendStatus) -> CheckResult {
 fn check_dns_name(status: &BackendStatus) -> CheckResult {
     match status.dns_name.as_deref() {
         Some(name) => CheckResult {
-            name: "DNS name available".to_string(),
+            check: DoctorCheck::Dns,
             passed: true,
             message: format!("Node name: {}", name),
             error_code: None,
         },
         None => CheckResult {
-            name: "DNS name available".to_string(),
+            check: DoctorCheck::Dns,
             passed: false,
             message: "Node not yet assigned DNS name".to_string(),
-            error_code: Some(12),
+            error_code: Some(ErrorCode::Prerequisites.code()),
+        },
+    }
+}
+
+/// Without MagicDNS the node has no `*.ts.net` name, so Funnel URLs do not resolve publicly
+fn check_magic_dns(status: &BackendStatus) -> CheckResult {
+    match status.magic_dns_enabled {
+        Some(true) => CheckResult {
+            check: DoctorCheck::MagicDns,
+            passed: true,
+            message: "MagicDNS enabled".to_string(),
+            error_code: None,
+        },
+        Some(false) => CheckResult {
+            check: DoctorCheck::MagicDns,
+            passed: false,
+            message: "MagicDNS disabled; Funnel URLs will not resolve. Enable it at https://login.tailscale.com/admin/dns".to_string(),
+            error_code: Some(ErrorCode::Prerequisites.code()),
+        },
+        None => CheckResult {
+            check: DoctorCheck::MagicDns,
+            passed: true,
+            message: "Not reported by this tailscaled; assuming enabled".to_string(),
+            error_code: None,
+        },
+    }
+}
+
+/// Prints redacted raw LocalAPI documents so bug reports show what tailscaled returned
+async fn print_raw_dump(backend: &dyn Backend) {
+    match backend.diagnostics().await {
+        Ok(documents) => {
+            for (label, document) in documents {
+                println!();
+                println!("--- {} (redacted) ---", label);
+                match serde_json::to_string_pretty(&document) {
+                    Ok(pretty) => println!("{}", pretty),
+                    Err(err) => println!("<unprintable: {}>", err),
+                }
+            }
+        }
+        Err(err) => {
+            println!();
+            println!("--- raw LocalAPI dump unavailable: {} ---", err);
+        }
+    }
+}
+
+/// Opens a temporary Funnel route to a loopback responder and fetches it through the
+/// node's public ingress addresses, catching firewalls and DNS problems local checks miss
+async fn check_external_reachability(backend: &dyn Backend) -> CheckResult {
+    const ATTEMPTS: u32 = 5;
+    const RETRY_DELAY: Duration = Duration::from_secs(2);
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    let fail = |message: String, error_code: Option<i32>| CheckResult {
+        check: DoctorCheck::External,
+        passed: false,
+        message,
+        error_code,
+    };
+
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect();
+    let responder = match ProbeResponder::start(token.clone()).await {
+        Ok(responder) => responder,
+        Err(err) => {
+            return fail(
+                format!("Cannot start probe responder: {}", err),
+                Some(ErrorCode::Other.code()),
+            )
+        }
+    };
+
+    let target = LocalTarget::new("127.0.0.1".to_string(), responder.port());
+    let path = format!("/funnelctl-probe/{}", token);
+    let spec = TunnelSpec::new(target, 443, path.clone(), true);
+    let applied = match LockGuard::acquire() {
+        Ok(_lock) => backend.apply(&spec, &NoProgress).await,
+        Err(err) => Err(err),
+    };
+    let result = match applied {
+        Ok(result) => result,
+        Err(err) => {
+            return fail(
+                format!("Cannot open probe route: {}", err),
+                Some(err.exit_code()),
+            )
+        }
+    };
+
+    let outcome = probe_public_url(
+        &result.url,
+        &path,
+        &token,
+        ATTEMPTS,
+        RETRY_DELAY,
+        REQUEST_TIMEOUT,
+    )
+    .await;
+    if let Err(err) = backend.remove(&result.lease_id).await {
+        tracing::warn!(error = %err, "failed to remove probe route");
+    }
+
+    match outcome {
+        Ok(addr) => CheckResult {
+            check: DoctorCheck::External,
+            passed: true,
+            message: format!("Reached {} via public ingress {}", result.url, addr),
+            error_code: None,
+        },
+        Err(message) => fail(
+            format!(
+                "Funnel not reachable from the public internet ({}). Check upstream firewalls and that the tailnet allows Funnel on this node",
+                message
+            ),
+            Some(ErrorCode::Prerequisites.code()),
+        ),
+    }
+}
+
+/// Fetches `path` from the public ingress addresses of `url`'s host until one answers `token`
+pub(crate) async fn probe_public_url(
+    url: &url::Url,
+    path: &str,
+    token: &str,
+    attempts: u32,
+    retry_delay: Duration,
+    request_timeout: Duration,
+) -> std::result::Result<IpAddr, String> {
+    let host = url.host_str().ok_or("probe URL has no host")?;
+    let addrs = probe::public_addresses(host, request_timeout)
+        .await
+        .map_err(|err| format!("public DNS lookup for {} failed: {}", host, err))?;
+
+    let mut last_error = String::new();
+    for attempt in 0..attempts {
+        if attempt > 0 {
+            tokio::time::sleep(retry_delay).await;
+        }
+        for addr in &addrs {
+            match probe::https_get(*addr, 443, host, path, request_timeout).await {
+                Ok((status, body)) if status.is_success() && body == token.as_bytes() => {
+                    return Ok(*addr)
+                }
+                Ok((status, _)) => last_error = format!("{} answered {}", addr, status),
+                Err(err) => last_error = format!("{}: {}", addr, err),
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Stands in for a path `open` would generate when asking whether the port has room
+const PORT_PROBE_PATH: &str = "/funnelctl/doctor";
+
+/// Runs `open`'s conflict check for a new path on `https_port`. A background handler
+/// whose prefix captures it, typically a full-site `tailscale serve --bg` on `/`, makes
+/// every `open` there fail with exit 13.
+async fn check_https_port(
+    backend: &dyn Backend,
+    status: &BackendStatus,
+    https_port: u16,
+) -> CheckResult {
+    let Some(dns_name) = status.dns_name.as_deref() else {
+        return CheckResult {
+            check: DoctorCheck::Port,
+            passed: false,
+            message: "Cannot check HTTPS port (node has no DNS name)".to_string(),
+            error_code: None,
+        };
+    };
+    let config = match backend.serve_config().await {
+        Ok(config) => config,
+        Err(err) => {
+            return CheckResult {
+                check: DoctorCheck::Port,
+                passed: false,
+                message: format!("Cannot read the serve config: {}", err),
+                error_code: Some(err.exit_code()),
+            }
+        }
+    };
+
+    let host_port = format!("{}:{}", dns_name, https_port);
+    let routes = config
+        .get_handlers(&host_port)
+        .map_or(0, |handlers| handlers.len());
+    let captured = detect_conflicts(&config, &host_port, PORT_PROBE_PATH, "", true)
+        .err()
+        .into_iter()
+        .flatten()
+        .find_map(|conflict| match conflict {
+            Conflict::CapturedByExistingPrefix {
+                existing_prefix,
+                existing_target,
+                ..
+            } => Some((existing_prefix, existing_target)),
+            _ => None,
+        });
+    match captured {
+        Some((existing_prefix, existing_target)) => {
+            let alternate = if https_port == 8443 { 10000 } else { 8443 };
+            CheckResult {
+                check: DoctorCheck::Port,
+                passed: false,
+                message: format!(
+                    "Port {} already serves {} -> {} in the background (tailscale serve --bg), so `open` there fails. Use --https-port {}, add --force to override it, or remove it with `tailscale serve --https={} off`",
+                    https_port,
+                    existing_prefix,
+                    existing_target,
+                    alternate,
+                    https_port
+                ),
+                error_code: Some(ErrorCode::Conflict.code()),
+            }
+        }
+        _ if routes == 0 => CheckResult {
+            check: DoctorCheck::Port,
+            passed: true,
+            message: format!("Port {} has no background routes", https_port),
+            error_code: None,
+        },
+        _ => CheckResult {
+            check: DoctorCheck::Port,
+            passed: true,
+            message: format!(
+                "Port {} has {} background route(s); new paths are free",
+                https_port, routes
+            ),
+            error_code: None,
+        },
+    }
+}
+
+/// How long each candidate socket gets to answer the status request
+const DAEMON_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Asks every candidate socket for its status, all at once
+async fn survey_daemons(sockets: &[PathBuf]) -> Vec<(PathBuf, Result<BackendStatus>)> {
+    let probes = sockets.iter().map(|socket| async move {
+        let backend = LocalApiBackend::new(LocalApiTransport::unix_socket(socket.clone()), false);
+        let status = match tokio::time::timeout(DAEMON_PROBE_TIMEOUT, backend.status()).await {
+            Ok(status) => status,
+            Err(_) => Err(FunnelError::Unreachable {
+                source: None,
+                context: "No answer".to_string(),
+            }),
+        };
+        (socket.clone(), status)
+    });
+    futures::future::join_all(probes).await
+}
+
+/// Warns when more than one tailscaled answers: without `--socket`, funnelctl talks to
+/// whichever owns the first default path, which may not be the one the user means. Never
+/// changes the exit code.
+fn check_daemons(
+    daemons: &[(PathBuf, Result<BackendStatus>)],
+    in_use: Option<&Path>,
+) -> CheckResult {
+    if daemons.is_empty() {
+        return CheckResult {
+            check: DoctorCheck::Daemons,
+            passed: true,
+            message: "No tailscaled socket at the usual paths".to_string(),
+            error_code: None,
+        };
+    }
+
+    // Sockets of one daemon reached through different paths report the same node
+    let mut nodes = HashSet::new();
+    let running = daemons
+        .iter()
+        .filter(|(_, status)| match status {
+            Ok(status) => status
+                .dns_name
+                .as_ref()
+                .map_or(true, |name| nodes.insert(name.clone())),
+            Err(FunnelError::Permission { .. }) => true,
+            Err(_) => false,
+        })
+        .count();
+    let listed = daemons
+        .iter()
+        .map(|(socket, status)| {
+            let about = match status {
+                Ok(status) => format!(
+                    "{}, {}",
+                    status.dns_name.as_deref().unwrap_or("no node name"),
+                    status.version.as_deref().unwrap_or("unknown version")
+                ),
+                Err(FunnelError::Permission { .. }) => "permission denied".to_string(),
+                Err(_) => "not answering".to_string(),
+            };
+            format!("{} ({})", socket.display(), about)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    if running <= 1 {
+        return CheckResult {
+            check: DoctorCheck::Daemons,
+            passed: true,
+            message: listed,
+            error_code: None,
+        };
+    }
+    let using = match in_use {
+        Some(socket) => format!("funnelctl uses {}", socket.display()),
+        None => "funnelctl uses --localapi-port".to_string(),
+    };
+    CheckResult {
+        check: DoctorCheck::Daemons,
+        passed: false,
+        message: format!(
+            "{} tailscaled instances: {}; {}. Pass --socket to choose one explicitly",
+            running, listed, using
+        ),
+        error_code: None,
+    }
+}
+
+/// Every other command needs the state directory, so doctor is where an unusable one
+/// (HOME unset and no fallback, a foreign directory in /tmp) gets explained
+fn check_state_dir(dir: Result<PathBuf>) -> CheckResult {
+    match dir {
+        Ok(dir) => CheckResult {
+            check: DoctorCheck::State,
+            passed: true,
+            message: dir.display().to_string(),
+            error_code: None,
+        },
+        Err(err) => CheckResult {
+            check: DoctorCheck::State,
+            passed: false,
+            message: format!("{}; pass --state-dir or set XDG_STATE_HOME", err),
+            error_code: Some(err.exit_code()),
         },
     }
 }
 
 fn select_exit_code(checks: &[CheckResult]) -> i32 {
-    let priority = [10, 11, 16, 12, 13, 14, 15, 2, 1];
+    let priority = [
+        ErrorCode::Unreachable,
+        ErrorCode::Permission,
+        ErrorCode::VersionTooOld,
+        ErrorCode::Prerequisites,
+        ErrorCode::Conflict,
+        ErrorCode::Locked,
+        ErrorCode::ApplyFailed,
+        ErrorCode::TargetPortInaccessible,
+        ErrorCode::InvalidArgument,
+        ErrorCode::Other,
+    ];
     for code in priority {
         if checks
             .iter()
-            .any(|check| !check.passed && check.error_code == Some(code))
+            .any(|check| !check.passed && check.error_code == Some(code.code()))
         {
-            return code;
+            return code.code();
         }
     }
-    0
+    checks
+        .iter()
+        .filter(|check| !check.passed)
+        .find_map(|check| check.error_code)
+        .unwrap_or(0)
 }
 
 fn version_supported(version: &str) -> bool {
@@ -297,3 +1047,209 @@ fn version_supported(version: &str) -> bool {
     let patch: u32 = parts.next().and_then(|v| v.parse().ok()).unwrap_or(0);
     (major, minor, patch) >= (1, 50, 0)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{MockBackend, UnreachableBackend};
+
+    #[tokio::test]
+    async fn test_report_all_pass() {
+        let report = DoctorCommand::check(&MockBackend::new(), &DoctorOptions::default()).await;
+        assert_eq!(report.exit_code, 0);
+        assert!(report.checks.iter().all(|check| check.passed));
+        assert!(!report
+            .checks
+            .iter()
+            .any(|check| check.check == DoctorCheck::Auth));
+    }
+
+    #[tokio::test]
+    async fn test_port_check_flags_full_site_background_serve() {
+        let backend = MockBackend::new();
+        let config: crate::core::ServeConfig = serde_json::from_value(serde_json::json!({
+            "Web": {
+                format!("{}:443", crate::backend::MOCK_DNS_NAME): {
+                    "Handlers": { "/": { "Proxy": "http://127.0.0.1:3000" } }
+                }
+            }
+        }))
+        .unwrap();
+        backend.replace_serve_config(&config).await.unwrap();
+
+        let report = DoctorCommand::check(&backend, &DoctorOptions::default()).await;
+        let port = report
+            .checks
+            .iter()
+            .find(|check| check.check == DoctorCheck::Port)
+            .unwrap();
+        assert!(!port.passed);
+        assert!(port.message.contains("--https-port 8443"));
+        assert_eq!(report.exit_code, 13);
+
+        let options = DoctorOptions {
+            https_port: Some(8443),
+            ..DoctorOptions::default()
+        };
+        let report = DoctorCommand::check(&backend, &options).await;
+        assert_eq!(report.exit_code, 0);
+    }
+
+    #[tokio::test]
+    async fn test_report_unreachable_exit_code() {
+        let backend = UnreachableBackend::new("socket missing");
+        let report = DoctorCommand::check(&backend, &DoctorOptions::default()).await;
+        assert_eq!(report.exit_code, 10);
+    }
+
+    #[test]
+    fn test_check_permissions_read_only() {
+        let status = |write: Option<bool>, read: Option<bool>| BackendStatus {
+            permissions_ok: write,
+            serve_config_readable: read,
+            operator_user: Some("bob".to_string()),
+            ..BackendStatus::default()
+        };
+        let check = |status: &BackendStatus| check_permissions(status, Some("alice"));
+        assert!(check(&status(Some(true), Some(true))).passed);
+        let read_only = check(&status(Some(false), Some(true)));
+        assert!(!read_only.passed);
+        assert!(read_only.message.starts_with("Read-only access"));
+        assert!(read_only
+            .message
+            .ends_with("run: sudo tailscale set --operator=alice; the operator is bob"));
+        assert_eq!(read_only.error_code, Some(ErrorCode::Permission.code()));
+        assert!(check(&status(Some(false), Some(false)))
+            .message
+            .starts_with("Permission denied"));
+        assert!(check(&status(None, Some(true))).passed);
+        assert!(!check(&status(None, None)).passed);
+
+        let operator = check_permissions(&status(Some(false), Some(true)), Some("bob"));
+        assert!(operator.message.contains("bob is already the operator"));
+    }
+
+    #[test]
+    fn test_check_daemons_warns_about_a_second_node() {
+        let status = |name: &str| BackendStatus {
+            dns_name: Some(name.to_string()),
+            version: Some("1.76.1".to_string()),
+            ..BackendStatus::default()
+        };
+        let system = PathBuf::from("/run/tailscale/tailscaled.sock");
+        let container = PathBuf::from("/tmp/tailscaled.sock");
+
+        let same = [
+            (system.clone(), Ok(status("laptop.ts.net"))),
+            (container.clone(), Ok(status("laptop.ts.net"))),
+        ];
+        assert!(check_daemons(&same, Some(&system)).passed);
+
+        let two = [
+            (system.clone(), Ok(status("laptop.ts.net"))),
+            (container.clone(), Ok(status("dev-box.ts.net"))),
+            (
+                PathBuf::from("/var/run/tailscaled.socket"),
+                Err(FunnelError::Unreachable {
+                    source: None,
+                    context: "No answer".to_string(),
+                }),
+            ),
+        ];
+        let check = check_daemons(&two, Some(&system));
+        assert!(!check.passed);
+        assert_eq!(check.error_code, None);
+        assert!(check.message.starts_with("2 tailscaled instances"));
+        assert!(check
+            .message
+            .contains("/tmp/tailscaled.sock (dev-box.ts.net, 1.76.1)"));
+        assert!(check.message.contains("(not answering)"));
+        assert!(check
+            .message
+            .contains("funnelctl uses /run/tailscale/tailscaled.sock"));
+    }
+
+    #[test]
+    fn test_summary_gives_one_line_reason() {
+        let result = |check: DoctorCheck, error_code: Option<i32>| CheckResult {
+            check,
+            passed: error_code.is_none(),
+            message: "msg".to_string(),
+            error_code,
+        };
+        let mut checks: Vec<CheckResult> = READY_CHECKS
+            .iter()
+            .map(|check| result(*check, None))
+            .collect();
+        let report = DoctorReport {
+            exit_code: select_exit_code(&checks),
+            checks,
+        };
+        let summary = Summary::new(&report);
+        assert!(summary.ready_for_funnel);
+        assert_eq!((summary.total, summary.passed, summary.failed), (6, 6, 0));
+
+        checks = report.checks;
+        checks[4] = result(DoctorCheck::Funnel, Some(ErrorCode::Prerequisites.code()));
+        checks[2] = result(DoctorCheck::Permissions, Some(ErrorCode::Permission.code()));
+        let report = DoctorReport {
+            exit_code: select_exit_code(&checks),
+            checks,
+        };
+        let summary = Summary::new(&report);
+        assert!(!summary.ready_for_funnel);
+        assert_eq!(summary.exit_code, 11);
+        assert_eq!(summary.failed, 2);
+        assert_eq!(summary.reason.as_deref(), Some("Permissions: msg"));
+        let lines = json_lines(&report).unwrap();
+        assert_eq!(lines.len(), 7);
+        assert!(lines[2].starts_with(r#"{"type":"check","check":"permissions","passed":false"#));
+        assert!(lines[6].starts_with(r#"{"type":"summary","total":6"#));
+
+        let partial = DoctorReport {
+            checks: vec![result(DoctorCheck::Version, None)],
+            exit_code: 0,
+        };
+        assert_eq!(
+            Summary::new(&partial).reason.as_deref(),
+            Some("not checked: reachable, permissions, https, funnel, dns")
+        );
+    }
+
+    #[test]
+    fn test_check_state_dir() {
+        let ok = check_state_dir(Ok(PathBuf::from("/srv/funnelctl")));
+        assert!(ok.passed);
+        assert_eq!(ok.message, "/srv/funnelctl");
+        let broken = check_state_dir(Err(FunnelError::Other(
+            "Unable to resolve HOME directory".to_string(),
+        )));
+        assert!(!broken.passed);
+        assert!(broken
+            .message
+            .ends_with("pass --state-dir or set XDG_STATE_HOME"));
+        assert_eq!(broken.error_code, Some(ErrorCode::Other.code()));
+    }
+
+    #[tokio::test]
+    async fn test_report_only_counts_selected_checks() {
+        let backend = UnreachableBackend::new("socket missing");
+        let options = DoctorOptions {
+            skip: vec![
+                DoctorCheck::Reachable,
+                DoctorCheck::Version,
+                DoctorCheck::Permissions,
+                DoctorCheck::Https,
+                DoctorCheck::Funnel,
+                DoctorCheck::Dns,
+                DoctorCheck::MagicDns,
+                DoctorCheck::Port,
+                DoctorCheck::State,
+            ],
+            ..DoctorOptions::default()
+        };
+        let report = DoctorCommand::check(&backend, &options).await;
+        assert!(report.checks.is_empty());
+        assert_eq!(report.exit_code, 0);
+    }
+}
@@ -0,0 +1,46 @@
+//! `funnelctl errors`: the exit code registry, so scripts can map codes to names without
+//! copying the table from the docs.
+
+use crate::cli::ErrorsArgs;
+use crate::error::{ErrorCode, ErrorCodeEntry, FunnelError, Result};
+use crate::output::render_table;
+
+pub struct ErrorsCommand {
+    args: ErrorsArgs,
+}
+
+impl ErrorsCommand {
+    pub fn new(args: ErrorsArgs) -> Self {
+        Self { args }
+    }
+
+    pub fn run(self) -> Result<()> {
+        if self.args.json {
+            for code in ErrorCode::ALL {
+                let line = serde_json::to_string(&ErrorCodeEntry::from(*code))
+                    .map_err(|err| FunnelError::Other(err.to_string()))?;
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+
+        let rows: Vec<Vec<String>> = ErrorCode::ALL
+            .iter()
+            .map(|code| {
+                vec![
+                    code.code().to_string(),
+                    code.name().to_string(),
+                    code.retry_after()
+                        .map(|seconds| format!("after {}s", seconds))
+                        .unwrap_or_else(|| "no".to_string()),
+                    code.description().to_string(),
+                ]
+            })
+            .collect();
+        println!(
+            "{}",
+            render_table(&["CODE", "NAME", "RETRY", "DESCRIPTION"], &rows)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,34 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+
+use crate::backend::Backend;
+use crate::cli::ExportArgs;
+use crate::core::{LeaseStore, Snapshot};
+use crate::error::{FunnelError, Result};
+
+pub struct ExportCommand {
+    args: ExportArgs,
+}
+
+impl ExportCommand {
+    pub fn new(args: ExportArgs) -> Self {
+        Self { args }
+    }
+
+    /// Prints the snapshot as JSON on stdout, for `funnelctl export > backup.json`
+    pub async fn run(self, backend: Arc<dyn Backend>) -> Result<()> {
+        let live = backend.serve_config().await?;
+        let now = Utc::now();
+        let leases: Vec<_> = LeaseStore::open()?
+            .list()?
+            .into_iter()
+            .filter(|lease| lease.is_active(now))
+            .collect();
+        let snapshot = Snapshot::new(&live, &leases, self.args.owned)?;
+        let json = serde_json::to_string_pretty(&snapshot)
+            .map_err(|err| FunnelError::Other(format!("JSON serialization failed: {}", err)))?;
+        println!("{}", json);
+        Ok(())
+    }
+}
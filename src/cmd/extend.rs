@@ -0,0 +1,83 @@
+use chrono::Utc;
+use humantime::format_duration;
+
+use crate::cli::ExtendArgs;
+use crate::control::{self, ControlCommand};
+use crate::core::{resolve_lease, LeaseStore};
+use crate::error::{FunnelError, Result};
+
+pub struct ExtendCommand {
+    args: ExtendArgs,
+}
+
+impl ExtendCommand {
+    pub fn new(args: ExtendArgs) -> Self {
+        Self { args }
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let extension = humantime::parse_duration(&self.args.duration).map_err(|err| {
+            FunnelError::InvalidArgument(format!(
+                "Invalid duration '{}': {}",
+                self.args.duration, err
+            ))
+        })?;
+        if extension.is_zero() {
+            return Err(FunnelError::InvalidArgument(
+                "Duration must be greater than zero".to_string(),
+            ));
+        }
+
+        let store = LeaseStore::open()?;
+        let leases = store.list()?;
+        let mut lease = resolve_lease(&leases, &self.args.lease)?.clone();
+        let label = lease
+            .url
+            .clone()
+            .unwrap_or_else(|| lease.tunnel_spec.path.clone());
+
+        let expires_at = if lease.owner_is_running() {
+            let command = ControlCommand::Extend {
+                seconds: extension.as_secs().max(1),
+            };
+            let reply = control::send(&lease.lease_id, &command).await?;
+            if !reply.ok {
+                return Err(FunnelError::Other(
+                    reply
+                        .error
+                        .unwrap_or_else(|| "Tunnel rejected the extension".to_string()),
+                ));
+            }
+            reply.expires_at
+        } else {
+            // The owner is gone; only the recorded deadline is left to move.
+            let Some(expires_at) = lease.expires_at else {
+                return Err(FunnelError::InvalidArgument(format!(
+                    "Tunnel {} has no TTL; it stays up until closed",
+                    label
+                )));
+            };
+            let extension = chrono::Duration::from_std(extension).map_err(|_| {
+                FunnelError::InvalidArgument(format!(
+                    "Duration '{}' is too large",
+                    self.args.duration
+                ))
+            })?;
+            lease.expires_at = Some(expires_at.max(Utc::now()) + extension);
+            lease.stale_since = None;
+            store.save(&lease)?;
+            lease.expires_at
+        };
+
+        match expires_at {
+            Some(expires_at) => println!(
+                "Extended {} by {}; expires at {}",
+                label,
+                format_duration(extension),
+                expires_at.format("%Y-%m-%d %H:%M:%S UTC")
+            ),
+            None => println!("Extended {} by {}", label, format_duration(extension)),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::backend::Backend;
+use crate::cli::FunnelPolicyArgs;
+use crate::core::funnel_policy::{node_attrs_entry, policy_targets, POLICY_EDITOR_URL};
+use crate::core::validate_https_port;
+use crate::error::{FunnelError, Result};
+
+pub struct FunnelPolicyCommand {
+    args: FunnelPolicyArgs,
+}
+
+/// What `funnel-policy --json` prints
+#[derive(Debug, Serialize)]
+struct PolicyReport {
+    node: Option<String>,
+    https_port: u16,
+    funnel_allowed: bool,
+    /// Ports the policy lets this node use for Funnel; empty when Funnel is off
+    permitted_ports: Vec<u16>,
+    targets: Vec<String>,
+    node_attrs: Value,
+    policy_url: &'static str,
+}
+
+impl FunnelPolicyCommand {
+    pub fn new(args: FunnelPolicyArgs) -> Self {
+        Self { args }
+    }
+
+    /// Prints the `nodeAttrs` entry an admin adds so this node may use Funnel. Read-only.
+    pub async fn run(self, backend: Arc<dyn Backend>) -> Result<()> {
+        validate_https_port(self.args.https_port)?;
+        let status = backend.status().await?;
+        let permitted = status.funnel_permitted().unwrap_or_default();
+        let targets = policy_targets(&status.tags, status.login_name.as_deref());
+        let report = PolicyReport {
+            node: status.dns_name.clone(),
+            https_port: self.args.https_port,
+            funnel_allowed: permitted.contains(&self.args.https_port),
+            node_attrs: node_attrs_entry(&targets),
+            permitted_ports: permitted,
+            targets,
+            policy_url: POLICY_EDITOR_URL,
+        };
+
+        if self.args.json {
+            let line = serde_json::to_string(&report)
+                .map_err(|err| FunnelError::Other(err.to_string()))?;
+            println!("{}", line);
+            return Ok(());
+        }
+
+        let node = report.node.as_deref().unwrap_or("this node");
+        let port = report.https_port;
+        if report.funnel_allowed {
+            println!(
+                "Funnel is already allowed on port {} for {}; nothing to add.",
+                port, node
+            );
+            return Ok(());
+        }
+        if !report.permitted_ports.is_empty() {
+            // Funnel itself is granted; only the port list stands in the way
+            println!(
+                "Funnel is enabled for {}, but its funnel-ports attribute allows only {:?}, not {}.",
+                node, report.permitted_ports, port
+            );
+            println!(
+                "Add {} to the ports= list of the funnel-ports attribute that applies to this node, or remove that attribute.",
+                port
+            );
+        } else {
+            let entry = serde_json::to_string_pretty(&report.node_attrs)
+                .map_err(|err| FunnelError::Other(err.to_string()))?;
+            println!("Funnel is not enabled for {} in the tailnet policy.", node);
+            println!();
+            println!("Add this entry to \"nodeAttrs\" in the tailnet policy file:");
+            println!();
+            println!("{}", entry);
+            println!();
+        }
+        println!("Edit the policy file at {}", POLICY_EDITOR_URL);
+        Ok(())
+    }
+}
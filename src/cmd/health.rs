@@ -0,0 +1,180 @@
+//! `funnelctl health <lease>`: a cheap liveness probe for scripts. Exits 0 only when the
+//! lease is active, its route is in the serve config, and the target accepts connections.
+
+use std::sync::Arc;
+
+use chrono::Utc;
+use serde::Serialize;
+
+use crate::backend::Backend;
+use crate::cli::HealthArgs;
+use crate::core::{resolve_lease, Lease, LeaseStore};
+use crate::error::{ErrorCode, FunnelError, Result};
+use crate::heartbeat::{probe_target, TargetHealth};
+
+/// Exit codes of the first failed check, matching the error codes for the same problem
+const EXIT_INACTIVE: ErrorCode = ErrorCode::Other;
+const EXIT_ROUTE_MISSING: ErrorCode = ErrorCode::ApplyFailed;
+const EXIT_TARGET_DOWN: ErrorCode = ErrorCode::TargetPortInaccessible;
+
+#[derive(Debug, Serialize)]
+struct HealthReport {
+    version: u32,
+    lease_id: String,
+    name: Option<String>,
+    url: Option<String>,
+    healthy: bool,
+    active: bool,
+    route: bool,
+    target: TargetHealth,
+}
+
+impl HealthReport {
+    fn exit_code(&self) -> i32 {
+        if !self.active {
+            EXIT_INACTIVE.code()
+        } else if !self.route {
+            EXIT_ROUTE_MISSING.code()
+        } else if self.target == TargetHealth::Down {
+            EXIT_TARGET_DOWN.code()
+        } else {
+            0
+        }
+    }
+}
+
+pub struct HealthCommand {
+    args: HealthArgs,
+}
+
+impl HealthCommand {
+    pub fn new(args: HealthArgs) -> Self {
+        Self { args }
+    }
+
+    /// Returns the exit code; errors are only for leases that cannot be found or checked
+    pub async fn run(self, backend: Arc<dyn Backend>) -> Result<i32> {
+        let leases = LeaseStore::open()?.list()?;
+        let lease = resolve_lease(&leases, &self.args.lease)?;
+
+        let active = lease.is_active(Utc::now());
+        let route = backend
+            .routes()
+            .await?
+            .iter()
+            .any(|route| lease.owns(route));
+        let target = probe_target(&lease.tunnel_spec.local_target).await;
+        let mut report = HealthReport {
+            version: 1,
+            lease_id: lease.lease_id.clone(),
+            name: lease.name.clone(),
+            url: lease.url.clone(),
+            healthy: false,
+            active,
+            route,
+            target,
+        };
+        report.healthy = report.exit_code() == 0;
+
+        if self.args.json {
+            let json = serde_json::to_string(&report)
+                .map_err(|err| FunnelError::Other(format!("JSON serialization failed: {}", err)))?;
+            println!("{}", json);
+        } else {
+            print_report(lease, &report);
+        }
+        Ok(report.exit_code())
+    }
+}
+
+fn print_report(lease: &Lease, report: &HealthReport) {
+    let label = lease
+        .url
+        .as_deref()
+        .unwrap_or(lease.tunnel_spec.path.as_str());
+    if report.healthy {
+        println!("Healthy: {}", label);
+        return;
+    }
+    println!("Unhealthy: {}", label);
+    if !report.active {
+        let reason = if lease.pid.is_some() {
+            "its funnelctl process exited"
+        } else {
+            "its TTL has passed"
+        };
+        println!("  - lease is not active: {}", reason);
+    }
+    if !report.route {
+        println!("  - route is missing from the serve config");
+    }
+    if report.target == TargetHealth::Down {
+        println!(
+            "  - target {} does not accept connections",
+            lease.tunnel_spec.local_target
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MockBackend;
+    use crate::core::{LocalTarget, TunnelSpec};
+    use crate::progress::NoProgress;
+    use std::path::PathBuf;
+    use std::sync::OnceLock;
+
+    fn isolate_state_dir() {
+        static DIR: OnceLock<PathBuf> = OnceLock::new();
+        let dir = DIR.get_or_init(|| tempfile::tempdir().unwrap().keep());
+        crate::dirs::set_state_dir(dir.clone()).unwrap();
+    }
+
+    fn health(lease: &str) -> HealthCommand {
+        HealthCommand::new(HealthArgs {
+            lease: lease.to_string(),
+            json: true,
+            localapi: crate::cli::LocalApiArgs {
+                socket: None,
+                localapi_port: None,
+                localapi_password_file: None,
+                no_etag: false,
+                no_cache: false,
+                apply_timeout: None,
+            },
+        })
+    }
+
+    #[tokio::test]
+    async fn test_exit_code_names_first_failed_check() {
+        isolate_state_dir();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = LocalTarget::new(
+            "127.0.0.1".to_string(),
+            listener.local_addr().unwrap().port(),
+        );
+        let spec = TunnelSpec::new(target, 443, "/funnelctl/health".to_string(), true).detached();
+        let backend = Arc::new(MockBackend::new());
+        let result = backend.apply(&spec, &NoProgress).await.unwrap();
+        let lease = Lease::new(result.lease_id, spec, None).with_name("health-check");
+        LeaseStore::open().unwrap().save(&lease).unwrap();
+
+        assert_eq!(
+            health("health-check").run(backend.clone()).await.unwrap(),
+            0
+        );
+        drop(listener);
+        assert_eq!(
+            health("health-check").run(backend.clone()).await.unwrap(),
+            15
+        );
+        let routes = backend.routes().await.unwrap();
+        backend.remove_routes(&routes).await.unwrap();
+        assert_eq!(
+            health("health-check").run(backend.clone()).await.unwrap(),
+            14
+        );
+        assert!(health("no-such-tunnel").run(backend).await.is_err());
+    }
+}
@@ -0,0 +1,256 @@
+use std::io::{BufRead, IsTerminal, Write};
+
+use clap::{CommandFactory, FromArgMatches};
+use humantime::format_duration;
+
+use crate::cli::{Cli, Commands, HistoryArgs, OpenArgs};
+use crate::core::{find_entry, HistoryEntry, HistoryLog};
+use crate::error::{FunnelError, Result};
+use crate::output::{format_usage, render_table};
+
+pub struct HistoryCommand {
+    args: HistoryArgs,
+}
+
+impl HistoryCommand {
+    pub fn new(args: HistoryArgs) -> Self {
+        Self { args }
+    }
+
+    pub fn run(self) -> Result<()> {
+        let entries = HistoryLog::open()?.read()?;
+        let recent: Vec<_> = entries.iter().rev().take(self.args.limit).collect();
+
+        if self.args.json {
+            for entry in recent {
+                let line = serde_json::to_string(entry)
+                    .map_err(|err| FunnelError::Other(err.to_string()))?;
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+
+        if recent.is_empty() {
+            println!("No tunnels recorded yet");
+            return Ok(());
+        }
+
+        let rows: Vec<Vec<String>> = recent
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| {
+                let ran_for = (entry.stopped_at - entry.started_at)
+                    .to_std()
+                    .unwrap_or_default();
+                vec![
+                    (index + 1).to_string(),
+                    entry.name.clone().unwrap_or_else(|| "-".to_string()),
+                    entry.started_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+                    format_duration(std::time::Duration::from_secs(ran_for.as_secs())).to_string(),
+                    entry.reason.clone(),
+                    format_usage(entry.usage.as_ref()),
+                    entry.expose_for.clone().unwrap_or_else(|| "-".to_string()),
+                    entry.url.clone(),
+                ]
+            })
+            .collect();
+        println!(
+            "{}",
+            render_table(
+                &["#", "NAME", "STARTED", "RAN FOR", "REASON", "USAGE", "FOR", "URL"],
+                &rows
+            )
+        );
+        Ok(())
+    }
+}
+
+/// The arguments after `open`: what history records, and what `--from-history` replays
+pub fn open_arguments(argv: &[String]) -> Vec<String> {
+    argv.iter()
+        .position(|arg| arg == "open" || arg == "o")
+        .map(|at| argv[at + 1..].to_vec())
+        .unwrap_or_default()
+}
+
+/// `open --from-history`: the recorded arguments of a past tunnel, followed by the ones
+/// given now (minus `--from-history` and `--yes`), which override them. `None` when the
+/// user declines.
+pub fn replay_open(query: &str, given: &[String], yes: bool) -> Result<Option<OpenArgs>> {
+    let entries = HistoryLog::open()?.read()?;
+    let entry = find_entry(&entries, query)?;
+    if entry.open_args.is_empty() {
+        return Err(FunnelError::InvalidArgument(format!(
+            "Tunnel {} was recorded without its `open` arguments and cannot be reopened",
+            entry.name.as_deref().unwrap_or(&entry.lease_id)
+        )));
+    }
+    let mut replayed = pinned_path(entry);
+    replayed.extend(entry.open_args.iter().cloned());
+    replayed.extend(without_replay_flags(given));
+    let args = parse_open(&replayed)?;
+
+    describe(entry, &replayed);
+    if !yes && !confirm()? {
+        eprintln!("Nothing opened");
+        return Ok(None);
+    }
+    Ok(Some(args))
+}
+
+/// A run that got a random path is reopened at the same URL: `--path <recorded>` goes
+/// first, so a `--path` given now still wins
+fn pinned_path(entry: &HistoryEntry) -> Vec<String> {
+    let chose_path = entry
+        .open_args
+        .iter()
+        .any(|arg| arg == "--path" || arg.starts_with("--path=") || arg == "--reuse-path");
+    match entry.route_path() {
+        Some(path) if !chose_path => vec!["--path".to_string(), path],
+        _ => Vec::new(),
+    }
+}
+
+fn without_replay_flags(given: &[String]) -> Vec<String> {
+    let mut kept = Vec::with_capacity(given.len());
+    let mut args = given.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from-history" => {
+                args.next();
+            }
+            "--yes" | "-y" => {}
+            arg if arg.starts_with("--from-history=") => {}
+            _ => kept.push(arg.clone()),
+        }
+    }
+    kept
+}
+
+/// Later flags win, so the arguments given now override the recorded ones
+fn parse_open(args: &[String]) -> Result<OpenArgs> {
+    let argv = ["funnelctl", "open"]
+        .into_iter()
+        .map(str::to_string)
+        .chain(args.iter().cloned());
+    let matches = Cli::command()
+        .mut_subcommand("open", |open| open.args_override_self(true))
+        .try_get_matches_from(argv)
+        .and_then(|matches| Cli::from_arg_matches(&matches))
+        .map_err(|err| {
+            FunnelError::InvalidArgument(format!(
+                "Cannot reopen with `funnelctl open {}`: {}",
+                shell_words(args),
+                err.to_string().lines().next().unwrap_or_default()
+            ))
+        })?;
+    match matches.command {
+        Commands::Open(mut open) => {
+            open.raw_args = args.to_vec();
+            Ok(*open)
+        }
+        _ => Err(FunnelError::Other(
+            "Replayed arguments did not parse as `open`".to_string(),
+        )),
+    }
+}
+
+/// What is about to be exposed again, on stderr so stdout keeps only the URL
+fn describe(entry: &HistoryEntry, args: &[String]) {
+    eprintln!(
+        "Reopening {} (started {}):",
+        entry.name.as_deref().unwrap_or(&entry.lease_id),
+        entry.started_at.format("%Y-%m-%d %H:%M UTC")
+    );
+    eprintln!("  funnelctl open {}", shell_words(args));
+    eprintln!("  was {} -> {}", entry.local_target, entry.url);
+}
+
+fn confirm() -> Result<bool> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Err(FunnelError::InvalidArgument(
+            "--from-history needs --yes when stdin is not a terminal".to_string(),
+        ));
+    }
+    eprint!("Open it again? [y/N] ");
+    std::io::stderr()
+        .flush()
+        .map_err(|err| FunnelError::Other(err.to_string()))?;
+    let mut answer = String::new();
+    stdin
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|err| FunnelError::Other(err.to_string()))?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Arguments joined for display, single-quoted where the shell would split or expand them
+fn shell_words(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| {
+            let safe = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./:%+=,@~".contains(c));
+            if safe {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', r"'\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_replayed_args_take_overrides() {
+        assert_eq!(
+            open_arguments(&args(&["--plain", "open", "8081", "--ttl", "30m"])),
+            args(&["8081", "--ttl", "30m"])
+        );
+        assert_eq!(
+            without_replay_flags(&args(&["--from-history", "2", "-y", "--ttl", "2h"])),
+            args(&["--ttl", "2h"])
+        );
+
+        let entry = HistoryEntry {
+            lease_id: "session-1".to_string(),
+            name: None,
+            url: "https://node.ts.net/funnelctl/abc".to_string(),
+            local_target: "http://127.0.0.1:8081".to_string(),
+            started_at: chrono::Utc::now(),
+            stopped_at: chrono::Utc::now(),
+            reason: "user_interrupt".to_string(),
+            project: None,
+            path: Some("/funnelctl/abc".to_string()),
+            https_port: Some(443),
+            usage: None,
+            expose_for: None,
+            open_args: args(&["8081", "--ttl", "30m"]),
+        };
+        assert_eq!(pinned_path(&entry), args(&["--path", "/funnelctl/abc"]));
+
+        let mut replayed = args(&["8081", "--ttl", "30m", "--path", "/hook"]);
+        replayed.extend(args(&["--ttl", "2h"]));
+        let open = parse_open(&replayed).unwrap();
+        assert_eq!(open.port, Some(8081));
+        assert_eq!(open.ttl.as_deref(), Some("2h"));
+        assert_eq!(open.path.as_deref(), Some("/hook"));
+        assert_eq!(open.raw_args, replayed);
+        assert!(parse_open(&args(&["8081", "--no-such-flag"])).is_err());
+
+        assert_eq!(
+            shell_words(&args(&["8081", "--format", "{url} up"])),
+            "8081 --format '{url} up'"
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+
+use crate::backend::Backend;
+use crate::cli::ImportArgs;
+use crate::core::{list_routes, remove_routes, Lease, LeaseStore, RestoreOutcome, Route, Snapshot};
+use crate::error::Result;
+use crate::lock::LockGuard;
+
+pub struct ImportCommand {
+    args: ImportArgs,
+}
+
+impl ImportCommand {
+    pub fn new(args: ImportArgs) -> Self {
+        Self { args }
+    }
+
+    pub async fn run(self, backend: Arc<dyn Backend>) -> Result<()> {
+        let snapshot = Snapshot::from_path(&self.args.file)?;
+        let mut config = snapshot.serve_config.clone();
+
+        // Restoring an expired detached tunnel would only hand it to the reaper
+        let now = Utc::now();
+        let (expired, leases): (Vec<Lease>, Vec<Lease>) = snapshot
+            .leases
+            .into_iter()
+            .partition(|lease| lease.is_expired_detached(now));
+        let expired_routes: Vec<Route> = list_routes(&config)?
+            .into_iter()
+            .filter(|route| expired.iter().any(|lease| lease.owns(route)))
+            .collect();
+        remove_routes(&mut config, &expired_routes)?;
+
+        let _lock = LockGuard::acquire()?;
+        let outcomes = backend.restore_snapshot(&config).await?;
+        for outcome in &outcomes {
+            match outcome {
+                RestoreOutcome::Restored(route) => println!(
+                    "Restored {}{} ({})",
+                    route.host_port, route.path, route.target
+                ),
+                RestoreOutcome::Present(route) => println!(
+                    "Already present {}{} ({})",
+                    route.host_port, route.path, route.target
+                ),
+            }
+        }
+        for lease in &expired {
+            println!(
+                "Skipped {} (expired {})",
+                describe(lease),
+                lease
+                    .expires_at
+                    .map(|at| at.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                    .unwrap_or_default()
+            );
+        }
+        for session in config
+            .foreground
+            .iter()
+            .flatten()
+            .map(|(session, _)| session)
+        {
+            println!(
+                "Skipped foreground session {} (it ended with the `open` that owned it)",
+                session
+            );
+        }
+
+        self.register_leases(backend.as_ref(), &leases).await?;
+        if outcomes.is_empty() {
+            println!("Nothing to restore");
+        }
+        Ok(())
+    }
+
+    /// Saves the snapshot's detached leases whose routes are now live, so `close`,
+    /// `status`, and the reaper manage them again
+    async fn register_leases(&self, backend: &dyn Backend, leases: &[Lease]) -> Result<()> {
+        if leases.is_empty() {
+            return Ok(());
+        }
+        let store = LeaseStore::open()?;
+        let now = Utc::now();
+        let existing = store.list()?;
+        let routes = backend.routes().await?;
+        for lease in leases {
+            if existing
+                .iter()
+                .any(|other| other.lease_id == lease.lease_id)
+            {
+                continue;
+            }
+            let name_taken = lease.name.as_ref().is_some_and(|name| {
+                existing
+                    .iter()
+                    .any(|other| other.is_active(now) && other.name.as_ref() == Some(name))
+            });
+            if name_taken || !routes.iter().any(|route| lease.owns(route)) {
+                println!(
+                    "Not tracking {}: its name or route is now used by another tunnel",
+                    describe(lease)
+                );
+                continue;
+            }
+            store.save(lease)?;
+            println!("Tracking {} again", describe(lease));
+        }
+        Ok(())
+    }
+}
+
+fn describe(lease: &Lease) -> &str {
+    lease
+        .name
+        .as_deref()
+        .or(lease.url.as_deref())
+        .unwrap_or(lease.tunnel_spec.path.as_str())
+}
@@ -0,0 +1,44 @@
+//! `funnelctl migrate ngrok <file>`: prints a spec file for `up`/`apply` translated from
+//! another tool's config, for `funnelctl migrate ngrok ngrok.yml > funnel.yaml`.
+
+use crate::cli::{MigrateArgs, MigrateNgrokArgs, MigrateSource};
+use crate::core::migrate::from_ngrok;
+use crate::error::{FunnelError, Result};
+
+pub struct MigrateCommand {
+    args: MigrateArgs,
+}
+
+impl MigrateCommand {
+    pub fn new(args: MigrateArgs) -> Self {
+        Self { args }
+    }
+
+    /// The spec file goes to stdout; what could not be carried over also goes to stderr
+    pub fn run(self) -> Result<()> {
+        match self.args.source {
+            MigrateSource::Ngrok(args) => migrate_ngrok(args),
+        }
+    }
+}
+
+fn migrate_ngrok(args: MigrateNgrokArgs) -> Result<()> {
+    let contents = std::fs::read_to_string(&args.file).map_err(|err| {
+        FunnelError::InvalidArgument(format!(
+            "Failed to read ngrok config {}: {}",
+            args.file.display(),
+            err
+        ))
+    })?;
+    let migration = from_ngrok(&contents, &args.project)?;
+    let source = args
+        .file
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| args.file.display().to_string());
+    for note in &migration.notes {
+        eprintln!("Note: {}", note);
+    }
+    print!("{}", migration.to_yaml(&source)?);
+    Ok(())
+}
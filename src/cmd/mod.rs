@@ -1,11 +1,51 @@
+pub mod apply;
+pub mod audit;
+pub mod check;
+pub mod clean;
 pub mod close;
 pub mod completions;
 pub mod doctor;
+pub mod errors;
+pub mod export;
+pub mod extend;
+pub mod funnel_policy;
+pub mod health;
+pub mod history;
+pub mod import;
+pub mod migrate;
 pub mod open;
+pub mod pause;
+pub mod prompt;
+pub mod reaper;
+pub mod relay;
+pub mod rollback;
+pub mod selftest;
 pub mod status;
+pub mod up;
+pub mod watch_requests;
 
+pub use apply::ApplyCommand;
+pub use audit::AuditCommand;
+pub use check::CheckCommand;
+pub use clean::CleanCommand;
 pub use close::CloseCommand;
 pub use completions::CompletionsCommand;
-pub use doctor::DoctorCommand;
+pub use doctor::{CheckResult, DoctorCheck, DoctorCommand, DoctorOptions, DoctorReport};
+pub use errors::ErrorsCommand;
+pub use export::ExportCommand;
+pub use extend::ExtendCommand;
+pub use funnel_policy::FunnelPolicyCommand;
+pub use health::HealthCommand;
+pub use history::HistoryCommand;
+pub use import::ImportCommand;
+pub use migrate::MigrateCommand;
 pub use open::OpenCommand;
+pub use pause::PauseCommand;
+pub use prompt::PromptCommand;
+pub use reaper::ReaperCommand;
+pub use relay::RelayCommand;
+pub use rollback::RollbackCommand;
+pub use selftest::{SelftestCommand, SelftestOptions};
 pub use status::StatusCommand;
+pub use up::{DownCommand, UpCommand};
+pub use watch_requests::WatchRequestsCommand;
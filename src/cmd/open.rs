@@ -1,91 +1,712 @@
+use std::io::{BufRead, IsTerminal, Write};
 use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use chrono::Utc;
+use chrono::{DateTime, Local, Utc};
 use humantime::format_duration;
 use rand::distributions::Alphanumeric;
 use rand::Rng;
 use tokio::signal;
-use tokio::time::{sleep_until, Duration, Instant};
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::time::{sleep, sleep_until, Duration, Instant};
 
 use crate::backend::Backend;
 use crate::cli::OpenArgs;
+use crate::cmd::clean::stale_reason;
+use crate::cmd::close::supersede_displaced;
+use crate::config::OpenConfig;
+use crate::control::{ControlCommand, ControlMessage, ControlReply, ControlServer};
 use crate::core::{
-    validate_https_port, validate_path, validate_port, validate_ttl, LocalTarget, TunnelSpec,
-    ValidationWarning,
+    default_name, diff_serve_configs, last_path, normalize_dns_name, parse_expose_for, parse_port,
+    parse_public_url_base, parse_start_time, parse_tag, parse_ttl, validate_https_port,
+    validate_name, validate_path_with, validate_port, validate_ttl_with_warning, HistoryEntry,
+    HistoryLog, Lease, LeaseStore, LocalTarget, PathPolicy, Route, RouteState, TunnelSpec, Usage,
+    ValidationWarning, WarningId, ALLOWED_HTTPS_PORTS, DEFAULT_TTL_WARNING,
 };
-use crate::error::{FunnelError, Result};
+use crate::dirs;
+use crate::error::{ErrorCode, FunnelError, Result};
+use crate::heartbeat::{
+    probe_target, wait_until_healthy, StatusFile, TargetHealth, TargetWatch, TunnelStatus,
+};
+use crate::journald;
 use crate::lock::LockGuard;
-use crate::output::{Event, HumanOutput, StopReason};
+use crate::net::probe;
+use crate::output::{
+    self, curl_example, public_url, render_serve_diff, Event, HumanOutput, Started, StopReason,
+    Theme,
+};
+use crate::plugin::{self, Plugins};
+use crate::progress::{NoProgress, Phase, Progress, Spinner};
+use crate::proxy::{FirstRequest, LocalProxy, ProxyOptions, DEFAULT_HOLDING_PAGE};
+use crate::teardown::TeardownGuard;
+
+/// How often a foreground `open` re-reads the serve config, for edits the watch bus
+/// does not announce
+const ROUTE_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+/// Bus notifications arrive in bursts; the check waits this long after the first one
+const ROUTE_EVENT_SETTLE: Duration = Duration::from_secs(1);
+/// How long `--port-file` waits for the file, and how often it looks
+const PORT_FILE_WAIT: Duration = Duration::from_secs(120);
+const PORT_FILE_POLL: Duration = Duration::from_millis(250);
+/// How long `open.url_hook` gets to print the replacement URL
+const URL_HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the public DNS lookup may hold back the URL
+const DNS_CHECK_TIMEOUT: Duration = Duration::from_secs(3);
 
 pub struct OpenCommand {
     args: OpenArgs,
+    config: OpenConfig,
+    theme: Theme,
 }
 
 impl OpenCommand {
-    pub fn new(args: OpenArgs) -> Self {
-        Self { args }
+    pub fn new(args: OpenArgs, config: OpenConfig, theme: Theme) -> Self {
+        Self {
+            args,
+            config,
+            theme,
+        }
     }
 
     pub async fn run(self, backend: Arc<dyn Backend>, json: bool) -> Result<()> {
-        validate_port(self.args.port)?;
-        validate_https_port(self.args.https_port)?;
+        output::set_quiet_warnings(self.args.quiet_warnings);
+        let mut plugins = if self.args.dry_run {
+            Plugins::none()
+        } else {
+            Plugins::start(&self.config.plugins, json)?
+        };
+        let result = self.open(backend, json, &mut plugins).await;
+        if let Err(err) = &result {
+            plugin::publish(&Event::from_error(err));
+        }
+        plugins.shutdown().await;
+        result
+    }
+
+    async fn open(
+        self,
+        backend: Arc<dyn Backend>,
+        json: bool,
+        plugins: &mut Plugins,
+    ) -> Result<()> {
+        let remote = self.args.remote_node.is_some();
+        let open_args = self.args.raw_args.clone();
+        let port = resolve_port(&self.args, json).await?;
+        validate_port(port).map_err(|err| err.for_field("port", port.to_string()))?;
+        validate_https_port(self.args.https_port)
+            .map_err(|err| err.for_field("--https-port", self.args.https_port.to_string()))?;
+        if let Some(name) = self.args.name.as_deref() {
+            validate_name(name).map_err(|err| err.for_field("--name", name))?;
+        }
+        let public_url_base = match (
+            self.args.public_url_base.as_deref(),
+            self.config.public_url_base.as_deref(),
+        ) {
+            (Some(raw), _) => Some(
+                parse_public_url_base(raw)
+                    .map_err(|err| err.for_field("--public-url-base", raw))?,
+            ),
+            (None, Some(raw)) => Some(
+                parse_public_url_base(raw)
+                    .map_err(|err| err.for_field("open.public_url_base", raw))?,
+            ),
+            (None, None) => None,
+        };
+        let dns_name = self
+            .args
+            .dns_name
+            .as_deref()
+            .map(|raw| normalize_dns_name(raw).map_err(|err| err.for_field("--dns-name", raw)))
+            .transpose()?;
+        let tags = self
+            .args
+            .tags
+            .iter()
+            .map(|tag| parse_tag(tag).map_err(|err| err.for_field("--tag", tag.as_str())))
+            .collect::<Result<Vec<_>>>()?;
+        let expose_for = self
+            .args
+            .expose_for
+            .as_deref()
+            .map(|raw| parse_expose_for(raw).map_err(|err| err.for_field("--expose-for", raw)))
+            .transpose()?;
+
+        let path_policy = path_policy(&self.args, &self.config)?;
+
+        let show_curl =
+            self.args.curl_example || self.args.example_path.is_some() || self.config.curl_example;
+        let example_path = self.args.example_path.or(self.config.example_path);
+        let template = self.args.format;
 
         let bind_ip = resolve_bind(&self.args.bind, self.args.allow_non_loopback).await?;
 
-        let path = self.args.path.unwrap_or_else(generate_random_path);
-        let path_result = validate_path(&path)?;
+        // `--strict-port` leaves the refusal to the prerequisite check in apply
+        let permitted = if self.args.strict_port || self.args.skip_checks {
+            None
+        } else {
+            backend.status().await?.funnel_permitted()
+        };
+        let (https_port, mirror_ports) = pick_https_ports(
+            self.args.https_port,
+            self.args.all_https_ports,
+            permitted.as_deref(),
+        );
+        if let (false, Some(permitted)) = (json, permitted.as_deref()) {
+            if https_port != self.args.https_port {
+                output::advise(format_args!(
+                    "Note: Funnel is not permitted on port {} for this node; using {} instead (--strict-port to fail).",
+                    self.args.https_port, https_port
+                ));
+            }
+            let refused: Vec<String> = ALLOWED_HTTPS_PORTS
+                .iter()
+                .filter(|port| !permitted.contains(port))
+                .map(u16::to_string)
+                .collect();
+            if self.args.all_https_ports && !refused.is_empty() {
+                output::advise(format_args!(
+                    "Note: Funnel is not permitted on port {} for this node; not mirroring there.",
+                    refused.join(", ")
+                ));
+            }
+        }
+
+        let path = match self.args.path {
+            Some(path) => path,
+            None if self.args.reuse_path => {
+                reused_path(port, https_port, json)?.unwrap_or_else(generate_random_path)
+            }
+            None => generate_random_path(),
+        };
+        let path_result = validate_path_with(&path, &path_policy)
+            .map_err(|err| err.for_field("--path", &path))?;
         for warning in &path_result.warnings {
-            emit_warning(warning, json);
+            emit_warning(
+                warning,
+                &self.config.ignore_warnings,
+                self.args.deny_warnings,
+                json,
+            )?;
         }
         let path = path_result.normalized_path;
 
-        let ttl = match self.args.ttl.as_deref() {
-            Some(value) => Some(parse_ttl(value)?),
-            None => None,
-        };
+        let starts_at = self
+            .args
+            .at
+            .as_deref()
+            .map(|raw| {
+                parse_start_time(raw, Local::now()).map_err(|err| err.for_field("--at", raw))
+            })
+            .transpose()?;
+        let ttl = resolve_ttl(
+            self.args.ttl.as_deref(),
+            self.args.no_ttl,
+            self.config.ttl.as_deref(),
+            self.config.require_ttl,
+            starts_at.unwrap_or_else(Local::now),
+        )?;
         if let Some(ttl) = ttl {
-            let ttl_result = validate_ttl(ttl)?;
-            for warning in ttl_result.warnings {
-                emit_warning(&warning, json);
+            let warn_below = self.config.ttl_warning.unwrap_or(DEFAULT_TTL_WARNING);
+            let ttl_result = validate_ttl_with_warning(ttl, warn_below).map_err(|err| {
+                match (self.args.ttl.as_deref(), self.config.ttl.as_deref()) {
+                    (Some(raw), _) => err.for_field("--ttl", raw),
+                    (None, Some(raw)) => err.for_field("open.ttl", raw),
+                    (None, None) => err,
+                }
+            })?;
+            for warning in &ttl_result.warnings {
+                emit_warning(
+                    warning,
+                    &self.config.ignore_warnings,
+                    self.args.deny_warnings,
+                    json,
+                )?;
+            }
+        }
+
+        if self.args.skip_checks && !json {
+            warn_unchecked();
+        }
+
+        if self.args.max_concurrency == Some(0) {
+            return Err(FunnelError::InvalidField {
+                field: "--max-concurrency".to_string(),
+                value: "0".to_string(),
+                constraint: "--max-concurrency must be at least 1".to_string(),
+            });
+        }
+        let target_retry = self
+            .args
+            .target_retry
+            .as_deref()
+            .map(|raw| {
+                humantime::parse_duration(raw).map_err(|err| FunnelError::InvalidField {
+                    field: "--target-retry".to_string(),
+                    value: raw.to_string(),
+                    constraint: format!("Invalid --target-retry '{}': {}", raw, err),
+                })
+            })
+            .transpose()?;
+        let health_check = self
+            .args
+            .health_path
+            .as_deref()
+            .map(|path| health_check(path, &self.args.health_timeout))
+            .transpose()?;
+        let proxy_options = ProxyOptions {
+            max_concurrency: self.args.max_concurrency,
+            inspect: self.args.inspect,
+            noindex: self.args.noindex,
+            holding_page: holding_page(
+                self.args.holding_page,
+                self.args.holding_page_file.as_deref(),
+            )?,
+        };
+
+        let local_target = LocalTarget::new(bind_ip.to_string(), port);
+        let mut spec = TunnelSpec::new(local_target, https_port, path.clone(), true)
+            .with_mirror_ports(mirror_ports);
+        if self.args.detach {
+            spec = spec.detached();
+        }
+        if let Some(dns_name) = dns_name {
+            spec = spec.with_dns_name(dns_name);
+        }
+        if !remote {
+            let leases = LeaseStore::open()?.list()?;
+            if let Some(existing) = already_exposed(&spec, &leases, Utc::now()) {
+                let name = existing
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| existing.lease_id.clone());
+                let url = existing
+                    .url
+                    .clone()
+                    .unwrap_or_else(|| existing.tunnel_spec.path.clone());
+                if self.config.refuse_duplicate_target {
+                    return Err(FunnelError::Conflict {
+                        source: None,
+                        context: format!(
+                            "{} is already exposed at {} by tunnel {}; reuse that URL, or close it first",
+                            spec.local_target, url, name
+                        ),
+                    });
+                }
+                emit_warning(
+                    &ValidationWarning::TargetAlreadyExposed {
+                        target: spec.local_target.to_string(),
+                        url,
+                        name,
+                    },
+                    &self.config.ignore_warnings,
+                    self.args.deny_warnings,
+                    json,
+                )?;
             }
         }
+        if self.args.dry_run {
+            return print_dry_run(
+                backend.as_ref(),
+                &spec,
+                public_url_base.as_ref(),
+                json,
+                &self.theme,
+            )
+            .await;
+        }
+        if let Some(starts_at) = starts_at {
+            wait_for_start(starts_at, json, &self.theme).await?;
+        }
+        handle_leftovers(backend.as_ref(), &spec, self.args.auto_clean, json).await?;
+        plugins
+            .check_exposure(&Event::Exposing {
+                version: 1,
+                name: self.args.name.clone(),
+                local_target: spec.local_target.to_string(),
+                path: path.clone(),
+                https_port: spec.https_port,
+                funnel: spec.funnel,
+            })
+            .await?;
 
-        let local_target = LocalTarget::new(bind_ip.to_string(), self.args.port);
-        let spec = TunnelSpec::new(local_target, self.args.https_port, path.clone(), true);
+        let mut proxy = if proxy_options.is_enabled() {
+            let proxy = LocalProxy::start(&spec.local_target, proxy_options).await?;
+            spec = spec.with_proxy(proxy.local_target());
+            Some(proxy)
+        } else {
+            None
+        };
 
-        let result = {
+        let spinner = if json {
+            None
+        } else {
+            Spinner::start(&self.theme)
+        };
+        let progress: &dyn Progress = match &spinner {
+            Some(spinner) => spinner,
+            None => &NoProgress,
+        };
+        // Outside the lock: a slow app should not hold up other tunnels
+        if let Some((path, within)) = &health_check {
+            progress.phase(Phase::WaitingForHealth);
+            wait_until_healthy(&spec.local_target, path, *within).await?;
+        }
+        let (name, result) = {
             let _lock = LockGuard::acquire()?;
-            backend.apply(&spec).await?
+            let name = pick_name(self.args.name.clone())?;
+            let result = match backend.apply(&spec, progress).await {
+                Err(err) if self.args.auto_resolve => {
+                    let alternative = err
+                        .suggested_path()
+                        .and_then(|path| validate_path_with(path, &path_policy).ok());
+                    let Some(alternative) = alternative else {
+                        return Err(err);
+                    };
+                    if !json {
+                        output::advise(format_args!(
+                            "Note: {} conflicts with existing routes; using {} instead.",
+                            spec.path, alternative.normalized_path
+                        ));
+                    }
+                    spec.path = alternative.normalized_path;
+                    plugins
+                        .check_exposure(&Event::Exposing {
+                            version: 1,
+                            name: self.args.name.clone(),
+                            local_target: spec.local_target.to_string(),
+                            path: spec.path.clone(),
+                            https_port: spec.https_port,
+                            funnel: spec.funnel,
+                        })
+                        .await?;
+                    backend.apply(&spec, progress).await?
+                }
+                result => result?,
+            };
+            (name, result)
+        };
+        let path = spec.path.clone();
+        // Until the normal teardown below takes over, an early return, a cancelled run, or
+        // a panic must not leave the route behind.
+        let guard = TeardownGuard::arm(
+            backend.clone(),
+            Lease::new(result.lease_id.clone(), spec.clone(), None),
+        );
+        if let Some(spinner) = spinner {
+            spinner.finish();
+        }
+        let warnings = backend.take_warnings();
+        if !json {
+            for warning in &warnings {
+                output::advise(format_args!("Warning: {}", warning));
+            }
+        }
+        let dns_warning = match result.url.host_str() {
+            Some(host) if spec.funnel && !self.args.no_dns_check => check_public_dns(host).await,
+            _ => None,
         };
+        if let (Some(warning), false) = (&dns_warning, json) {
+            output::advise(format_args!("Warning: {}", warning));
+        }
         let started_at = result.applied_at;
+        let url = match &public_url_base {
+            Some(base) => public_url(base, &result.url),
+            None => result.url.clone(),
+        };
+        let url = match &self.config.url_hook {
+            Some(hook) => match run_url_hook(hook, &url).await {
+                Ok(replacement) => replacement,
+                Err(err) => {
+                    tracing::warn!(error = %err, "url_hook failed");
+                    if !json {
+                        output::advise(format_args!(
+                            "Warning: url_hook failed ({}); showing the tunnel URL.",
+                            err
+                        ));
+                    }
+                    url
+                }
+            },
+            None => url,
+        };
         let expires_at = ttl
             .and_then(|ttl| chrono::Duration::from_std(ttl).ok())
             .map(|duration| started_at + duration);
 
+        let mut lease = Lease::new(result.lease_id.clone(), spec.clone(), expires_at)
+            .with_name(name.as_str())
+            .with_tags(tags)
+            .with_expose_for(expose_for.clone())
+            .with_url(url.as_str())
+            .with_open_args(open_args.clone());
+        if !spec.detached {
+            lease = lease.with_owner(std::process::id());
+        }
+        let (store, guard) = if spec.detached {
+            // The lease is the only handle on a detached route; without it nobody can
+            // close or reap the tunnel, so roll back instead of leaving it behind.
+            let saved = LeaseStore::open().and_then(|store| store.save(&lease));
+            guard.disarm();
+            if let Err(err) = saved {
+                rollback_detached(backend.as_ref(), &lease).await;
+                return Err(err);
+            }
+            (None, None)
+        } else if remote {
+            // The route is on another node's tailscaled; `status`, `close` and the reaper
+            // here would look for it on this one
+            (None, Some(guard))
+        } else {
+            (record_lease(&lease), Some(guard))
+        };
+
+        if !remote {
+            for displaced in supersede_displaced(&spec, &lease.lease_id, &name).await {
+                if !json {
+                    output::advise(format_args!(
+                        "Note: superseded tunnel {} on {}; it is shutting down.",
+                        displaced.name.as_deref().unwrap_or(&displaced.lease_id),
+                        displaced.tunnel_spec.path
+                    ));
+                }
+            }
+        }
+
+        journald::set_context("lease_id", result.lease_id.as_str());
+        journald::set_context("url", url.as_str());
+        if let Some(expose_for) = &lease.expose_for {
+            journald::set_context("expose_for", expose_for.as_str());
+        }
+        tracing::info!(name = %name, local_target = %spec.local_target, "tunnel up");
+        let mirror_urls: Vec<String> = result.mirror_urls.iter().map(url::Url::to_string).collect();
+        let event = Event::Started {
+            version: 1,
+            url: url.to_string(),
+            mirror_urls: mirror_urls.clone(),
+            local_target: spec.local_target.to_string(),
+            path: path.clone(),
+            https_port: spec.https_port,
+            name: Some(name.clone()),
+            expose_for,
+            started_at,
+            expires_at,
+            unchecked: self.args.skip_checks,
+            dns_warning,
+            warnings,
+        };
+        plugin::publish(&event);
+        if let Some(path) = &self.args.url_file {
+            write_url_file(path, url.as_str(), &event, self.args.url_file_json)?;
+        }
         if json {
-            let event = Event::Started {
-                version: 1,
-                url: result.url.to_string(),
-                local_target: spec.local_target.to_string(),
-                path: path.clone(),
-                https_port: spec.https_port,
-                started_at,
-                expires_at,
-            };
             event
                 .emit_json()
                 .map_err(|err| FunnelError::Other(err.to_string()))?;
+        } else if let Some(template) = &template {
+            println!("{}", template.render(&event));
         } else {
-            let output = HumanOutput::new();
+            let output = HumanOutput::new(self.theme)
+                .banner(!self.args.no_banner)
+                .compact(self.args.compact || output::prefers_compact());
             let local_target = spec.local_target.to_string();
+            let curl = show_curl.then(|| curl_example(url.as_str(), example_path.as_deref()));
+            let urls: Vec<String> = std::iter::once(url.to_string())
+                .chain(mirror_urls)
+                .collect();
             output
-                .print_started(result.url.as_str(), &local_target, expires_at)
+                .print_started(&Started {
+                    urls: &urls,
+                    name: Some(&name),
+                    local_target: &local_target,
+                    expires_at,
+                    detached: spec.detached,
+                    curl_example: curl.as_deref(),
+                })
                 .map_err(|err| FunnelError::Other(err.to_string()))?;
         }
+        if spec.detached {
+            return Ok(());
+        }
+
+        let request_log = proxy.as_ref().map(LocalProxy::request_log);
+        let mut control = match ControlServer::bind(&lease.lease_id, request_log) {
+            Ok((server, rx)) => Some((server, rx)),
+            Err(err) => {
+                tracing::warn!(error = %err, "control socket unavailable; close/extend will fall back to signals");
+                None
+            }
+        };
+
+        // A remote tunnel's target is on the other node, so there is nothing to probe here
+        let mut status_file = if remote {
+            None
+        } else {
+            let status = TunnelStatus::new(
+                lease.lease_id.clone(),
+                lease.name.clone(),
+                url.to_string(),
+                started_at,
+                expires_at,
+            );
+            match StatusFile::create(status) {
+                Ok(file) => Some(file),
+                Err(err) => {
+                    tracing::warn!(error = %err, "status file unavailable");
+                    None
+                }
+            }
+        };
 
-        let stop_reason = wait_for_stop(ttl).await;
+        let mut ttl = ttl;
+        let mut deadline = ttl.map(|ttl| Instant::now() + ttl);
+        let mut close_reply = None;
+        let mut route_state = RouteState::Intact;
+        let mut heartbeat_at = (!remote).then(Instant::now);
+        let mut last_active = Some(0);
+        let mut target_watch = TargetWatch::new(target_retry);
+        let mut backend_link = BackendLink::default();
+        let mut first_request = proxy.as_mut().and_then(LocalProxy::first_request);
+        let upstream_failed = proxy.as_ref().map(LocalProxy::upstream_failed);
+        let stop_reason = loop {
+            let control_rx = control.as_mut().map(|(_, rx)| rx);
+            // Nothing can bring a route back once tailscaled dropped the session.
+            let route_backend =
+                (route_state != RouteState::SessionEnded).then_some(backend.as_ref());
+            let message = match wait_for_stop(
+                deadline,
+                control_rx,
+                route_backend,
+                heartbeat_at,
+                first_request.as_mut(),
+                upstream_failed.as_deref().filter(|_| target_watch.is_up()),
+            )
+            .await
+            {
+                Wake::Stop(reason) => break reason,
+                Wake::FirstRequest(request) => {
+                    first_request = None;
+                    report_first_request(&request, &self.theme, json)?;
+                    continue;
+                }
+                Wake::Control(message) => message,
+                Wake::Heartbeat => {
+                    let target = probe_target(&spec.local_target).await;
+                    let (change, next) = target_watch.observe(target, Instant::now());
+                    heartbeat_at = Some(Instant::now() + next);
+                    let active = proxy.as_ref().map(|proxy| proxy.in_flight() as u64);
+                    if let Some(file) = &mut status_file {
+                        if let Err(err) =
+                            file.beat(route_state, target, lease.expires_at, active, Utc::now())
+                        {
+                            tracing::warn!(error = %err, "failed to update status file");
+                        }
+                    }
+                    if let Some(active) = active.filter(|active| Some(*active) != last_active) {
+                        last_active = Some(active);
+                        report_activity(active, json)?;
+                    }
+                    if let (Some(proxy), Some(store)) = (&proxy, &store) {
+                        let usage = proxy.usage();
+                        if lease.usage != Some(usage) {
+                            lease.usage = Some(usage);
+                            if let Err(err) = store.save(&lease) {
+                                tracing::warn!(error = %err, "failed to save tunnel usage");
+                            }
+                        }
+                    }
+                    if let Some(change) = change {
+                        if change == TargetHealth::Down && self.args.stop_on_target_down {
+                            break StopReason::TargetDown;
+                        }
+                        report_target_health(change, &spec.local_target, json)?;
+                    }
+                    continue;
+                }
+                Wake::RouteCheck => {
+                    route_state = check_route(
+                        backend.as_ref(),
+                        &result.lease_id,
+                        route_state,
+                        self.args.self_heal,
+                        &path,
+                        json,
+                        &mut backend_link,
+                    )
+                    .await?;
+                    continue;
+                }
+            };
+            match message.command {
+                ControlCommand::Close => {
+                    close_reply = Some(message.reply);
+                    break StopReason::Closed;
+                }
+                ControlCommand::Extend { seconds } => {
+                    let extension = Duration::from_secs(seconds);
+                    let reply = match (deadline, lease.expires_at) {
+                        (Some(current), Some(expires)) => {
+                            deadline = Some(current + extension);
+                            ttl = ttl.map(|ttl| ttl + extension);
+                            lease.expires_at = chrono::Duration::from_std(extension)
+                                .ok()
+                                .map(|extension| expires + extension);
+                            if let Some(store) = &store {
+                                if let Err(err) = store.save(&lease) {
+                                    tracing::warn!(error = %err, "failed to update lease record");
+                                }
+                            }
+                            report_extended(extension, lease.expires_at, json)?;
+                            ControlReply::ok(lease.expires_at)
+                        }
+                        _ => ControlReply::error("Tunnel has no TTL; it stays up until stopped"),
+                    };
+                    let _ = message.reply.send(reply);
+                }
+                ControlCommand::Stats => {
+                    let active = proxy.as_ref().map(|proxy| proxy.in_flight() as u64);
+                    let _ = message.reply.send(ControlReply::stats(active));
+                }
+                ControlCommand::Supersede { by } => {
+                    let _ = message.reply.send(ControlReply::ok(None));
+                    if !json {
+                        eprintln!("Superseded by {} (--force). Tearing down tunnel.", by);
+                    }
+                    break StopReason::Superseded;
+                }
+                command @ (ControlCommand::Pause | ControlCommand::Resume) => {
+                    let reply = match set_paused(
+                        command == ControlCommand::Pause,
+                        proxy.as_ref(),
+                        backend.as_ref(),
+                        &mut lease,
+                        store.as_ref(),
+                        json,
+                    )
+                    .await
+                    {
+                        Ok(()) => ControlReply::ok(None),
+                        Err(err) => ControlReply::error(err.to_string()),
+                    };
+                    let _ = message.reply.send(reply);
+                }
+                // The control server streams the request log without asking the tunnel
+                ControlCommand::WatchRequests => {
+                    let _ = message
+                        .reply
+                        .send(ControlReply::error("unexpected watch_requests"));
+                }
+            }
+        };
 
+        if let Some(file) = status_file {
+            file.remove();
+        }
+        if let Some(path) = &self.args.url_file {
+            if let Err(err) = std::fs::remove_file(path) {
+                tracing::warn!(path = %path.display(), error = %err, "failed to remove URL file");
+            }
+        }
         if matches!(stop_reason, StopReason::TtlExpired) && !json {
             eprintln!(
                 "TTL expired ({}). Tearing down tunnel.",
@@ -93,44 +714,144 @@ impl OpenCommand {
                     .unwrap_or_else(|| format_duration(Duration::from_secs(0)))
             );
         }
+        if matches!(stop_reason, StopReason::TargetDown) && !json {
+            eprintln!(
+                "Target {} stopped accepting connections. Tearing down tunnel.",
+                spec.local_target
+            );
+        }
 
+        if let Some(guard) = guard {
+            guard.disarm();
+        }
         let cleanup = backend.remove(&result.lease_id);
         let second_ctrl_c = signal::ctrl_c();
         let cleanup_result = tokio::select! {
             res = cleanup => res,
             _ = second_ctrl_c => {
-                std::process::exit(130);
+                std::process::exit(ErrorCode::Interrupted.code());
             }
         };
         cleanup_result.map_err(|err| FunnelError::ApplyFailed {
             source: Some(Box::new(err)),
             context: "Failed to tear down tunnel".to_string(),
         })?;
+        if let Some(store) = store {
+            if let Err(err) = store.remove(&lease.lease_id) {
+                tracing::warn!(error = %err, "failed to remove lease record");
+            }
+        }
+        if let Some(reply) = close_reply {
+            let _ = reply.send(ControlReply::ok(None));
+        }
 
+        let proxy_summary = proxy.map(LocalProxy::shutdown);
         let stopped_at = Utc::now();
+        record_history(HistoryEntry {
+            lease_id: lease.lease_id.clone(),
+            name: lease.name.clone(),
+            url: url.to_string(),
+            local_target: spec.local_target.to_string(),
+            started_at,
+            stopped_at,
+            reason: stop_reason.as_str().to_string(),
+            project: current_project(),
+            path: Some(spec.path.clone()),
+            https_port: Some(spec.https_port),
+            usage: proxy_summary.as_ref().map(Usage::from),
+            expose_for: lease.expose_for.clone(),
+            open_args,
+        });
         let duration_seconds = (stopped_at - started_at).num_seconds().max(0) as u64;
 
+        tracing::info!(reason = stop_reason.as_str(), "tunnel down");
+        let target_down = matches!(stop_reason, StopReason::TargetDown);
+        let event = Event::Stopped {
+            version: 1,
+            reason: stop_reason.clone(),
+            stopped_at,
+            duration_seconds: Some(duration_seconds),
+            proxy: proxy_summary.clone(),
+        };
+        plugin::publish(&event);
         if json {
-            let event = Event::Stopped {
-                version: 1,
-                reason: stop_reason,
-                stopped_at,
-                duration_seconds: Some(duration_seconds),
-            };
             event
                 .emit_json()
                 .map_err(|err| FunnelError::Other(err.to_string()))?;
         } else {
-            let output = HumanOutput::new();
+            let output = HumanOutput::new(self.theme);
             output
-                .print_stopped(stop_reason, Some(duration_seconds))
+                .print_stopped(stop_reason, Some(duration_seconds), proxy_summary.as_ref())
                 .map_err(|err| FunnelError::Other(err.to_string()))?;
         }
 
+        // A supervisor restarting on failure must not take this for a clean stop
+        if target_down {
+            return Err(FunnelError::TargetDown {
+                source: None,
+                context: format!("Target {} stopped accepting connections", spec.local_target),
+            });
+        }
         Ok(())
     }
 }
 
+/// The page the proxy serves while the target is down: `--holding-page-file`, else the
+/// built-in one under `--holding-page`
+fn holding_page(enabled: bool, file: Option<&Path>) -> Result<Option<bytes::Bytes>> {
+    match file {
+        Some(path) => std::fs::read(path)
+            .map(|page| Some(page.into()))
+            .map_err(|err| FunnelError::InvalidField {
+                field: "--holding-page-file".to_string(),
+                value: path.display().to_string(),
+                constraint: format!("Failed to read {}: {}", path.display(), err),
+            }),
+        None => Ok(enabled.then(|| bytes::Bytes::from_static(DEFAULT_HOLDING_PAGE.as_bytes()))),
+    }
+}
+
+/// Writes the URL, or with `as_json` the `started` event, for tools that watch a file
+/// instead of stdout. Replaced atomically, so a watcher never reads half a line.
+fn write_url_file(path: &Path, url: &str, event: &Event, as_json: bool) -> Result<()> {
+    let contents = if as_json {
+        serde_json::to_string(event)
+            .map_err(|err| FunnelError::Other(format!("JSON serialization failed: {}", err)))?
+    } else {
+        url.to_string()
+    };
+    dirs::write_private(path, format!("{}\n", contents).as_bytes())
+}
+
+/// Resolves the tunnel's host over public DNS, past MagicDNS and any split-DNS override.
+/// Funnel ingress is a public address; no answer, or only tailnet or private addresses,
+/// means the URL works on this machine and nowhere else. A failed lookup proves nothing
+/// and is only logged.
+async fn check_public_dns(host: &str) -> Option<String> {
+    let addrs = match probe::lookup_a(host, DNS_CHECK_TIMEOUT).await {
+        Ok(addrs) => addrs,
+        Err(err) => {
+            tracing::debug!(error = %err, host, "public DNS check skipped");
+            return None;
+        }
+    };
+    if addrs.is_empty() {
+        return Some(format!(
+            "{} does not resolve in public DNS yet; the URL may only work from this tailnet until the name propagates.",
+            host
+        ));
+    }
+    if !addrs.iter().any(|addr| probe::is_public_address(*addr)) {
+        let addrs: Vec<String> = addrs.iter().map(IpAddr::to_string).collect();
+        return Some(format!(
+            "public DNS resolves {} to {}, not to Funnel ingress; check the tailnet's DNS settings, as the URL may only work from this machine.",
+            host,
+            addrs.join(", ")
+        ));
+    }
+    None
+}
+
 fn generate_random_path() -> String {
     let token: String = rand::thread_rng()
         .sample_iter(&Alphanumeric)
@@ -180,50 +901,1296 @@ async fn resolve_localhost() -> Result<IpAddr> {
     first.ok_or_else(|| FunnelError::InvalidArgument("localhost did not resolve".to_string()))
 }
 
-fn parse_ttl(value: &str) -> Result<Duration> {
-    humantime::parse_duration(value)
-        .map_err(|err| FunnelError::InvalidArgument(format!("Invalid TTL '{}': {}", value, err)))
+/// Returns the requested name, or one derived from the current directory, making sure no
+/// active lease already uses it. Callers hold the lock so two `open`s cannot race.
+fn pick_name(requested: Option<String>) -> Result<String> {
+    let active: Vec<Lease> = match LeaseStore::open().and_then(|store| store.list()) {
+        Ok(leases) => leases
+            .into_iter()
+            .filter(|lease| lease.is_active(Utc::now()))
+            .collect(),
+        Err(err) => {
+            tracing::warn!(error = %err, "lease store unavailable; skipping name check");
+            Vec::new()
+        }
+    };
+    let taken: Vec<&str> = active
+        .iter()
+        .filter_map(|lease| lease.name.as_deref())
+        .collect();
+
+    match requested {
+        Some(name) => match active
+            .iter()
+            .find(|lease| lease.name.as_deref() == Some(name.as_str()))
+        {
+            Some(lease) => Err(FunnelError::Conflict {
+                source: None,
+                context: format!(
+                    "Name '{}' is already used by active tunnel {} ({})",
+                    name,
+                    lease.lease_id,
+                    lease
+                        .url
+                        .as_deref()
+                        .unwrap_or(lease.tunnel_spec.path.as_str())
+                ),
+            }),
+            None => Ok(name),
+        },
+        None => {
+            let dir = std::env::current_dir().unwrap_or_default();
+            Ok(default_name(&dir, &taken))
+        }
+    }
 }
 
-fn emit_warning(warning: &ValidationWarning, json: bool) {
-    if json {
-        return;
+/// Routes still owned by stale leases (see `LeaseStore::collect_garbage`), e.g. after a
+/// crashed `open`. `--auto-clean` or a yes at the prompt removes them before applying;
+/// otherwise `open` goes on, unless one of them sits where the new route goes.
+async fn handle_leftovers(
+    backend: &dyn Backend,
+    spec: &TunnelSpec,
+    auto_clean: bool,
+    json: bool,
+) -> Result<()> {
+    let Ok(store) = LeaseStore::open() else {
+        return Ok(());
+    };
+    let stale: Vec<Lease> = match store.list() {
+        Ok(leases) => leases
+            .into_iter()
+            .filter(|lease| lease.stale_since.is_some())
+            .collect(),
+        Err(_) => return Ok(()),
+    };
+    if stale.is_empty() {
+        return Ok(());
+    }
+    let Ok(routes) = backend.routes().await else {
+        return Ok(());
+    };
+    let leftovers: Vec<Route> = routes
+        .into_iter()
+        .filter(|route| stale.iter().any(|lease| lease.owns(route)))
+        .collect();
+    if leftovers.is_empty() {
+        return Ok(());
     }
-    match warning {
-        ValidationWarning::PathTooShort { path, .. } => {
+    let ghosts: Vec<&Lease> = stale
+        .iter()
+        .filter(|lease| leftovers.iter().any(|route| lease.owns(route)))
+        .collect();
+
+    let interactive = !json && std::io::stdin().is_terminal();
+    if interactive && !auto_clean {
+        eprintln!("Routes left behind by tunnels that are no longer running:");
+        for lease in &ghosts {
             eprintln!(
-                "Warning: Short path '{}' is guessable. Consider a longer path or use default random path.",
-                path
+                "  {}  {}  ({})",
+                lease.name.as_deref().unwrap_or("-"),
+                lease
+                    .url
+                    .as_deref()
+                    .unwrap_or(lease.tunnel_spec.path.as_str()),
+                stale_reason(lease)
             );
         }
-        ValidationWarning::TtlTooShort { ttl } => {
+    }
+    if auto_clean || (interactive && confirm_leftovers(leftovers.len())?) {
+        {
+            let _lock = LockGuard::acquire()?;
+            backend.remove_routes(&leftovers).await?;
+        }
+        for lease in &ghosts {
+            if let Err(err) = store.remove(&lease.lease_id) {
+                tracing::warn!(lease = %lease.lease_id, error = %err, "failed to remove lease record");
+            }
+        }
+        if !json {
             eprintln!(
-                "Warning: Short TTL ({}). Tunnel expires quickly.",
-                format_duration(*ttl)
+                "Removed {} route(s) left behind by stale tunnels",
+                leftovers.len()
             );
         }
+        return Ok(());
     }
+
+    let in_the_way = leftovers.iter().find(|route| {
+        route.path == spec.path
+            && route
+                .https_port
+                .is_some_and(|port| spec.https_ports().any(|own| own == port))
+    });
+    if let Some(route) = in_the_way {
+        let owner = ghosts
+            .iter()
+            .find(|lease| lease.owns(route))
+            .map(|lease| describe_ghost(lease))
+            .unwrap_or_default();
+        return Err(FunnelError::Conflict {
+            source: None,
+            context: format!(
+                "Path {} on port {} is still held by a tunnel that is no longer running{}; rerun with --auto-clean or run `funnelctl clean`",
+                spec.path,
+                route.https_port.unwrap_or(spec.https_port),
+                owner
+            ),
+        });
+    }
+    if !json {
+        output::advise(format_args!(
+            "Note: {} route(s) left behind by stale tunnels. Run `funnelctl clean` to remove them.",
+            leftovers.len()
+        ));
+    }
+    Ok(())
 }
 
-async fn wait_for_stop(ttl: Option<Duration>) -> StopReason {
+fn describe_ghost(lease: &Lease) -> String {
+    match lease.pid {
+        Some(pid) => format!(" ({}, pid {} exited)", lease.lease_id, pid),
+        None => format!(" ({}, expired)", lease.lease_id),
+    }
+}
+
+fn confirm_leftovers(routes: usize) -> Result<bool> {
+    eprint!("Remove {} leftover route(s) before opening? [y/N] ", routes);
+    std::io::stderr()
+        .flush()
+        .map_err(|err| FunnelError::Other(err.to_string()))?;
+    let mut answer = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|err| FunnelError::Other(err.to_string()))?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Shows what `apply` would write for `spec`; the proxy, if any, is not started, so the
+/// diff names the target itself
+async fn print_dry_run(
+    backend: &dyn Backend,
+    spec: &TunnelSpec,
+    public_url_base: Option<&url::Url>,
+    json: bool,
+    theme: &Theme,
+) -> Result<()> {
+    let before = backend.serve_config().await?;
+    let mut after = before.clone();
+    let url = backend.preview(spec, &mut after).await?;
+    let url = match public_url_base {
+        Some(base) => public_url(base, &url),
+        None => url,
+    };
+    let diff = diff_serve_configs(&before, &after)?;
+    if !json {
+        println!("Would open {}", url);
+        println!("{}", render_serve_diff(&diff, theme));
+    }
+    let event = Event::DryRun {
+        version: 1,
+        url: url.to_string(),
+        changes: diff.changes,
+        patch: diff.patch,
+    };
+    plugin::publish(&event);
+    if json {
+        event
+            .emit_json()
+            .map_err(|err| FunnelError::Other(err.to_string()))?;
+    }
+    Ok(())
+}
+
+/// Removes the background route of a detached tunnel whose lease could not be recorded
+async fn rollback_detached(backend: &dyn Backend, lease: &Lease) {
+    let routes = match backend.routes().await {
+        Ok(routes) => routes
+            .into_iter()
+            .filter(|route| lease.owns(route))
+            .collect::<Vec<_>>(),
+        Err(err) => {
+            tracing::warn!(error = %err, "failed to roll back detached tunnel");
+            return;
+        }
+    };
+    let _lock = LockGuard::acquire();
+    if let Err(err) = backend.remove_routes(&routes).await {
+        tracing::warn!(error = %err, "failed to roll back detached tunnel");
+    }
+}
+
+/// Runs the configured `url_hook` with `url` on stdin and returns the URL it prints
+async fn run_url_hook(hook: &Path, url: &url::Url) -> Result<url::Url> {
+    rewrite_url(
+        tokio::process::Command::new(hook),
+        &hook.display().to_string(),
+        url,
+    )
+    .await
+}
+
+/// The body of `run_url_hook`, for any command; `label` names it in errors
+async fn rewrite_url(
+    mut command: tokio::process::Command,
+    label: &str,
+    url: &url::Url,
+) -> Result<url::Url> {
+    use tokio::io::AsyncWriteExt;
+
+    let failed = |message: String| FunnelError::Other(format!("{}: {}", label, message));
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| failed(err.to_string()))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A hook that ignores stdin may exit before reading it; its output still counts.
+        let _ = stdin.write_all(format!("{}\n", url).as_bytes()).await;
+    }
+    let output = tokio::time::timeout(URL_HOOK_TIMEOUT, child.wait_with_output())
+        .await
+        .map_err(|_| failed(format!("no answer within {}s", URL_HOOK_TIMEOUT.as_secs())))?
+        .map_err(|err| failed(err.to_string()))?;
+    if !output.status.success() {
+        return Err(failed(format!("exited with {}", output.status)));
+    }
+    let replacement = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let parsed = url::Url::parse(&replacement)
+        .map_err(|err| failed(format!("printed '{}', not a URL: {}", replacement, err)))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(failed(format!(
+            "printed '{}', not an http(s) URL",
+            replacement
+        )));
+    }
+    Ok(parsed)
+}
+
+/// The directory `open` runs in; history entries from the same one share a project
+fn current_project() -> Option<String> {
+    std::env::current_dir()
+        .ok()
+        .map(|dir| dir.display().to_string())
+}
+
+/// The HTTPS port to open on and, with `all`, the ports to mirror it to, within what the
+/// node's Funnel policy permits. A refused `requested` port moves to the first permitted one.
+fn pick_https_ports(requested: u16, all: bool, permitted: Option<&[u16]>) -> (u16, Vec<u16>) {
+    let allowed = |port: &u16| permitted.map_or(true, |ports| ports.contains(port));
+    let https_port = match permitted {
+        Some([first, ..]) if !allowed(&requested) => *first,
+        _ => requested,
+    };
+    let mirrors = if all {
+        ALLOWED_HTTPS_PORTS
+            .iter()
+            .copied()
+            .filter(|port| *port != https_port && allowed(port))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    (https_port, mirrors)
+}
+
+/// The path of the last tunnel opened from this directory for the same ports, unless an
+/// active tunnel holds it. Leftovers of dead ones are handled like any other leftover.
+fn reused_path(port: u16, https_port: u16, json: bool) -> Result<Option<String>> {
+    let project = current_project().ok_or_else(|| {
+        FunnelError::InvalidArgument("--reuse-path needs a readable current directory".to_string())
+    })?;
+    let entries = HistoryLog::open()?.read()?;
+    let Some(path) = last_path(&entries, &project, port, https_port) else {
+        if !json {
+            output::advise(format_args!(
+                "Note: no earlier tunnel for port {} from this directory; using a new path.",
+                port
+            ));
+        }
+        return Ok(None);
+    };
+
+    let leases = LeaseStore::open()?.list()?;
+    let holder = leases.iter().find(|lease| {
+        lease.is_active(Utc::now())
+            && lease.tunnel_spec.path == path
+            && lease.tunnel_spec.https_port == https_port
+    });
+    if let Some(lease) = holder {
+        return Err(FunnelError::Conflict {
+            source: None,
+            context: format!(
+                "Path {} from the last run is held by active tunnel {}; close it or drop --reuse-path",
+                path,
+                lease.name.as_deref().unwrap_or(lease.lease_id.as_str())
+            ),
+        });
+    }
+    if !json {
+        output::advise(format_args!(
+            "Reusing path {} from the last run in this directory.",
+            path
+        ));
+    }
+    Ok(Some(path))
+}
+
+/// Appends the finished tunnel to the history log; failures only cost `history` output
+fn record_history(entry: HistoryEntry) {
+    if let Err(err) = HistoryLog::open().and_then(|log| log.append(&entry)) {
+        tracing::warn!(error = %err, "failed to record history");
+    }
+}
+
+/// Persists the lease so `close`/`status` can find it; failures only cost those features
+fn record_lease(lease: &Lease) -> Option<LeaseStore> {
+    let store = match LeaseStore::open() {
+        Ok(store) => store,
+        Err(err) => {
+            tracing::warn!(error = %err, "lease store unavailable");
+            return None;
+        }
+    };
+    if let Err(err) = store.save(lease) {
+        tracing::warn!(error = %err, "failed to record lease");
+        return None;
+    }
+    Some(store)
+}
+
+/// The positional port, else the one in `--port-from-env` or `--port-file`
+async fn resolve_port(args: &OpenArgs, json: bool) -> Result<u16> {
+    if let Some(port) = args.port {
+        return Ok(port);
+    }
+    if let Some(var) = &args.port_from_env {
+        let value = std::env::var(var).map_err(|_| {
+            FunnelError::InvalidArgument(format!("--port-from-env: ${} is not set", var))
+                .for_field("--port-from-env", var.as_str())
+        })?;
+        return parse_port(&value, &format!("${}", var))
+            .map_err(|err| err.for_field("--port-from-env", value.trim()));
+    }
+    if let Some(path) = &args.port_file {
+        return wait_for_port_file(path, json).await;
+    }
+    Err(FunnelError::InvalidArgument(
+        "a port, --port-from-env or --port-file is required".to_string(),
+    ))
+}
+
+/// Dev servers often write the port file after they start listening, so a missing,
+/// still-empty or not yet parseable (half-written) file is polled until `PORT_FILE_WAIT`
+/// runs out
+async fn wait_for_port_file(path: &Path, json: bool) -> Result<u16> {
+    let deadline = Instant::now() + PORT_FILE_WAIT;
+    let mut announced = false;
+    let mut invalid = None;
+    loop {
+        match std::fs::read_to_string(path) {
+            Ok(text) if !text.trim().is_empty() => {
+                match parse_port(&text, &format!("Port file {}", path.display())) {
+                    Ok(port) => return Ok(port),
+                    Err(err) => invalid = Some(err.for_field("--port-file", text.trim())),
+                }
+            }
+            Ok(_) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(FunnelError::Other(format!(
+                    "Failed to read port file {}: {}",
+                    path.display(),
+                    err
+                )))
+            }
+        }
+
+        if Instant::now() >= deadline {
+            return Err(invalid.unwrap_or_else(|| {
+                FunnelError::InvalidArgument(format!(
+                    "Port file {} did not appear within {}",
+                    path.display(),
+                    format_duration(PORT_FILE_WAIT)
+                ))
+            }));
+        }
+        if !announced && !json {
+            output::advise(format_args!("Waiting for port file {}...", path.display()));
+            announced = true;
+        }
+        tokio::select! {
+            _ = sleep(PORT_FILE_POLL) => {}
+            _ = signal::ctrl_c() => {
+                return Err(FunnelError::Interrupted(format!(
+                    "Interrupted while waiting for port file {}",
+                    path.display()
+                )))
+            }
+        }
+    }
+}
+
+/// `--at`: waits for the start time, with a countdown on a terminal. Nothing has been
+/// applied yet, so Ctrl-C just gives up.
+async fn wait_for_start(starts_at: DateTime<Local>, json: bool, theme: &Theme) -> Result<()> {
+    let event = Event::Scheduled {
+        version: 1,
+        starts_at: starts_at.with_timezone(&Utc),
+    };
+    plugin::publish(&event);
+    if json {
+        event
+            .emit_json()
+            .map_err(|err| FunnelError::Other(err.to_string()))?;
+    }
+    let countdown = !json && !theme.plain && std::io::stderr().is_terminal();
+    if !json && !countdown {
+        eprintln!(
+            "Opening at {}; Ctrl-C to cancel.",
+            starts_at.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+
+    let clear = || {
+        if countdown {
+            eprint!("\r\x1b[K");
+        }
+    };
+    loop {
+        let left = (starts_at - Local::now()).to_std().unwrap_or_default();
+        if left.is_zero() {
+            break;
+        }
+        if countdown {
+            let seconds = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+            eprint!(
+                "\r\x1b[KOpening at {} (in {}); Ctrl-C to cancel",
+                starts_at.format("%H:%M:%S"),
+                format_duration(Duration::from_secs(seconds))
+            );
+            let _ = std::io::stderr().flush();
+        }
+        tokio::select! {
+            _ = sleep(left.min(Duration::from_secs(1))) => {}
+            _ = signal::ctrl_c() => {
+                clear();
+                return Err(FunnelError::Interrupted(
+                    "Interrupted while waiting for --at; nothing was opened".to_string(),
+                ));
+            }
+        }
+    }
+    clear();
+    Ok(())
+}
+
+/// Path rules from the flags, with limits and reserved prefixes the config may replace
+fn path_policy(args: &OpenArgs, config: &OpenConfig) -> Result<PathPolicy> {
+    Ok(PathPolicy {
+        allow_reserved: args.allow_reserved_path,
+        strict: !args.lax_path,
+        ..config_path_policy(config)?
+    })
+}
+
+/// The path rules set in the `[open]` config section, shared with `apply`
+pub(crate) fn config_path_policy(config: &OpenConfig) -> Result<PathPolicy> {
+    let mut policy = PathPolicy::default();
+    if let Some(reserved) = &config.reserved_paths {
+        if let Some(bad) = reserved.iter().find(|path| !path.starts_with('/')) {
+            return Err(FunnelError::InvalidArgument(format!(
+                "open.reserved_paths entry '{}' in config must start with '/'",
+                bad
+            )));
+        }
+        policy.reserved = reserved.clone();
+    }
+    if let Some(max_length) = config.max_path_length {
+        policy.max_length = max_length;
+    }
+    if let Some(max_segments) = config.max_path_segments {
+        policy.max_segments = max_segments;
+    }
+    Ok(policy)
+}
+
+/// `--ttl`, else the config default unless `--no-ttl`; `require_ttl` rejects ending up
+/// with none
+/// Deadline forms count from `now`, which is the `--at` start time when there is one
+pub(crate) fn resolve_ttl(
+    flag: Option<&str>,
+    no_ttl: bool,
+    default: Option<&str>,
+    required: bool,
+    now: DateTime<Local>,
+) -> Result<Option<Duration>> {
+    let ttl = match (flag, default) {
+        (Some(value), _) => {
+            Some(parse_ttl(value, now).map_err(|err| err.for_field("--ttl", value))?)
+        }
+        (None, Some(value)) if !no_ttl => Some(parse_ttl(value, now).map_err(|err| match err {
+            FunnelError::InvalidArgument(message) => FunnelError::InvalidField {
+                field: "open.ttl".to_string(),
+                value: value.to_string(),
+                constraint: format!("{} (open.ttl in config)", message),
+            },
+            other => other,
+        })?),
+        _ => None,
+    };
+    if ttl.is_none() && required {
+        return Err(FunnelError::PolicyViolation {
+            source: None,
+            context: "A TTL is required (open.require_ttl in config); pass --ttl".to_string(),
+        });
+    }
+    Ok(ttl)
+}
+
+/// Starts the `--json-fd` / `--json-file` event copy; before the backend is built, so
+/// an unreachable tailscaled is reported there too
+pub fn open_event_stream(args: &OpenArgs) -> Result<()> {
+    let (flag, path) = match (args.json_fd, &args.json_file) {
+        (Some(fd), _) => (
+            format!("--json-fd {}", fd),
+            PathBuf::from(format!("/dev/fd/{}", fd)),
+        ),
+        (None, Some(path)) => (format!("--json-file {}", path.display()), path.clone()),
+        (None, None) => return Ok(()),
+    };
+    output::open_event_stream(&path)
+        .map_err(|err| FunnelError::InvalidArgument(format!("Cannot open {}: {}", flag, err)))
+}
+
+/// `--skip-checks` on `open`, `apply` and `up`
+pub(crate) fn warn_unchecked() {
+    output::advise(format_args!(
+        "Warning: preflight checks skipped (--skip-checks); the target, tailscaled version, HTTPS and Funnel were not verified."
+    ));
+}
+
+/// An active tunnel serving the same bind:port on another route; one on the same route
+/// is a conflict, or is superseded with `--force`
+fn already_exposed<'a>(
+    spec: &TunnelSpec,
+    leases: &'a [Lease],
+    now: DateTime<Utc>,
+) -> Option<&'a Lease> {
+    leases.iter().find(|lease| {
+        let other = &lease.tunnel_spec;
+        lease.is_active(now)
+            && other.local_target.bind == spec.local_target.bind
+            && other.local_target.port == spec.local_target.port
+            && (other.path != spec.path || other.https_port != spec.https_port)
+    })
+}
+
+/// Prints a validation warning as `Warning: ... [id]`, skips one listed in
+/// `open.ignore_warnings`, and fails the run on the rest under `--deny-warnings`
+fn emit_warning(
+    warning: &ValidationWarning,
+    ignored: &[WarningId],
+    deny: bool,
+    json: bool,
+) -> Result<()> {
+    let id = warning.id();
+    if ignored.contains(&id) {
+        return Ok(());
+    }
+    if deny {
+        return Err(FunnelError::InvalidArgument(format!(
+            "{} (warning {} is an error under --deny-warnings; list it in open.ignore_warnings to allow it)",
+            warning, id
+        )));
+    }
+    if !json {
+        output::advise(format_args!("Warning: {} [{}]", warning, id));
+    }
+    Ok(())
+}
+
+/// `--health-path` and `--health-timeout`, checked before anything is applied
+fn health_check(path: &str, raw_timeout: &str) -> Result<(String, Duration)> {
+    if !path.starts_with('/') {
+        return Err(FunnelError::InvalidField {
+            field: "--health-path".to_string(),
+            value: path.to_string(),
+            constraint: "must start with '/'".to_string(),
+        });
+    }
+    let within =
+        humantime::parse_duration(raw_timeout).map_err(|err| FunnelError::InvalidField {
+            field: "--health-timeout".to_string(),
+            value: raw_timeout.to_string(),
+            constraint: format!("Invalid --health-timeout '{}': {}", raw_timeout, err),
+        })?;
+    if within.is_zero() {
+        return Err(FunnelError::InvalidField {
+            field: "--health-timeout".to_string(),
+            value: raw_timeout.to_string(),
+            constraint: "--health-timeout must be longer than 0s".to_string(),
+        });
+    }
+    Ok((path.to_string(), within))
+}
+
+fn report_extended(
+    extension: Duration,
+    expires_at: Option<chrono::DateTime<Utc>>,
+    json: bool,
+) -> Result<()> {
+    let event = Event::Extended {
+        version: 1,
+        expires_at,
+    };
+    plugin::publish(&event);
+    if json {
+        return event
+            .emit_json()
+            .map_err(|err| FunnelError::Other(err.to_string()));
+    }
+    if let Some(expires_at) = expires_at {
+        eprintln!(
+            "TTL extended by {}. Expires {}.",
+            format_duration(extension),
+            expires_at.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+    }
+    Ok(())
+}
+
+/// `funnelctl pause` and `resume`. With the local proxy, Funnel requests get the holding
+/// page; without it, Funnel is turned off for the route. Either way the session and URL
+/// stay, and tailnet clients still reach the target.
+async fn set_paused(
+    paused: bool,
+    proxy: Option<&LocalProxy>,
+    backend: &dyn Backend,
+    lease: &mut Lease,
+    store: Option<&LeaseStore>,
+    json: bool,
+) -> Result<()> {
+    if paused == lease.paused_since.is_some() {
+        let state = if paused {
+            "already paused"
+        } else {
+            "not paused"
+        };
+        return Err(FunnelError::Other(format!("Tunnel is {}", state)));
+    }
+    if !lease.tunnel_spec.funnel {
+        return Err(FunnelError::Other(
+            "Tunnel is tailnet-only; there is no public traffic to pause".to_string(),
+        ));
+    }
+    match proxy {
+        Some(proxy) => proxy.set_paused(paused),
+        None => backend.set_route_funnel(&lease.lease_id, !paused).await?,
+    }
+
+    let at = Utc::now();
+    let event = if paused {
+        lease.paused_since = Some(at);
+        Event::Paused {
+            version: 1,
+            at,
+            holding_page: proxy.is_some(),
+        }
+    } else {
+        let since = lease.paused_since.take().unwrap_or(at);
+        Event::Resumed {
+            version: 1,
+            at,
+            paused_seconds: (at - since).num_seconds().max(0) as u64,
+        }
+    };
+    if let Some(store) = store {
+        if let Err(err) = store.save(lease) {
+            tracing::warn!(error = %err, "failed to update lease record");
+        }
+    }
+    plugin::publish(&event);
+    if json {
+        return event
+            .emit_json()
+            .map_err(|err| FunnelError::Other(err.to_string()));
+    }
+    match event {
+        Event::Paused {
+            holding_page: true, ..
+        } => eprintln!("Paused. Public requests get the holding page until `funnelctl resume`."),
+        Event::Paused { .. } => eprintln!(
+            "Paused. Funnel is off for this route until `funnelctl resume`; tailnet clients still reach it."
+        ),
+        Event::Resumed { paused_seconds, .. } => eprintln!(
+            "Resumed after {}. Public requests reach the target again.",
+            format_duration(Duration::from_secs(paused_seconds))
+        ),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Re-reads our route and reports when its state changed since `previous`; with
+/// `self_heal`, a removed or replaced route is written back. Returns the state the next
+/// check compares against.
+async fn check_route(
+    backend: &dyn Backend,
+    lease_id: &str,
+    previous: RouteState,
+    self_heal: bool,
+    path: &str,
+    json: bool,
+    link: &mut BackendLink,
+) -> Result<RouteState> {
+    let state = match backend.route_state(lease_id).await {
+        Ok(state) => {
+            link.connected(json)?;
+            state
+        }
+        Err(err) => {
+            tracing::warn!(error = %err, "failed to check route");
+            link.disconnected(&err, json)?;
+            return Ok(previous);
+        }
+    };
+    if state == previous {
+        return Ok(state);
+    }
+
+    let mut restored = false;
+    if self_heal && matches!(state, RouteState::Removed | RouteState::Replaced) {
+        let restore = match LockGuard::acquire() {
+            Ok(_lock) => backend.restore_route(lease_id).await,
+            // Another command is changing routes right now; look again at the next check
+            // rather than write over it or give up on the route
+            Err(err @ FunnelError::Locked { .. }) => {
+                tracing::info!(error = %err, "route changed while locked; restoring later");
+                return Ok(previous);
+            }
+            Err(err) => Err(err),
+        };
+        match restore {
+            Ok(()) => restored = true,
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to restore route");
+                if !json {
+                    eprintln!("Warning: could not restore route {}: {}", path, err);
+                }
+            }
+        }
+    }
+    report_route_state(state, restored, path, json)?;
+    if restored {
+        let event = Event::Reapplied {
+            version: 1,
+            at: Utc::now(),
+            path: path.to_string(),
+            after: state,
+        };
+        plugin::publish(&event);
+        if json {
+            event
+                .emit_json()
+                .map_err(|err| FunnelError::Other(err.to_string()))?;
+        }
+    }
+    Ok(if restored { RouteState::Intact } else { state })
+}
+
+/// Whether the last route check reached tailscaled; each loss and recovery is reported once
+#[derive(Default)]
+struct BackendLink {
+    down_since: Option<chrono::DateTime<Utc>>,
+}
+
+impl BackendLink {
+    fn disconnected(&mut self, err: &FunnelError, json: bool) -> Result<()> {
+        if self.down_since.is_some() {
+            return Ok(());
+        }
+        let at = Utc::now();
+        self.down_since = Some(at);
+        let event = Event::BackendDisconnected {
+            version: 1,
+            at,
+            error: err.to_string(),
+        };
+        plugin::publish(&event);
+        if !json {
+            eprintln!("Warning: lost contact with tailscaled ({}); retrying.", err);
+            return Ok(());
+        }
+        event
+            .emit_json()
+            .map_err(|err| FunnelError::Other(err.to_string()))
+    }
+
+    fn connected(&mut self, json: bool) -> Result<()> {
+        let Some(since) = self.down_since.take() else {
+            return Ok(());
+        };
+        let at = Utc::now();
+        let downtime_seconds = (at - since).num_seconds().max(0) as u64;
+        let event = Event::BackendReconnected {
+            version: 1,
+            at,
+            downtime_seconds,
+        };
+        plugin::publish(&event);
+        if !json {
+            eprintln!("Reconnected to tailscaled after {}s.", downtime_seconds);
+            return Ok(());
+        }
+        event
+            .emit_json()
+            .map_err(|err| FunnelError::Other(err.to_string()))
+    }
+}
+
+fn report_route_state(state: RouteState, restored: bool, path: &str, json: bool) -> Result<()> {
+    let event = Event::RouteChanged {
+        version: 1,
+        state,
+        restored,
+    };
+    plugin::publish(&event);
+    if json {
+        return event
+            .emit_json()
+            .map_err(|err| FunnelError::Other(err.to_string()));
+    }
+    let (change, effect) = match state {
+        RouteState::Intact => {
+            eprintln!("Route {} is back in the serve config.", path);
+            return Ok(());
+        }
+        RouteState::SessionEnded => {
+            eprintln!("Warning: tailscaled closed the session; the tunnel is no longer served.");
+            return Ok(());
+        }
+        RouteState::Removed => ("was removed", "the tunnel is not serving"),
+        RouteState::Replaced => ("was changed", "requests may not reach your service"),
+    };
+    let effect = if restored { "restored it" } else { effect };
+    eprintln!(
+        "Warning: route {} {} by another tool; {}.",
+        path, change, effect
+    );
+    Ok(())
+}
+
+fn report_first_request(request: &FirstRequest, theme: &Theme, json: bool) -> Result<()> {
+    let event = Event::FirstRequest {
+        version: 1,
+        at: request.at,
+        method: request.method.clone(),
+        path: request.path.clone(),
+    };
+    plugin::publish(&event);
+    if json {
+        return event
+            .emit_json()
+            .map_err(|err| FunnelError::Other(err.to_string()));
+    }
+    eprintln!(
+        "{} first external request received at {} ({} {})",
+        theme.marks().0,
+        request.at.format("%H:%M:%S UTC"),
+        request.method,
+        request.path
+    );
+    Ok(())
+}
+
+/// Heartbeat: the requests in flight through the local proxy changed since the last one
+fn report_activity(active_requests: u64, json: bool) -> Result<()> {
+    let event = Event::Activity {
+        version: 1,
+        at: Utc::now(),
+        active_requests,
+    };
+    plugin::publish(&event);
+    if json {
+        return event
+            .emit_json()
+            .map_err(|err| FunnelError::Other(err.to_string()));
+    }
+    // A line per change would flood a log file; only a watching user wants them
+    if std::io::stderr().is_terminal() {
+        let noun = if active_requests == 1 {
+            "connection"
+        } else {
+            "connections"
+        };
+        eprintln!("{} active {} through the tunnel", active_requests, noun);
+    }
+    Ok(())
+}
+
+fn report_target_health(health: TargetHealth, target: &LocalTarget, json: bool) -> Result<()> {
+    let local_target = target.to_string();
+    let at = Utc::now();
+    let event = match health {
+        TargetHealth::Up => Event::TargetUp {
+            version: 1,
+            at,
+            local_target,
+        },
+        TargetHealth::Down => Event::TargetDown {
+            version: 1,
+            at,
+            local_target,
+        },
+    };
+    plugin::publish(&event);
+    if json {
+        return event
+            .emit_json()
+            .map_err(|err| FunnelError::Other(err.to_string()));
+    }
+    match health {
+        TargetHealth::Up => eprintln!("Target {} is accepting connections again.", target),
+        TargetHealth::Down => eprintln!(
+            "Warning: target {} is not accepting connections; requests will fail.",
+            target
+        ),
+    }
+    Ok(())
+}
+
+/// Why the foreground wait returned
+enum Wake {
+    Stop(StopReason),
+    /// The local proxy saw its first external request
+    FirstRequest(FirstRequest),
+    Control(ControlMessage),
+    /// Time to compare the live serve config with what we applied
+    RouteCheck,
+    /// Time to probe the target and rewrite the status file
+    Heartbeat,
+}
+
+async fn wait_for_stop(
+    deadline: Option<Instant>,
+    control: Option<&mut mpsc::Receiver<ControlMessage>>,
+    route: Option<&dyn Backend>,
+    heartbeat: Option<Instant>,
+    first_request: Option<&mut oneshot::Receiver<FirstRequest>>,
+    upstream_failed: Option<&Notify>,
+) -> Wake {
     let ctrl_c = async {
         let _ = signal::ctrl_c().await;
-        StopReason::UserInterrupt
+        Wake::Stop(StopReason::UserInterrupt)
     };
 
     let ttl_wait = async {
-        match ttl {
-            Some(ttl) => {
-                let deadline = Instant::now() + ttl;
+        match deadline {
+            Some(deadline) => {
                 sleep_until(deadline).await;
-                StopReason::TtlExpired
+                Wake::Stop(StopReason::TtlExpired)
             }
             None => futures::future::pending().await,
         }
     };
 
+    let control_wait = async {
+        match control {
+            Some(rx) => match rx.recv().await {
+                Some(message) => Wake::Control(message),
+                None => futures::future::pending().await,
+            },
+            None => futures::future::pending().await,
+        }
+    };
+
+    let route_check = async {
+        match route {
+            Some(backend) => {
+                tokio::select! {
+                    _ = backend.route_event() => sleep(ROUTE_EVENT_SETTLE).await,
+                    _ = sleep(ROUTE_CHECK_INTERVAL) => {}
+                }
+                Wake::RouteCheck
+            }
+            None => futures::future::pending().await,
+        }
+    };
+
+    let heartbeat_wait = async {
+        match heartbeat {
+            Some(heartbeat) => {
+                sleep_until(heartbeat).await;
+                Wake::Heartbeat
+            }
+            None => futures::future::pending().await,
+        }
+    };
+
+    // A request the proxy could not forward is news the next heartbeat need not wait for
+    let upstream_wait = async {
+        match upstream_failed {
+            Some(notify) => {
+                notify.notified().await;
+                Wake::Heartbeat
+            }
+            None => futures::future::pending().await,
+        }
+    };
+
+    let first_request_wait = async {
+        match first_request {
+            Some(rx) => match rx.await {
+                Ok(request) => Wake::FirstRequest(request),
+                // The proxy stopped before anyone called
+                Err(_) => futures::future::pending().await,
+            },
+            None => futures::future::pending().await,
+        }
+    };
+
+    let terminate = async {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            match signal(SignalKind::terminate()) {
+                Ok(mut sigterm) => {
+                    sigterm.recv().await;
+                    return Wake::Stop(StopReason::Terminated);
+                }
+                Err(err) => {
+                    tracing::debug!(error = %err, "SIGTERM handler unavailable");
+                }
+            }
+        }
+        #[cfg(windows)]
+        {
+            if console_stop_event().await {
+                return Wake::Stop(StopReason::Terminated);
+            }
+        }
+        futures::future::pending::<Wake>().await
+    };
+
     tokio::select! {
-        reason = ctrl_c => reason,
-        reason = ttl_wait => reason,
+        wake = ctrl_c => wake,
+        wake = ttl_wait => wake,
+        wake = terminate => wake,
+        wake = control_wait => wake,
+        wake = route_check => wake,
+        wake = heartbeat_wait => wake,
+        wake = first_request_wait => wake,
+        wake = upstream_wait => wake,
+    }
+}
+
+/// Waits for Ctrl-Break or the console being closed, the user logging off, or shutdown,
+/// the Windows counterparts of SIGTERM. Returns false if no handler could be installed.
+/// Windows kills the process a few seconds after a close, logoff, or shutdown event, which
+/// is enough for the teardown. Ctrl-C is handled by the caller.
+#[cfg(windows)]
+async fn console_stop_event() -> bool {
+    use futures::future::{select_all, BoxFuture};
+    use tokio::signal::windows;
+
+    let mut events: Vec<BoxFuture<'static, ()>> = Vec::new();
+    match windows::ctrl_break() {
+        Ok(mut event) => events.push(Box::pin(async move {
+            event.recv().await;
+        })),
+        Err(err) => tracing::debug!(error = %err, "Ctrl-Break handler unavailable"),
+    }
+    match windows::ctrl_close() {
+        Ok(mut event) => events.push(Box::pin(async move {
+            event.recv().await;
+        })),
+        Err(err) => tracing::debug!(error = %err, "console close handler unavailable"),
+    }
+    match windows::ctrl_logoff() {
+        Ok(mut event) => events.push(Box::pin(async move {
+            event.recv().await;
+        })),
+        Err(err) => tracing::debug!(error = %err, "logoff handler unavailable"),
+    }
+    match windows::ctrl_shutdown() {
+        Ok(mut event) => events.push(Box::pin(async move {
+            event.recv().await;
+        })),
+        Err(err) => tracing::debug!(error = %err, "shutdown handler unavailable"),
+    }
+
+    if events.is_empty() {
+        return false;
+    }
+    select_all(events).await;
+    true
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    /// A hook run as `sh -c <body>`. Writing a script and executing it right away can
+    /// fail with ETXTBSY when another test forks while the file is still open for writing.
+    async fn hook(body: &str, url: &url::Url) -> Result<url::Url> {
+        let mut command = tokio::process::Command::new("sh");
+        command.arg("-c").arg(body);
+        rewrite_url(command, "sh", url).await
+    }
+
+    #[test]
+    fn test_emit_warning_ignored_or_denied() {
+        let warning = ValidationWarning::PathTooShort {
+            path: "/ab".to_string(),
+            length: 3,
+        };
+        assert!(emit_warning(&warning, &[], false, true).is_ok());
+        assert!(emit_warning(&warning, &[WarningId::PathTooShort], true, true).is_ok());
+        let err = emit_warning(&warning, &[WarningId::TtlTooShort], true, true).unwrap_err();
+        assert_eq!(err.exit_code(), 2);
+        assert!(err.to_string().contains("path_too_short"));
+    }
+
+    #[test]
+    fn test_already_exposed_ignores_other_targets_and_same_route() {
+        let spec = |port: u16, path: &str| {
+            TunnelSpec::new(
+                LocalTarget::new("127.0.0.1".to_string(), port),
+                443,
+                path.to_string(),
+                true,
+            )
+        };
+        let running =
+            |id: &str, spec: TunnelSpec| Lease::new(id.to_string(), spec.detached(), None);
+        let now = Utc::now();
+        let leases = vec![
+            running("other-port", spec(3000, "/a")),
+            running("same-route", spec(8081, "/hook")),
+        ];
+        assert!(already_exposed(&spec(8081, "/hook"), &leases, now).is_none());
+
+        let leases = vec![
+            Lease::new(
+                "expired".to_string(),
+                spec(8081, "/old").detached(),
+                Some(now - chrono::Duration::minutes(1)),
+            ),
+            running("live", spec(8081, "/docs")),
+        ];
+        let existing = already_exposed(&spec(8081, "/hook"), &leases, now).unwrap();
+        assert_eq!(existing.lease_id, "live");
+    }
+
+    #[test]
+    fn test_pick_https_ports_within_funnel_policy() {
+        assert_eq!(pick_https_ports(443, false, None), (443, vec![]));
+        assert_eq!(pick_https_ports(443, true, None), (443, vec![8443, 10000]));
+        assert_eq!(pick_https_ports(443, false, Some(&[8443])), (8443, vec![]));
+        assert_eq!(
+            pick_https_ports(10000, true, Some(&[443, 10000])),
+            (10000, vec![443])
+        );
+        // Nothing permitted: keep the request so apply reports the refusal
+        assert_eq!(pick_https_ports(443, false, Some(&[])), (443, vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_pause_turns_funnel_off_without_proxy() {
+        let backend = crate::backend::MockBackend::new();
+        let spec = TunnelSpec::new(
+            LocalTarget::new("127.0.0.1".to_string(), 3000),
+            443,
+            "/hook".to_string(),
+            true,
+        );
+        let result = backend.apply(&spec, &NoProgress).await.unwrap();
+        let mut lease = Lease::new(result.lease_id.clone(), spec, None);
+        let funnel = |routes: Vec<Route>| routes.iter().all(|route| route.funnel);
+
+        set_paused(true, None, &backend, &mut lease, None, false)
+            .await
+            .unwrap();
+        assert!(lease.paused_since.is_some());
+        assert!(!funnel(backend.routes().await.unwrap()));
+        // Paused is how the route is meant to look now, so the route watch leaves it be
+        assert_eq!(
+            backend.route_state(&lease.lease_id).await.unwrap(),
+            RouteState::Intact
+        );
+        assert!(set_paused(true, None, &backend, &mut lease, None, false)
+            .await
+            .is_err());
+
+        set_paused(false, None, &backend, &mut lease, None, false)
+            .await
+            .unwrap();
+        assert!(lease.paused_since.is_none());
+        assert!(funnel(backend.routes().await.unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_port_file_rereads_unparseable_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("port");
+        std::fs::write(&path, "80 # half").unwrap();
+        let writer = {
+            let path = path.clone();
+            tokio::spawn(async move {
+                sleep(PORT_FILE_POLL * 2).await;
+                std::fs::write(&path, "8080\n").unwrap();
+            })
+        };
+        assert_eq!(wait_for_port_file(&path, true).await.unwrap(), 8080);
+        writer.await.unwrap();
+    }
+
+    #[test]
+    fn test_write_url_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("url.txt");
+        let url = "https://node.ts.net/funnelctl/abc";
+        let event = Event::Started {
+            version: 1,
+            url: url.to_string(),
+            mirror_urls: Vec::new(),
+            local_target: "http://127.0.0.1:3000".to_string(),
+            path: "/funnelctl/abc".to_string(),
+            https_port: 443,
+            name: None,
+            expose_for: None,
+            started_at: Utc::now(),
+            expires_at: None,
+            unchecked: false,
+            dns_warning: None,
+            warnings: Vec::new(),
+        };
+        write_url_file(&path, url, &event, false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", url)
+        );
+        write_url_file(&path, url, &event, true).unwrap();
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(written["event"], "started");
+        assert_eq!(written["url"], url);
+    }
+
+    #[tokio::test]
+    async fn test_url_hook_replaces_url() {
+        let url = url::Url::parse("https://node.ts.net/funnelctl/abc").unwrap();
+
+        let short = hook("read url; echo \"https://sho.rt/${url##*/}\"", &url)
+            .await
+            .unwrap();
+        assert_eq!(short.as_str(), "https://sho.rt/abc");
+
+        assert!(hook("echo not a url", &url).await.is_err());
+        assert!(hook("exit 3", &url).await.is_err());
     }
 }
@@ -0,0 +1,60 @@
+use crate::cli::PauseArgs;
+use crate::control::{self, ControlCommand};
+use crate::core::{resolve_lease, LeaseStore};
+use crate::error::{FunnelError, Result};
+
+/// `funnelctl pause` and `funnelctl resume`
+pub struct PauseCommand {
+    args: PauseArgs,
+    pause: bool,
+}
+
+impl PauseCommand {
+    pub fn pause(args: PauseArgs) -> Self {
+        Self { args, pause: true }
+    }
+
+    pub fn resume(args: PauseArgs) -> Self {
+        Self { args, pause: false }
+    }
+
+    /// Asks the running `open` to stop or restart public traffic; the session, route
+    /// and URL stay as they are
+    pub async fn run(self) -> Result<()> {
+        let leases = LeaseStore::open()?.list()?;
+        let lease = resolve_lease(&leases, &self.args.lease)?;
+        let label = lease
+            .url
+            .clone()
+            .unwrap_or_else(|| lease.tunnel_spec.path.clone());
+        if !lease.owner_is_running() {
+            return Err(FunnelError::InvalidArgument(format!(
+                "Tunnel {} is not run by a foreground `funnelctl open`; only those can be paused",
+                label
+            )));
+        }
+
+        let command = if self.pause {
+            ControlCommand::Pause
+        } else {
+            ControlCommand::Resume
+        };
+        let reply = control::send(&lease.lease_id, &command).await?;
+        if !reply.ok {
+            return Err(FunnelError::Other(
+                reply
+                    .error
+                    .unwrap_or_else(|| "Tunnel rejected the request".to_string()),
+            ));
+        }
+        if self.pause {
+            println!(
+                "Paused {}; public requests are held until `funnelctl resume`",
+                label
+            );
+        } else {
+            println!("Resumed {}", label);
+        }
+        Ok(())
+    }
+}
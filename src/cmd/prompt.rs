@@ -0,0 +1,188 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use humantime::format_duration;
+use serde::Serialize;
+
+use crate::backend::localapi::LocalApiBackend;
+use crate::cli::PromptArgs;
+use crate::cmd::status::lease_state;
+use crate::core::{Lease, LeaseStore};
+use crate::error::{FunnelError, Result};
+
+/// A prompt is redrawn on every command; a hung tailscaled must not stall it
+const NODE_CHECK_TIMEOUT: Duration = Duration::from_millis(300);
+
+pub struct PromptCommand {
+    args: PromptArgs,
+}
+
+/// What `prompt --json` prints
+#[derive(Debug, Default, Serialize)]
+struct PromptSummary {
+    /// Running and detached tunnels
+    tunnels: usize,
+    /// Tunnels expiring within `--expiring-within`
+    expiring: usize,
+    next_expires_at: Option<DateTime<Utc>>,
+    stale: usize,
+    /// `None` when tailscaled was not asked: no tunnels, `--offline`, or no answer in time
+    node_reachable: Option<bool>,
+    segment: String,
+}
+
+impl PromptCommand {
+    pub fn new(args: PromptArgs) -> Self {
+        Self { args }
+    }
+
+    /// Prints a one-line summary for shell prompts. Reads the lease store; asks tailscaled
+    /// (through the status cache) only when tunnels depend on it. Prints nothing when
+    /// there is nothing to show.
+    pub async fn run(self, backend: Result<Arc<LocalApiBackend>>) -> Result<()> {
+        let window = humantime::parse_duration(&self.args.expiring_within).map_err(|err| {
+            FunnelError::InvalidField {
+                field: "--expiring-within".to_string(),
+                value: self.args.expiring_within.clone(),
+                constraint: format!(
+                    "Invalid --expiring-within '{}': {}",
+                    self.args.expiring_within, err
+                ),
+            }
+        })?;
+        let leases = LeaseStore::open()?.list()?;
+        let now = Utc::now();
+        let mut summary = summarize(&leases, window, now);
+        if summary.tunnels > 0 && !self.args.offline {
+            summary.node_reachable = node_reachable(backend).await;
+        }
+        summary.segment = segment(&summary, now);
+
+        if self.args.json {
+            let line = serde_json::to_string(&summary)
+                .map_err(|err| FunnelError::Other(err.to_string()))?;
+            println!("{}", line);
+        } else if !summary.segment.is_empty() {
+            println!("{}", summary.segment);
+        }
+        Ok(())
+    }
+}
+
+fn summarize(leases: &[Lease], window: Duration, now: DateTime<Utc>) -> PromptSummary {
+    let mut summary = PromptSummary::default();
+    let horizon = chrono::Duration::from_std(window)
+        .ok()
+        .and_then(|window| now.checked_add_signed(window))
+        .unwrap_or(DateTime::<Utc>::MAX_UTC);
+    for lease in leases {
+        if lease_state(lease) == "stale" {
+            summary.stale += 1;
+            continue;
+        }
+        summary.tunnels += 1;
+        if let Some(expires_at) = lease.expires_at.filter(|at| *at <= horizon) {
+            summary.expiring += 1;
+            summary.next_expires_at = Some(
+                summary
+                    .next_expires_at
+                    .map_or(expires_at, |next| next.min(expires_at)),
+            );
+        }
+    }
+    summary
+}
+
+/// `None` when tailscaled did not answer in time; a prompt should not guess
+async fn node_reachable(backend: Result<Arc<LocalApiBackend>>) -> Option<bool> {
+    let backend = match backend {
+        Ok(backend) => backend,
+        Err(err) => {
+            tracing::debug!(error = %err, "no LocalAPI for prompt");
+            return Some(false);
+        }
+    };
+    match tokio::time::timeout(NODE_CHECK_TIMEOUT, backend.fetch_status()).await {
+        Ok(Ok(_)) => Some(true),
+        Ok(Err(FunnelError::Unreachable { .. })) => Some(false),
+        Ok(Err(err)) => {
+            tracing::debug!(error = %err, "node status failed");
+            None
+        }
+        Err(_) => None,
+    }
+}
+
+/// `2 tunnels (1 expiring in 4m)`; empty when there are no tunnels at all
+fn segment(summary: &PromptSummary, now: DateTime<Utc>) -> String {
+    if summary.tunnels == 0 && summary.stale == 0 {
+        return String::new();
+    }
+    let mut notes = Vec::new();
+    if let Some(next) = summary.next_expires_at {
+        let left = format_left(next, now);
+        notes.push(match summary.expiring {
+            1 => format!("1 expiring in {}", left),
+            count => format!("{} expiring, first in {}", count, left),
+        });
+    }
+    if summary.stale > 0 {
+        notes.push(format!("{} stale", summary.stale));
+    }
+    if summary.node_reachable == Some(false) {
+        notes.push("tailscaled unreachable".to_string());
+    }
+
+    let mut segment = match summary.tunnels {
+        1 => "1 tunnel".to_string(),
+        count => format!("{} tunnels", count),
+    };
+    if !notes.is_empty() {
+        segment.push_str(&format!(" ({})", notes.join(", ")));
+    }
+    segment
+}
+
+/// Whole minutes, rounded up, so a tunnel never shows `0m` while it is still up
+fn format_left(at: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let secs = (at - now).num_seconds().max(0) as u64;
+    format_duration(Duration::from_secs(secs.div_ceil(60) * 60)).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment_is_compact() {
+        let now = Utc::now();
+        let mut summary = PromptSummary {
+            tunnels: 2,
+            expiring: 1,
+            next_expires_at: Some(now + chrono::Duration::seconds(200)),
+            ..PromptSummary::default()
+        };
+        assert_eq!(segment(&summary, now), "2 tunnels (1 expiring in 4m)");
+
+        summary.expiring = 2;
+        summary.stale = 1;
+        summary.node_reachable = Some(false);
+        assert_eq!(
+            segment(&summary, now),
+            "2 tunnels (2 expiring, first in 4m, 1 stale, tailscaled unreachable)"
+        );
+
+        assert_eq!(segment(&PromptSummary::default(), now), "");
+        let one = PromptSummary {
+            tunnels: 1,
+            node_reachable: Some(true),
+            ..PromptSummary::default()
+        };
+        assert_eq!(segment(&one, now), "1 tunnel");
+        assert_eq!(
+            format_left(now + chrono::Duration::minutes(65), now),
+            "1h 5m"
+        );
+    }
+}
@@ -0,0 +1,88 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+
+use crate::backend::Backend;
+use crate::core::{HistoryEntry, HistoryLog, Lease, LeaseStore, Route};
+use crate::error::Result;
+use crate::lock::LockGuard;
+use crate::output::StopReason;
+
+pub struct ReaperCommand;
+
+impl ReaperCommand {
+    pub async fn run(backend: Arc<dyn Backend>) -> Result<()> {
+        for lease in reap_expired(backend.as_ref()).await? {
+            println!(
+                "Reaped {} (expired {})",
+                lease
+                    .url
+                    .as_deref()
+                    .unwrap_or(lease.tunnel_spec.path.as_str()),
+                lease
+                    .expires_at
+                    .map(|at| at.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                    .unwrap_or_default()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Tears down detached tunnels whose TTL has passed, returning the reaped leases.
+/// Detached tunnels have no process enforcing their TTL, so every command that talks to
+/// tailscaled runs this on the way in.
+pub async fn reap_expired(backend: &dyn Backend) -> Result<Vec<Lease>> {
+    reap_expired_from(backend, &LeaseStore::open()?).await
+}
+
+/// `reap_expired` for the leases in `store`, whose routes are on `backend`
+pub async fn reap_expired_from(backend: &dyn Backend, store: &LeaseStore) -> Result<Vec<Lease>> {
+    let now = Utc::now();
+    let expired: Vec<Lease> = store
+        .list()?
+        .into_iter()
+        .filter(|lease| lease.is_expired_detached(now))
+        .collect();
+    if expired.is_empty() {
+        return Ok(expired);
+    }
+
+    let routes: Vec<Route> = backend
+        .routes()
+        .await?
+        .into_iter()
+        .filter(|route| expired.iter().any(|lease| lease.owns(route)))
+        .collect();
+    if !routes.is_empty() {
+        let _lock = LockGuard::acquire()?;
+        backend.remove_routes(&routes).await?;
+    }
+
+    let history = HistoryLog::open();
+    for lease in &expired {
+        store.remove(&lease.lease_id)?;
+        tracing::info!(lease = %lease.lease_id, "reaped expired detached tunnel");
+        if let Ok(history) = &history {
+            let entry = HistoryEntry {
+                lease_id: lease.lease_id.clone(),
+                name: lease.name.clone(),
+                url: lease.url.clone().unwrap_or_default(),
+                local_target: lease.tunnel_spec.local_target.to_string(),
+                started_at: lease.created_at,
+                stopped_at: now,
+                reason: StopReason::TtlExpired.as_str().to_string(),
+                project: None,
+                path: Some(lease.tunnel_spec.path.clone()),
+                https_port: Some(lease.tunnel_spec.https_port),
+                usage: lease.usage,
+                expose_for: lease.expose_for.clone(),
+                open_args: lease.open_args.clone(),
+            };
+            if let Err(err) = history.append(&entry) {
+                tracing::warn!(error = %err, "failed to record history");
+            }
+        }
+    }
+    Ok(expired)
+}
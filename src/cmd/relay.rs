@@ -0,0 +1,349 @@
+//! `funnelctl relay`: serves this node's LocalAPI to `open --remote-node` on another
+//! machine. It listens on loopback only; `tailscale serve` puts it on the tailnet with the
+//! node's certificate, and every request must carry the relay token.
+//!
+//! Only the endpoints funnelctl needs are relayed, so the token cannot be used to change
+//! preferences, log the node out, or fetch its certificate keys.
+
+use std::convert::Infallible;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, IF_MATCH};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use tokio::net::TcpListener;
+use tokio::signal;
+use tokio::task::JoinSet;
+
+use crate::cli::RelayArgs;
+use crate::dirs;
+use crate::error::{FunnelError, Result};
+use crate::net::localapi_transport::read_token_file;
+use crate::net::{LocalApiTransport, TransportRequest};
+
+/// Endpoints `open` uses
+const RELAYED_ENDPOINTS: &[&str] = &[
+    "/localapi/v0/status",
+    "/localapi/v0/serve-config",
+    "/localapi/v0/watch-ipn-bus",
+];
+/// Set by tailscaled on requests that came in over Funnel rather than the tailnet
+const FUNNEL_REQUEST_HEADER: &str = "tailscale-funnel-request";
+const TOKEN_LEN: usize = 40;
+/// ServeConfig documents are small; anything bigger is not a funnelctl request
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+type RelayBody = BoxBody<Bytes, hyper::Error>;
+
+struct Relay {
+    transport: LocalApiTransport,
+    authorization: String,
+}
+
+pub struct RelayCommand {
+    args: RelayArgs,
+}
+
+impl RelayCommand {
+    pub fn new(args: RelayArgs) -> Self {
+        Self { args }
+    }
+
+    pub async fn run(self, transport: LocalApiTransport) -> Result<()> {
+        let token = load_or_create_token(&self.args.token_file)?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, self.args.port))
+            .await
+            .map_err(|err| {
+                FunnelError::Other(format!(
+                    "Failed to listen on 127.0.0.1:{}: {}",
+                    self.args.port, err
+                ))
+            })?;
+        println!(
+            "Relaying LocalAPI on http://127.0.0.1:{}. Put it on the tailnet (not Funnel) with:",
+            self.args.port
+        );
+        println!(
+            "  tailscale serve --bg --https=4443 http://127.0.0.1:{}",
+            self.args.port
+        );
+
+        let relay = Arc::new(Relay {
+            transport,
+            authorization: format!("Bearer {}", token),
+        });
+        tokio::select! {
+            _ = accept_loop(listener, relay) => Ok(()),
+            _ = signal::ctrl_c() => Ok(()),
+        }
+    }
+}
+
+/// The token in `path`, or a new one written there when the file does not exist yet
+fn load_or_create_token(path: &Path) -> Result<String> {
+    if path.exists() {
+        return read_token_file(path).map_err(|err| FunnelError::InvalidArgument(err.to_string()));
+    }
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LEN)
+        .map(char::from)
+        .collect();
+    dirs::write_private(path, format!("{}\n", token).as_bytes())?;
+    eprintln!(
+        "Wrote a new relay token to {}. Copy it (mode 0600) to the machine that runs `open --remote-node`.",
+        path.display()
+    );
+    Ok(token)
+}
+
+async fn accept_loop(listener: TcpListener, relay: Arc<Relay>) {
+    let mut connections = JoinSet::new();
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::debug!(error = %err, "relay accept failed");
+                continue;
+            }
+        };
+        while connections.try_join_next().is_some() {}
+
+        let relay = Arc::clone(&relay);
+        connections.spawn(async move {
+            let service = service_fn(move |request| handle(Arc::clone(&relay), request, peer));
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!(error = %err, %peer, "relay connection ended with error");
+            }
+        });
+    }
+}
+
+async fn handle(
+    relay: Arc<Relay>,
+    request: Request<Incoming>,
+    peer: SocketAddr,
+) -> std::result::Result<Response<RelayBody>, Infallible> {
+    if request.headers().contains_key(FUNNEL_REQUEST_HEADER) {
+        return Ok(plain_response(
+            StatusCode::FORBIDDEN,
+            "funnelctl relay: not over Funnel\n",
+        ));
+    }
+    let authorized = request
+        .headers()
+        .get(AUTHORIZATION)
+        .is_some_and(|value| same_secret(value.as_bytes(), relay.authorization.as_bytes()));
+    if !authorized {
+        tracing::warn!(%peer, path = %request.uri().path(), "relay request without a valid token");
+        return Ok(plain_response(
+            StatusCode::UNAUTHORIZED,
+            "funnelctl relay: invalid token\n",
+        ));
+    }
+    if !RELAYED_ENDPOINTS.contains(&request.uri().path()) {
+        return Ok(plain_response(
+            StatusCode::FORBIDDEN,
+            "funnelctl relay: endpoint not relayed\n",
+        ));
+    }
+
+    tracing::info!(method = %request.method(), path = %request.uri().path(), "relaying LocalAPI request");
+    match forward(&relay.transport, request).await {
+        Ok(response) => Ok(response.map(BodyExt::boxed)),
+        Err(err) => {
+            tracing::warn!(error = %err, "relayed LocalAPI request failed");
+            Ok(plain_response(
+                StatusCode::BAD_GATEWAY,
+                "funnelctl relay: tailscaled unavailable\n",
+            ))
+        }
+    }
+}
+
+async fn forward(
+    transport: &LocalApiTransport,
+    request: Request<Incoming>,
+) -> std::result::Result<Response<Incoming>, String> {
+    let (parts, body) = request.into_parts();
+    let path = parts
+        .uri
+        .path_and_query()
+        .map(|value| value.as_str())
+        .unwrap_or("/");
+    let mut forwarded = TransportRequest::new(parts.method.clone(), path);
+    for name in [IF_MATCH, CONTENT_TYPE] {
+        if let Some(value) = parts.headers.get(&name) {
+            forwarded.headers.insert(name, value.clone());
+        }
+    }
+    let body = Limited::new(body, MAX_BODY_BYTES)
+        .collect()
+        .await
+        .map_err(|err| format!("request body: {}", err))?
+        .to_bytes();
+    if !body.is_empty() {
+        forwarded = forwarded.with_body(body.to_vec());
+    }
+    transport
+        .send(forwarded)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Compares without stopping at the first differing byte, so response times do not
+/// reveal how much of a guessed token was right
+fn same_secret(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn plain_response(status: StatusCode, body: &'static str) -> Response<RelayBody> {
+    let mut response = Response::new(
+        Full::new(Bytes::from_static(body.as_bytes()))
+            .map_err(|never| match never {})
+            .boxed(),
+    );
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Empty;
+    use hyper_util::client::legacy::connect::HttpConnector;
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const TOKEN: &str = "Bearer test-token";
+
+    /// A relay on a loopback port in front of a fake tailscaled that counts the requests
+    /// reaching it and answers each with an empty status
+    async fn relay() -> (SocketAddr, Arc<AtomicUsize>, tempfile::TempDir) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("tailscaled.sock");
+        let daemon = tokio::net::UnixListener::bind(&socket).unwrap();
+        let forwarded = Arc::new(AtomicUsize::new(0));
+        let seen = Arc::clone(&forwarded);
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = daemon.accept().await.unwrap();
+                let mut buf = vec![0; 4096];
+                let _ = stream.read(&mut buf).await.unwrap();
+                seen.fetch_add(1, Ordering::SeqCst);
+                stream
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\n{}",
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let relay = Arc::new(Relay {
+            transport: LocalApiTransport::unix_socket(socket),
+            authorization: TOKEN.to_string(),
+        });
+        tokio::spawn(accept_loop(listener, relay));
+        (addr, forwarded, dir)
+    }
+
+    async fn send(addr: SocketAddr, path: &str, headers: &[(&str, &str)]) -> StatusCode {
+        let client: Client<HttpConnector, Empty<Bytes>> =
+            Client::builder(TokioExecutor::new()).build(HttpConnector::new());
+        let mut request = Request::get(format!("http://{}{}", addr, path));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        client
+            .request(request.body(Empty::new()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn test_relay_forwards_allowed_request() {
+        let (addr, forwarded, _dir) = relay().await;
+        let status = send(addr, "/localapi/v0/status", &[("authorization", TOKEN)]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+    }
+
+    /// Sends one request to a fresh relay and checks that tailscaled never saw it
+    async fn rejected(path: &str, headers: &[(&str, &str)]) -> StatusCode {
+        let (addr, forwarded, _dir) = relay().await;
+        let status = send(addr, path, headers).await;
+        assert_eq!(
+            forwarded.load(Ordering::SeqCst),
+            0,
+            "{} was forwarded",
+            path
+        );
+        status
+    }
+
+    #[tokio::test]
+    async fn test_relay_rejects_missing_token() {
+        let status = rejected("/localapi/v0/status", &[]).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_relay_rejects_wrong_token() {
+        let headers = [("authorization", "Bearer wrong-token")];
+        let status = rejected("/localapi/v0/status", &headers).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_relay_rejects_funneled_request() {
+        // Refused even with the right token: the relay must only be reachable on the tailnet
+        let headers = [("authorization", TOKEN), ("tailscale-funnel-request", "?1")];
+        let status = rejected("/localapi/v0/status", &headers).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_relay_rejects_endpoint_not_relayed() {
+        let headers = [("authorization", TOKEN)];
+        for path in ["/localapi/v0/prefs", "/localapi/v0/cert/node.ts.net"] {
+            assert!(!RELAYED_ENDPOINTS.contains(&path));
+            assert_eq!(rejected(path, &headers).await, StatusCode::FORBIDDEN);
+        }
+    }
+
+    #[test]
+    fn test_same_secret() {
+        assert!(same_secret(b"Bearer abc", b"Bearer abc"));
+        assert!(!same_secret(b"Bearer abd", b"Bearer abc"));
+        assert!(!same_secret(b"Bearer ab", b"Bearer abc"));
+    }
+}
@@ -0,0 +1,110 @@
+use std::io::{BufRead, IsTerminal, Write};
+use std::sync::Arc;
+
+use crate::backend::Backend;
+use crate::cli::RollbackArgs;
+use crate::core::{diff_serve_configs, list_routes, BackupStore, LeaseStore};
+use crate::error::{FunnelError, Result};
+use crate::lock::LockGuard;
+use crate::output::{render_serve_diff, render_table, Theme};
+
+pub struct RollbackCommand {
+    args: RollbackArgs,
+    theme: Theme,
+}
+
+impl RollbackCommand {
+    pub fn new(args: RollbackArgs, theme: Theme) -> Self {
+        Self { args, theme }
+    }
+
+    pub async fn run(self, backend: Arc<dyn Backend>) -> Result<()> {
+        let store = BackupStore::open()?;
+        if self.args.list {
+            return print_backups(&store);
+        }
+
+        let backup = store.find(self.args.backup.as_deref())?;
+        let live = backend.serve_config().await?;
+        let diff = diff_serve_configs(&live, &backup.serve_config)?;
+        println!(
+            "Backup {} (saved {})",
+            backup.id,
+            backup.saved_at.format("%Y-%m-%d %H:%M:%S UTC")
+        );
+        if diff.is_empty() {
+            println!("The serve config already matches this backup");
+            return Ok(());
+        }
+        println!("{}", render_serve_diff(&diff, &self.theme));
+
+        if self.args.dry_run {
+            return Ok(());
+        }
+        if !self.args.yes && !confirm()? {
+            println!("Nothing restored");
+            return Ok(());
+        }
+
+        {
+            let _lock = LockGuard::acquire()?;
+            backend.replace_serve_config(&backup.serve_config).await?;
+        }
+        println!("Restored serve config from backup {}", backup.id);
+
+        // Detached leases whose route the backup does not have would list a dead tunnel
+        let routes = list_routes(&backup.serve_config)?;
+        let leases = LeaseStore::open()?;
+        for lease in leases.list()? {
+            if lease.tunnel_spec.detached && !routes.iter().any(|route| lease.owns(route)) {
+                leases.remove(&lease.lease_id)?;
+                println!(
+                    "Forgot {} (not in the backup)",
+                    lease
+                        .name
+                        .as_deref()
+                        .unwrap_or(lease.tunnel_spec.path.as_str())
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn print_backups(store: &BackupStore) -> Result<()> {
+    let backups = store.list()?;
+    if backups.is_empty() {
+        println!("No serve config backups");
+        return Ok(());
+    }
+    let mut rows = Vec::new();
+    for backup in &backups {
+        rows.push(vec![
+            backup.id.clone(),
+            backup.saved_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+            list_routes(&backup.serve_config)?.len().to_string(),
+        ]);
+    }
+    println!("{}", render_table(&["ID", "SAVED", "ROUTES"], &rows));
+    Ok(())
+}
+
+fn confirm() -> Result<bool> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return Err(FunnelError::InvalidArgument(
+            "rollback needs --yes when stdin is not a terminal".to_string(),
+        ));
+    }
+
+    print!("Replace the live serve config with this backup? [y/N] ");
+    std::io::stdout()
+        .flush()
+        .map_err(|err| FunnelError::Other(err.to_string()))?;
+    let mut answer = String::new();
+    stdin
+        .lock()
+        .read_line(&mut answer)
+        .map_err(|err| FunnelError::Other(err.to_string()))?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
@@ -0,0 +1,394 @@
+//! `funnelctl selftest`: opens a tunnel to a throwaway responder, fetches it locally and
+//! through Funnel's public ingress, closes it, and checks nothing is left behind.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use crate::backend::Backend;
+use crate::cmd::doctor::probe_public_url;
+use crate::core::{LocalTarget, Route, TunnelResult, TunnelSpec};
+use crate::error::ErrorCode;
+use crate::lock::LockGuard;
+use crate::net::probe::{self, ProbeResponder};
+use crate::output::Theme;
+use crate::progress::NoProgress;
+
+/// Longest the selftest tunnel stays up; verification is cut off after this
+const SELFTEST_TTL: Duration = Duration::from_secs(120);
+const LOCAL_TIMEOUT: Duration = Duration::from_secs(5);
+const PUBLIC_ATTEMPTS: u32 = 5;
+const PUBLIC_RETRY_DELAY: Duration = Duration::from_secs(2);
+const PUBLIC_TIMEOUT: Duration = Duration::from_secs(10);
+/// tailscaled drops a closed foreground session asynchronously
+const CLEANUP_ATTEMPTS: u32 = 10;
+const CLEANUP_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+pub struct SelftestCommand;
+
+#[derive(Debug, Clone, Default)]
+pub struct SelftestOptions {
+    /// Use the in-memory backend and skip the public fetch
+    pub offline: bool,
+    pub theme: Theme,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelftestStep {
+    Responder,
+    Open,
+    Route,
+    Local,
+    Public,
+    Close,
+    Cleanup,
+}
+
+impl SelftestStep {
+    fn label(self) -> &'static str {
+        match self {
+            SelftestStep::Responder => "Local responder",
+            SelftestStep::Open => "Open tunnel",
+            SelftestStep::Route => "Route in serve config",
+            SelftestStep::Local => "Local fetch",
+            SelftestStep::Public => "Public fetch",
+            SelftestStep::Close => "Close tunnel",
+            SelftestStep::Cleanup => "Route removed",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct StepResult {
+    pub step: SelftestStep,
+    /// `None` when the step was skipped
+    pub passed: Option<bool>,
+    pub message: String,
+    /// Exit code this failure maps to; `None` for passes and skipped steps
+    pub error_code: Option<i32>,
+}
+
+impl StepResult {
+    fn pass(step: SelftestStep, message: impl Into<String>) -> Self {
+        Self {
+            step,
+            passed: Some(true),
+            message: message.into(),
+            error_code: None,
+        }
+    }
+
+    fn fail(step: SelftestStep, message: impl Into<String>, error_code: i32) -> Self {
+        Self {
+            step,
+            passed: Some(false),
+            message: message.into(),
+            error_code: Some(error_code),
+        }
+    }
+
+    fn skip(step: SelftestStep, message: impl Into<String>) -> Self {
+        Self {
+            step,
+            passed: None,
+            message: message.into(),
+            error_code: None,
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.passed == Some(false)
+    }
+}
+
+/// Outcome of a selftest run; the caller decides whether to exit with `exit_code`
+#[derive(Debug)]
+pub struct SelftestReport {
+    pub steps: Vec<StepResult>,
+    /// Exit code of the first failed step, 0 if none failed
+    pub exit_code: i32,
+}
+
+impl SelftestCommand {
+    pub async fn run(backend: Arc<dyn Backend>, options: SelftestOptions) -> SelftestReport {
+        let report = Self::check(backend.as_ref(), options.offline).await;
+        Self::print_results(&report, &options.theme);
+        report
+    }
+
+    /// Runs every step without printing anything. Once the tunnel is open, close and
+    /// cleanup run even if a verification step failed.
+    pub async fn check(backend: &dyn Backend, offline: bool) -> SelftestReport {
+        let mut steps = Vec::new();
+        run_steps(backend, offline, &mut steps).await;
+        let exit_code = steps.iter().find_map(|step| step.error_code).unwrap_or(0);
+        SelftestReport { steps, exit_code }
+    }
+
+    fn print_results(report: &SelftestReport, theme: &Theme) {
+        let (pass_mark, fail_mark) = theme.marks();
+        for step in &report.steps {
+            let mark = match step.passed {
+                Some(true) => pass_mark.as_str(),
+                Some(false) => fail_mark.as_str(),
+                None => "-",
+            };
+            println!(
+                "{} {}: {}",
+                mark,
+                step.step.label(),
+                theme.text(&step.message)
+            );
+        }
+        match report.steps.iter().find(|step| step.failed()) {
+            Some(step) => println!("Selftest failed at: {}", step.step.label()),
+            None => println!("Selftest passed"),
+        }
+    }
+}
+
+async fn run_steps(backend: &dyn Backend, offline: bool, steps: &mut Vec<StepResult>) {
+    let token: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect();
+    let responder = match ProbeResponder::start(token.clone()).await {
+        Ok(responder) => responder,
+        Err(err) => {
+            steps.push(StepResult::fail(
+                SelftestStep::Responder,
+                format!("Cannot start responder: {}", err),
+                ErrorCode::Other.code(),
+            ));
+            return;
+        }
+    };
+    let target = LocalTarget::new("127.0.0.1".to_string(), responder.port());
+    steps.push(StepResult::pass(
+        SelftestStep::Responder,
+        format!("Listening on {}", target),
+    ));
+
+    let path = format!("/funnelctl-selftest/{}", token);
+    let spec = TunnelSpec::new(target, 443, path.clone(), true);
+    let applied = match LockGuard::acquire() {
+        Ok(_lock) => backend.apply(&spec, &NoProgress).await,
+        Err(err) => Err(err),
+    };
+    let result = match applied {
+        Ok(result) => result,
+        Err(err) => {
+            steps.push(StepResult::fail(
+                SelftestStep::Open,
+                format!("Cannot open tunnel: {}", err),
+                err.exit_code(),
+            ));
+            return;
+        }
+    };
+    steps.push(StepResult::pass(
+        SelftestStep::Open,
+        format!("{} (up to {}s)", result.url, SELFTEST_TTL.as_secs()),
+    ));
+
+    let verify = verify(backend, &spec, &result, &token, offline, steps);
+    if tokio::time::timeout(SELFTEST_TTL, verify).await.is_err() {
+        steps.push(StepResult::fail(
+            SelftestStep::Public,
+            format!("Timed out after {}s", SELFTEST_TTL.as_secs()),
+            ErrorCode::Prerequisites.code(),
+        ));
+    }
+
+    if let Err(err) = backend.remove(&result.lease_id).await {
+        steps.push(StepResult::fail(
+            SelftestStep::Close,
+            format!("Cannot close tunnel {}: {}", result.lease_id, err),
+            err.exit_code(),
+        ));
+        return;
+    }
+    steps.push(StepResult::pass(SelftestStep::Close, "Tunnel closed"));
+    steps.push(check_cleanup(backend, &result.lease_id, &path).await);
+}
+
+/// The route, local and public steps; cut off by `SELFTEST_TTL`
+async fn verify(
+    backend: &dyn Backend,
+    spec: &TunnelSpec,
+    result: &TunnelResult,
+    token: &str,
+    offline: bool,
+    steps: &mut Vec<StepResult>,
+) {
+    steps.push(match backend.routes().await {
+        Ok(routes) if is_ours(&routes, &result.lease_id, &spec.path) => StepResult::pass(
+            SelftestStep::Route,
+            format!("{} -> {}", spec.path, spec.serve_target()),
+        ),
+        Ok(_) => StepResult::fail(
+            SelftestStep::Route,
+            "Route missing from the serve config after apply",
+            ErrorCode::ApplyFailed.code(),
+        ),
+        Err(err) => StepResult::fail(
+            SelftestStep::Route,
+            format!("Cannot read serve config: {}", err),
+            err.exit_code(),
+        ),
+    });
+
+    steps.push(check_local(spec, token).await);
+
+    if offline {
+        steps.push(StepResult::skip(
+            SelftestStep::Public,
+            "Offline; no public ingress to fetch through",
+        ));
+        return;
+    }
+    steps.push(
+        match probe_public_url(
+            &result.url,
+            &spec.path,
+            token,
+            PUBLIC_ATTEMPTS,
+            PUBLIC_RETRY_DELAY,
+            PUBLIC_TIMEOUT,
+        )
+        .await
+        {
+            Ok(addr) => StepResult::pass(
+                SelftestStep::Public,
+                format!("Reached {} via public ingress {}", result.url, addr),
+            ),
+            Err(message) => StepResult::fail(
+                SelftestStep::Public,
+                format!(
+                    "Funnel not reachable from the public internet ({})",
+                    message
+                ),
+                ErrorCode::Prerequisites.code(),
+            ),
+        },
+    );
+}
+
+/// Fetches the path from the address tailscaled forwards to
+async fn check_local(spec: &TunnelSpec, token: &str) -> StepResult {
+    let target = spec.serve_target();
+    let addr = match target.bind.parse() {
+        Ok(ip) => SocketAddr::new(ip, target.port),
+        Err(_) => {
+            return StepResult::fail(
+                SelftestStep::Local,
+                format!("Target {} is not an IP address", target),
+                ErrorCode::TargetPortInaccessible.code(),
+            )
+        }
+    };
+    match probe::http_get(addr, &spec.path, LOCAL_TIMEOUT).await {
+        Ok((status, body)) if status.is_success() && body == token.as_bytes() => {
+            StepResult::pass(SelftestStep::Local, format!("{} answered", target))
+        }
+        Ok((status, _)) => StepResult::fail(
+            SelftestStep::Local,
+            format!("{} answered {} without the token", target, status),
+            ErrorCode::TargetPortInaccessible.code(),
+        ),
+        Err(err) => StepResult::fail(
+            SelftestStep::Local,
+            format!("Cannot fetch {}: {}", target, err),
+            ErrorCode::TargetPortInaccessible.code(),
+        ),
+    }
+}
+
+async fn check_cleanup(backend: &dyn Backend, lease_id: &str, path: &str) -> StepResult {
+    let mut last_error = None;
+    for attempt in 0..CLEANUP_ATTEMPTS {
+        if attempt > 0 {
+            tokio::time::sleep(CLEANUP_RETRY_DELAY).await;
+        }
+        match backend.routes().await {
+            Ok(routes) if !is_ours(&routes, lease_id, path) => {
+                return StepResult::pass(SelftestStep::Cleanup, "Serve config is clean")
+            }
+            Ok(_) => last_error = None,
+            Err(err) => last_error = Some(err),
+        }
+    }
+    match last_error {
+        Some(err) => StepResult::fail(
+            SelftestStep::Cleanup,
+            format!("Cannot read serve config: {}", err),
+            err.exit_code(),
+        ),
+        None => StepResult::fail(
+            SelftestStep::Cleanup,
+            format!(
+                "Route {} still in the serve config; run `funnelctl clean`",
+                path
+            ),
+            ErrorCode::ApplyFailed.code(),
+        ),
+    }
+}
+
+/// Foreground routes carry the session; detached ones have none
+fn is_ours(routes: &[Route], lease_id: &str, path: &str) -> bool {
+    routes.iter().any(|route| {
+        route.path == path
+            && route
+                .session
+                .as_deref()
+                .map_or(true, |session| session == lease_id)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{MockBackend, UnreachableBackend};
+    use std::path::PathBuf;
+    use std::sync::OnceLock;
+
+    /// Keeps leases and backups out of the real state directory. The run lock follows
+    /// `XDG_RUNTIME_DIR` when that is set, so a real funnelctl holding it at the same
+    /// time fails these tests with exit 18.
+    fn isolate_state_dir() {
+        static DIR: OnceLock<PathBuf> = OnceLock::new();
+        let dir = DIR.get_or_init(|| tempfile::tempdir().unwrap().keep());
+        crate::dirs::set_state_dir(dir.clone()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_offline_selftest_passes() {
+        isolate_state_dir();
+
+        let backend = MockBackend::new();
+        let report = SelftestCommand::check(&backend, true).await;
+        assert_eq!(report.exit_code, 0, "{:?}", report.steps);
+        let public = report
+            .steps
+            .iter()
+            .find(|step| step.step == SelftestStep::Public)
+            .unwrap();
+        assert_eq!(public.passed, None);
+        assert!(backend.routes().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_selftest_stops_when_open_fails() {
+        isolate_state_dir();
+
+        let backend = UnreachableBackend::new("socket missing");
+        let report = SelftestCommand::check(&backend, false).await;
+        assert_eq!(report.exit_code, ErrorCode::Unreachable.code());
+        assert_eq!(report.steps.last().unwrap().step, SelftestStep::Open);
+    }
+}
@@ -1,11 +1,282 @@
+use std::collections::BTreeMap;
+use std::io::IsTerminal;
+
+use chrono::{DateTime, Utc};
+use futures::future::join_all;
+use serde::Serialize;
+use tokio::signal;
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::cli::StatusArgs;
+use crate::control::{self, ControlCommand};
+use crate::core::{parse_tag, Lease, LeaseStore, Usage};
 use crate::error::{FunnelError, Result};
+use crate::heartbeat::TunnelStatus;
+use crate::output::{format_usage, render_table};
+
+/// How long `status` waits for all running tunnels together to report their counters
+const STATS_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub struct StatusCommand {
+    args: StatusArgs,
+}
 
-pub struct StatusCommand;
+/// A lease as reported by `status --json`
+#[derive(Debug, Serialize)]
+struct StatusEntry<'a> {
+    lease_id: &'a str,
+    name: Option<&'a str>,
+    tags: &'a BTreeMap<String, String>,
+    expose_for: Option<&'a str>,
+    url: Option<&'a str>,
+    local_target: String,
+    path: &'a str,
+    https_port: u16,
+    pid: Option<u32>,
+    state: &'static str,
+    created_at: DateTime<Utc>,
+    expires_at: Option<DateTime<Utc>>,
+    stale_since: Option<DateTime<Utc>>,
+    paused_since: Option<DateTime<Utc>>,
+    active_requests: Option<u64>,
+    /// The last heartbeat of a foreground `open` (its status file's `updated_at`)
+    last_seen: Option<DateTime<Utc>>,
+    last_healthy: Option<DateTime<Utc>>,
+    usage: Option<Usage>,
+}
+
+impl<'a> StatusEntry<'a> {
+    fn new(lease: &'a Lease, live: &Live) -> Self {
+        Self {
+            lease_id: &lease.lease_id,
+            name: lease.name.as_deref(),
+            tags: &lease.tags,
+            expose_for: lease.expose_for.as_deref(),
+            url: lease.url.as_deref(),
+            local_target: lease.tunnel_spec.local_target.to_string(),
+            path: &lease.tunnel_spec.path,
+            https_port: lease.tunnel_spec.https_port,
+            pid: lease.pid,
+            state: lease_state(lease),
+            created_at: lease.created_at,
+            expires_at: lease.expires_at,
+            stale_since: lease.stale_since,
+            paused_since: lease.paused_since,
+            active_requests: live.active_requests,
+            last_seen: live.heartbeat.as_ref().map(|status| status.updated_at),
+            last_healthy: live
+                .heartbeat
+                .as_ref()
+                .and_then(|status| status.last_healthy),
+            usage: lease.usage,
+        }
+    }
+}
 
 impl StatusCommand {
-    pub async fn run() -> Result<()> {
-        Err(FunnelError::Other(
-            "status command not yet implemented (Phase 2 feature)".to_string(),
-        ))
+    pub fn new(args: StatusArgs) -> Self {
+        Self { args }
+    }
+
+    pub async fn run(self) -> Result<()> {
+        let tags = self
+            .args
+            .tags
+            .iter()
+            .map(|tag| parse_tag(tag))
+            .collect::<Result<Vec<_>>>()?;
+        if !self.args.watch {
+            return self.show(&tags, false).await;
+        }
+
+        let interval = humantime::parse_duration(&self.args.interval).map_err(|err| {
+            FunnelError::InvalidArgument(format!(
+                "Invalid --interval '{}': {}",
+                self.args.interval, err
+            ))
+        })?;
+        if interval.is_zero() {
+            return Err(FunnelError::InvalidArgument(
+                "--interval must be longer than 0s".to_string(),
+            ));
+        }
+        let redraw = !self.args.json && std::io::stdout().is_terminal();
+        loop {
+            self.show(&tags, redraw).await?;
+            tokio::select! {
+                _ = sleep(interval) => {}
+                _ = signal::ctrl_c() => return Ok(()),
+            }
+        }
+    }
+
+    /// Prints the table (or JSON lines) once; `redraw` clears the terminal first
+    async fn show(&self, tags: &[(String, String)], redraw: bool) -> Result<()> {
+        let leases: Vec<Lease> = LeaseStore::open()?
+            .list()?
+            .into_iter()
+            .filter(|lease| lease.has_tags(tags))
+            .collect();
+
+        // One deadline for all of them, so stuck owners cost 500 ms in total, not each
+        let deadline = Instant::now() + STATS_TIMEOUT;
+        let live = join_all(leases.iter().map(|lease| Live::query(lease, deadline))).await;
+
+        if self.args.json {
+            for (lease, live) in leases.iter().zip(&live) {
+                let line = serde_json::to_string(&StatusEntry::new(lease, live))
+                    .map_err(|err| FunnelError::Other(err.to_string()))?;
+                println!("{}", line);
+            }
+            return Ok(());
+        }
+
+        if redraw {
+            print!("\x1b[2J\x1b[H");
+        }
+        if leases.is_empty() {
+            println!("No active tunnels");
+            return Ok(());
+        }
+
+        let now = Utc::now();
+        let rows: Vec<Vec<String>> = leases
+            .iter()
+            .zip(&live)
+            .map(|(lease, live)| {
+                vec![
+                    lease.name.clone().unwrap_or_else(|| "-".to_string()),
+                    lease_state(lease).to_string(),
+                    lease
+                        .url
+                        .clone()
+                        .unwrap_or_else(|| lease.tunnel_spec.path.clone()),
+                    lease.tunnel_spec.local_target.to_string(),
+                    lease
+                        .expires_at
+                        .map(|at| at.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                        .unwrap_or_else(|| "never".to_string()),
+                    live.active_requests
+                        .map_or_else(|| "-".to_string(), |count| count.to_string()),
+                    live.heartbeat.as_ref().map_or_else(
+                        || "-".to_string(),
+                        |status| format_age(status.updated_at, now),
+                    ),
+                    format_usage(lease.usage.as_ref()),
+                    format_tags(&lease.tags),
+                    lease.expose_for.clone().unwrap_or_else(|| "-".to_string()),
+                    short_id(&lease.lease_id).to_string(),
+                ]
+            })
+            .collect();
+        println!(
+            "{}",
+            render_table(
+                &[
+                    "NAME", "STATE", "URL", "LOCAL", "EXPIRES", "ACTIVE", "SEEN", "USAGE", "TAGS",
+                    "FOR", "ID"
+                ],
+                &rows
+            )
+        );
+        Ok(())
+    }
+}
+
+/// What a running tunnel reports about itself beyond its lease record
+struct Live {
+    active_requests: Option<u64>,
+    heartbeat: Option<TunnelStatus>,
+}
+
+impl Live {
+    /// Asks the owning `open` for its in-flight requests, until `deadline`. tailscaled
+    /// keeps no per-route traffic counters, so only tunnels behind the local proxy can
+    /// answer; an owner that does not answer in time falls back to its last heartbeat.
+    async fn query(lease: &Lease, deadline: Instant) -> Self {
+        if !lease.owner_is_running() {
+            return Self {
+                active_requests: None,
+                heartbeat: None,
+            };
+        }
+        let heartbeat = TunnelStatus::read(&lease.lease_id);
+        let wait = deadline.saturating_duration_since(Instant::now());
+        let active_requests =
+            match control::send_with_timeout(&lease.lease_id, &ControlCommand::Stats, wait).await {
+                Ok(reply) => reply.active_requests,
+                Err(err) => {
+                    tracing::debug!(error = %err, lease = %lease.lease_id, "no stats from tunnel");
+                    heartbeat.as_ref().and_then(|status| status.active_requests)
+                }
+            };
+        Self {
+            active_requests,
+            heartbeat,
+        }
+    }
+}
+
+pub(crate) fn lease_state(lease: &Lease) -> &'static str {
+    if lease.owner_is_running() {
+        if lease.paused_since.is_some() {
+            "paused"
+        } else {
+            "running"
+        }
+    } else if lease.stale_since.is_some() || lease.is_stale(Utc::now()) {
+        "stale"
+    } else {
+        "detached"
+    }
+}
+
+/// `at` as time before `now`, e.g. `12s ago`
+fn format_age(at: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let seconds = (now - at).num_seconds().max(0) as u64;
+    format!(
+        "{} ago",
+        humantime::format_duration(std::time::Duration::from_secs(seconds))
+    )
+}
+
+fn format_tags(tags: &BTreeMap<String, String>) -> String {
+    if tags.is_empty() {
+        return "-".to_string();
+    }
+    tags.iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Shortened lease id; any unique prefix works with `close` and `extend`
+fn short_id(lease_id: &str) -> &str {
+    lease_id
+        .char_indices()
+        .nth(12)
+        .map_or(lease_id, |(idx, _)| &lease_id[..idx])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_age() {
+        let now = Utc::now();
+        assert_eq!(
+            format_age(now - chrono::Duration::seconds(12), now),
+            "12s ago"
+        );
+        assert_eq!(
+            format_age(now - chrono::Duration::seconds(75), now),
+            "1m 15s ago"
+        );
+        // Clock skew between writer and reader never shows a negative age
+        assert_eq!(
+            format_age(now + chrono::Duration::seconds(3), now),
+            "0s ago"
+        );
     }
 }
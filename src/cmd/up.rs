@@ -0,0 +1,307 @@
+//! `funnelctl up` / `funnelctl down`: compose-style handling of a spec file. `up` makes
+//! the file's tunnels exist like `apply` does, bringing them up concurrently with a
+//! status line each, and with `--watch` keeps them that way; `down` removes them.
+
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::signal;
+use tokio::time::{sleep, Duration};
+
+use crate::backend::Backend;
+use crate::cli::{DownArgs, UpArgs};
+use crate::cmd::apply::{create, describe, load_spec, remove};
+use crate::cmd::open::warn_unchecked;
+use crate::config::OpenConfig;
+use crate::core::manifest::{plan, ApplyAction, DesiredTunnel, Manifest};
+use crate::core::{Lease, LeaseStore};
+use crate::error::{FunnelError, Result};
+use crate::lock::LockGuard;
+use crate::output;
+
+pub struct UpCommand {
+    args: UpArgs,
+    config: OpenConfig,
+}
+
+impl UpCommand {
+    pub fn new(args: UpArgs, config: OpenConfig) -> Self {
+        Self { args, config }
+    }
+
+    pub async fn run(self, backend: Arc<dyn Backend>) -> Result<()> {
+        if self.args.skip_checks {
+            warn_unchecked();
+        }
+        if !self.args.watch {
+            let pass = self.reconcile(backend.as_ref(), true).await?;
+            return match pass.failures.into_iter().next() {
+                Some(err) => Err(err),
+                None => Ok(()),
+            };
+        }
+
+        let interval = humantime::parse_duration(&self.args.interval).map_err(|err| {
+            FunnelError::InvalidArgument(format!(
+                "Invalid --interval '{}': {}",
+                self.args.interval, err
+            ))
+        })?;
+        if interval.is_zero() {
+            return Err(FunnelError::InvalidArgument(
+                "--interval must be longer than 0s".to_string(),
+            ));
+        }
+
+        // A broken file or held lock on the first pass is a usage problem; later on it is
+        // more likely a passing one, so watching carries on.
+        self.reconcile(backend.as_ref(), true).await?;
+        loop {
+            tokio::select! {
+                _ = sleep(interval) => {}
+                _ = signal::ctrl_c() => return Ok(()),
+            }
+            if let Err(err) = self.reconcile(backend.as_ref(), false).await {
+                tracing::warn!(error = %err, "reconcile failed");
+                eprintln!("Warning: could not reconcile tunnels ({}); retrying.", err);
+            }
+        }
+    }
+
+    async fn reconcile(&self, backend: &dyn Backend, verbose: bool) -> Result<Pass> {
+        // Read on every pass so `--watch` picks up edits to the file
+        let (project, desired) = load_spec(&self.args.file, &self.config)?;
+        let _lock = LockGuard::acquire()?;
+        let store = LeaseStore::open()?;
+        let pass = reconcile(backend, &store, &project, desired, verbose).await;
+        for warning in backend.take_warnings() {
+            output::advise(format_args!("Warning: {}", warning));
+        }
+        pass
+    }
+}
+
+/// What one reconcile pass did; a tunnel that fails does not stop the others
+#[derive(Debug, Default)]
+struct Pass {
+    created: usize,
+    updated: usize,
+    restored: usize,
+    unchanged: usize,
+    removed: usize,
+    failures: Vec<FunnelError>,
+}
+
+impl Pass {
+    fn fail(&mut self, name: &str, err: FunnelError) {
+        eprintln!("Failed {}: {}", name, err);
+        self.failures.push(err);
+    }
+
+    fn changed(&self) -> bool {
+        self.created + self.updated + self.restored + self.removed > 0 || !self.failures.is_empty()
+    }
+}
+
+/// A tunnel to bring up; these run concurrently
+enum Step {
+    Create(DesiredTunnel),
+    Update {
+        lease: Lease,
+        desired: DesiredTunnel,
+    },
+    /// The lease is current but its route is gone from the serve config
+    Restore(Lease),
+}
+
+impl Step {
+    fn name(&self) -> String {
+        match self {
+            Step::Create(desired) | Step::Update { desired, .. } => desired.name.clone(),
+            Step::Restore(lease) => lease.name.clone().unwrap_or(lease.lease_id.clone()),
+        }
+    }
+}
+
+/// Removes unwanted tunnels one by one, then brings up the rest concurrently and prints
+/// a line for each as it finishes. Unchanged tunnels are only listed when `verbose`.
+async fn reconcile(
+    backend: &dyn Backend,
+    store: &LeaseStore,
+    project: &str,
+    desired: Vec<DesiredTunnel>,
+    verbose: bool,
+) -> Result<Pass> {
+    let actions = plan(desired, &store.list()?, project, Utc::now())?;
+    let routes = backend.routes().await?;
+
+    let mut pass = Pass::default();
+    let mut steps = Vec::new();
+    for action in actions {
+        match action {
+            ApplyAction::Remove(lease) => match remove(backend, store, &lease).await {
+                Ok(()) => {
+                    println!("Removed {}", describe(&lease));
+                    pass.removed += 1;
+                }
+                Err(err) => pass.fail(&describe(&lease), err),
+            },
+            ApplyAction::Unchanged(lease) if !routes.iter().any(|route| lease.owns(route)) => {
+                steps.push(Step::Restore(lease));
+            }
+            ApplyAction::Unchanged(lease) => {
+                if verbose {
+                    println!("Unchanged {}", describe(&lease));
+                }
+                pass.unchanged += 1;
+            }
+            ApplyAction::Update { lease, desired } => steps.push(Step::Update { lease, desired }),
+            ApplyAction::Create(desired) => steps.push(Step::Create(desired)),
+        }
+    }
+
+    let mut pending: FuturesUnordered<_> = steps
+        .into_iter()
+        .map(|step| async move {
+            let name = step.name();
+            let outcome = match step {
+                Step::Create(desired) => create(backend, store, desired, project)
+                    .await
+                    .map(|lease| ("Created", lease)),
+                Step::Update { lease, desired } => async {
+                    remove(backend, store, &lease).await?;
+                    create(backend, store, desired, project).await
+                }
+                .await
+                .map(|lease| ("Updated", lease)),
+                Step::Restore(lease) => restore(backend, store, lease, project)
+                    .await
+                    .map(|lease| ("Restored", lease)),
+            };
+            (name, outcome)
+        })
+        .collect();
+    while let Some((name, outcome)) = pending.next().await {
+        match outcome {
+            Ok((verb, lease)) => {
+                println!("{} {}", verb, describe(&lease));
+                match verb {
+                    "Created" => pass.created += 1,
+                    "Updated" => pass.updated += 1,
+                    _ => pass.restored += 1,
+                }
+            }
+            Err(err) => pass.fail(&name, err),
+        }
+    }
+
+    if verbose || pass.changed() {
+        let mut summary = format!(
+            "{}: {} created, {} updated, {} restored, {} unchanged, {} removed",
+            project, pass.created, pass.updated, pass.restored, pass.unchanged, pass.removed
+        );
+        if !pass.failures.is_empty() {
+            summary.push_str(&format!(", {} failed", pass.failures.len()));
+        }
+        println!("{}", summary);
+    }
+    Ok(pass)
+}
+
+/// Applies a lease's route again under a new lease, keeping its name and what is left
+/// of its TTL
+async fn restore(
+    backend: &dyn Backend,
+    store: &LeaseStore,
+    lease: Lease,
+    project: &str,
+) -> Result<Lease> {
+    let ttl = lease
+        .expires_at
+        .map(|expires_at| (expires_at - Utc::now()).to_std().unwrap_or(Duration::ZERO));
+    let desired = DesiredTunnel {
+        name: lease.name.clone().unwrap_or(lease.lease_id.clone()),
+        spec: lease.tunnel_spec.clone(),
+        ttl,
+    };
+    store.remove(&lease.lease_id)?;
+    create(backend, store, desired, project).await
+}
+
+pub struct DownCommand {
+    args: DownArgs,
+}
+
+impl DownCommand {
+    pub fn new(args: DownArgs) -> Self {
+        Self { args }
+    }
+
+    pub async fn run(self, backend: Arc<dyn Backend>) -> Result<()> {
+        let manifest = Manifest::from_path(&self.args.file)?;
+        let project = manifest.project()?;
+        let _lock = LockGuard::acquire()?;
+        let store = LeaseStore::open()?;
+        let removed = down(backend.as_ref(), &store, project).await?;
+        println!("{}: {} removed", project, removed);
+        Ok(())
+    }
+}
+
+/// Removes every tunnel the project owns, as `apply` would for a file with no tunnels
+async fn down(backend: &dyn Backend, store: &LeaseStore, project: &str) -> Result<usize> {
+    let mut removed = 0;
+    for action in plan(Vec::new(), &store.list()?, project, Utc::now())? {
+        if let ApplyAction::Remove(lease) = action {
+            remove(backend, store, &lease).await?;
+            println!("Removed {}", describe(&lease));
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MockBackend;
+    use crate::core::PathPolicy;
+
+    fn desired(yaml: &str) -> Vec<DesiredTunnel> {
+        Manifest::parse(yaml)
+            .unwrap()
+            .desired(&PathPolicy::default(), chrono::Local::now())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_up_restores_missing_routes_and_down_removes_all() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LeaseStore::at(dir.path()).unwrap();
+        let backend = MockBackend::new();
+        let spec = "project: shop\ntunnels:\n  - name: stripe\n    port: 3000\n  - name: docs\n    port: 8080\n";
+
+        let pass = reconcile(&backend, &store, "shop", desired(spec), true)
+            .await
+            .unwrap();
+        assert_eq!(pass.created, 2);
+        assert!(pass.failures.is_empty());
+
+        let routes = backend.routes().await.unwrap();
+        let stripe: Vec<_> = routes
+            .into_iter()
+            .filter(|route| route.path == "/funnelctl/stripe")
+            .collect();
+        backend.remove_routes(&stripe).await.unwrap();
+        let pass = reconcile(&backend, &store, "shop", desired(spec), false)
+            .await
+            .unwrap();
+        assert_eq!((pass.restored, pass.unchanged), (1, 1));
+        assert_eq!(backend.routes().await.unwrap().len(), 2);
+
+        assert_eq!(down(&backend, &store, "shop").await.unwrap(), 2);
+        assert!(backend.routes().await.unwrap().is_empty());
+        assert!(store.list().unwrap().is_empty());
+    }
+}
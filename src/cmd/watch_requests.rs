@@ -0,0 +1,179 @@
+use chrono::Local;
+
+use crate::cli::WatchRequestsArgs;
+use crate::control;
+use crate::core::{resolve_lease, LeaseStore};
+use crate::error::{FunnelError, Result};
+use crate::proxy::RequestLog;
+
+pub struct WatchRequestsCommand {
+    args: WatchRequestsArgs,
+}
+
+/// A `--status` value: one code, or a class like `5xx`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StatusMatch {
+    Code(u16),
+    Class(u16),
+}
+
+impl StatusMatch {
+    fn parse(raw: &str) -> Result<Self> {
+        let invalid = || FunnelError::InvalidField {
+            field: "--status".to_string(),
+            value: raw.to_string(),
+            constraint: "a status code like 404, or a class like 5xx".to_string(),
+        };
+        let lower = raw.trim().to_ascii_lowercase();
+        if let Some(class) = lower.strip_suffix("xx") {
+            return match class.parse::<u16>() {
+                Ok(class @ 1..=5) => Ok(Self::Class(class)),
+                _ => Err(invalid()),
+            };
+        }
+        match lower.parse::<u16>() {
+            Ok(code @ 100..=599) => Ok(Self::Code(code)),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn matches(self, status: u16) -> bool {
+        match self {
+            Self::Code(code) => status == code,
+            Self::Class(class) => status / 100 == class,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RequestFilter {
+    path_prefix: Option<String>,
+    statuses: Vec<StatusMatch>,
+    /// Upper-cased
+    methods: Vec<String>,
+}
+
+impl RequestFilter {
+    fn from_args(args: &WatchRequestsArgs) -> Result<Self> {
+        Ok(Self {
+            path_prefix: args.path.clone(),
+            statuses: args
+                .status
+                .iter()
+                .map(|raw| StatusMatch::parse(raw))
+                .collect::<Result<_>>()?,
+            methods: args
+                .method
+                .iter()
+                .map(|method| method.to_ascii_uppercase())
+                .collect(),
+        })
+    }
+
+    /// Each given filter must match; repeated values of one filter are alternatives
+    fn matches(&self, entry: &RequestLog) -> bool {
+        self.path_prefix
+            .as_deref()
+            .map_or(true, |prefix| entry.path.starts_with(prefix))
+            && (self.statuses.is_empty()
+                || self
+                    .statuses
+                    .iter()
+                    .any(|status| status.matches(entry.status)))
+            && (self.methods.is_empty() || self.methods.contains(&entry.method))
+    }
+}
+
+impl WatchRequestsCommand {
+    pub fn new(args: WatchRequestsArgs) -> Self {
+        Self { args }
+    }
+
+    /// Tails the request log of a running `open --inspect` until it stops or Ctrl+C
+    pub async fn run(self) -> Result<()> {
+        let filter = RequestFilter::from_args(&self.args)?;
+        let leases = LeaseStore::open()?.list()?;
+        let lease = resolve_lease(&leases, &self.args.lease)?;
+        let label = lease
+            .url
+            .clone()
+            .unwrap_or_else(|| lease.tunnel_spec.path.clone());
+        if !lease.owner_is_running() {
+            return Err(FunnelError::InvalidArgument(format!(
+                "Tunnel {} is not run by a foreground `funnelctl open`; only those keep a request log",
+                label
+            )));
+        }
+
+        let json = self.args.json;
+        if !json {
+            eprintln!("Watching requests to {} (Ctrl+C to stop)", label);
+        }
+        control::watch_requests(&lease.lease_id, |entry| {
+            if !filter.matches(&entry) {
+                return Ok(());
+            }
+            if json {
+                let line = serde_json::to_string(&entry)
+                    .map_err(|err| FunnelError::Other(err.to_string()))?;
+                println!("{}", line);
+            } else {
+                println!("{}", format_entry(&entry));
+            }
+            Ok(())
+        })
+        .await?;
+        if !json {
+            eprintln!("Tunnel {} stopped.", label);
+        }
+        Ok(())
+    }
+}
+
+/// `12:30:01  POST    /webhook/github  204    12ms  funnel`, in local time
+fn format_entry(entry: &RequestLog) -> String {
+    format!(
+        "{}  {:<7} {:<30} {}  {:>5}ms  {}",
+        entry.at.with_timezone(&Local).format("%H:%M:%S"),
+        entry.method,
+        entry.path,
+        entry.status,
+        entry.duration_ms,
+        if entry.external { "funnel" } else { "tailnet" }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn test_filter_matches_path_status_and_method() {
+        assert_eq!(StatusMatch::parse("5XX").unwrap(), StatusMatch::Class(5));
+        assert_eq!(StatusMatch::parse("404").unwrap(), StatusMatch::Code(404));
+        assert!(StatusMatch::parse("9xx").is_err());
+        assert!(StatusMatch::parse("ok").is_err());
+
+        let entry = RequestLog {
+            at: Utc::now(),
+            method: "POST".to_string(),
+            path: "/webhook/github".to_string(),
+            status: 502,
+            duration_ms: 12,
+            external: true,
+        };
+        assert!(RequestFilter::default().matches(&entry));
+        let filter = RequestFilter {
+            path_prefix: Some("/webhook".to_string()),
+            statuses: vec![StatusMatch::Code(404), StatusMatch::Class(5)],
+            methods: vec!["POST".to_string()],
+        };
+        assert!(filter.matches(&entry));
+        let filter = RequestFilter {
+            methods: vec!["GET".to_string()],
+            ..RequestFilter::default()
+        };
+        assert!(!filter.matches(&entry));
+    }
+}
@@ -0,0 +1,226 @@
+//! User configuration from `$XDG_CONFIG_HOME/funnelctl/config.toml`.
+//!
+//! Every key is optional and command-line flags take precedence. A missing file is the
+//! same as an empty one.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Deserializer};
+
+use crate::core::WarningId;
+use crate::dirs;
+use crate::error::{FunnelError, Result};
+use crate::output::Accent;
+use crate::plugin::PluginConfig;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub open: OpenConfig,
+    pub theme: ThemeConfig,
+    pub localapi: LocalApiConfig,
+}
+
+/// Defaults for `funnelctl open`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OpenConfig {
+    /// Print a ready-to-copy `curl` command after the URL
+    pub curl_example: bool,
+    /// Path appended to the tunnel URL in the `curl` example
+    pub example_path: Option<String>,
+    /// TTL used when `--ttl` is not given, in `--ttl` syntax
+    pub ttl: Option<String>,
+    /// TTLs below this get a warning; `0s` turns the warning off
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub ttl_warning: Option<Duration>,
+    /// Refuse to open a tunnel that would have no TTL
+    pub require_ttl: bool,
+    /// Path prefixes refused without `--allow-reserved-path`; replaces the built-in list
+    pub reserved_paths: Option<Vec<String>>,
+    /// Longest accepted `--path`, in bytes
+    pub max_path_length: Option<usize>,
+    /// Most accepted `--path` segments
+    pub max_path_segments: Option<usize>,
+    /// Base URL shown instead of the tailnet URL, for a CDN or CNAME in front of Funnel
+    pub public_url_base: Option<String>,
+    /// Executable that reads the URL on stdin and prints the one to show, e.g. a shortener
+    pub url_hook: Option<PathBuf>,
+    /// Executables fed the event stream of every `open`, see `plugin`
+    pub plugins: Vec<PluginConfig>,
+    /// Validation warnings never printed, nor failed on by `--deny-warnings`
+    pub ignore_warnings: Vec<WarningId>,
+    /// Fail instead of warning when another active tunnel exposes the same bind:port
+    pub refuse_duplicate_target: bool,
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> std::result::Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    humantime::parse_duration(&value)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// How every command talks to tailscaled
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalApiConfig {
+    /// How long node status is reused between runs; `0s` always fetches it
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub status_cache: Option<Duration>,
+    /// Deadline for applying a tunnel or any other serve config change
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub apply_timeout: Option<Duration>,
+}
+
+/// Styling of human output; `--plain` overrides all of it
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    /// Label color
+    pub accent: Accent,
+    /// ASCII tree characters and marks, for terminals without Unicode
+    pub ascii: bool,
+    /// Emoji check marks in `doctor`
+    pub emoji: bool,
+}
+
+impl Config {
+    pub fn load() -> Result<Self> {
+        Self::from_path(&dirs::config_dir()?.join("config.toml"))
+    }
+
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(FunnelError::Other(format!(
+                    "Failed to read config {}: {}",
+                    path.display(),
+                    err
+                )))
+            }
+        };
+        Self::parse(&contents).map_err(|err| {
+            FunnelError::InvalidArgument(format!("Invalid config {}: {}", path.display(), err))
+        })
+    }
+
+    fn parse(contents: &str) -> std::result::Result<Self, toml::de::Error> {
+        toml::from_str(contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_config_is_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config::from_path(&dir.path().join("config.toml")).unwrap();
+        assert!(!config.open.curl_example);
+        assert!(config.open.example_path.is_none());
+    }
+
+    #[test]
+    fn test_parse_open_section() {
+        let config =
+            Config::parse("[open]\ncurl_example = true\nexample_path = \"/health\"\n").unwrap();
+        assert!(config.open.curl_example);
+        assert_eq!(config.open.example_path.as_deref(), Some("/health"));
+    }
+
+    #[test]
+    fn test_parse_url_hook() {
+        let config = Config::parse("[open]\nurl_hook = \"/usr/local/bin/shorten\"\n").unwrap();
+        assert_eq!(
+            config.open.url_hook.as_deref(),
+            Some(Path::new("/usr/local/bin/shorten"))
+        );
+    }
+
+    #[test]
+    fn test_parse_plugins() {
+        let config = Config::parse(
+            "[[open.plugins]]\ncommand = \"/opt/audit\"\n\n[[open.plugins]]\ncommand = \"policy\"\nargs = [\"--strict\"]\npolicy = true\n",
+        )
+        .unwrap();
+        let plugins = &config.open.plugins;
+        assert_eq!(plugins.len(), 2);
+        assert!(!plugins[0].policy && plugins[0].args.is_empty());
+        assert!(plugins[1].policy);
+        assert_eq!(plugins[1].args, vec!["--strict".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_ttl_keys() {
+        let config =
+            Config::parse("[open]\nttl = \"2h\"\nttl_warning = \"0s\"\nrequire_ttl = true\n")
+                .unwrap();
+        assert_eq!(config.open.ttl.as_deref(), Some("2h"));
+        assert_eq!(config.open.ttl_warning, Some(Duration::ZERO));
+        assert!(config.open.require_ttl);
+
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.open.ttl_warning, None);
+        assert!(!config.open.require_ttl);
+        assert!(Config::parse("[open]\nttl_warning = \"soon\"\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_ignore_warnings() {
+        let config =
+            Config::parse("[open]\nignore_warnings = [\"path_too_short\", \"ttl_too_short\"]\n")
+                .unwrap();
+        assert_eq!(
+            config.open.ignore_warnings,
+            vec![WarningId::PathTooShort, WarningId::TtlTooShort]
+        );
+        assert!(Config::parse("[open]\nignore_warnings = [\"short_path\"]\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_reserved_paths() {
+        let config = Config::parse("[open]\nreserved_paths = [\"/admin\"]\n").unwrap();
+        assert_eq!(config.open.reserved_paths, Some(vec!["/admin".to_string()]));
+        assert!(Config::parse("").unwrap().open.reserved_paths.is_none());
+    }
+
+    #[test]
+    fn test_parse_path_limits() {
+        let config =
+            Config::parse("[open]\nmax_path_length = 128\nmax_path_segments = 4\n").unwrap();
+        assert_eq!(config.open.max_path_length, Some(128));
+        assert_eq!(config.open.max_path_segments, Some(4));
+        assert!(Config::parse("[open]\nmax_path_length = -1\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_theme_section() {
+        let config = Config::parse("[theme]\naccent = \"cyan\"\nascii = true\n").unwrap();
+        assert_eq!(config.theme.accent, Accent::Cyan);
+        assert!(config.theme.ascii);
+        assert!(!config.theme.emoji);
+        assert!(Config::parse("[theme]\naccent = \"pink\"\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_localapi_section() {
+        let config =
+            Config::parse("[localapi]\nstatus_cache = \"10s\"\napply_timeout = \"1m\"\n").unwrap();
+        assert_eq!(config.localapi.status_cache, Some(Duration::from_secs(10)));
+        assert_eq!(config.localapi.apply_timeout, Some(Duration::from_secs(60)));
+        assert!(Config::parse("").unwrap().localapi.status_cache.is_none());
+    }
+
+    #[test]
+    fn test_unknown_keys_rejected() {
+        assert!(Config::parse("[open]\ncurl = true\n").is_err());
+    }
+}
@@ -0,0 +1,466 @@
+//! Per-lease control socket letting other funnelctl invocations talk to a running `open`.
+//!
+//! The protocol is one JSON request line answered by one JSON reply line over a Unix
+//! socket at `<runtime_dir>/control/<lease_id>.sock`. `watch_requests` is the exception:
+//! after its reply, the server keeps writing one `RequestLog` line per request until
+//! either side hangs up.
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::dirs;
+use crate::error::{FunnelError, Result};
+use crate::proxy::RequestLog;
+
+/// Why `watch_requests` gets nothing from a tunnel without the local proxy
+const NO_REQUEST_LOG: &str =
+    "tunnel runs without the local proxy, so it keeps no request log; open it with --inspect";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlCommand {
+    Close,
+    Extend {
+        seconds: u64,
+    },
+    /// Live traffic counters, for `status`
+    Stats,
+    /// Another tunnel took the route over with `--force`; `by` names it
+    Supersede {
+        by: String,
+    },
+    /// Stream the proxy's request log; answered by the server itself, not the tunnel
+    WatchRequests,
+    /// Stop public traffic but keep the session and URL
+    Pause,
+    /// Undo `Pause`
+    Resume,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ControlReply {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Requests in flight through the tunnel; only known when the local proxy runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_requests: Option<u64>,
+}
+
+impl ControlReply {
+    pub fn ok(expires_at: Option<DateTime<Utc>>) -> Self {
+        Self {
+            ok: true,
+            expires_at,
+            error: None,
+            active_requests: None,
+        }
+    }
+
+    pub fn stats(active_requests: Option<u64>) -> Self {
+        Self {
+            active_requests,
+            ..Self::ok(None)
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            expires_at: None,
+            error: Some(message.into()),
+            active_requests: None,
+        }
+    }
+}
+
+/// A command received by the server, answered through `reply`
+pub struct ControlMessage {
+    pub command: ControlCommand,
+    pub reply: oneshot::Sender<ControlReply>,
+}
+
+pub struct ControlServer {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl ControlServer {
+    /// Listens on the control socket for `lease_id`, forwarding commands to the receiver.
+    /// `request_log` feeds `watch_requests`; `None` when the tunnel has no local proxy.
+    #[cfg(unix)]
+    pub fn bind(
+        lease_id: &str,
+        request_log: Option<broadcast::Sender<RequestLog>>,
+    ) -> Result<(Self, mpsc::Receiver<ControlMessage>)> {
+        Self::bind_at(socket_path(lease_id)?, request_log)
+    }
+
+    /// `bind` on an explicit socket path
+    #[cfg(unix)]
+    fn bind_at(
+        path: PathBuf,
+        request_log: Option<broadcast::Sender<RequestLog>>,
+    ) -> Result<(Self, mpsc::Receiver<ControlMessage>)> {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+        use tokio::net::UnixListener;
+
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).map_err(|err| {
+            FunnelError::Other(format!(
+                "Failed to bind control socket {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        set_socket_permissions(&path)?;
+
+        let (tx, rx) = mpsc::channel(8);
+        let task = tokio::spawn(async move {
+            loop {
+                let (stream, _) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        tracing::debug!(error = %err, "control socket accept failed");
+                        continue;
+                    }
+                };
+                let tx = tx.clone();
+                let request_log = request_log.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut line = String::new();
+                    if BufReader::new(read).read_line(&mut line).await.is_err() {
+                        return;
+                    }
+                    let reply = match serde_json::from_str::<ControlCommand>(&line) {
+                        Ok(ControlCommand::WatchRequests) => match request_log {
+                            Some(request_log) => {
+                                stream_request_log(request_log.subscribe(), write).await;
+                                return;
+                            }
+                            None => ControlReply::error(NO_REQUEST_LOG),
+                        },
+                        Ok(command) => {
+                            let (reply_tx, reply_rx) = oneshot::channel();
+                            let message = ControlMessage {
+                                command,
+                                reply: reply_tx,
+                            };
+                            if tx.send(message).await.is_err() {
+                                ControlReply::error("tunnel is shutting down")
+                            } else {
+                                reply_rx
+                                    .await
+                                    .unwrap_or_else(|_| ControlReply::error("no reply from tunnel"))
+                            }
+                        }
+                        Err(err) => ControlReply::error(format!("invalid command: {}", err)),
+                    };
+                    if let Ok(mut bytes) = serde_json::to_vec(&reply) {
+                        bytes.push(b'\n');
+                        let _ = write.write_all(&bytes).await;
+                    }
+                });
+            }
+        });
+
+        Ok((Self { path, task }, rx))
+    }
+
+    #[cfg(not(unix))]
+    pub fn bind(
+        _lease_id: &str,
+        _request_log: Option<broadcast::Sender<RequestLog>>,
+    ) -> Result<(Self, mpsc::Receiver<ControlMessage>)> {
+        Err(FunnelError::Other(
+            "Control sockets are not supported on this platform".to_string(),
+        ))
+    }
+}
+
+/// Acknowledges a `watch_requests`, then forwards entries until the watcher hangs up or
+/// the proxy stops
+#[cfg(unix)]
+async fn stream_request_log(
+    mut entries: broadcast::Receiver<RequestLog>,
+    mut write: tokio::net::unix::OwnedWriteHalf,
+) {
+    use tokio::io::AsyncWriteExt;
+
+    let mut line = match serde_json::to_vec(&ControlReply::ok(None)) {
+        Ok(line) => line,
+        Err(_) => return,
+    };
+    loop {
+        line.push(b'\n');
+        if write.write_all(&line).await.is_err() {
+            return;
+        }
+        let entry = loop {
+            match entries.recv().await {
+                Ok(entry) => break entry,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::debug!(missed, "request log watcher fell behind");
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        };
+        line = match serde_json::to_vec(&entry) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Sends a command to the `open` process holding `lease_id`
+pub async fn send(lease_id: &str, command: &ControlCommand) -> Result<ControlReply> {
+    send_with_timeout(lease_id, command, Duration::from_secs(10)).await
+}
+
+/// Like `send`, giving up after `wait`; for callers that only want a quick answer
+#[cfg(unix)]
+pub async fn send_with_timeout(
+    lease_id: &str,
+    command: &ControlCommand,
+    wait: Duration,
+) -> Result<ControlReply> {
+    send_at(&socket_path(lease_id)?, command, wait).await
+}
+
+/// `send_with_timeout` on an explicit socket path
+#[cfg(unix)]
+async fn send_at(
+    path: &std::path::Path,
+    command: &ControlCommand,
+    wait: Duration,
+) -> Result<ControlReply> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+    use tokio::time::timeout;
+
+    let exchange = async {
+        let mut stream = UnixStream::connect(path).await?;
+        let mut request = serde_json::to_vec(command)?;
+        request.push(b'\n');
+        stream.write_all(&request).await?;
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line).await?;
+        let reply: ControlReply = serde_json::from_str(&line)?;
+        Ok::<_, std::io::Error>(reply)
+    };
+
+    match timeout(wait, exchange).await {
+        Ok(Ok(reply)) => Ok(reply),
+        Ok(Err(err)) => Err(FunnelError::Other(format!(
+            "Control socket {} unavailable: {}",
+            path.display(),
+            err
+        ))),
+        Err(_) => Err(FunnelError::Other(format!(
+            "Timed out waiting for reply on {}",
+            path.display()
+        ))),
+    }
+}
+
+/// Tails the request log of the `open` holding `lease_id`, calling `on_entry` for each
+/// request until the tunnel stops
+#[cfg(unix)]
+pub async fn watch_requests(
+    lease_id: &str,
+    on_entry: impl FnMut(RequestLog) -> Result<()>,
+) -> Result<()> {
+    watch_requests_at(&socket_path(lease_id)?, on_entry).await
+}
+
+/// `watch_requests` on an explicit socket path
+#[cfg(unix)]
+async fn watch_requests_at(
+    path: &std::path::Path,
+    mut on_entry: impl FnMut(RequestLog) -> Result<()>,
+) -> Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+
+    let unavailable = |err: std::io::Error| {
+        FunnelError::Other(format!(
+            "Control socket {} unavailable: {}",
+            path.display(),
+            err
+        ))
+    };
+    let mut stream = UnixStream::connect(path).await.map_err(unavailable)?;
+    let mut request = serde_json::to_vec(&ControlCommand::WatchRequests)
+        .map_err(|err| FunnelError::Other(err.to_string()))?;
+    request.push(b'\n');
+    stream.write_all(&request).await.map_err(unavailable)?;
+
+    let mut lines = BufReader::new(stream).lines();
+    let reply = match lines.next_line().await.map_err(unavailable)? {
+        Some(line) => serde_json::from_str::<ControlReply>(&line)
+            .map_err(|err| FunnelError::Other(format!("Invalid control reply: {}", err)))?,
+        None => {
+            return Err(FunnelError::Other(
+                "Tunnel closed the control socket".to_string(),
+            ))
+        }
+    };
+    if !reply.ok {
+        return Err(FunnelError::Other(reply.error.unwrap_or_else(|| {
+            "Tunnel refused to share its request log".to_string()
+        })));
+    }
+    while let Some(line) = lines.next_line().await.map_err(unavailable)? {
+        match serde_json::from_str::<RequestLog>(&line) {
+            Ok(entry) => on_entry(entry)?,
+            Err(err) => tracing::debug!(error = %err, "skipping unreadable request log line"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn watch_requests(
+    _lease_id: &str,
+    _on_entry: impl FnMut(RequestLog) -> Result<()>,
+) -> Result<()> {
+    Err(FunnelError::Other(
+        "Control sockets are not supported on this platform".to_string(),
+    ))
+}
+
+#[cfg(not(unix))]
+pub async fn send_with_timeout(
+    _lease_id: &str,
+    _command: &ControlCommand,
+    _wait: Duration,
+) -> Result<ControlReply> {
+    Err(FunnelError::Other(
+        "Control sockets are not supported on this platform".to_string(),
+    ))
+}
+
+fn socket_path(lease_id: &str) -> Result<PathBuf> {
+    let dir = dirs::runtime_dir()?.join("control");
+    dirs::ensure_dir(&dir)?;
+    Ok(dir.join(format!("{}.sock", dirs::sanitize_file_name(lease_id))))
+}
+
+#[cfg(unix)]
+fn set_socket_permissions(path: &std::path::Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).map_err(|err| {
+        FunnelError::Other(format!(
+            "Failed to set permissions on {}: {}",
+            path.display(),
+            err
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_serialization() {
+        let json = serde_json::to_string(&ControlCommand::Extend { seconds: 3600 }).unwrap();
+        assert_eq!(json, r#"{"command":"extend","seconds":3600}"#);
+
+        let parsed: ControlCommand = serde_json::from_str(r#"{"command":"close"}"#).unwrap();
+        assert_eq!(parsed, ControlCommand::Close);
+        let parsed: ControlCommand =
+            serde_json::from_str(r#"{"command":"supersede","by":"webhook-2"}"#).unwrap();
+        assert_eq!(
+            parsed,
+            ControlCommand::Supersede {
+                by: "webhook-2".to_string()
+            }
+        );
+        let parsed: ControlCommand = serde_json::from_str(r#"{"command":"pause"}"#).unwrap();
+        assert_eq!(parsed, ControlCommand::Pause);
+
+        let reply = serde_json::to_string(&ControlReply::stats(Some(3))).unwrap();
+        assert_eq!(reply, r#"{"ok":true,"active_requests":3}"#);
+        let reply = serde_json::to_string(&ControlReply::ok(None)).unwrap();
+        assert_eq!(reply, r#"{"ok":true}"#);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_control_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lease-roundtrip.sock");
+
+        let (_server, mut rx) = ControlServer::bind_at(path.clone(), None).unwrap();
+        let responder = tokio::spawn(async move {
+            let message = rx.recv().await.unwrap();
+            assert_eq!(message.command, ControlCommand::Close);
+            let _ = message.reply.send(ControlReply::ok(None));
+        });
+
+        let reply = send_at(&path, &ControlCommand::Close, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert!(reply.ok);
+        responder.await.unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_watch_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lease-watch.sock");
+        let (request_log, _) = broadcast::channel(8);
+        let (server, _rx) =
+            ControlServer::bind_at(path.clone(), Some(request_log.clone())).unwrap();
+        let entry = RequestLog {
+            at: Utc::now(),
+            method: "POST".to_string(),
+            path: "/hook".to_string(),
+            status: 204,
+            duration_ms: 3,
+            external: true,
+        };
+        let publisher = tokio::spawn({
+            let entry = entry.clone();
+            async move {
+                while request_log.receiver_count() == 0 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                request_log.send(entry).unwrap();
+            }
+        });
+
+        let mut seen = Vec::new();
+        let result = watch_requests_at(&path, |entry| {
+            seen.push(entry);
+            Err(FunnelError::Other("seen enough".to_string()))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(seen, vec![entry]);
+        publisher.await.unwrap();
+        drop(server);
+
+        let path = dir.path().join("lease-plain.sock");
+        let (_server, _rx) = ControlServer::bind_at(path.clone(), None).unwrap();
+        let err = watch_requests_at(&path, |_| Ok(())).await.unwrap_err();
+        assert!(err.to_string().contains("--inspect"));
+    }
+}
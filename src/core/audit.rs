@@ -0,0 +1,311 @@
+//! Risky Funnel exposures in a serve config, for `funnelctl audit`
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use humantime::format_duration;
+use serde::Serialize;
+
+use super::history::HistoryEntry;
+use super::lease::Lease;
+use super::route::Route;
+
+/// Paths shorter than this (leading `/` included) are easy to guess, e.g. `/api`
+pub const SHORT_PATH_LEN: usize = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Low,
+    Medium,
+    High,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditCheck {
+    /// The handler proxies to another machine, which the Internet can now reach
+    NonLoopbackTarget,
+    /// `/` is mounted, so the whole site is public
+    RootMount,
+    /// The path is short enough to find by guessing
+    ShortPath,
+    /// The route has been public for longer than `--max-age`
+    OldRoute,
+    /// The tunnel's proxy counted more requests per hour than `--max-requests-per-hour`
+    ChattyRoute,
+}
+
+impl AuditCheck {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditCheck::NonLoopbackTarget => "non_loopback_target",
+            AuditCheck::RootMount => "root_mount",
+            AuditCheck::ShortPath => "short_path",
+            AuditCheck::OldRoute => "old_route",
+            AuditCheck::ChattyRoute => "chatty_route",
+        }
+    }
+
+    fn severity(&self) -> Severity {
+        match self {
+            AuditCheck::NonLoopbackTarget | AuditCheck::RootMount => Severity::High,
+            AuditCheck::ShortPath | AuditCheck::ChattyRoute => Severity::Medium,
+            AuditCheck::OldRoute => Severity::Low,
+        }
+    }
+}
+
+/// One risky route, as reported by `audit --json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub check: AuditCheck,
+    pub host_port: String,
+    pub path: String,
+    pub target: String,
+    /// Foreground session owning the route; absent for background routes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    /// funnelctl installed the route: a lease owns it or its handler carries the marker
+    pub funnelctl: bool,
+    /// The funnelctl lease that installed the route, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lease_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Who or what the lease says the route is for (`open --expose-for`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expose_for: Option<String>,
+    /// When the route went up, from its lease or the history log
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<DateTime<Utc>>,
+    pub message: String,
+}
+
+/// Checks every Funnel route; tailnet-only routes are not public and are skipped.
+/// Findings come most severe first, then by `host:port` and path.
+pub fn audit_routes(
+    routes: &[Route],
+    leases: &[Lease],
+    history: &[HistoryEntry],
+    max_age: Duration,
+    max_hourly_requests: u64,
+    now: DateTime<Utc>,
+) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for route in routes.iter().filter(|route| route.funnel) {
+        let lease = leases.iter().find(|lease| lease.owns(route));
+        let since = lease
+            .map(|lease| lease.created_at)
+            .or_else(|| last_started(route, history));
+        let mut report = |check: AuditCheck, message: String| {
+            findings.push(Finding {
+                severity: check.severity(),
+                check,
+                host_port: route.host_port.clone(),
+                path: route.path.clone(),
+                target: route.target.clone(),
+                session: route.session.clone(),
+                funnelctl: lease.is_some() || route.owned,
+                lease_id: lease.map(|lease| lease.lease_id.clone()),
+                name: lease.and_then(|lease| lease.name.clone()),
+                expose_for: lease.and_then(|lease| lease.expose_for.clone()),
+                since,
+                message,
+            })
+        };
+
+        if let Some(host) = proxy_host(&route.target).filter(|host| !is_loopback(host)) {
+            report(
+                AuditCheck::NonLoopbackTarget,
+                format!("Funnel route forwards to {}, off this machine", host),
+            );
+        }
+        if route.path == "/" {
+            report(
+                AuditCheck::RootMount,
+                format!("the whole site on {} is public", route.host_port),
+            );
+        } else if route.path.len() < SHORT_PATH_LEN {
+            report(
+                AuditCheck::ShortPath,
+                format!("path {} is short enough to guess", route.path),
+            );
+        }
+        // Only tunnels behind the local proxy count their traffic
+        if let Some(lease) = lease {
+            let until = lease.stale_since.unwrap_or(now);
+            let rate = lease
+                .usage
+                .map(|usage| usage.hourly_rate(lease.created_at, until));
+            if let Some(rate) = rate.filter(|rate| *rate > max_hourly_requests) {
+                report(
+                    AuditCheck::ChattyRoute,
+                    format!(
+                        "{} requests per hour, more than {}",
+                        rate, max_hourly_requests
+                    ),
+                );
+            }
+        }
+        let age = since.and_then(|since| (now - since).to_std().ok());
+        if let Some(age) = age.filter(|age| *age > max_age) {
+            report(
+                AuditCheck::OldRoute,
+                format!(
+                    "public for {}, longer than {}",
+                    format_duration(Duration::from_secs(age.as_secs())),
+                    format_duration(max_age)
+                ),
+            );
+        }
+    }
+    findings.sort_by(|a, b| {
+        b.severity
+            .cmp(&a.severity)
+            .then_with(|| (&a.host_port, &a.path).cmp(&(&b.host_port, &b.path)))
+    });
+    findings
+}
+
+/// Start of the newest tunnel recorded at the route's place, for routes left behind by a
+/// tunnel whose lease is gone
+fn last_started(route: &Route, history: &[HistoryEntry]) -> Option<DateTime<Utc>> {
+    history
+        .iter()
+        .filter(|entry| {
+            entry.route_path().as_deref() == Some(route.path.as_str())
+                && entry
+                    .https_port
+                    .map_or(true, |port| route.https_port == Some(port))
+        })
+        .map(|entry| entry.started_at)
+        .max()
+}
+
+/// Host of a proxy target as tailscaled accepts it: `3000`, `localhost:3000` or a URL.
+/// `None` for path and text handlers, which forward nowhere.
+fn proxy_host(target: &str) -> Option<String> {
+    if target.parse::<u16>().is_ok() {
+        return Some("localhost".to_string());
+    }
+    if target.contains(' ') {
+        return None;
+    }
+    let with_scheme = if target.contains("://") {
+        target.to_string()
+    } else {
+        format!("http://{}", target)
+    };
+    let url = url::Url::parse(&with_scheme).ok()?;
+    url.host_str()
+        .map(|host| host.trim_matches(|c| c == '[' || c == ']').to_string())
+}
+
+fn is_loopback(host: &str) -> bool {
+    host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{LocalTarget, TunnelSpec, Usage};
+
+    fn route(path: &str, target: &str, funnel: bool) -> Route {
+        Route {
+            host_port: "node.ts.net:443".to_string(),
+            https_port: Some(443),
+            path: path.to_string(),
+            target: target.to_string(),
+            funnel,
+            session: None,
+            owned: false,
+        }
+    }
+
+    #[test]
+    fn test_audit_ranks_findings() {
+        let now = Utc::now();
+        let mut marked = route("/api", "3000", true);
+        marked.owned = true;
+        let routes = vec![
+            route("/funnelctl/a7Xk9mPq", "http://127.0.0.1:3000", true),
+            route("/", "http://192.168.1.20:8080", true),
+            marked,
+            route("/", "http://10.0.0.5:80", false),
+            route("/docs", "text handler", true),
+        ];
+        let spec = TunnelSpec::new(
+            LocalTarget::new("127.0.0.1".to_string(), 3000),
+            443,
+            "/funnelctl/a7Xk9mPq".to_string(),
+            true,
+        )
+        .detached();
+        let mut lease = Lease::new("detached-old".to_string(), spec, None);
+        lease.created_at = now - chrono::Duration::days(10);
+        lease.expose_for = Some("alice@example.com".to_string());
+        lease.usage = Some(Usage {
+            requests: 10 * 24 * 2000,
+            ..Usage::default()
+        });
+
+        let week = Duration::from_secs(7 * 86400);
+        let findings = audit_routes(&routes, &[lease], &[], week, 1000, now);
+        let checks: Vec<_> = findings
+            .iter()
+            .map(|finding| (finding.severity, finding.check, finding.path.as_str()))
+            .collect();
+        assert_eq!(
+            checks,
+            [
+                (Severity::High, AuditCheck::NonLoopbackTarget, "/"),
+                (Severity::High, AuditCheck::RootMount, "/"),
+                (Severity::Medium, AuditCheck::ShortPath, "/api"),
+                (Severity::Medium, AuditCheck::ShortPath, "/docs"),
+                (
+                    Severity::Medium,
+                    AuditCheck::ChattyRoute,
+                    "/funnelctl/a7Xk9mPq"
+                ),
+                (Severity::Low, AuditCheck::OldRoute, "/funnelctl/a7Xk9mPq"),
+            ]
+        );
+        assert_eq!(findings[5].lease_id.as_deref(), Some("detached-old"));
+        assert_eq!(findings[5].expose_for.as_deref(), Some("alice@example.com"));
+        assert_eq!(findings[0].expose_for, None);
+        let owners: Vec<_> = findings.iter().map(|finding| finding.funnelctl).collect();
+        assert_eq!(owners, [false, false, true, false, true, true]);
+        assert!(findings[4].message.starts_with("2000 requests per hour"));
+        assert!(findings[0].message.contains("192.168.1.20"));
+    }
+
+    #[test]
+    fn test_proxy_host() {
+        assert_eq!(proxy_host("3000").as_deref(), Some("localhost"));
+        assert_eq!(proxy_host("localhost:3000").as_deref(), Some("localhost"));
+        assert_eq!(
+            proxy_host("https+insecure://[::1]:8443").as_deref(),
+            Some("::1")
+        );
+        assert_eq!(
+            proxy_host("http://nas.lan:5000").as_deref(),
+            Some("nas.lan")
+        );
+        assert_eq!(proxy_host("path handler /srv/www"), None);
+        assert!(is_loopback("::1") && is_loopback("127.0.0.2") && !is_loopback("nas.lan"));
+    }
+}
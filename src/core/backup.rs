@@ -0,0 +1,194 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::types::ServeConfig;
+use crate::dirs;
+use crate::error::{FunnelError, Result};
+
+/// How many pre-write serve configs are kept; older ones are deleted on save
+const MAX_BACKUPS: usize = 10;
+
+/// The serve config as it was just before one of funnelctl's writes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigBackup {
+    pub id: String,
+    pub saved_at: DateTime<Utc>,
+    pub serve_config: ServeConfig,
+}
+
+/// Keeps the last few pre-write serve configs under `$XDG_STATE_HOME/funnelctl/backups/`
+/// for `funnelctl rollback`
+pub struct BackupStore {
+    dir: PathBuf,
+}
+
+impl BackupStore {
+    pub fn open() -> Result<Self> {
+        Self::at(dirs::state_dir()?.join("backups"))
+    }
+
+    pub fn at(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        dirs::ensure_dir(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Records `config` unless it equals the newest backup, then prunes old backups.
+    /// Returns the new backup, if one was written.
+    pub fn save(&self, config: &ServeConfig, now: DateTime<Utc>) -> Result<Option<ConfigBackup>> {
+        let backups = self.list()?;
+        if backups
+            .first()
+            .is_some_and(|newest| &newest.serve_config == config)
+        {
+            return Ok(None);
+        }
+
+        let mut id = now.format("%Y%m%dT%H%M%S%.3fZ").to_string();
+        if backups.iter().any(|backup| backup.id == id) {
+            id = format!("{}-{}", id, backups.len());
+        }
+        let backup = ConfigBackup {
+            id,
+            saved_at: now,
+            serve_config: config.clone(),
+        };
+        let contents = serde_json::to_vec_pretty(&backup)
+            .map_err(|err| FunnelError::Other(format!("Failed to serialize backup: {}", err)))?;
+        dirs::write_private(&self.path_for(&backup.id), &contents)?;
+
+        for old in backups.iter().skip(MAX_BACKUPS - 1) {
+            if let Err(err) = std::fs::remove_file(self.path_for(&old.id)) {
+                tracing::warn!(backup = %old.id, error = %err, "failed to prune backup");
+            }
+        }
+        Ok(Some(backup))
+    }
+
+    /// Lists readable backups, newest first
+    pub fn list(&self) -> Result<Vec<ConfigBackup>> {
+        let entries = std::fs::read_dir(&self.dir).map_err(|err| {
+            FunnelError::Other(format!(
+                "Failed to read backup directory {}: {}",
+                self.dir.display(),
+                err
+            ))
+        })?;
+
+        let mut backups = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let backup = std::fs::read(&path)
+                .map_err(|err| err.to_string())
+                .and_then(|bytes| {
+                    serde_json::from_slice::<ConfigBackup>(&bytes).map_err(|err| err.to_string())
+                });
+            match backup {
+                Ok(backup) => backups.push(backup),
+                Err(err) => {
+                    tracing::warn!(path = %path.display(), error = %err, "skipping unreadable backup")
+                }
+            }
+        }
+        backups.sort_by(|a, b| b.saved_at.cmp(&a.saved_at).then(b.id.cmp(&a.id)));
+        Ok(backups)
+    }
+
+    /// The newest backup, or the one whose id is or starts with `query`
+    pub fn find(&self, query: Option<&str>) -> Result<ConfigBackup> {
+        let backups = self.list()?;
+        let Some(query) = query else {
+            return backups.into_iter().next().ok_or_else(|| {
+                FunnelError::InvalidArgument(
+                    "No serve config backups yet; funnelctl saves one before each write"
+                        .to_string(),
+                )
+            });
+        };
+        if let Some(backup) = backups.iter().find(|backup| backup.id == query) {
+            return Ok(backup.clone());
+        }
+        let mut matches = backups
+            .into_iter()
+            .filter(|backup| backup.id.starts_with(query));
+        match (matches.next(), matches.next()) {
+            (Some(backup), None) => Ok(backup),
+            (Some(_), Some(_)) => Err(FunnelError::InvalidArgument(format!(
+                "Backup id '{}' is ambiguous",
+                query
+            ))),
+            (None, _) => Err(FunnelError::InvalidArgument(format!(
+                "No backup matches '{}'",
+                query
+            ))),
+        }
+    }
+
+    fn path_for(&self, id: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.json", dirs::sanitize_file_name(id)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::apply_background_patch;
+    use chrono::Duration;
+
+    fn config(path: &str) -> ServeConfig {
+        let mut config = ServeConfig::new();
+        apply_background_patch(
+            &mut config,
+            "node.tail1234.ts.net:443",
+            path,
+            "http://127.0.0.1:3000",
+            true,
+        );
+        config
+    }
+
+    #[test]
+    fn test_save_skips_duplicates_and_prunes() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BackupStore::at(dir.path()).unwrap();
+        let start = Utc::now();
+
+        assert!(store.save(&config("/a"), start).unwrap().is_some());
+        assert!(store.save(&config("/a"), start).unwrap().is_none());
+        for i in 0..MAX_BACKUPS + 2 {
+            let at = start + Duration::seconds(i as i64 + 1);
+            store.save(&config(&format!("/{}", i)), at).unwrap();
+        }
+
+        let backups = store.list().unwrap();
+        assert_eq!(backups.len(), MAX_BACKUPS);
+        assert_eq!(
+            backups[0].serve_config,
+            config(&format!("/{}", MAX_BACKUPS + 1))
+        );
+    }
+
+    #[test]
+    fn test_find_by_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = BackupStore::at(dir.path()).unwrap();
+        assert!(store.find(None).is_err());
+
+        let now = Utc::now();
+        let first = store.save(&config("/a"), now).unwrap().unwrap();
+        let second = store
+            .save(&config("/b"), now + Duration::days(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(store.find(None).unwrap().id, second.id);
+        assert_eq!(store.find(Some(&first.id)).unwrap().id, first.id);
+        assert_eq!(store.find(Some(&first.id[..8])).unwrap().id, first.id);
+        assert!(store.find(Some("1999")).is_err());
+    }
+}
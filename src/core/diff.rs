@@ -0,0 +1,252 @@
+//! What a ServeConfig write would change, for `open --dry-run` and `apply --diff`
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::route::{list_routes, parse_session};
+use super::types::ServeConfig;
+use crate::error::{FunnelError, Result};
+
+/// One handler or AllowFunnel change between two serve configs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum ServeChange {
+    HandlerAdded {
+        host_port: String,
+        path: String,
+        target: String,
+        /// Foreground session; `None` for the background config
+        session: Option<String>,
+    },
+    HandlerRemoved {
+        host_port: String,
+        path: String,
+        target: String,
+        session: Option<String>,
+    },
+    TargetChanged {
+        host_port: String,
+        path: String,
+        from: String,
+        to: String,
+        session: Option<String>,
+    },
+    FunnelChanged {
+        host_port: String,
+        enabled: bool,
+        session: Option<String>,
+    },
+}
+
+/// The summary a human reads, and the RFC 6902 patch that turns one config into the other
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServeDiff {
+    pub changes: Vec<ServeChange>,
+    pub patch: Vec<Value>,
+}
+
+impl ServeDiff {
+    pub fn is_empty(&self) -> bool {
+        self.patch.is_empty()
+    }
+}
+
+pub fn diff_serve_configs(before: &ServeConfig, after: &ServeConfig) -> Result<ServeDiff> {
+    type Key = (Option<String>, String, String);
+    let handlers = |config: &ServeConfig| -> Result<BTreeMap<Key, String>> {
+        Ok(list_routes(config)?
+            .into_iter()
+            .map(|route| ((route.session, route.host_port, route.path), route.target))
+            .collect())
+    };
+    let old = handlers(before)?;
+    let new = handlers(after)?;
+
+    let mut changes = Vec::new();
+    for ((session, host_port, path), target) in &old {
+        let key = (session.clone(), host_port.clone(), path.clone());
+        match new.get(&key) {
+            None => changes.push(ServeChange::HandlerRemoved {
+                host_port: host_port.clone(),
+                path: path.clone(),
+                target: target.clone(),
+                session: session.clone(),
+            }),
+            Some(to) if to != target => changes.push(ServeChange::TargetChanged {
+                host_port: host_port.clone(),
+                path: path.clone(),
+                from: target.clone(),
+                to: to.clone(),
+                session: session.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    for ((session, host_port, path), target) in &new {
+        if !old.contains_key(&(session.clone(), host_port.clone(), path.clone())) {
+            changes.push(ServeChange::HandlerAdded {
+                host_port: host_port.clone(),
+                path: path.clone(),
+                target: target.clone(),
+                session: session.clone(),
+            });
+        }
+    }
+
+    let old_funnel = allow_funnel(before)?;
+    let new_funnel = allow_funnel(after)?;
+    let keys: BTreeSet<&(Option<String>, String)> =
+        old_funnel.keys().chain(new_funnel.keys()).collect();
+    for key in keys {
+        let enabled = new_funnel.get(key).copied().unwrap_or(false);
+        if old_funnel.get(key).copied().unwrap_or(false) != enabled {
+            changes.push(ServeChange::FunnelChanged {
+                host_port: key.1.clone(),
+                enabled,
+                session: key.0.clone(),
+            });
+        }
+    }
+
+    let to_value = |config: &ServeConfig| {
+        serde_json::to_value(config)
+            .map_err(|err| FunnelError::Other(format!("Failed to serialize serve config: {}", err)))
+    };
+    let mut patch = Vec::new();
+    json_patch(&to_value(before)?, &to_value(after)?, "", &mut patch);
+    Ok(ServeDiff { changes, patch })
+}
+
+/// AllowFunnel entries of the background config and every foreground session
+fn allow_funnel(config: &ServeConfig) -> Result<BTreeMap<(Option<String>, String), bool>> {
+    let mut entries = BTreeMap::new();
+    let mut collect = |session: Option<&String>, config: &ServeConfig| {
+        for (host_port, enabled) in config.allow_funnel.iter().flatten() {
+            entries.insert((session.cloned(), host_port.clone()), *enabled);
+        }
+    };
+    collect(None, config);
+    for (session, value) in config.foreground.iter().flatten() {
+        collect(Some(session), &parse_session(value)?);
+    }
+    Ok(entries)
+}
+
+/// Appends the RFC 6902 operations turning `before` into `after`. Objects are compared
+/// key by key; anything else that differs is replaced whole.
+fn json_patch(before: &Value, after: &Value, pointer: &str, patch: &mut Vec<Value>) {
+    match (before, after) {
+        (Value::Object(old), Value::Object(new)) => {
+            for (key, value) in old {
+                let path = format!("{}/{}", pointer, escape_pointer(key));
+                match new.get(key) {
+                    Some(next) => json_patch(value, next, &path, patch),
+                    None => patch.push(json!({ "op": "remove", "path": path })),
+                }
+            }
+            for (key, value) in new {
+                if !old.contains_key(key) {
+                    let path = format!("{}/{}", pointer, escape_pointer(key));
+                    patch.push(json!({ "op": "add", "path": path, "value": value }));
+                }
+            }
+        }
+        _ if before != after => {
+            patch.push(json!({ "op": "replace", "path": pointer, "value": after }));
+        }
+        _ => {}
+    }
+}
+
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{apply_background_patch, apply_patch};
+
+    const HOST_PORT: &str = "node.tail1234.ts.net:443";
+
+    #[test]
+    fn test_background_patch_diff() {
+        let before = ServeConfig::new();
+        let mut after = before.clone();
+        apply_background_patch(
+            &mut after,
+            HOST_PORT,
+            "/hook",
+            "http://127.0.0.1:3000",
+            true,
+        );
+
+        let diff = diff_serve_configs(&before, &after).unwrap();
+        assert_eq!(
+            diff.changes,
+            vec![
+                ServeChange::HandlerAdded {
+                    host_port: HOST_PORT.to_string(),
+                    path: "/hook".to_string(),
+                    target: "http://127.0.0.1:3000".to_string(),
+                    session: None,
+                },
+                ServeChange::FunnelChanged {
+                    host_port: HOST_PORT.to_string(),
+                    enabled: true,
+                    session: None,
+                },
+            ]
+        );
+        let paths: Vec<&str> = diff
+            .patch
+            .iter()
+            .map(|op| op["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(paths, vec!["/AllowFunnel", "/Web"]);
+        assert!(diff_serve_configs(&after, &after).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_target_change_in_session() {
+        let mut before = ServeConfig::new();
+        apply_patch(
+            &mut before,
+            "s1",
+            HOST_PORT,
+            "/a",
+            "http://127.0.0.1:1",
+            true,
+        )
+        .unwrap();
+        let mut after = before.clone();
+        apply_patch(
+            &mut after,
+            "s1",
+            HOST_PORT,
+            "/a",
+            "http://127.0.0.1:2",
+            true,
+        )
+        .unwrap();
+
+        let diff = diff_serve_configs(&before, &after).unwrap();
+        assert_eq!(
+            diff.changes,
+            vec![ServeChange::TargetChanged {
+                host_port: HOST_PORT.to_string(),
+                path: "/a".to_string(),
+                from: "http://127.0.0.1:1".to_string(),
+                to: "http://127.0.0.1:2".to_string(),
+                session: Some("s1".to_string()),
+            }]
+        );
+        assert_eq!(diff.patch.len(), 1);
+        assert_eq!(
+            diff.patch[0]["path"],
+            "/Foreground/s1/Web/node.tail1234.ts.net:443/Handlers/~1a/Proxy"
+        );
+    }
+}
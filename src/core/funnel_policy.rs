@@ -0,0 +1,46 @@
+//! The tailnet policy entry that lets a node use Funnel, for `funnelctl funnel-policy`
+
+use serde_json::{json, Value};
+
+/// Where admins edit the tailnet policy file
+pub const POLICY_EDITOR_URL: &str = "https://login.tailscale.com/admin/acls/file";
+
+/// Who the `nodeAttrs` entry should name: the node's tags when it has any (a tagged
+/// node belongs to no user), else its owner, else every member of the tailnet
+pub fn policy_targets(tags: &[String], login_name: Option<&str>) -> Vec<String> {
+    if !tags.is_empty() {
+        return tags.to_vec();
+    }
+    match login_name {
+        Some(login) => vec![login.to_string()],
+        None => vec!["autogroup:member".to_string()],
+    }
+}
+
+/// The `nodeAttrs` entry granting Funnel to `targets`
+pub fn node_attrs_entry(targets: &[String]) -> Value {
+    json!({
+        "target": targets,
+        "attr": ["funnel"],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_targets_prefer_tags() {
+        let tags = vec!["tag:server".to_string(), "tag:web".to_string()];
+        assert_eq!(policy_targets(&tags, Some("alice@example.com")), tags);
+        assert_eq!(
+            policy_targets(&[], Some("alice@example.com")),
+            ["alice@example.com"]
+        );
+        assert_eq!(policy_targets(&[], None), ["autogroup:member"]);
+        assert_eq!(
+            node_attrs_entry(&tags),
+            json!({"target": ["tag:server", "tag:web"], "attr": ["funnel"]})
+        );
+    }
+}
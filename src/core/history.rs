@@ -0,0 +1,267 @@
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::lease::Usage;
+use crate::dirs;
+use crate::error::{FunnelError, Result};
+
+/// One finished tunnel, as recorded in the history log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub lease_id: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    pub url: String,
+    pub local_target: String,
+    pub started_at: DateTime<Utc>,
+    pub stopped_at: DateTime<Utc>,
+    /// Stop reason in its `stopped` event spelling, e.g. `ttl_expired`
+    pub reason: String,
+    /// Directory `open` ran in, for `--reuse-path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub project: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub https_port: Option<u16>,
+    /// Traffic through the local proxy over the whole run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// Who or what the tunnel was for (`--expose-for`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expose_for: Option<String>,
+    /// The arguments after `open`, as given, for `open --from-history`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub open_args: Vec<String>,
+}
+
+impl HistoryEntry {
+    /// The route path; entries written before `path` was recorded fall back to the URL's
+    pub fn route_path(&self) -> Option<String> {
+        self.path.clone().or_else(|| {
+            url::Url::parse(&self.url)
+                .ok()
+                .map(|url| url.path().to_string())
+        })
+    }
+
+    fn local_port(&self) -> Option<u16> {
+        url::Url::parse(&self.local_target)
+            .ok()
+            .and_then(|url| url.port_or_known_default())
+    }
+}
+
+/// The path of the newest tunnel opened from `project` for the same local and public ports
+pub fn last_path(
+    entries: &[HistoryEntry],
+    project: &str,
+    local_port: u16,
+    https_port: u16,
+) -> Option<String> {
+    entries
+        .iter()
+        .filter(|entry| entry.project.as_deref() == Some(project))
+        .filter(|entry| entry.local_port() == Some(local_port))
+        .filter(|entry| entry.https_port.unwrap_or(443) == https_port)
+        .max_by_key(|entry| entry.started_at)
+        .and_then(HistoryEntry::route_path)
+}
+
+/// An entry by its place in `funnelctl history` (`1` is the newest), or the newest one
+/// with that name, lease ID, or lease ID prefix
+pub fn find_entry<'a>(entries: &'a [HistoryEntry], query: &str) -> Result<&'a HistoryEntry> {
+    if let Ok(index) = query.parse::<usize>() {
+        return index
+            .checked_sub(1)
+            .and_then(|index| entries.iter().rev().nth(index))
+            .ok_or_else(|| {
+                FunnelError::InvalidArgument(format!(
+                    "No tunnel #{} in history ({} recorded)",
+                    query,
+                    entries.len()
+                ))
+            });
+    }
+    entries
+        .iter()
+        .rev()
+        .find(|entry| entry.name.as_deref() == Some(query) || entry.lease_id == query)
+        .or_else(|| {
+            entries
+                .iter()
+                .rev()
+                .find(|entry| entry.lease_id.starts_with(query))
+        })
+        .ok_or_else(|| {
+            FunnelError::InvalidArgument(format!("No tunnel in history matches '{}'", query))
+        })
+}
+
+/// Append-only NDJSON log at `$XDG_STATE_HOME/funnelctl/history.jsonl`
+pub struct HistoryLog {
+    path: PathBuf,
+}
+
+impl HistoryLog {
+    pub fn open() -> Result<Self> {
+        Ok(Self::at(dirs::state_dir()?.join("history.jsonl")))
+    }
+
+    pub fn at(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn append(&self, entry: &HistoryEntry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry).map_err(|err| {
+            FunnelError::Other(format!("Failed to serialize history entry: {}", err))
+        })?;
+        line.push(b'\n');
+
+        let mut options = std::fs::OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&self.path).map_err(|err| self.io_error(err))?;
+        file.write_all(&line).map_err(|err| self.io_error(err))
+    }
+
+    /// Reads entries oldest first, skipping lines that fail to parse
+    pub fn read(&self) -> Result<Vec<HistoryEntry>> {
+        let file = match std::fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(self.io_error(err)),
+        };
+
+        let mut entries = Vec::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|err| self.io_error(err))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(err) => tracing::warn!(error = %err, "skipping unreadable history entry"),
+            }
+        }
+        Ok(entries)
+    }
+
+    fn io_error(&self, err: std::io::Error) -> FunnelError {
+        FunnelError::Other(format!(
+            "Failed to access history {}: {}",
+            self.path.display(),
+            err
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_history_append_and_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = HistoryLog::at(dir.path().join("history.jsonl"));
+        assert!(log.read().unwrap().is_empty());
+
+        let entry = HistoryEntry {
+            lease_id: "session-1".to_string(),
+            name: Some("api".to_string()),
+            url: "https://node.ts.net/funnelctl/abc".to_string(),
+            local_target: "http://127.0.0.1:8081".to_string(),
+            started_at: Utc::now(),
+            stopped_at: Utc::now(),
+            reason: "user_interrupt".to_string(),
+            project: None,
+            path: None,
+            https_port: None,
+            usage: Some(Usage {
+                requests: 3,
+                bytes_in: 10,
+                bytes_out: 2048,
+            }),
+            expose_for: Some("alice@example.com".to_string()),
+            open_args: vec!["8081".to_string(), "--name".to_string(), "api".to_string()],
+        };
+        log.append(&entry).unwrap();
+        log.append(&entry).unwrap();
+
+        let entries = log.read().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], entry);
+    }
+
+    #[test]
+    fn test_last_path_matches_project_and_ports() {
+        let entry = |project: &str, local: u16, path: &str, hours: i64| HistoryEntry {
+            lease_id: format!("session-{}", path),
+            name: None,
+            url: format!("https://node.ts.net{}", path),
+            local_target: format!("http://127.0.0.1:{}", local),
+            started_at: Utc::now() - chrono::Duration::hours(hours),
+            stopped_at: Utc::now(),
+            reason: "user_interrupt".to_string(),
+            project: Some(project.to_string()),
+            path: Some(path.to_string()),
+            https_port: Some(443),
+            usage: None,
+            expose_for: None,
+            open_args: Vec::new(),
+        };
+        let entries = vec![
+            entry("/src/shop", 8081, "/funnelctl/old", 5),
+            entry("/src/shop", 8081, "/funnelctl/new", 1),
+            entry("/src/shop", 3000, "/funnelctl/other-port", 0),
+            entry("/src/blog", 8081, "/funnelctl/blog", 0),
+        ];
+        assert_eq!(
+            last_path(&entries, "/src/shop", 8081, 443).as_deref(),
+            Some("/funnelctl/new")
+        );
+        assert_eq!(last_path(&entries, "/src/shop", 8081, 8443), None);
+        assert_eq!(last_path(&entries, "/src/none", 8081, 443), None);
+    }
+
+    #[test]
+    fn test_find_entry_by_index_or_id() {
+        let entry = |lease_id: &str, name: Option<&str>| HistoryEntry {
+            lease_id: lease_id.to_string(),
+            name: name.map(str::to_string),
+            url: "https://node.ts.net/hook".to_string(),
+            local_target: "http://127.0.0.1:8081".to_string(),
+            started_at: Utc::now(),
+            stopped_at: Utc::now(),
+            reason: "user_interrupt".to_string(),
+            project: None,
+            path: None,
+            https_port: None,
+            usage: None,
+            expose_for: None,
+            open_args: Vec::new(),
+        };
+        let entries = vec![
+            entry("session-a1", Some("api")),
+            entry("session-b2", Some("api")),
+            entry("session-c3", None),
+        ];
+        assert_eq!(find_entry(&entries, "1").unwrap().lease_id, "session-c3");
+        assert_eq!(find_entry(&entries, "3").unwrap().lease_id, "session-a1");
+        assert!(find_entry(&entries, "0").is_err());
+        assert!(find_entry(&entries, "4").is_err());
+        assert_eq!(find_entry(&entries, "api").unwrap().lease_id, "session-b2");
+        assert_eq!(
+            find_entry(&entries, "session-a").unwrap().lease_id,
+            "session-a1"
+        );
+        assert!(find_entry(&entries, "web").is_err());
+    }
+}
@@ -1,17 +1,76 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
+use super::route::Route;
 use super::spec::TunnelSpec;
+use crate::dirs;
+use crate::error::{FunnelError, Result};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Lease {
     pub lease_id: String,
+    /// Human-friendly name, unique among active leases
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Free-form `key=value` labels for managing groups of tunnels
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    /// Who or what the tunnel was opened for (`--expose-for`), for later review
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expose_for: Option<String>,
     pub created_at: DateTime<Utc>,
     pub expires_at: Option<DateTime<Utc>>,
     pub tunnel_spec: TunnelSpec,
     pub backend_kind: BackendKind,
+    /// PID of the funnelctl process holding the session
+    #[serde(default)]
+    pub pid: Option<u32>,
+    /// When `pid` started, so a reused PID is not taken for the owner; absent in leases
+    /// written by older versions and where the platform does not report it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid_start_time: Option<u64>,
+    #[serde(default)]
+    pub url: Option<String>,
+    /// Set by garbage collection once the owner died or the TTL passed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stale_since: Option<DateTime<Utc>>,
+    /// Traffic through the local proxy, saved by the running `open` on each heartbeat;
+    /// absent when no proxy runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<Usage>,
+    /// When `funnelctl pause` stopped public traffic; cleared by `resume`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paused_since: Option<DateTime<Utc>>,
+    /// The arguments after `open`, copied into history when a detached tunnel is reaped
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub open_args: Vec<String>,
+}
+
+/// Cumulative traffic of one tunnel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub requests: u64,
+    /// Request body bytes sent to the target
+    pub bytes_in: u64,
+    /// Response body bytes the target sent back
+    pub bytes_out: u64,
+}
+
+impl Usage {
+    /// Requests per hour since `since`, counting at least a full hour so a burst
+    /// right after start does not look like a flood
+    pub fn hourly_rate(&self, since: DateTime<Utc>, now: DateTime<Utc>) -> u64 {
+        let seconds = (now - since).num_seconds().max(3600) as u64;
+        self.requests.saturating_mul(3600) / seconds
+    }
 }
 
+/// How long stale lease records are kept before garbage collection deletes them
+const STALE_RETENTION: chrono::Duration = chrono::Duration::days(7);
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum BackendKind {
@@ -26,19 +85,353 @@ impl Lease {
     ) -> Self {
         Self {
             lease_id,
+            name: None,
+            tags: BTreeMap::new(),
+            expose_for: None,
             created_at: Utc::now(),
             expires_at,
             tunnel_spec,
             backend_kind: BackendKind::LocalApi,
+            pid: None,
+            pid_start_time: None,
+            url: None,
+            stale_since: None,
+            usage: None,
+            paused_since: None,
+            open_args: Vec::new(),
+        }
+    }
+
+    pub fn with_owner(mut self, pid: u32) -> Self {
+        self.pid = Some(pid);
+        self.pid_start_time = crate::lock::process_start_time(pid);
+        self
+    }
+
+    pub fn with_open_args(mut self, open_args: Vec<String>) -> Self {
+        self.open_args = open_args;
+        self
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn with_tags(mut self, tags: impl IntoIterator<Item = (String, String)>) -> Self {
+        self.tags.extend(tags);
+        self
+    }
+
+    pub fn with_expose_for(mut self, expose_for: Option<String>) -> Self {
+        self.expose_for = expose_for;
+        self
+    }
+
+    /// Checks that every `key=value` in `tags` is set on the lease
+    pub fn has_tags(&self, tags: &[(String, String)]) -> bool {
+        tags.iter()
+            .all(|(key, value)| self.tags.get(key) == Some(value))
+    }
+
+    pub fn with_url(mut self, url: impl Into<String>) -> Self {
+        self.url = Some(url.into());
+        self
+    }
+
+    /// Checks if the process that opened this lease is still running. A different start
+    /// time means the PID was reused by another process.
+    pub fn owner_is_running(&self) -> bool {
+        self.pid.is_some_and(|pid| {
+            pid != std::process::id()
+                && crate::lock::pid_is_alive(pid)
+                && match (self.pid_start_time, crate::lock::process_start_time(pid)) {
+                    (Some(recorded), Some(actual)) => recorded == actual,
+                    _ => true,
+                }
+        })
+    }
+
+    /// A lease is stale when nobody runs it any more: its owner died, or it has no owner
+    /// and its TTL has passed
+    pub fn is_stale(&self, now: DateTime<Utc>) -> bool {
+        if self.owner_is_running() {
+            return false;
+        }
+        let owner_died = self.pid.is_some_and(|pid| pid != std::process::id());
+        let expired = self.expires_at.is_some_and(|expires_at| expires_at <= now);
+        owner_died || expired
+    }
+
+    /// Checks if `route` is the handler this lease installed. Detached tunnels live in the
+    /// background config, so they are recognised by their target instead of a session.
+    pub fn owns(&self, route: &Route) -> bool {
+        let same_place = route.path == self.tunnel_spec.path
+            && route
+                .https_port
+                .is_some_and(|port| self.tunnel_spec.https_ports().any(|own| own == port));
+        if self.tunnel_spec.detached {
+            same_place
+                && route.session.is_none()
+                && route.target == self.tunnel_spec.serve_target().to_string()
+        } else {
+            same_place && route.session.as_deref() == Some(self.lease_id.as_str())
+        }
+    }
+
+    /// Checks if the tunnel is still up: its owner runs, or it is detached and unexpired
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.owner_is_running() || (self.tunnel_spec.detached && !self.is_stale(now))
+    }
+
+    /// Checks if this is a detached tunnel whose TTL has passed
+    pub fn is_expired_detached(&self, now: DateTime<Utc>) -> bool {
+        self.tunnel_spec.detached && self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Checks if `spec` serves this tunnel's path on one of its HTTPS ports, so that a
+    /// forced apply of `spec` took the route over
+    pub fn displaced_by(&self, spec: &TunnelSpec) -> bool {
+        let own = &self.tunnel_spec;
+        let other_name = match (&own.dns_name, &spec.dns_name) {
+            (Some(own), Some(other)) => own != other,
+            _ => false,
+        };
+        own.path == spec.path
+            && !other_name
+            && own
+                .https_ports()
+                .any(|port| spec.https_ports().any(|new| new == port))
+    }
+
+    /// Checks if the lease matches the given path prefix and HTTPS port filters
+    pub fn matches(&self, path_prefix: Option<&str>, https_port: Option<u16>) -> bool {
+        if let Some(prefix) = path_prefix {
+            if !self.tunnel_spec.path.starts_with(prefix) {
+                return false;
+            }
+        }
+        match https_port {
+            Some(port) => self.tunnel_spec.https_ports().any(|own| own == port),
+            None => true,
         }
     }
 }
 
+/// Persists leases as one JSON file per lease under `$XDG_STATE_HOME/funnelctl/leases/`
+pub struct LeaseStore {
+    dir: PathBuf,
+}
+
+impl LeaseStore {
+    pub fn open() -> Result<Self> {
+        Self::at(dirs::state_dir()?.join("leases"))
+    }
+
+    /// The leases `apply --node` keeps for tunnels on another node. They live apart from
+    /// this node's, so commands working on the local tailscaled never see them.
+    pub fn for_node(node: &str) -> Result<Self> {
+        let node = dirs::sanitize_file_name(&node.to_ascii_lowercase());
+        Self::at(dirs::state_dir()?.join("nodes").join(node).join("leases"))
+    }
+
+    pub fn at(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        dirs::ensure_dir(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn save(&self, lease: &Lease) -> Result<()> {
+        let contents = serde_json::to_vec_pretty(lease)
+            .map_err(|err| FunnelError::Other(format!("Failed to serialize lease: {}", err)))?;
+        dirs::write_private(&self.path_for(&lease.lease_id), &contents)
+    }
+
+    /// Removes a lease file, returning false if it did not exist
+    pub fn remove(&self, lease_id: &str) -> Result<bool> {
+        let path = self.path_for(lease_id);
+        match std::fs::remove_file(&path) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(FunnelError::Other(format!(
+                "Failed to remove lease {}: {}",
+                path.display(),
+                err
+            ))),
+        }
+    }
+
+    /// Lists all readable leases, oldest first. Unparsable files are skipped.
+    pub fn list(&self) -> Result<Vec<Lease>> {
+        let entries = std::fs::read_dir(&self.dir).map_err(|err| {
+            FunnelError::Other(format!(
+                "Failed to read lease directory {}: {}",
+                self.dir.display(),
+                err
+            ))
+        })?;
+
+        let mut leases = Vec::new();
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            match read_lease(&path) {
+                Ok(lease) => leases.push(lease),
+                Err(err) => {
+                    tracing::warn!(path = %path.display(), error = %err, "skipping unreadable lease")
+                }
+            }
+        }
+        leases.sort_by_key(|lease| lease.created_at);
+        Ok(leases)
+    }
+
+    /// Marks newly stale leases and deletes records that have been stale for longer than
+    /// the retention period. Returns the leases that are stale after the pass.
+    pub fn collect_garbage(&self, now: DateTime<Utc>) -> Result<Vec<Lease>> {
+        let mut stale = Vec::new();
+        for mut lease in self.list()? {
+            if !lease.is_stale(now) {
+                continue;
+            }
+            match lease.stale_since {
+                Some(since) if now - since > STALE_RETENTION => {
+                    tracing::debug!(lease = %lease.lease_id, "pruning stale lease");
+                    self.remove(&lease.lease_id)?;
+                }
+                Some(_) => stale.push(lease),
+                None => {
+                    tracing::debug!(lease = %lease.lease_id, "marking lease stale");
+                    lease.stale_since = Some(now);
+                    self.save(&lease)?;
+                    stale.push(lease);
+                }
+            }
+        }
+        Ok(stale)
+    }
+
+    fn path_for(&self, lease_id: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.json", dirs::sanitize_file_name(lease_id)))
+    }
+}
+
+/// Finds the lease identified by `query`: a name, an exact lease id, or a unique id prefix.
+/// Names are only unique among running tunnels, so a live lease wins over stale ones
+/// left with the same name.
+pub fn resolve_lease<'a>(leases: &'a [Lease], query: &str) -> Result<&'a Lease> {
+    let now = Utc::now();
+    let mut named = leases
+        .iter()
+        .filter(|lease| lease.name.as_deref() == Some(query));
+    if let Some(lease) = named
+        .clone()
+        .find(|lease| !lease.is_stale(now))
+        .or_else(|| named.next())
+    {
+        return Ok(lease);
+    }
+    if let Some(lease) = leases.iter().find(|lease| lease.lease_id == query) {
+        return Ok(lease);
+    }
+
+    let matches: Vec<&Lease> = leases
+        .iter()
+        .filter(|lease| lease.lease_id.starts_with(query))
+        .collect();
+    match matches.as_slice() {
+        [lease] => Ok(lease),
+        [] => Err(FunnelError::InvalidArgument(format!(
+            "No active lease matches '{}'",
+            query
+        ))),
+        many => {
+            let listing: Vec<String> = many
+                .iter()
+                .map(|lease| {
+                    format!(
+                        "  {}  {}  {}",
+                        lease.lease_id,
+                        lease.name.as_deref().unwrap_or("-"),
+                        describe_lease(lease)
+                    )
+                })
+                .collect();
+            Err(FunnelError::InvalidArgument(format!(
+                "Lease '{}' is ambiguous; it matches:\n{}",
+                query,
+                listing.join("\n")
+            )))
+        }
+    }
+}
+
+/// Derives a default tunnel name from a directory, e.g. `~/src/My App` -> `my-app`.
+/// A numeric suffix is added when the name is already in `taken`.
+pub fn default_name(dir: &Path, taken: &[&str]) -> String {
+    let base = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(slugify)
+        .filter(|slug| !slug.is_empty())
+        .unwrap_or_else(|| "tunnel".to_string());
+
+    if !taken.contains(&base.as_str()) {
+        return base;
+    }
+    (2..)
+        .map(|n| format!("{}-{}", base, n))
+        .find(|candidate| !taken.contains(&candidate.as_str()))
+        .unwrap_or(base)
+}
+
+/// Lowercases and replaces anything outside the name alphabet with '-'
+fn slugify(raw: &str) -> String {
+    let mut slug = String::new();
+    for c in raw.chars() {
+        let c = c.to_ascii_lowercase();
+        if c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '_' | '.') {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let slug = slug.trim_matches(|c| c == '-' || c == '.' || c == '_');
+    slug.chars()
+        .take(55)
+        .collect::<String>()
+        .trim_end_matches('-')
+        .to_string()
+}
+
+fn describe_lease(lease: &Lease) -> &str {
+    lease
+        .url
+        .as_deref()
+        .unwrap_or(lease.tunnel_spec.path.as_str())
+}
+
+fn read_lease(path: &Path) -> Result<Lease> {
+    let contents = std::fs::read(path)
+        .map_err(|err| FunnelError::Other(format!("Failed to read lease: {}", err)))?;
+    serde_json::from_slice(&contents)
+        .map_err(|err| FunnelError::Other(format!("Failed to parse lease: {}", err)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::core::spec::LocalTarget;
 
+    fn test_lease(id: &str, path: &str, https_port: u16) -> Lease {
+        let target = LocalTarget::new("127.0.0.1".to_string(), 8081);
+        let spec = TunnelSpec::new(target, https_port, path.to_string(), true);
+        Lease::new(id.to_string(), spec, None)
+    }
+
     #[test]
     fn test_lease_creation() {
         let target = LocalTarget::new("127.0.0.1".to_string(), 8081);
@@ -49,4 +442,250 @@ mod tests {
         assert!(lease.expires_at.is_none());
         assert!(matches!(lease.backend_kind, BackendKind::LocalApi));
     }
+
+    #[test]
+    fn test_lease_matches_filters() {
+        let lease = test_lease("a", "/funnelctl/abc", 8443);
+        assert!(lease.matches(None, None));
+        assert!(lease.matches(Some("/funnelctl/"), Some(8443)));
+        assert!(!lease.matches(Some("/other"), None));
+        assert!(!lease.matches(None, Some(443)));
+    }
+
+    #[test]
+    fn test_lease_has_tags() {
+        let lease = test_lease("a", "/a", 443).with_tags([
+            ("env".to_string(), "preview".to_string()),
+            ("pr".to_string(), "123".to_string()),
+        ]);
+        assert!(lease.has_tags(&[]));
+        assert!(lease.has_tags(&[("pr".to_string(), "123".to_string())]));
+        assert!(!lease.has_tags(&[("pr".to_string(), "124".to_string())]));
+        assert!(!lease.has_tags(&[("team".to_string(), "web".to_string())]));
+    }
+
+    #[test]
+    fn test_resolve_lease_by_id_and_prefix() {
+        let leases = vec![
+            test_lease("abc123", "/a", 443),
+            test_lease("abd456", "/b", 443),
+        ];
+
+        assert_eq!(resolve_lease(&leases, "abc123").unwrap().lease_id, "abc123");
+        assert_eq!(resolve_lease(&leases, "abd").unwrap().lease_id, "abd456");
+
+        match resolve_lease(&leases, "ab").unwrap_err() {
+            FunnelError::InvalidArgument(msg) => {
+                assert!(msg.contains("ambiguous"));
+                assert!(msg.contains("abc123"));
+                assert!(msg.contains("abd456"));
+            }
+            _ => panic!("Expected InvalidArgument error"),
+        }
+        assert!(resolve_lease(&leases, "zzz").is_err());
+    }
+
+    #[test]
+    fn test_resolve_lease_by_name() {
+        let leases = vec![
+            test_lease("abc123", "/a", 443).with_name("preview"),
+            test_lease("preview-id", "/b", 443),
+        ];
+        assert_eq!(
+            resolve_lease(&leases, "preview").unwrap().lease_id,
+            "abc123"
+        );
+
+        let mut dead = test_lease("old", "/a", 443).with_name("preview");
+        dead.expires_at = Some(Utc::now() - chrono::Duration::seconds(1));
+        let leases = vec![dead, test_lease("new", "/a", 443).with_name("preview")];
+        assert_eq!(resolve_lease(&leases, "preview").unwrap().lease_id, "new");
+        assert_eq!(
+            resolve_lease(&leases[..1], "preview").unwrap().lease_id,
+            "old"
+        );
+    }
+
+    #[test]
+    fn test_default_name_from_directory() {
+        assert_eq!(default_name(Path::new("/home/me/My App"), &[]), "my-app");
+        assert_eq!(default_name(Path::new("/"), &[]), "tunnel");
+        assert_eq!(
+            default_name(Path::new("/src/api"), &["api", "api-2"]),
+            "api-3"
+        );
+    }
+
+    #[test]
+    fn test_lease_store_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LeaseStore::at(dir.path().join("leases")).unwrap();
+
+        let lease = test_lease("session-1", "/funnelctl/abc", 443)
+            .with_owner(42)
+            .with_url("https://node.ts.net/funnelctl/abc");
+        store.save(&lease).unwrap();
+
+        let leases = store.list().unwrap();
+        assert_eq!(leases.len(), 1);
+        assert_eq!(leases[0].lease_id, "session-1");
+        assert_eq!(leases[0].pid, Some(42));
+
+        assert!(store.remove("session-1").unwrap());
+        assert!(!store.remove("session-1").unwrap());
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_lease_owns_route() {
+        let lease = test_lease("session1", "/funnelctl/abc", 443);
+
+        let mut route = Route {
+            host_port: "node.ts.net:443".to_string(),
+            https_port: Some(443),
+            path: "/funnelctl/abc".to_string(),
+            target: "http://127.0.0.1:8081".to_string(),
+            funnel: true,
+            session: Some("session1".to_string()),
+            owned: true,
+        };
+        assert!(lease.owns(&route));
+
+        route.session = None;
+        assert!(!lease.owns(&route));
+
+        let mut detached = test_lease("detached-x", "/funnelctl/abc", 443);
+        detached.tunnel_spec = detached.tunnel_spec.clone().detached();
+        assert!(detached.owns(&route));
+        route.target = "http://127.0.0.1:9999".to_string();
+        assert!(!detached.owns(&route));
+    }
+
+    #[test]
+    fn test_lease_owns_mirror_ports() {
+        let mut lease = test_lease("session1", "/funnelctl/abc", 443);
+        lease.tunnel_spec = lease
+            .tunnel_spec
+            .clone()
+            .with_mirror_ports(vec![8443, 10000]);
+        let mut route = Route {
+            host_port: "node.ts.net:10000".to_string(),
+            https_port: Some(10000),
+            path: "/funnelctl/abc".to_string(),
+            target: "http://127.0.0.1:8081".to_string(),
+            funnel: true,
+            session: Some("session1".to_string()),
+            owned: true,
+        };
+        assert!(lease.owns(&route));
+        assert!(lease.matches(None, Some(8443)));
+
+        lease.tunnel_spec.mirror_ports.clear();
+        assert!(!lease.owns(&route));
+        route.https_port = Some(443);
+        assert!(lease.owns(&route));
+        assert!(!lease.matches(None, Some(8443)));
+    }
+
+    #[test]
+    fn test_lease_displaced_by_same_path_and_port() {
+        let lease = test_lease("session1", "/hook", 443);
+        let spec = |path: &str, port: u16| {
+            TunnelSpec::new(
+                LocalTarget::new("127.0.0.1".to_string(), 9000),
+                port,
+                path.to_string(),
+                true,
+            )
+        };
+        assert!(lease.displaced_by(&spec("/hook", 443)));
+        assert!(lease.displaced_by(&spec("/hook", 8443).with_mirror_ports(vec![443])));
+        assert!(!lease.displaced_by(&spec("/hook", 8443)));
+        assert!(!lease.displaced_by(&spec("/hook/", 443)));
+    }
+
+    #[test]
+    fn test_lease_staleness() {
+        let now = Utc::now();
+        let mut lease = test_lease("a", "/a", 443);
+        assert!(!lease.is_stale(now));
+
+        lease.expires_at = Some(now - chrono::Duration::seconds(1));
+        assert!(lease.is_stale(now));
+
+        // Our own pid never counts as a dead owner
+        let mut own = test_lease("b", "/b", 443).with_owner(std::process::id());
+        own.expires_at = Some(now - chrono::Duration::seconds(1));
+        assert!(own.is_stale(now));
+        own.expires_at = None;
+        assert!(!own.is_stale(now));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_reused_pid_is_not_the_owner() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let mut lease = test_lease("a", "/a", 443).with_owner(child.id());
+        assert!(lease.pid_start_time.is_some());
+        assert!(lease.owner_is_running());
+
+        lease.pid_start_time = lease.pid_start_time.map(|start| start + 1);
+        assert!(!lease.owner_is_running());
+        assert!(lease.is_stale(Utc::now()));
+
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_expired_detached() {
+        let now = Utc::now();
+        let mut lease = test_lease("detached-a", "/a", 443);
+        lease.expires_at = Some(now - chrono::Duration::seconds(1));
+        assert!(!lease.is_expired_detached(now));
+
+        lease.tunnel_spec = lease.tunnel_spec.clone().detached();
+        assert!(lease.is_expired_detached(now));
+        assert!(!lease.is_active(now));
+
+        lease.expires_at = Some(now + chrono::Duration::hours(1));
+        assert!(!lease.is_expired_detached(now));
+        assert!(lease.is_active(now));
+    }
+
+    #[test]
+    fn test_collect_garbage_marks_then_prunes() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LeaseStore::at(dir.path()).unwrap();
+        let now = Utc::now();
+
+        let mut expired = test_lease("expired", "/a", 443);
+        expired.expires_at = Some(now - chrono::Duration::minutes(5));
+        store.save(&expired).unwrap();
+        store.save(&test_lease("live", "/b", 443)).unwrap();
+
+        let stale = store.collect_garbage(now).unwrap();
+        assert_eq!(stale.len(), 1);
+        assert_eq!(stale[0].stale_since, Some(now));
+        assert_eq!(store.list().unwrap().len(), 2);
+
+        let later = now + STALE_RETENTION + chrono::Duration::seconds(1);
+        assert!(store.collect_garbage(later).unwrap().is_empty());
+        let remaining = store.list().unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].lease_id, "live");
+    }
+
+    #[test]
+    fn test_lease_store_sanitizes_ids() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = LeaseStore::at(dir.path()).unwrap();
+        store.save(&test_lease("../escape", "/a", 443)).unwrap();
+
+        assert!(dir.path().join("___escape.json").exists());
+        assert_eq!(store.list().unwrap()[0].lease_id, "../escape");
+    }
 }
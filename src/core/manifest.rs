@@ -0,0 +1,488 @@
+//! Spec files for `funnelctl apply`: the tunnels that should exist, and the plan that
+//! turns the current leases into them.
+//!
+//! YAML or JSON (JSON is valid YAML):
+//!
+//! ```yaml
+//! version: 1
+//! project: shop
+//! tunnels:
+//!   - name: stripe
+//!     port: 3000
+//!     path: /hooks/stripe
+//!     ttl: 8h
+//! ```
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Duration;
+
+use chrono::{DateTime, Local, Utc};
+use serde::Deserialize;
+
+use super::lease::Lease;
+use super::spec::{LocalTarget, TunnelSpec};
+use super::validation::{
+    parse_ttl, validate_https_port, validate_name, validate_path_with, validate_port, validate_ttl,
+    PathPolicy,
+};
+use crate::error::{FunnelError, Result};
+
+/// Lease tag naming the manifest project that owns a tunnel
+pub const PROJECT_TAG: &str = "apply";
+
+pub const MANIFEST_VERSION: u32 = 1;
+const DEFAULT_BIND: &str = "127.0.0.1";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default = "default_version")]
+    pub version: u32,
+    /// Tags every tunnel this file creates; only tunnels with the same project are
+    /// updated or removed. Defaults to the file name without extension.
+    #[serde(default)]
+    pub project: Option<String>,
+    #[serde(default)]
+    pub tunnels: Vec<ManifestTunnel>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestTunnel {
+    pub name: String,
+    pub port: u16,
+    /// Defaults to `/funnelctl/<name>`, so re-applying keeps the URL
+    #[serde(default)]
+    pub path: Option<String>,
+    #[serde(default = "default_https_port")]
+    pub https_port: u16,
+    #[serde(default)]
+    pub visibility: Visibility,
+    /// `--ttl` syntax; counted from when the tunnel is created or updated
+    #[serde(default)]
+    pub ttl: Option<String>,
+    /// Loopback IP address of the target
+    #[serde(default)]
+    pub bind: Option<String>,
+    /// Accepted so the error can say why: the proxy needs a running `open`
+    #[serde(default)]
+    pub max_concurrency: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    /// Reachable from the internet through Funnel
+    #[default]
+    Public,
+    /// Served to the tailnet only
+    Tailnet,
+}
+
+fn default_version() -> u32 {
+    MANIFEST_VERSION
+}
+
+fn default_https_port() -> u16 {
+    443
+}
+
+/// A validated manifest entry
+#[derive(Debug, Clone)]
+pub struct DesiredTunnel {
+    pub name: String,
+    pub spec: TunnelSpec,
+    pub ttl: Option<Duration>,
+}
+
+impl DesiredTunnel {
+    pub fn expires_at(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.ttl
+            .and_then(|ttl| chrono::Duration::from_std(ttl).ok())
+            .map(|ttl| now + ttl)
+    }
+}
+
+impl Manifest {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|err| {
+            FunnelError::InvalidArgument(format!(
+                "Failed to read spec file {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+        let mut manifest = Self::parse(&contents).map_err(|err| {
+            FunnelError::InvalidArgument(format!("Invalid spec file {}: {}", path.display(), err))
+        })?;
+        if manifest.project.is_none() {
+            manifest.project = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .map(str::to_string);
+        }
+        Ok(manifest)
+    }
+
+    pub fn parse(contents: &str) -> std::result::Result<Self, serde_norway::Error> {
+        serde_norway::from_str(contents)
+    }
+
+    /// The project tag value; `from_path` fills it from the file name when unset
+    pub fn project(&self) -> Result<&str> {
+        let project = self.project.as_deref().ok_or_else(|| {
+            FunnelError::InvalidArgument("Spec file needs a `project`".to_string())
+        })?;
+        validate_name(project)
+            .map_err(|err| FunnelError::InvalidArgument(format!("project: {}", err)))?;
+        Ok(project)
+    }
+
+    /// Checks every entry with the same rules as `open` and builds the detached specs
+    pub fn desired(&self, policy: &PathPolicy, now: DateTime<Local>) -> Result<Vec<DesiredTunnel>> {
+        self.check(policy, now)?
+            .into_iter()
+            .map(|(_, tunnel)| tunnel)
+            .collect()
+    }
+
+    /// Like `desired`, but keeps going past invalid entries: one result per entry, in
+    /// file order. Only an unsupported version fails the whole file.
+    pub fn check(
+        &self,
+        policy: &PathPolicy,
+        now: DateTime<Local>,
+    ) -> Result<Vec<(String, Result<DesiredTunnel>)>> {
+        if self.version != MANIFEST_VERSION {
+            return Err(FunnelError::InvalidArgument(format!(
+                "Unsupported spec file version {} (expected {})",
+                self.version, MANIFEST_VERSION
+            )));
+        }
+
+        let mut names = HashSet::new();
+        let mut places = HashSet::new();
+        Ok(self
+            .tunnels
+            .iter()
+            .map(|tunnel| {
+                let result = tunnel.desired(policy, now).and_then(|desired| {
+                    let invalid = |message: String| {
+                        FunnelError::InvalidArgument(format!(
+                            "tunnel '{}': {}",
+                            tunnel.name, message
+                        ))
+                    };
+                    if !names.insert(desired.name.clone()) {
+                        return Err(invalid("name is used twice".to_string()));
+                    }
+                    let place = (desired.spec.https_port, desired.spec.path.clone());
+                    if !places.insert(place) {
+                        return Err(invalid(format!(
+                            "path {} on port {} is used twice",
+                            desired.spec.path, desired.spec.https_port
+                        )));
+                    }
+                    Ok(desired)
+                });
+                (tunnel.name.clone(), result)
+            })
+            .collect())
+    }
+}
+
+impl ManifestTunnel {
+    /// Validates this entry on its own; `Manifest::check` adds the checks across entries
+    fn desired(&self, policy: &PathPolicy, now: DateTime<Local>) -> Result<DesiredTunnel> {
+        let invalid = |message: String| {
+            FunnelError::InvalidArgument(format!("tunnel '{}': {}", self.name, message))
+        };
+        validate_name(&self.name).map_err(|err| invalid(err.to_string()))?;
+        if self.max_concurrency.is_some() {
+            return Err(invalid(
+                "max_concurrency needs a running `funnelctl open`; apply creates detached tunnels"
+                    .to_string(),
+            ));
+        }
+        validate_port(self.port).map_err(|err| invalid(err.to_string()))?;
+        validate_https_port(self.https_port).map_err(|err| invalid(err.to_string()))?;
+
+        let bind = self.bind.as_deref().unwrap_or(DEFAULT_BIND);
+        match bind.parse::<std::net::IpAddr>() {
+            Ok(ip) if ip.is_loopback() => {}
+            _ => return Err(invalid(format!("bind '{}' is not a loopback IP", bind))),
+        }
+
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => format!("/funnelctl/{}", self.name),
+        };
+        let path = validate_path_with(&path, policy)
+            .map_err(|err| invalid(err.to_string()))?
+            .normalized_path;
+
+        let ttl = match &self.ttl {
+            Some(ttl) => {
+                let ttl = parse_ttl(ttl, now).map_err(|err| invalid(err.to_string()))?;
+                validate_ttl(ttl).map_err(|err| invalid(err.to_string()))?;
+                Some(ttl)
+            }
+            None => None,
+        };
+
+        let target = LocalTarget::new(bind.to_string(), self.port);
+        let spec = TunnelSpec::new(
+            target,
+            self.https_port,
+            path,
+            self.visibility == Visibility::Public,
+        )
+        .detached();
+        Ok(DesiredTunnel {
+            name: self.name.clone(),
+            spec,
+            ttl,
+        })
+    }
+}
+
+/// One step of reconciling the leases with a manifest
+#[derive(Debug, Clone)]
+pub enum ApplyAction {
+    Create(DesiredTunnel),
+    /// The route changed; the old tunnel is removed and a new one created
+    Update {
+        lease: Lease,
+        desired: DesiredTunnel,
+    },
+    Unchanged(Lease),
+    Remove(Lease),
+}
+
+impl ApplyAction {
+    pub fn name(&self) -> &str {
+        match self {
+            ApplyAction::Create(desired) | ApplyAction::Update { desired, .. } => &desired.name,
+            ApplyAction::Unchanged(lease) | ApplyAction::Remove(lease) => {
+                lease.name.as_deref().unwrap_or(lease.lease_id.as_str())
+            }
+        }
+    }
+}
+
+/// Compares `desired` with the active leases. Leases tagged with `project` are the ones
+/// the manifest owns: they are kept, updated, or removed. Any other active lease using a
+/// desired name is a conflict. Removals come first so their paths are free for creates.
+pub fn plan(
+    desired: Vec<DesiredTunnel>,
+    leases: &[Lease],
+    project: &str,
+    now: DateTime<Utc>,
+) -> Result<Vec<ApplyAction>> {
+    let owned: Vec<&Lease> = leases
+        .iter()
+        .filter(|lease| lease.is_active(now) && owned_by(lease, project))
+        .collect();
+
+    let mut removals = Vec::new();
+    for lease in &owned {
+        let wanted = desired
+            .iter()
+            .any(|tunnel| lease.name.as_deref() == Some(tunnel.name.as_str()));
+        if !wanted {
+            removals.push(ApplyAction::Remove((*lease).clone()));
+        }
+    }
+
+    let mut changes = Vec::new();
+    for tunnel in desired {
+        let existing = owned
+            .iter()
+            .find(|lease| lease.name.as_deref() == Some(tunnel.name.as_str()));
+        match existing {
+            Some(lease) if same_route(&lease.tunnel_spec, &tunnel.spec) => {
+                changes.push(ApplyAction::Unchanged((*lease).clone()))
+            }
+            Some(lease) => changes.push(ApplyAction::Update {
+                lease: (*lease).clone(),
+                desired: tunnel,
+            }),
+            None => {
+                check_name(&tunnel.name, leases, project, now)?;
+                changes.push(ApplyAction::Create(tunnel));
+            }
+        }
+    }
+
+    removals.extend(changes);
+    Ok(removals)
+}
+
+/// Fails when an active lease outside `project` already uses `name`; a lease the
+/// project owns is fine, since applying updates it
+pub fn check_name(name: &str, leases: &[Lease], project: &str, now: DateTime<Utc>) -> Result<()> {
+    let active = || {
+        leases
+            .iter()
+            .filter(|lease| lease.is_active(now) && lease.name.as_deref() == Some(name))
+    };
+    if active().any(|lease| owned_by(lease, project)) {
+        return Ok(());
+    }
+    match active().next() {
+        Some(other) => Err(FunnelError::Conflict {
+            source: None,
+            context: format!(
+                "Name '{}' is already used by tunnel {}, which this spec file does not own",
+                name, other.lease_id
+            ),
+        }),
+        None => Ok(()),
+    }
+}
+
+fn owned_by(lease: &Lease, project: &str) -> bool {
+    lease.tunnel_spec.detached && lease.tags.get(PROJECT_TAG).map(String::as_str) == Some(project)
+}
+
+/// The fields a manifest controls; the proxy and DNS overrides are never set by apply
+fn same_route(current: &TunnelSpec, desired: &TunnelSpec) -> bool {
+    current.local_target.bind == desired.local_target.bind
+        && current.local_target.port == desired.local_target.port
+        && current.https_port == desired.https_port
+        && current.path == desired.path
+        && current.funnel == desired.funnel
+        && current.via.is_none()
+        && current.dns_name == desired.dns_name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const YAML: &str = "
+project: shop
+tunnels:
+  - name: stripe
+    port: 3000
+    path: /hooks/stripe
+    ttl: 8h
+  - name: docs
+    port: 8080
+    visibility: tailnet
+";
+
+    fn desired(yaml: &str) -> Result<Vec<DesiredTunnel>> {
+        let now = Local.with_ymd_and_hms(2026, 3, 10, 12, 0, 0).unwrap();
+        Manifest::parse(yaml)
+            .map_err(|err| FunnelError::InvalidArgument(err.to_string()))?
+            .desired(&PathPolicy::default(), now)
+    }
+
+    fn lease(name: &str, tunnel: &DesiredTunnel, project: Option<&str>) -> Lease {
+        let mut lease =
+            Lease::new(format!("detached-{}", name), tunnel.spec.clone(), None).with_name(name);
+        if let Some(project) = project {
+            lease = lease.with_tags([(PROJECT_TAG.to_string(), project.to_string())]);
+        }
+        lease
+    }
+
+    #[test]
+    fn test_parse_yaml_and_json() {
+        let tunnels = desired(YAML).unwrap();
+        assert_eq!(tunnels.len(), 2);
+        assert_eq!(tunnels[0].spec.path, "/hooks/stripe");
+        assert_eq!(tunnels[0].ttl, Some(Duration::from_secs(8 * 3600)));
+        assert!(tunnels[0].spec.funnel && tunnels[0].spec.detached);
+        assert_eq!(tunnels[1].spec.path, "/funnelctl/docs");
+        assert!(!tunnels[1].spec.funnel);
+
+        let json = r#"{"project": "shop", "tunnels": [{"name": "api", "port": 4000}]}"#;
+        assert_eq!(desired(json).unwrap()[0].spec.local_target.port, 4000);
+    }
+
+    #[test]
+    fn test_invalid_entries_rejected() {
+        assert!(desired("tunnels:\n  - {name: a, port: 1, colour: red}\n").is_err());
+        assert!(desired("version: 2\ntunnels: []\n").is_err());
+        assert!(desired("tunnels:\n  - {name: a, port: 1}\n  - {name: a, port: 2}\n").is_err());
+        assert!(desired(
+            "tunnels:\n  - {name: a, port: 1, path: /x}\n  - {name: b, port: 2, path: /x}\n"
+        )
+        .is_err());
+        assert!(desired("tunnels:\n  - {name: a, port: 1, max_concurrency: 4}\n").is_err());
+        assert!(desired("tunnels:\n  - {name: a, port: 1, bind: 10.0.0.5}\n").is_err());
+        assert!(desired("tunnels:\n  - {name: a, port: 1, path: /metrics}\n").is_err());
+        assert!(desired("tunnels:\n  - {name: a, port: 1, https_port: 8080}\n").is_err());
+    }
+
+    #[test]
+    fn test_check_reports_every_entry() {
+        let now = Local.with_ymd_and_hms(2026, 3, 10, 12, 0, 0).unwrap();
+        let manifest = Manifest::parse(
+            "tunnels:\n  - {name: a, port: 1, path: /x}\n  - {name: b, port: 0}\n  - {name: c, port: 2, path: /x}\n  - {name: d, port: 3}\n",
+        )
+        .unwrap();
+        let results = manifest.check(&PathPolicy::default(), now).unwrap();
+        let names: Vec<(&str, bool)> = results
+            .iter()
+            .map(|(name, result)| (name.as_str(), result.is_ok()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![("a", true), ("b", false), ("c", false), ("d", true)]
+        );
+        assert!(manifest.desired(&PathPolicy::default(), now).is_err());
+    }
+
+    #[test]
+    fn test_plan_creates_updates_and_removes() {
+        let tunnels = desired(YAML).unwrap();
+        let stale_docs = {
+            let mut docs = tunnels[1].clone();
+            docs.spec.local_target.port = 9090;
+            docs
+        };
+        let leases = vec![
+            lease("stripe", &tunnels[0], Some("shop")),
+            lease("docs", &stale_docs, Some("shop")),
+            lease("old", &tunnels[0], Some("shop")),
+            lease("other", &tunnels[0], Some("blog")),
+        ];
+
+        let actions = plan(tunnels, &leases, "shop", Utc::now()).unwrap();
+        let summary: Vec<(&str, &str)> = actions
+            .iter()
+            .map(|action| {
+                let kind = match action {
+                    ApplyAction::Create(_) => "create",
+                    ApplyAction::Update { .. } => "update",
+                    ApplyAction::Unchanged(_) => "unchanged",
+                    ApplyAction::Remove(_) => "remove",
+                };
+                (kind, action.name())
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("remove", "old"),
+                ("unchanged", "stripe"),
+                ("update", "docs")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_refuses_names_owned_elsewhere() {
+        let tunnels = desired(YAML).unwrap();
+        let leases = vec![lease("stripe", &tunnels[0], None)];
+        assert!(check_name("docs", &leases, "shop", Utc::now()).is_ok());
+        assert!(check_name("stripe", &leases, "shop", Utc::now()).is_err());
+        let err = plan(tunnels, &leases, "shop", Utc::now()).unwrap_err();
+        assert_eq!(err.exit_code(), 13);
+    }
+}
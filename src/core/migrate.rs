@@ -0,0 +1,339 @@
+//! Translation of other tunnel tools' configuration into spec files for `apply` and `up`.
+//!
+//! Only what Funnel can do is carried over; everything else becomes a note, so a
+//! migrated tunnel is never quietly less protected than the original.
+
+use std::collections::HashSet;
+
+use serde::Serialize;
+use serde_norway::{Mapping, Value};
+
+use super::validation::validate_name;
+use crate::error::{FunnelError, Result};
+
+const MAX_NAME_LEN: usize = 63;
+
+/// A spec file as written by `migrate`; `Manifest` reads it back
+#[derive(Debug, Clone, Serialize)]
+pub struct SpecFile {
+    pub version: u32,
+    pub project: String,
+    pub tunnels: Vec<SpecTunnel>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SpecTunnel {
+    pub name: String,
+    pub port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bind: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Migration {
+    pub spec: SpecFile,
+    /// What could not be carried over, one line per tunnel and option
+    pub notes: Vec<String>,
+}
+
+impl Migration {
+    /// The spec file, headed by the notes as comments so they survive a redirect
+    pub fn to_yaml(&self, source: &str) -> Result<String> {
+        let body = serde_norway::to_string(&self.spec)
+            .map_err(|err| FunnelError::Other(format!("Failed to write spec file: {}", err)))?;
+        let mut yaml = format!("# Migrated from {} by funnelctl\n", source);
+        for note in &self.notes {
+            yaml.push_str(&format!("# - {}\n", note));
+        }
+        yaml.push_str(&body);
+        Ok(yaml)
+    }
+}
+
+/// Keys that need no translation: ngrok metadata, or the default behaviour of Funnel
+const NGROK_IGNORED: &[&str] = &[
+    "name",
+    "proto",
+    "addr",
+    "upstream",
+    "inspect",
+    "metadata",
+    "description",
+    "schemes",
+    "bind_tls",
+];
+const NGROK_HOSTNAMES: &[&str] = &["hostname", "subdomain", "domain", "url"];
+const NGROK_AUTH: &[&str] = &["auth", "basic_auth", "oauth", "oidc"];
+
+/// Reads an ngrok agent config (v2 `tunnels:` or v3 `endpoints:`) and builds a spec file
+/// for `project`. Fails when nothing in it can be migrated.
+pub fn from_ngrok(contents: &str, project: &str) -> Result<Migration> {
+    validate_name(project)
+        .map_err(|err| FunnelError::InvalidArgument(format!("project: {}", err)))?;
+    let doc: Value = serde_norway::from_str(contents)
+        .map_err(|err| FunnelError::InvalidArgument(format!("Invalid ngrok config: {}", err)))?;
+
+    let mut entries: Vec<(String, &Mapping)> = Vec::new();
+    if let Some(tunnels) = doc.get("tunnels").and_then(Value::as_mapping) {
+        for (name, tunnel) in tunnels {
+            if let (Some(name), Some(tunnel)) = (name.as_str(), tunnel.as_mapping()) {
+                entries.push((name.to_string(), tunnel));
+            }
+        }
+    }
+    if let Some(endpoints) = doc.get("endpoints").and_then(Value::as_sequence) {
+        for (index, endpoint) in endpoints.iter().enumerate() {
+            if let Some(endpoint) = endpoint.as_mapping() {
+                let name = endpoint
+                    .get("name")
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("endpoint-{}", index + 1));
+                entries.push((name, endpoint));
+            }
+        }
+    }
+    if entries.is_empty() {
+        return Err(FunnelError::InvalidArgument(
+            "No tunnels or endpoints found in the ngrok config".to_string(),
+        ));
+    }
+
+    let mut notes = Vec::new();
+    let mut names = HashSet::new();
+    let mut tunnels = Vec::new();
+    for (original, entry) in entries {
+        let name = unique_name(&original, &mut names);
+        let mut note = |message: String| notes.push(format!("{}: {}", original, message));
+        if name != original {
+            note(format!("renamed to '{}'", name));
+        }
+        match ngrok_target(entry) {
+            Ok((port, bind)) => {
+                note_dropped_options(entry, &mut note);
+                tunnels.push(SpecTunnel { name, port, bind });
+            }
+            Err(reason) => note(format!("skipped, {}", reason)),
+        }
+    }
+    if tunnels.is_empty() {
+        return Err(FunnelError::InvalidArgument(format!(
+            "None of the ngrok tunnels can be migrated:\n  {}",
+            notes.join("\n  ")
+        )));
+    }
+
+    Ok(Migration {
+        spec: SpecFile {
+            version: 1,
+            project: project.to_string(),
+            tunnels,
+        },
+        notes,
+    })
+}
+
+/// The local port and, unless it is the default, bind address an ngrok entry forwards to
+fn ngrok_target(entry: &Mapping) -> std::result::Result<(u16, Option<String>), String> {
+    let proto = entry.get("proto").and_then(Value::as_str).unwrap_or("http");
+    if proto != "http" {
+        return Err(format!("funnelctl only forwards HTTP, not {}", proto));
+    }
+    if let Some(url) = entry.get("url").and_then(Value::as_str) {
+        if let Some((scheme, _)) = url.split_once("://") {
+            if !matches!(scheme, "http" | "https") {
+                return Err(format!("funnelctl only forwards HTTP, not {}", scheme));
+            }
+        }
+    }
+    let addr = entry
+        .get("addr")
+        .or_else(|| {
+            entry
+                .get("upstream")
+                .and_then(|upstream| upstream.get("url"))
+        })
+        .ok_or_else(|| "it has no local address".to_string())?;
+    parse_ngrok_addr(addr)
+}
+
+/// ngrok accepts `8080`, `host:8080`, and `http://host:8080`
+fn parse_ngrok_addr(addr: &Value) -> std::result::Result<(u16, Option<String>), String> {
+    let addr = match addr {
+        Value::Number(port) => port.to_string(),
+        Value::String(addr) => addr.clone(),
+        _ => return Err("its local address is not a port or URL".to_string()),
+    };
+    let (rest, default_port) = match addr.split_once("://") {
+        Some(("http", rest)) => (rest, 80),
+        Some(("https", _)) => {
+            return Err(format!(
+                "{} is HTTPS and funnelctl forwards plain HTTP to the target",
+                addr
+            ))
+        }
+        Some((scheme, _)) => return Err(format!("{} targets are not supported", scheme)),
+        None => (addr.as_str(), 80),
+    };
+    let rest = rest.trim_end_matches('/');
+    let (host, port) = match rest.rsplit_once(':') {
+        Some((host, port)) => (host, port),
+        None if rest.chars().all(|c| c.is_ascii_digit()) => ("", rest),
+        None => (rest, ""),
+    };
+    let port = if port.is_empty() {
+        default_port
+    } else {
+        port.parse::<u16>()
+            .map_err(|_| format!("'{}' is not a valid local address", addr))?
+    };
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let bind = match host {
+        "" | "localhost" | "127.0.0.1" => None,
+        host => match host.parse::<std::net::IpAddr>() {
+            Ok(ip) if ip.is_loopback() => Some(ip.to_string()),
+            _ => {
+                return Err(format!(
+                    "{} is not on this machine's loopback and funnelctl only forwards there",
+                    host
+                ))
+            }
+        },
+    };
+    Ok((port, bind))
+}
+
+fn note_dropped_options(entry: &Mapping, note: &mut impl FnMut(String)) {
+    for key in entry.keys().filter_map(Value::as_str) {
+        if NGROK_HOSTNAMES.contains(&key) {
+            note(format!(
+                "{} is not kept; Funnel serves at the node's ts.net name (`open --dns-name` can use another name the node has a certificate for)",
+                key
+            ));
+        } else if NGROK_AUTH.contains(&key) {
+            note(format!(
+                "{} is dropped; Funnel has no built-in authentication, so the tunnel is open to anyone with the URL",
+                key
+            ));
+        } else if !NGROK_IGNORED.contains(&key) {
+            note(format!("{} has no Funnel equivalent and is dropped", key));
+        }
+    }
+}
+
+/// A valid, unused tunnel name close to `original`
+fn unique_name(original: &str, taken: &mut HashSet<String>) -> String {
+    let mut base: String = original
+        .to_ascii_lowercase()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect::<String>()
+        .trim_start_matches(|c: char| !c.is_ascii_alphanumeric())
+        .to_string();
+    if base.is_empty() {
+        base = "tunnel".to_string();
+    }
+    base.truncate(MAX_NAME_LEN - 4);
+
+    let mut name = base.clone();
+    let mut suffix = 2;
+    while taken.contains(&name) {
+        name = format!("{}-{}", base, suffix);
+        suffix += 1;
+    }
+    taken.insert(name.clone());
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::manifest::Manifest;
+    use crate::core::PathPolicy;
+
+    #[test]
+    fn test_from_ngrok_v2_maps_http_tunnels_and_notes_the_rest() {
+        let config = r#"
+version: "2"
+authtoken: secret
+tunnels:
+  Web App:
+    proto: http
+    addr: 8080
+    hostname: app.example.com
+    basic_auth: ["user:pass"]
+    inspect: false
+  api:
+    addr: "http://127.0.0.2:3000"
+    host_header: rewrite
+  ssh:
+    proto: tcp
+    addr: 22
+  lan:
+    addr: "192.168.1.5:80"
+"#;
+        let migration = from_ngrok(config, "ngrok").unwrap();
+        let tunnels = &migration.spec.tunnels;
+        assert_eq!(tunnels.len(), 2);
+        assert_eq!(
+            (tunnels[0].name.as_str(), tunnels[0].port),
+            ("web-app", 8080)
+        );
+        assert_eq!(tunnels[0].bind, None);
+        assert_eq!(tunnels[1].bind.as_deref(), Some("127.0.0.2"));
+
+        let notes = migration.notes.join("\n");
+        for expected in [
+            "Web App: renamed to 'web-app'",
+            "Web App: hostname is not kept",
+            "Web App: basic_auth is dropped",
+            "api: host_header has no Funnel equivalent",
+            "ssh: skipped, funnelctl only forwards HTTP, not tcp",
+            "lan: skipped, 192.168.1.5 is not on this machine's loopback",
+        ] {
+            assert!(
+                notes.contains(expected),
+                "missing note {:?} in\n{}",
+                expected,
+                notes
+            );
+        }
+        assert!(!notes.contains("inspect"));
+
+        let yaml = migration.to_yaml("ngrok.yml").unwrap();
+        let manifest = Manifest::parse(&yaml).unwrap();
+        let desired = manifest
+            .desired(&PathPolicy::default(), chrono::Local::now())
+            .unwrap();
+        assert_eq!(desired[0].spec.path, "/funnelctl/web-app");
+    }
+
+    #[test]
+    fn test_from_ngrok_v3_endpoints() {
+        let config = r#"
+version: 3
+endpoints:
+  - name: hooks
+    url: https://hooks.ngrok.app
+    upstream:
+      url: 4000
+  - url: tcp://1.tcp.ngrok.io:20000
+    upstream:
+      url: 5432
+"#;
+        let migration = from_ngrok(config, "ngrok").unwrap();
+        assert_eq!(migration.spec.tunnels.len(), 1);
+        assert_eq!(migration.spec.tunnels[0].port, 4000);
+        assert!(migration.notes[0].starts_with("hooks: url is not kept"));
+        assert!(migration.notes[1].starts_with("endpoint-2: skipped"));
+
+        assert!(from_ngrok("version: 3\nendpoints: []\n", "ngrok").is_err());
+    }
+}
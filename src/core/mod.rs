@@ -1,14 +1,38 @@
+pub mod audit;
+pub mod backup;
+pub mod diff;
+pub mod funnel_policy;
+pub mod history;
 pub mod lease;
+pub mod manifest;
+pub mod migrate;
 pub mod patch;
+pub mod route;
+pub mod snapshot;
 pub mod spec;
 pub mod types;
 pub mod validation;
 
-pub use lease::{BackendKind, Lease};
-pub use patch::{apply_patch, detect_conflicts, remove_patch, Conflict};
+pub use audit::{audit_routes, AuditCheck, Finding, Severity};
+pub use backup::{BackupStore, ConfigBackup};
+pub use diff::{diff_serve_configs, ServeChange, ServeDiff};
+pub use history::{find_entry, last_path, HistoryEntry, HistoryLog};
+pub use lease::{default_name, resolve_lease, BackendKind, Lease, LeaseStore, Usage};
+pub use patch::{
+    alternative_paths, apply_background_patch, apply_patch, detect_conflicts,
+    foreground_route_state, remove_patch, Conflict, ConflictIndex, RouteState,
+};
+pub use route::{list_routes, remove_routes, Route};
+pub use snapshot::{restore_snapshot, RestoreOutcome, Snapshot};
 pub use spec::{LocalTarget, TunnelResult, TunnelSpec};
-pub use types::{HttpHandler, PathMapping, ServeConfig, WebServerConfig};
+pub use types::{
+    HttpHandler, PathMapping, ServeConfig, ServiceConfig, TcpPortHandler, WebServerConfig,
+};
 pub use validation::{
-    validate_https_port, validate_path, validate_port, validate_ttl, PathValidationResult,
-    TtlValidationResult, ValidationWarning,
+    normalize_dns_name, parse_expose_for, parse_port, parse_public_url_base, parse_start_time,
+    parse_tag, parse_ttl, validate_https_port, validate_name, validate_path, validate_path_with,
+    validate_port, validate_ttl, validate_ttl_with_warning, PathPolicy, PathValidationResult,
+    TtlValidationResult, ValidationWarning, WarningId, ALLOWED_HTTPS_PORTS,
+    DEFAULT_MAX_PATH_LENGTH, DEFAULT_MAX_PATH_SEGMENTS, DEFAULT_RESERVED_PATHS,
+    DEFAULT_TTL_WARNING,
 };
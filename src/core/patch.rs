@@ -1,6 +1,10 @@
-use crate::core::types::{HttpHandler, PathMapping, ServeConfig};
-use crate::error::{FunnelError, Result};
-use std::collections::HashMap;
+use crate::core::types::{HttpHandler, ServeConfig};
+use crate::error::{ConflictEntry, FunnelError, Result};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
 
 /// Represents a conflict between existing and new configuration
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -61,6 +65,50 @@ impl Conflict {
             }
         }
     }
+
+    /// Stable name of the conflict kind, as used in JSON errors
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Conflict::ExactPathDifferentTarget { .. } => "exact_path",
+            Conflict::CapturedByExistingPrefix { .. } => "captured_by_prefix",
+            Conflict::NewPrefixCapturesExisting { .. } => "captures_existing",
+        }
+    }
+
+    /// The conflict as reported in errors; `session` is the foreground session owning
+    /// the existing handler, `None` for the background config
+    pub fn entry(&self, host_port: &str, session: Option<&str>) -> ConflictEntry {
+        let (path, existing_path, existing_target) = match self {
+            Conflict::ExactPathDifferentTarget {
+                path,
+                existing_target,
+                ..
+            } => (path, path, existing_target),
+            Conflict::CapturedByExistingPrefix {
+                new_path,
+                existing_prefix,
+                existing_target,
+            } => (new_path, existing_prefix, existing_target),
+            Conflict::NewPrefixCapturesExisting {
+                new_prefix,
+                captured_path,
+                captured_target,
+            } => (new_prefix, captured_path, captured_target),
+        };
+        let message = match session {
+            Some(session) => format!("{} (session {})", self.describe(), session),
+            None => self.describe(),
+        };
+        ConflictEntry {
+            kind: self.kind().to_string(),
+            host_port: host_port.to_string(),
+            path: path.clone(),
+            existing_path: existing_path.clone(),
+            existing_target: existing_target.clone(),
+            session: session.map(str::to_string),
+            message,
+        }
+    }
 }
 
 /// Detects conflicts between a new path mapping and existing configuration
@@ -68,73 +116,158 @@ impl Conflict {
 /// Returns:
 /// - Ok(None) if there are no conflicts
 /// - Ok(Some(true)) if the mapping is idempotent (exact match with funnel enabled)
-/// - Err(Conflict) if there is a conflict
+/// - Err(conflicts) with every conflicting handler otherwise
+///
+/// Conflicts come in a fixed order: an exact match with another target, then each
+/// capturing prefix from the shortest, then each captured path in sorted order. To check
+/// several mappings against one config, build a `ConflictIndex`.
 pub fn detect_conflicts(
     config: &ServeConfig,
     host_port: &str,
     new_path: &str,
     new_target: &str,
     funnel_enabled: bool,
-) -> std::result::Result<Option<bool>, Conflict> {
-    let handlers = match config.get_handlers(host_port) {
-        Some(h) => h,
-        None => return Ok(None), // No existing handlers, no conflict
-    };
+) -> std::result::Result<Option<bool>, Vec<Conflict>> {
+    match PortIndex::new(config, host_port) {
+        Some(index) => index.check(new_path, new_target, funnel_enabled),
+        None => Ok(None), // No existing handlers, no conflict
+    }
+}
 
-    let existing_funnel_enabled = config.is_funnel_enabled(host_port);
+/// The handlers of one ServeConfig by `host:port`, sorted by path. Checking a mapping
+/// costs one lookup per segment of its path plus one range query, instead of a scan
+/// over every handler, so a write builds this once and checks each port against it.
+#[derive(Debug, Default)]
+pub struct ConflictIndex {
+    ports: HashMap<String, PortIndex>,
+}
 
-    // Extract existing path mappings
-    let existing_mappings: Vec<PathMapping> = handlers
-        .iter()
-        .map(|(path, handler)| {
-            let target = describe_handler_target(handler);
-            PathMapping::new(path.clone(), target, existing_funnel_enabled)
+#[derive(Debug)]
+struct PortIndex {
+    funnel_enabled: bool,
+    /// Handler path to the target `describe_handler_target` gives it
+    paths: BTreeMap<String, String>,
+}
+
+impl ConflictIndex {
+    pub fn new(config: &ServeConfig) -> Self {
+        let ports = config
+            .web
+            .iter()
+            .flatten()
+            .filter_map(|(host_port, _)| {
+                PortIndex::new(config, host_port).map(|index| (host_port.clone(), index))
+            })
+            .collect();
+        Self { ports }
+    }
+
+    /// `detect_conflicts` against the config the index was built from
+    pub fn check(
+        &self,
+        host_port: &str,
+        new_path: &str,
+        new_target: &str,
+        funnel_enabled: bool,
+    ) -> std::result::Result<Option<bool>, Vec<Conflict>> {
+        match self.ports.get(host_port) {
+            Some(index) => index.check(new_path, new_target, funnel_enabled),
+            None => Ok(None),
+        }
+    }
+}
+
+impl PortIndex {
+    fn new(config: &ServeConfig, host_port: &str) -> Option<Self> {
+        let handlers = config.get_handlers(host_port)?;
+        Some(Self {
+            funnel_enabled: config.is_funnel_enabled(host_port),
+            paths: handlers
+                .iter()
+                .map(|(path, handler)| (path.clone(), describe_handler_target(handler)))
+                .collect(),
         })
-        .collect();
-
-    let new_mapping =
-        PathMapping::new(new_path.to_string(), new_target.to_string(), funnel_enabled);
-
-    // Check for conflicts
-    for existing in &existing_mappings {
-        // Exact path match
-        if existing.path == new_path {
-            if existing.target == new_target {
-                if existing_funnel_enabled && funnel_enabled {
-                    return Ok(Some(true));
-                }
-                return Ok(None);
+    }
+
+    fn check(
+        &self,
+        new_path: &str,
+        new_target: &str,
+        funnel_enabled: bool,
+    ) -> std::result::Result<Option<bool>, Vec<Conflict>> {
+        let mut conflicts = Vec::new();
+        if let Some(existing_target) = self.paths.get(new_path) {
+            if existing_target == new_target {
+                return Ok((self.funnel_enabled && funnel_enabled).then_some(true));
             }
-            return Err(Conflict::ExactPathDifferentTarget {
+            conflicts.push(Conflict::ExactPathDifferentTarget {
                 path: new_path.to_string(),
-                existing_target: existing.target.clone(),
+                existing_target: existing_target.clone(),
                 new_target: new_target.to_string(),
             });
         }
 
-        // Check if new path would be captured by existing prefix
-        if existing.is_prefix_of(new_path) {
-            return Err(Conflict::CapturedByExistingPrefix {
-                new_path: new_path.to_string(),
-                existing_prefix: existing.path.clone(),
-                existing_target: existing.target.clone(),
-            });
+        // Only a proper prefix ending in `/` is a mount that can capture the new path
+        for (end, _) in new_path.match_indices('/') {
+            let prefix = &new_path[..=end];
+            if prefix.len() == new_path.len() {
+                break;
+            }
+            if let Some(existing_target) = self.paths.get(prefix) {
+                conflicts.push(Conflict::CapturedByExistingPrefix {
+                    new_path: new_path.to_string(),
+                    existing_prefix: prefix.to_string(),
+                    existing_target: existing_target.clone(),
+                });
+            }
         }
 
-        // Check if new prefix would capture existing paths
-        if new_mapping.is_prefix_of(&existing.path) {
-            return Err(Conflict::NewPrefixCapturesExisting {
-                new_prefix: new_path.to_string(),
-                captured_path: existing.path.clone(),
-                captured_target: existing.target.clone(),
-            });
+        // Paths under a new prefix sort right after it
+        if new_path.ends_with('/') {
+            let captured = self
+                .paths
+                .range::<str, _>((Bound::Excluded(new_path), Bound::Unbounded))
+                .take_while(|(path, _)| path.starts_with(new_path));
+            for (captured_path, captured_target) in captured {
+                conflicts.push(Conflict::NewPrefixCapturesExisting {
+                    new_prefix: new_path.to_string(),
+                    captured_path: captured_path.clone(),
+                    captured_target: captured_target.clone(),
+                });
+            }
+        }
+
+        if conflicts.is_empty() {
+            Ok(None)
+        } else {
+            Err(conflicts)
         }
     }
+}
 
-    Ok(None)
+/// Paths to offer instead of a conflicting `path`, closest first: `-2` to `-9` appended to
+/// its last segment (before a trailing `/`, so a prefix stays a prefix), then a fresh
+/// `/funnelctl/<random>` path
+pub fn alternative_paths(path: &str) -> impl Iterator<Item = String> + '_ {
+    let (base, slash) = match path.strip_suffix('/') {
+        Some(base) if !base.is_empty() => (base, "/"),
+        _ => (path.trim_end_matches('/'), ""),
+    };
+    // The root has no segment to number
+    let numbered = if base.is_empty() { 0..0 } else { 2..10 };
+    numbered
+        .map(move |n| format!("{}-{}{}", base, n, slash))
+        .chain(std::iter::once_with(|| {
+            let token: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(8)
+                .map(char::from)
+                .collect();
+            format!("/funnelctl/{token}")
+        }))
 }
 
-fn describe_handler_target(handler: &HttpHandler) -> String {
+pub(crate) fn describe_handler_target(handler: &HttpHandler) -> String {
     if let Some(proxy) = handler.get_proxy_target() {
         return proxy.to_string();
     }
@@ -153,7 +286,7 @@ fn describe_handler_target(handler: &HttpHandler) -> String {
 /// This function:
 /// 1. Updates or creates the foreground config for the given session_id
 /// 2. Sets the handler for the specified path
-/// 3. Updates the AllowFunnel setting if funnel is enabled
+/// 3. Sets or clears AllowFunnel for the host:port; the session's config is its own
 /// 4. Preserves all unknown fields in the JSON structure
 pub fn apply_patch(
     config: &mut ServeConfig,
@@ -187,12 +320,20 @@ pub fn apply_patch(
     let handlers = web_config.handlers.get_or_insert_with(HashMap::new);
 
     // Add/update the handler
-    handlers.insert(path.to_string(), HttpHandler::new_proxy(target.to_string()));
+    handlers.insert(
+        path.to_string(),
+        HttpHandler::new_proxy(target.to_string()).owned(),
+    );
 
-    // Update funnel setting if enabled
+    // Update funnel setting; clearing it is how a paused tunnel stops public traffic
     if funnel_enabled {
         let allow_funnel = session_config.allow_funnel.get_or_insert_with(HashMap::new);
         allow_funnel.insert(host_port.to_string(), true);
+    } else if let Some(allow_funnel) = &mut session_config.allow_funnel {
+        allow_funnel.remove(host_port);
+        if allow_funnel.is_empty() {
+            session_config.allow_funnel = None;
+        }
     }
 
     // Serialize session config back
@@ -202,6 +343,29 @@ pub fn apply_patch(
     Ok(())
 }
 
+/// Applies a patch to the background (persistent) part of the ServeConfig. Unlike
+/// foreground sessions, tailscaled keeps these handlers after funnelctl exits.
+pub fn apply_background_patch(
+    config: &mut ServeConfig,
+    host_port: &str,
+    path: &str,
+    target: &str,
+    funnel_enabled: bool,
+) {
+    let web = config.web.get_or_insert_with(HashMap::new);
+    let web_config = web.entry(host_port.to_string()).or_default();
+    let handlers = web_config.handlers.get_or_insert_with(HashMap::new);
+    handlers.insert(
+        path.to_string(),
+        HttpHandler::new_proxy(target.to_string()).owned(),
+    );
+
+    if funnel_enabled {
+        let allow_funnel = config.allow_funnel.get_or_insert_with(HashMap::new);
+        allow_funnel.insert(host_port.to_string(), true);
+    }
+}
+
 /// Removes a path mapping from the foreground configuration
 pub fn remove_patch(
     config: &mut ServeConfig,
@@ -259,6 +423,74 @@ pub fn remove_patch(
     Ok(removed)
 }
 
+/// Whether a foreground route funnelctl applied is still served as applied
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteState {
+    /// Handler and Funnel flag are as applied
+    Intact,
+    /// The handler or the whole session config is gone, e.g. after `tailscale serve off`
+    Removed,
+    /// The path now points elsewhere, Funnel was switched off for it, or a background
+    /// handler took the same path
+    Replaced,
+    /// tailscaled closed the watch session, which drops its foreground config
+    SessionEnded,
+}
+
+impl RouteState {
+    /// The state as spelled in the `route_changed` event
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RouteState::Intact => "intact",
+            RouteState::Removed => "removed",
+            RouteState::Replaced => "replaced",
+            RouteState::SessionEnded => "session_ended",
+        }
+    }
+}
+
+/// Compares the live config against the route `apply_patch` wrote for `session_id`
+pub fn foreground_route_state(
+    config: &ServeConfig,
+    session_id: &str,
+    host_port: &str,
+    path: &str,
+    target: &str,
+    funnel_enabled: bool,
+) -> Result<RouteState> {
+    let background = config
+        .get_handlers(host_port)
+        .and_then(|handlers| handlers.get(path));
+    if background.is_some_and(|handler| handler.get_proxy_target() != Some(target)) {
+        return Ok(RouteState::Replaced);
+    }
+
+    let session_config_value = match config
+        .foreground
+        .as_ref()
+        .and_then(|foreground| foreground.get(session_id))
+    {
+        Some(value) => value,
+        None => return Ok(RouteState::Removed),
+    };
+    let session_config: ServeConfig = serde_json::from_value(session_config_value.clone())
+        .map_err(|e| FunnelError::Other(format!("Failed to parse session config: {}", e)))?;
+
+    let state = match session_config
+        .get_handlers(host_port)
+        .and_then(|handlers| handlers.get(path))
+    {
+        None => RouteState::Removed,
+        Some(handler) if handler.get_proxy_target() != Some(target) => RouteState::Replaced,
+        Some(_) if funnel_enabled && !session_config.is_funnel_enabled(host_port) => {
+            RouteState::Replaced
+        }
+        Some(_) => RouteState::Intact,
+    };
+    Ok(state)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -305,7 +537,7 @@ mod tests {
             false,
         );
         assert!(result.is_err());
-        match result.unwrap_err() {
+        match result.unwrap_err().remove(0) {
             Conflict::ExactPathDifferentTarget { path, .. } => {
                 assert_eq!(path, "/api");
             }
@@ -354,7 +586,7 @@ mod tests {
             false,
         );
         assert!(result.is_err());
-        match result.unwrap_err() {
+        match result.unwrap_err().remove(0) {
             Conflict::CapturedByExistingPrefix {
                 new_path,
                 existing_prefix,
@@ -389,7 +621,7 @@ mod tests {
             false,
         );
         assert!(result.is_err());
-        match result.unwrap_err() {
+        match result.unwrap_err().remove(0) {
             Conflict::NewPrefixCapturesExisting {
                 new_prefix,
                 captured_path,
@@ -402,6 +634,87 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_conflict_index_across_ports() {
+        let mut config = ServeConfig::new();
+        let mut web = HashMap::new();
+        for (host_port, paths) in [
+            ("example.com:443", vec!["/", "/api/v1", "/hooks/"]),
+            ("example.com:8443", vec!["/api", "/apiv2/x"]),
+        ] {
+            let mut web_config = WebServerConfig::new();
+            web_config.handlers = Some(
+                paths
+                    .into_iter()
+                    .map(|path| {
+                        (
+                            path.to_string(),
+                            HttpHandler::new_proxy("http://127.0.0.1:8080".to_string()),
+                        )
+                    })
+                    .collect(),
+            );
+            web.insert(host_port.to_string(), web_config);
+        }
+        config.web = Some(web);
+        let index = ConflictIndex::new(&config);
+        let target = "http://127.0.0.1:9000";
+
+        // The root mount captures everything on 443; an exact match is reported first
+        assert!(matches!(
+            index
+                .check("example.com:443", "/hooks/", target, true)
+                .unwrap_err()[..],
+            [
+                Conflict::ExactPathDifferentTarget { .. },
+                Conflict::CapturedByExistingPrefix { .. }
+            ]
+        ));
+        let conflicts = index
+            .check("example.com:443", "/hooks/a", target, true)
+            .unwrap_err();
+        assert_eq!(conflicts.len(), 2);
+        assert!(matches!(
+            &conflicts[0],
+            Conflict::CapturedByExistingPrefix { existing_prefix, .. } if existing_prefix == "/"
+        ));
+        assert!(matches!(
+            &conflicts[1],
+            Conflict::CapturedByExistingPrefix { existing_prefix, .. } if existing_prefix == "/hooks/"
+        ));
+        assert_eq!(
+            index.check("example.com:443", "/api/v1", "http://127.0.0.1:8080", false),
+            Ok(None)
+        );
+        // `/api` has no trailing slash, so it is no mount; `/apiv2/x` is not under `/api/`
+        assert_eq!(
+            index.check("example.com:8443", "/api/", target, true),
+            Ok(None)
+        );
+        assert!(matches!(
+            &index.check("example.com:8443", "/apiv2/", target, true).unwrap_err()[..],
+            [Conflict::NewPrefixCapturesExisting { captured_path, .. }] if captured_path == "/apiv2/x"
+        ));
+        assert_eq!(
+            index.check("example.com:10000", "/", target, true),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_alternative_paths() {
+        let paths: Vec<_> = alternative_paths("/hook").collect();
+        assert_eq!(paths.len(), 9);
+        assert_eq!(paths[0], "/hook-2");
+        assert_eq!(paths[7], "/hook-9");
+        assert!(paths[8].starts_with("/funnelctl/"));
+        assert_eq!(alternative_paths("/api/").next().unwrap(), "/api-2/");
+        assert!(alternative_paths("/")
+            .next()
+            .unwrap()
+            .starts_with("/funnelctl/"));
+    }
+
     #[test]
     fn test_apply_patch_new_session() {
         let mut config = ServeConfig::new();
@@ -446,6 +759,25 @@ mod tests {
                 .get("example.com:443"),
             Some(&true)
         );
+
+        // Re-applying without funnel clears it again
+        apply_patch(
+            &mut config,
+            "session123",
+            "example.com:443",
+            "/api",
+            "http://127.0.0.1:8080",
+            false,
+        )
+        .unwrap();
+        let session_value = config
+            .foreground
+            .as_ref()
+            .unwrap()
+            .get("session123")
+            .unwrap();
+        let session_config: ServeConfig = serde_json::from_value(session_value.clone()).unwrap();
+        assert!(session_config.allow_funnel.is_none());
     }
 
     #[test]
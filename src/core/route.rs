@@ -0,0 +1,258 @@
+use crate::core::types::{HttpHandler, ServeConfig};
+use crate::error::{FunnelError, Result};
+
+/// A single HTTP handler found in a ServeConfig, flattened for listing and filtering
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub host_port: String,
+    pub https_port: Option<u16>,
+    pub path: String,
+    pub target: String,
+    pub funnel: bool,
+    /// Foreground session owning the route; `None` for background config
+    pub session: Option<String>,
+    /// The handler carries funnelctl's ownership marker, so funnelctl installed it even
+    /// when no lease records it
+    pub owned: bool,
+}
+
+impl Route {
+    /// Checks if this route matches the given path prefix and HTTPS port filters
+    pub fn matches(&self, path_prefix: Option<&str>, https_port: Option<u16>) -> bool {
+        if let Some(prefix) = path_prefix {
+            if !self.path.starts_with(prefix) {
+                return false;
+            }
+        }
+        match https_port {
+            Some(port) => self.https_port == Some(port),
+            None => true,
+        }
+    }
+}
+
+/// Lists every HTTP handler in the config, including foreground sessions
+pub fn list_routes(config: &ServeConfig) -> Result<Vec<Route>> {
+    let mut routes = Vec::new();
+    collect_routes(config, None, &mut routes);
+
+    if let Some(foreground) = &config.foreground {
+        for (session, value) in foreground {
+            let session_config = parse_session(value)?;
+            collect_routes(&session_config, Some(session), &mut routes);
+        }
+    }
+
+    routes.sort_by(|a, b| {
+        (&a.host_port, &a.path, &a.session).cmp(&(&b.host_port, &b.path, &b.session))
+    });
+    Ok(routes)
+}
+
+/// Removes the given routes from the config, returning how many were removed
+pub fn remove_routes(config: &mut ServeConfig, routes: &[Route]) -> Result<usize> {
+    let mut removed = 0;
+    for route in routes {
+        match &route.session {
+            None => {
+                if remove_handler(config, &route.host_port, &route.path) {
+                    removed += 1;
+                }
+            }
+            Some(session) => {
+                let Some(value) = config
+                    .foreground
+                    .as_mut()
+                    .and_then(|foreground| foreground.get_mut(session))
+                else {
+                    continue;
+                };
+                let mut session_config = parse_session(value)?;
+                if remove_handler(&mut session_config, &route.host_port, &route.path) {
+                    removed += 1;
+                    *value = serde_json::to_value(&session_config).map_err(|e| {
+                        FunnelError::Other(format!("Failed to serialize session config: {}", e))
+                    })?;
+                }
+            }
+        }
+    }
+    Ok(removed)
+}
+
+/// Extracts the port from a "host:port" key
+pub fn host_port_port(host_port: &str) -> Option<u16> {
+    host_port
+        .rsplit_once(':')
+        .and_then(|(_, port)| port.parse().ok())
+}
+
+fn collect_routes(config: &ServeConfig, session: Option<&String>, routes: &mut Vec<Route>) {
+    let Some(web) = &config.web else {
+        return;
+    };
+    for (host_port, web_config) in web {
+        let Some(handlers) = &web_config.handlers else {
+            continue;
+        };
+        let funnel = config.is_funnel_enabled(host_port);
+        for (path, handler) in handlers {
+            routes.push(Route {
+                host_port: host_port.clone(),
+                https_port: host_port_port(host_port),
+                path: path.clone(),
+                target: describe_target(handler),
+                funnel,
+                session: session.cloned(),
+                owned: handler.is_owned(),
+            });
+        }
+    }
+}
+
+/// Removes a handler and prunes the web and AllowFunnel entries it leaves empty
+fn remove_handler(config: &mut ServeConfig, host_port: &str, path: &str) -> bool {
+    let Some(web) = config.web.as_mut() else {
+        return false;
+    };
+    let Some(web_config) = web.get_mut(host_port) else {
+        return false;
+    };
+    let Some(handlers) = web_config.handlers.as_mut() else {
+        return false;
+    };
+    if handlers.remove(path).is_none() {
+        return false;
+    }
+
+    if handlers.is_empty() {
+        web_config.handlers = None;
+    }
+    if web_config.handlers.is_none() && web_config.unknown_fields.is_empty() {
+        web.remove(host_port);
+        if let Some(allow_funnel) = config.allow_funnel.as_mut() {
+            allow_funnel.remove(host_port);
+            if allow_funnel.is_empty() {
+                config.allow_funnel = None;
+            }
+        }
+    }
+    if config.web.as_ref().is_some_and(|web| web.is_empty()) {
+        config.web = None;
+    }
+    true
+}
+
+pub(crate) fn parse_session(value: &serde_json::Value) -> Result<ServeConfig> {
+    if value.is_null() {
+        return Ok(ServeConfig::new());
+    }
+    serde_json::from_value(value.clone())
+        .map_err(|e| FunnelError::Other(format!("Failed to parse session config: {}", e)))
+}
+
+fn describe_target(handler: &HttpHandler) -> String {
+    if let Some(proxy) = handler.get_proxy_target() {
+        return proxy.to_string();
+    }
+    if let Some(path) = handler.path.as_deref() {
+        return format!("path handler {}", path);
+    }
+    if handler.text.is_some() {
+        return "text handler".to_string();
+    }
+    "non-proxy handler".to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::apply_patch;
+
+    fn config_with_background_and_session() -> ServeConfig {
+        let json = r#"{
+            "Web": {
+                "node.ts.net:443": {
+                    "Handlers": {
+                        "/": {"Proxy": "http://127.0.0.1:3000"},
+                        "/api/": {"Proxy": "http://127.0.0.1:4000"}
+                    }
+                }
+            },
+            "AllowFunnel": {"node.ts.net:443": true}
+        }"#;
+        let mut config: ServeConfig = serde_json::from_str(json).unwrap();
+        apply_patch(
+            &mut config,
+            "session1",
+            "node.ts.net:8443",
+            "/funnelctl/abc12345",
+            "http://127.0.0.1:8081",
+            true,
+        )
+        .unwrap();
+        config
+    }
+
+    #[test]
+    fn test_list_routes_includes_foreground() {
+        let config = config_with_background_and_session();
+        let routes = list_routes(&config).unwrap();
+        assert_eq!(routes.len(), 3);
+
+        let session_route = routes
+            .iter()
+            .find(|route| route.session.as_deref() == Some("session1"))
+            .unwrap();
+        assert_eq!(session_route.path, "/funnelctl/abc12345");
+        assert_eq!(session_route.https_port, Some(8443));
+        assert!(session_route.funnel);
+        assert!(session_route.owned);
+        assert_eq!(routes.iter().filter(|route| route.owned).count(), 1);
+    }
+
+    #[test]
+    fn test_route_matches_filters() {
+        let config = config_with_background_and_session();
+        let routes = list_routes(&config).unwrap();
+
+        let by_prefix: Vec<_> = routes
+            .iter()
+            .filter(|route| route.matches(Some("/funnelctl/"), None))
+            .collect();
+        assert_eq!(by_prefix.len(), 1);
+
+        let by_port: Vec<_> = routes
+            .iter()
+            .filter(|route| route.matches(None, Some(443)))
+            .collect();
+        assert_eq!(by_port.len(), 2);
+    }
+
+    #[test]
+    fn test_remove_routes_prunes_empty_entries() {
+        let mut config = config_with_background_and_session();
+        let routes = list_routes(&config).unwrap();
+
+        let removed = remove_routes(&mut config, &routes).unwrap();
+        assert_eq!(removed, 3);
+        assert!(config.web.is_none());
+        assert!(config.allow_funnel.is_none());
+        assert!(list_routes(&config).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_remove_routes_keeps_unrelated_handlers() {
+        let mut config = config_with_background_and_session();
+        let routes: Vec<Route> = list_routes(&config)
+            .unwrap()
+            .into_iter()
+            .filter(|route| route.path == "/api/")
+            .collect();
+
+        assert_eq!(remove_routes(&mut config, &routes).unwrap(), 1);
+        let remaining = list_routes(&config).unwrap();
+        assert_eq!(remaining.len(), 2);
+        assert!(config.is_funnel_enabled("node.ts.net:443"));
+    }
+}
@@ -0,0 +1,300 @@
+//! Serve-config snapshots for `export` and `import`
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::lease::Lease;
+use super::patch::{describe_handler_target, ConflictIndex};
+use super::route::{host_port_port, list_routes, parse_session, remove_routes, Route};
+use super::types::ServeConfig;
+use crate::error::{FunnelError, Result};
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// What `export` writes: the serve config, and the leases of the detached tunnels in it
+/// so they are funnelctl's again after an import
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+    pub exported_at: DateTime<Utc>,
+    /// Only routes of active funnelctl leases were kept
+    pub owned_only: bool,
+    pub serve_config: ServeConfig,
+    #[serde(default)]
+    pub leases: Vec<Lease>,
+}
+
+impl Snapshot {
+    /// `live` as a snapshot; with `owned_only`, every route no lease in `leases` owns is
+    /// dropped, along with TCP forwards, services, and fields funnelctl does not know
+    pub fn new(live: &ServeConfig, leases: &[Lease], owned_only: bool) -> Result<Self> {
+        let mut serve_config = live.clone();
+        if owned_only {
+            let foreign: Vec<Route> = list_routes(live)?
+                .into_iter()
+                .filter(|route| !leases.iter().any(|lease| lease.owns(route)))
+                .collect();
+            remove_routes(&mut serve_config, &foreign)?;
+            if let Some(foreground) = serve_config.foreground.as_mut() {
+                let mut empty = Vec::new();
+                for (session, value) in foreground.iter() {
+                    if parse_session(value)?.web.is_none() {
+                        empty.push(session.clone());
+                    }
+                }
+                foreground.retain(|session, _| !empty.contains(session));
+                if foreground.is_empty() {
+                    serve_config.foreground = None;
+                }
+            }
+            serve_config.tcp = None;
+            serve_config.services = None;
+            serve_config.unknown_fields.clear();
+        }
+
+        let routes = list_routes(&serve_config)?;
+        let leases = leases
+            .iter()
+            .filter(|lease| {
+                lease.tunnel_spec.detached && routes.iter().any(|route| lease.owns(route))
+            })
+            .cloned()
+            .collect();
+        Ok(Self {
+            version: SNAPSHOT_VERSION,
+            exported_at: Utc::now(),
+            owned_only,
+            serve_config,
+            leases,
+        })
+    }
+
+    /// Reads an `export` file, or a bare ServeConfig such as `tailscale serve status --json`
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path).map_err(|err| {
+            FunnelError::InvalidArgument(format!("Failed to read {}: {}", path.display(), err))
+        })?;
+        let invalid = |err: serde_json::Error| {
+            FunnelError::InvalidArgument(format!("Invalid snapshot {}: {}", path.display(), err))
+        };
+        let value: serde_json::Value = serde_json::from_str(&contents).map_err(invalid)?;
+        if value.get("serve_config").is_none() {
+            return Ok(Self {
+                version: SNAPSHOT_VERSION,
+                exported_at: Utc::now(),
+                owned_only: false,
+                serve_config: serde_json::from_value(value).map_err(invalid)?,
+                leases: Vec::new(),
+            });
+        }
+        let snapshot: Self = serde_json::from_value(value).map_err(invalid)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(FunnelError::InvalidArgument(format!(
+                "Unsupported snapshot version {} (expected {})",
+                snapshot.version, SNAPSHOT_VERSION
+            )));
+        }
+        Ok(snapshot)
+    }
+}
+
+/// What happened to one background handler of a snapshot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RestoreOutcome {
+    Restored(Route),
+    /// The live config already has this exact handler
+    Present(Route),
+}
+
+/// Writes the snapshot's background HTTP handlers, with their AllowFunnel and TCP port
+/// entries, into `live`. Every handler is checked like `open` checks a new route, against
+/// the background config and every foreground session; unless `force`, conflicts fail
+/// the whole restore before `live` is touched, all of them reported together. Foreground sessions are not
+/// restored: they died with the `open` that owned them.
+pub fn restore_snapshot(
+    live: &mut ServeConfig,
+    snapshot: &ServeConfig,
+    force: bool,
+) -> Result<Vec<RestoreOutcome>> {
+    let background = ConflictIndex::new(live);
+    let mut sessions = Vec::new();
+    for (session, value) in live.foreground.iter().flatten() {
+        sessions.push((session.clone(), ConflictIndex::new(&parse_session(value)?)));
+    }
+
+    let mut outcomes = Vec::new();
+    let mut writes = Vec::new();
+    let mut conflicts = Vec::new();
+    for (host_port, web_config) in snapshot.web.iter().flatten() {
+        let funnel = snapshot.is_funnel_enabled(host_port);
+        for (path, handler) in web_config.handlers.iter().flatten() {
+            let target = describe_handler_target(handler);
+            let route = Route {
+                host_port: host_port.clone(),
+                https_port: host_port_port(host_port),
+                path: path.clone(),
+                target: target.clone(),
+                funnel,
+                session: None,
+                owned: handler.is_owned(),
+            };
+            let existing = live.get_handlers(host_port).and_then(|h| h.get(path));
+            if existing == Some(handler) && live.is_funnel_enabled(host_port) == funnel {
+                outcomes.push(RestoreOutcome::Present(route));
+                continue;
+            }
+            if !force {
+                let found = std::iter::once((None, &background))
+                    .chain(
+                        sessions
+                            .iter()
+                            .map(|(id, index)| (Some(id.as_str()), index)),
+                    )
+                    .filter_map(|(session, index)| {
+                        index
+                            .check(host_port, path, &target, funnel)
+                            .err()
+                            .map(|found| (session, found))
+                    });
+                for (session, found) in found {
+                    conflicts.extend(found.iter().map(|conflict| {
+                        let mut entry = conflict.entry(host_port, session);
+                        entry.message =
+                            format!("Cannot restore {}{}: {}", host_port, path, entry.message);
+                        entry
+                    }));
+                }
+            }
+            writes.push((host_port.clone(), path.clone(), handler.clone(), funnel));
+            outcomes.push(RestoreOutcome::Restored(route));
+        }
+    }
+    if !conflicts.is_empty() {
+        return Err(FunnelError::Conflicts {
+            conflicts,
+            suggested_path: None,
+        });
+    }
+
+    for (host_port, path, handler, funnel) in writes {
+        let web = live.web.get_or_insert_with(HashMap::new);
+        web.entry(host_port.clone())
+            .or_default()
+            .handlers
+            .get_or_insert_with(HashMap::new)
+            .insert(path, handler);
+        if funnel {
+            live.allow_funnel
+                .get_or_insert_with(HashMap::new)
+                .insert(host_port.clone(), true);
+        }
+        let port = host_port_port(&host_port);
+        let tcp = port.and_then(|port| snapshot.tcp.as_ref()?.get(&port).map(|tcp| (port, tcp)));
+        if let Some((port, tcp)) = tcp {
+            live.tcp
+                .get_or_insert_with(HashMap::new)
+                .entry(port)
+                .or_insert_with(|| tcp.clone());
+        }
+    }
+    Ok(outcomes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::spec::{LocalTarget, TunnelSpec};
+    use crate::core::{apply_background_patch, apply_patch};
+
+    const HOST_PORT: &str = "node.tail1234.ts.net:443";
+
+    fn detached_lease(path: &str, port: u16) -> Lease {
+        let spec = TunnelSpec::new(
+            LocalTarget::new("127.0.0.1".to_string(), port),
+            443,
+            path.to_string(),
+            true,
+        )
+        .detached();
+        Lease::new(format!("detached-{}", port), spec, None)
+    }
+
+    fn live() -> ServeConfig {
+        let mut config = ServeConfig::new();
+        apply_background_patch(
+            &mut config,
+            HOST_PORT,
+            "/ours",
+            "http://127.0.0.1:3000",
+            true,
+        );
+        apply_background_patch(
+            &mut config,
+            HOST_PORT,
+            "/docs",
+            "http://127.0.0.1:8080",
+            false,
+        );
+        apply_patch(
+            &mut config,
+            "s1",
+            HOST_PORT,
+            "/fg",
+            "http://127.0.0.1:4000",
+            true,
+        )
+        .unwrap();
+        config
+    }
+
+    #[test]
+    fn test_owned_export_keeps_only_lease_routes() {
+        let lease = detached_lease("/ours", 3000);
+        let snapshot = Snapshot::new(&live(), &[lease], true).unwrap();
+        let routes = list_routes(&snapshot.serve_config).unwrap();
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].path, "/ours");
+        assert!(snapshot.serve_config.foreground.is_none());
+        assert_eq!(snapshot.leases.len(), 1);
+
+        let full = Snapshot::new(&live(), &[], false).unwrap();
+        assert_eq!(full.serve_config, live());
+        assert!(full.leases.is_empty());
+    }
+
+    #[test]
+    fn test_restore_is_idempotent_and_skips_foreground() {
+        let snapshot = live();
+        let mut target = ServeConfig::new();
+        let outcomes = restore_snapshot(&mut target, &snapshot, false).unwrap();
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, RestoreOutcome::Restored(_))));
+        assert!(target.foreground.is_none());
+        assert!(target.is_funnel_enabled(HOST_PORT));
+
+        let again = restore_snapshot(&mut target, &snapshot, false).unwrap();
+        assert!(again
+            .iter()
+            .all(|outcome| matches!(outcome, RestoreOutcome::Present(_))));
+    }
+
+    #[test]
+    fn test_restore_conflict_writes_nothing() {
+        let mut snapshot = ServeConfig::new();
+        apply_background_patch(&mut snapshot, HOST_PORT, "/new", "http://127.0.0.1:1", true);
+        apply_background_patch(&mut snapshot, HOST_PORT, "/fg", "http://127.0.0.1:2", true);
+
+        let mut target = live();
+        let err = restore_snapshot(&mut target, &snapshot, false).unwrap_err();
+        assert_eq!(err.exit_code(), 13);
+        assert_eq!(target, live());
+
+        restore_snapshot(&mut target, &snapshot, true).unwrap();
+        assert!(target.get_handlers(HOST_PORT).unwrap().contains_key("/new"));
+    }
+}
@@ -40,6 +40,18 @@ pub struct TunnelSpec {
     pub https_port: u16,
     pub path: String,
     pub funnel: bool,
+    /// Local proxy fronting `local_target`; tailscaled forwards here when set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub via: Option<LocalTarget>,
+    /// Written to the background config so the route outlives the funnelctl process
+    #[serde(default)]
+    pub detached: bool,
+    /// Host for the URL and the ServeConfig `host:port` key instead of the node's DNS name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_name: Option<String>,
+    /// More HTTPS ports serving the same path and handler (`open --all-https-ports`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirror_ports: Vec<u16>,
 }
 
 impl TunnelSpec {
@@ -49,8 +61,42 @@ impl TunnelSpec {
             https_port,
             path,
             funnel,
+            via: None,
+            detached: false,
+            dns_name: None,
+            mirror_ports: Vec::new(),
         }
     }
+
+    pub fn with_proxy(mut self, proxy: LocalTarget) -> Self {
+        self.via = Some(proxy);
+        self
+    }
+
+    pub fn detached(mut self) -> Self {
+        self.detached = true;
+        self
+    }
+
+    pub fn with_dns_name(mut self, dns_name: impl Into<String>) -> Self {
+        self.dns_name = Some(dns_name.into());
+        self
+    }
+
+    pub fn with_mirror_ports(mut self, ports: Vec<u16>) -> Self {
+        self.mirror_ports = ports;
+        self
+    }
+
+    /// Every HTTPS port the route is installed on, `https_port` first
+    pub fn https_ports(&self) -> impl Iterator<Item = u16> + '_ {
+        std::iter::once(self.https_port).chain(self.mirror_ports.iter().copied())
+    }
+
+    /// The target written into the ServeConfig handler
+    pub fn serve_target(&self) -> &LocalTarget {
+        self.via.as_ref().unwrap_or(&self.local_target)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -59,6 +105,9 @@ pub struct TunnelResult {
     pub lease_id: String,
     pub applied_at: chrono::DateTime<chrono::Utc>,
     pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// URLs on the spec's `mirror_ports`, in the same order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mirror_urls: Vec<Url>,
 }
 
 #[cfg(test)]
@@ -88,5 +137,17 @@ mod tests {
         assert_eq!(spec.https_port, 443);
         assert_eq!(spec.path, "/funnelctl/test");
         assert!(spec.funnel);
+        assert_eq!(spec.serve_target().to_string(), "http://127.0.0.1:8081");
+    }
+
+    #[test]
+    fn test_tunnel_spec_serve_target_uses_proxy() {
+        let target = LocalTarget::new("127.0.0.1".to_string(), 8081);
+        let proxy = LocalTarget::new("127.0.0.1".to_string(), 40000);
+        let spec =
+            TunnelSpec::new(target, 443, "/funnelctl/test".to_string(), true).with_proxy(proxy);
+
+        assert_eq!(spec.local_target.to_string(), "http://127.0.0.1:8081");
+        assert_eq!(spec.serve_target().to_string(), "http://127.0.0.1:40000");
     }
 }
@@ -7,12 +7,16 @@ use std::collections::HashMap;
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct ServeConfig {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub tcp: Option<HashMap<u16, Value>>,
+    #[serde(rename = "TCP", skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<HashMap<u16, TcpPortHandler>>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub web: Option<HashMap<String, WebServerConfig>>,
 
+    /// Tailscale Services (VIP services) hosted by this node, keyed by `svc:<name>`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub services: Option<HashMap<String, ServiceConfig>>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub allow_funnel: Option<HashMap<String, bool>>,
 
@@ -37,6 +41,7 @@ impl ServeConfig {
         Self {
             tcp: None,
             web: None,
+            services: None,
             allow_funnel: None,
             foreground: None,
             unknown_fields: HashMap::new(),
@@ -61,6 +66,53 @@ impl ServeConfig {
     }
 }
 
+/// TcpPortHandler says what tailscaled does with connections to one TCP port
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TcpPortHandler {
+    /// Terminate TLS and serve the port's `Web` handlers
+    #[serde(rename = "HTTPS", default, skip_serializing_if = "is_false")]
+    pub https: bool,
+
+    /// Serve the port's `Web` handlers over plain HTTP
+    #[serde(rename = "HTTP", default, skip_serializing_if = "is_false")]
+    pub http: bool,
+
+    /// Forward raw TCP to this `host:port`
+    #[serde(rename = "TCPForward", skip_serializing_if = "Option::is_none")]
+    pub tcp_forward: Option<String>,
+
+    /// With `TCPForward`, terminate TLS for this SNI name first
+    #[serde(rename = "TerminateTLS", skip_serializing_if = "Option::is_none")]
+    pub terminate_tls: Option<String>,
+
+    /// Preserve any unknown fields for round-trip compatibility
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, Value>,
+}
+
+/// ServiceConfig is the serve configuration of one Tailscale Service
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct ServiceConfig {
+    #[serde(rename = "TCP", skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<HashMap<u16, TcpPortHandler>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub web: Option<HashMap<String, WebServerConfig>>,
+
+    /// Traffic is delivered to the host's TUN device instead of handlers
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub tun: bool,
+
+    /// Preserve any unknown fields for round-trip compatibility
+    #[serde(flatten)]
+    pub unknown_fields: HashMap<String, Value>,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
 /// WebServerConfig represents configuration for a specific host:port
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
@@ -89,6 +141,11 @@ impl Default for WebServerConfig {
     }
 }
 
+/// Capability name funnelctl lists in `AcceptAppCaps` on the handlers it installs. No
+/// peer is ever granted it, so tailscaled forwards nothing extra, but it keeps the field
+/// across writes and restarts where it drops unknown ones.
+pub const OWNER_MARKER: &str = "github.com/reidond/funnelctl/cap/owned";
+
 /// HttpHandler represents a handler for a specific path
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "PascalCase")]
@@ -102,6 +159,10 @@ pub struct HttpHandler {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub text: Option<String>,
 
+    /// Peer capabilities forwarded to the backend in the `Tailscale-App-Capabilities` header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub accept_app_caps: Option<Vec<String>>,
+
     /// Preserve any unknown fields for round-trip compatibility
     #[serde(flatten)]
     pub unknown_fields: HashMap<String, Value>,
@@ -114,10 +175,27 @@ impl HttpHandler {
             proxy: Some(target),
             path: None,
             text: None,
+            accept_app_caps: None,
             unknown_fields: HashMap::new(),
         }
     }
 
+    /// Tags the handler as installed by funnelctl (see [`OWNER_MARKER`])
+    pub fn owned(mut self) -> Self {
+        let caps = self.accept_app_caps.get_or_insert_with(Vec::new);
+        if !caps.iter().any(|cap| cap == OWNER_MARKER) {
+            caps.push(OWNER_MARKER.to_string());
+        }
+        self
+    }
+
+    /// Whether the handler carries funnelctl's ownership marker
+    pub fn is_owned(&self) -> bool {
+        self.accept_app_caps
+            .as_ref()
+            .is_some_and(|caps| caps.iter().any(|cap| cap == OWNER_MARKER))
+    }
+
     /// Gets the target URL for a proxy handler
     pub fn get_proxy_target(&self) -> Option<&str> {
         self.proxy.as_deref()
@@ -225,6 +303,54 @@ mod tests {
         assert!(serialized.get("ExtraField").is_some());
     }
 
+    #[test]
+    fn test_typed_tcp_services_and_handler_options() {
+        let json = r#"{
+            "TCP": {
+                "443": {"HTTPS": true},
+                "5432": {"TCPForward": "127.0.0.1:5432", "TerminateTLS": "db.ts.net"}
+            },
+            "Services": {
+                "svc:web": {
+                    "TCP": {"443": {"HTTPS": true}},
+                    "Web": {
+                        "web.ts.net:443": {
+                            "Handlers": {
+                                "/": {"Proxy": "http://127.0.0.1:3000", "AcceptAppCaps": ["example.com/cap/read"]}
+                            }
+                        }
+                    }
+                },
+                "svc:vpn": {"Tun": true}
+            }
+        }"#;
+
+        let config: ServeConfig = serde_json::from_str(json).unwrap();
+        let tcp = config.tcp.as_ref().unwrap();
+        assert!(tcp[&443].https);
+        assert!(!tcp[&443].http);
+        assert_eq!(tcp[&5432].tcp_forward.as_deref(), Some("127.0.0.1:5432"));
+        assert_eq!(tcp[&5432].terminate_tls.as_deref(), Some("db.ts.net"));
+
+        let services = config.services.as_ref().unwrap();
+        assert!(services["svc:vpn"].tun);
+        let handler = &services["svc:web"].web.as_ref().unwrap()["web.ts.net:443"]
+            .handlers
+            .as_ref()
+            .unwrap()["/"];
+        assert_eq!(
+            handler.accept_app_caps.as_deref(),
+            Some(&["example.com/cap/read".to_string()][..])
+        );
+        assert!(!handler.is_owned());
+        let owned = handler.clone().owned().owned();
+        assert!(owned.is_owned());
+        assert_eq!(owned.accept_app_caps.as_ref().map(Vec::len), Some(2));
+
+        let original: Value = serde_json::from_str(json).unwrap();
+        assert_eq!(serde_json::to_value(&config).unwrap(), original);
+    }
+
     #[test]
     fn test_path_mapping_prefix_detection() {
         let prefix = PathMapping::new("/api/".to_string(), "target".to_string(), false);
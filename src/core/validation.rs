@@ -1,4 +1,7 @@
 use crate::error::{FunnelError, Result};
+use chrono::{DateTime, Local, NaiveTime, TimeZone};
+use serde::Deserialize;
+use std::fmt;
 use std::time::Duration;
 
 /// Validation warnings returned for informational purposes
@@ -8,6 +11,130 @@ pub enum ValidationWarning {
     PathTooShort { path: String, length: usize },
     /// TTL is less than 5 minutes
     TtlTooShort { ttl: Duration },
+    /// Path is under a reserved prefix, allowed by `--allow-reserved-path`
+    ReservedPath { path: String, reserved: String },
+    /// Path is within the limits but longer or deeper than a shareable URL should be
+    PathTooLong { length: usize, segments: usize },
+    /// Another active tunnel already exposes the same bind:port, under `url`
+    TargetAlreadyExposed {
+        target: String,
+        url: String,
+        name: String,
+    },
+}
+
+/// Stable name of a `ValidationWarning`, for `open.ignore_warnings` and `--deny-warnings`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WarningId {
+    PathTooShort,
+    TtlTooShort,
+    ReservedPath,
+    PathTooLong,
+    TargetAlreadyExposed,
+}
+
+impl WarningId {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WarningId::PathTooShort => "path_too_short",
+            WarningId::TtlTooShort => "ttl_too_short",
+            WarningId::ReservedPath => "reserved_path",
+            WarningId::PathTooLong => "path_too_long",
+            WarningId::TargetAlreadyExposed => "target_already_exposed",
+        }
+    }
+}
+
+impl fmt::Display for WarningId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl ValidationWarning {
+    pub fn id(&self) -> WarningId {
+        match self {
+            ValidationWarning::PathTooShort { .. } => WarningId::PathTooShort,
+            ValidationWarning::TtlTooShort { .. } => WarningId::TtlTooShort,
+            ValidationWarning::ReservedPath { .. } => WarningId::ReservedPath,
+            ValidationWarning::PathTooLong { .. } => WarningId::PathTooLong,
+            ValidationWarning::TargetAlreadyExposed { .. } => WarningId::TargetAlreadyExposed,
+        }
+    }
+}
+
+impl fmt::Display for ValidationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationWarning::PathTooShort { path, .. } => write!(
+                f,
+                "Short path '{}' is guessable. Consider a longer path or use default random path.",
+                path
+            ),
+            ValidationWarning::ReservedPath { path, reserved } => write!(
+                f,
+                "Path '{}' is under reserved prefix '{}' and will be public.",
+                path, reserved
+            ),
+            ValidationWarning::PathTooLong { length, segments } => write!(
+                f,
+                "Long path ({} bytes, {} segments). The URL may break when shared.",
+                length, segments
+            ),
+            ValidationWarning::TtlTooShort { ttl } => write!(
+                f,
+                "Short TTL ({}). Tunnel expires quickly.",
+                humantime::format_duration(*ttl)
+            ),
+            ValidationWarning::TargetAlreadyExposed { target, url, name } => write!(
+                f,
+                "{} is already exposed at {} (tunnel {}); this adds a second public URL for it.",
+                target, url, name
+            ),
+        }
+    }
+}
+
+/// Prefixes `open` refuses by default: ACME and other well-known endpoints, and app
+/// routes that should not be public
+pub const DEFAULT_RESERVED_PATHS: &[&str] = &["/.well-known", "/metrics", "/debug"];
+
+/// Hard limits; longer paths make URLs that break in chat clients and request-line limits
+pub const DEFAULT_MAX_PATH_LENGTH: usize = 1024;
+pub const DEFAULT_MAX_PATH_SEGMENTS: usize = 32;
+/// Above these, still under the hard limits, `PathTooLong` is reported
+const PATH_LENGTH_WARNING: usize = 256;
+const PATH_SEGMENTS_WARNING: usize = 16;
+
+/// Checks `validate_path_with` adds on top of the structural ones in `validate_path`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathPolicy {
+    /// Prefixes refused unless `allow_reserved` is set
+    pub reserved: Vec<String>,
+    /// Downgrade a reserved-path error to a warning
+    pub allow_reserved: bool,
+    /// Only allow `/` and unreserved URL characters, so the URL needs no percent-encoding
+    pub strict: bool,
+    /// Longest accepted path in bytes, after normalization
+    pub max_length: usize,
+    /// Most accepted non-empty segments
+    pub max_segments: usize,
+}
+
+impl Default for PathPolicy {
+    fn default() -> Self {
+        Self {
+            reserved: DEFAULT_RESERVED_PATHS
+                .iter()
+                .map(|path| path.to_string())
+                .collect(),
+            allow_reserved: false,
+            strict: true,
+            max_length: DEFAULT_MAX_PATH_LENGTH,
+            max_segments: DEFAULT_MAX_PATH_SEGMENTS,
+        }
+    }
 }
 
 /// Result of path validation including normalized path and any warnings
@@ -80,6 +207,72 @@ pub fn validate_path(path: &str) -> Result<PathValidationResult> {
     })
 }
 
+/// `validate_path`, then the checks in `policy`
+pub fn validate_path_with(path: &str, policy: &PathPolicy) -> Result<PathValidationResult> {
+    let mut result = validate_path(path)?;
+    let length = result.normalized_path.len();
+    let segments = result
+        .normalized_path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .count();
+    if length > policy.max_length {
+        return Err(FunnelError::InvalidArgument(format!(
+            "path is {} bytes long; the limit is {}",
+            length, policy.max_length
+        )));
+    }
+    if segments > policy.max_segments {
+        return Err(FunnelError::InvalidArgument(format!(
+            "path has {} segments; the limit is {}",
+            segments, policy.max_segments
+        )));
+    }
+    if length > PATH_LENGTH_WARNING || segments > PATH_SEGMENTS_WARNING {
+        result
+            .warnings
+            .push(ValidationWarning::PathTooLong { length, segments });
+    }
+
+    if policy.strict {
+        if let Some(ch) = result.normalized_path.chars().find(|&ch| !is_url_safe(ch)) {
+            return Err(FunnelError::InvalidArgument(format!(
+                "path contains {:?}, which needs percent-encoding in a URL; use A-Z a-z 0-9 - . _ ~ / or pass --lax-path",
+                ch
+            )));
+        }
+    }
+    if let Some(reserved) = reserved_prefix(&result.normalized_path, &policy.reserved) {
+        if !policy.allow_reserved {
+            return Err(FunnelError::InvalidArgument(format!(
+                "path '{}' is under reserved prefix '{}'; pass --allow-reserved-path to publish it anyway",
+                result.normalized_path, reserved
+            )));
+        }
+        result.warnings.push(ValidationWarning::ReservedPath {
+            path: result.normalized_path.clone(),
+            reserved: reserved.to_string(),
+        });
+    }
+    Ok(result)
+}
+
+/// RFC 3986 unreserved characters, plus the segment separator
+fn is_url_safe(ch: char) -> bool {
+    ch.is_ascii_alphanumeric() || matches!(ch, '/' | '-' | '.' | '_' | '~')
+}
+
+/// The reserved prefix `path` equals or sits below, compared by whole segments
+fn reserved_prefix<'a>(path: &str, reserved: &'a [String]) -> Option<&'a str> {
+    reserved.iter().map(String::as_str).find(|prefix| {
+        let prefix = prefix.trim_end_matches('/');
+        !prefix.is_empty()
+            && path
+                .strip_prefix(prefix)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
 /// Normalizes consecutive slashes to single slashes
 fn normalize_slashes(path: &str) -> String {
     let mut result = String::with_capacity(path.len());
@@ -100,12 +293,22 @@ fn normalize_slashes(path: &str) -> String {
     result
 }
 
+/// TTLs below this get a `TtlTooShort` warning unless the config sets `open.ttl_warning`
+pub const DEFAULT_TTL_WARNING: Duration = Duration::from_secs(5 * 60);
+
 /// Validates TTL according to the specification:
 /// - Minimum 30 seconds (hard requirement)
 /// - Warns if < 5 minutes
 pub fn validate_ttl(ttl: Duration) -> Result<TtlValidationResult> {
+    validate_ttl_with_warning(ttl, DEFAULT_TTL_WARNING)
+}
+
+/// Like `validate_ttl`, warning below `warn_below` instead; zero never warns
+pub fn validate_ttl_with_warning(
+    ttl: Duration,
+    warn_below: Duration,
+) -> Result<TtlValidationResult> {
     const MIN_TTL: Duration = Duration::from_secs(30);
-    const WARN_TTL: Duration = Duration::from_secs(5 * 60);
 
     if ttl < MIN_TTL {
         return Err(FunnelError::InvalidArgument(format!(
@@ -116,13 +319,106 @@ pub fn validate_ttl(ttl: Duration) -> Result<TtlValidationResult> {
     }
 
     let mut warnings = Vec::new();
-    if ttl < WARN_TTL {
+    if ttl < warn_below {
         warnings.push(ValidationWarning::TtlTooShort { ttl });
     }
 
     Ok(TtlValidationResult { ttl, warnings })
 }
 
+/// Parses a `--ttl` value: a duration (`30m`, `1h 30m`), or a deadline given as
+/// `until HH:MM[:SS]` (local time, today) or an RFC 3339 timestamp (with or without
+/// `until`). Deadlines become the time left from `now` and must be in the future.
+pub fn parse_ttl(value: &str, now: DateTime<Local>) -> Result<Duration> {
+    let trimmed = value.trim();
+    let deadline_text = trimmed.strip_prefix("until ").map(str::trim);
+
+    if let Ok(deadline) = DateTime::parse_from_rfc3339(deadline_text.unwrap_or(trimmed)) {
+        return time_until(value, deadline.with_timezone(&Local), now);
+    }
+
+    let Some(deadline_text) = deadline_text else {
+        return humantime::parse_duration(trimmed).map_err(|err| {
+            FunnelError::InvalidArgument(format!(
+                "Invalid TTL '{}': {}; use a duration like 30m, `until 18:00`, or an RFC 3339 time",
+                value, err
+            ))
+        });
+    };
+
+    let time = NaiveTime::parse_from_str(deadline_text, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(deadline_text, "%H:%M:%S"))
+        .map_err(|_| {
+            FunnelError::InvalidArgument(format!(
+                "Invalid TTL '{}': expected `until HH:MM` or `until <RFC 3339 time>`",
+                value
+            ))
+        })?;
+    let deadline = today_at(time, now).ok_or_else(|| {
+        FunnelError::InvalidArgument(format!(
+            "Invalid TTL '{}': {} does not exist today in the local time zone",
+            value, deadline_text
+        ))
+    })?;
+    time_until(value, deadline, now)
+}
+
+/// Parses an `open --at` value: `HH:MM[:SS]` (local time, today) or an RFC 3339
+/// timestamp. Like TTL deadlines, a time that already passed is rejected rather than
+/// moved to tomorrow.
+pub fn parse_start_time(value: &str, now: DateTime<Local>) -> Result<DateTime<Local>> {
+    let trimmed = value.trim();
+    let starts_at = match DateTime::parse_from_rfc3339(trimmed) {
+        Ok(at) => at.with_timezone(&Local),
+        Err(_) => {
+            let time = NaiveTime::parse_from_str(trimmed, "%H:%M")
+                .or_else(|_| NaiveTime::parse_from_str(trimmed, "%H:%M:%S"))
+                .map_err(|_| {
+                    FunnelError::InvalidArgument(format!(
+                        "Invalid start time '{}': expected HH:MM or an RFC 3339 time",
+                        value
+                    ))
+                })?;
+            today_at(time, now).ok_or_else(|| {
+                FunnelError::InvalidArgument(format!(
+                    "Invalid start time '{}': it does not exist today in the local time zone",
+                    value
+                ))
+            })?
+        }
+    };
+    if starts_at <= now {
+        return Err(FunnelError::InvalidArgument(format!(
+            "Start time '{}' ({}) is not in the future",
+            value,
+            starts_at.format("%Y-%m-%d %H:%M:%S %Z")
+        )));
+    }
+    Ok(starts_at)
+}
+
+/// A wall-clock time skipped by a DST change has no instant; an ambiguous one takes the
+/// earlier
+fn today_at(time: NaiveTime, now: DateTime<Local>) -> Option<DateTime<Local>> {
+    Local
+        .from_local_datetime(&now.date_naive().and_time(time))
+        .earliest()
+}
+
+fn time_until(value: &str, deadline: DateTime<Local>, now: DateTime<Local>) -> Result<Duration> {
+    (deadline - now)
+        .to_std()
+        .ok()
+        .filter(|left| !left.is_zero())
+        .ok_or_else(|| {
+            FunnelError::InvalidArgument(format!(
+                "TTL deadline '{}' ({}) is not in the future",
+                value,
+                deadline.format("%Y-%m-%d %H:%M:%S %Z")
+            ))
+        })
+}
+
 /// Validates port number is in valid range (1-65535)
 pub fn validate_port(port: u16) -> Result<()> {
     if port == 0 {
@@ -133,19 +429,148 @@ pub fn validate_port(port: u16) -> Result<()> {
     Ok(())
 }
 
+/// Parses a port written by another tool (`$PORT`, a `.port` file); `source` names it
+/// in errors. Surrounding whitespace is ignored.
+pub fn parse_port(value: &str, source: &str) -> Result<u16> {
+    value
+        .trim()
+        .parse::<u16>()
+        .ok()
+        .filter(|&port| port != 0)
+        .ok_or_else(|| {
+            FunnelError::InvalidArgument(format!(
+                "{} holds '{}', not a port between 1 and 65535",
+                source,
+                value.trim()
+            ))
+        })
+}
+
+/// HTTPS ports tailscaled accepts for Funnel
+pub const ALLOWED_HTTPS_PORTS: &[u16] = &[443, 8443, 10000];
+
 /// Validates HTTPS port is one of the allowed values (443, 8443, 10000)
 pub fn validate_https_port(port: u16) -> Result<()> {
-    const ALLOWED_PORTS: &[u16] = &[443, 8443, 10000];
-
-    if !ALLOWED_PORTS.contains(&port) {
+    if !ALLOWED_HTTPS_PORTS.contains(&port) {
         return Err(FunnelError::InvalidArgument(format!(
             "HTTPS port must be one of {:?}, got {}",
-            ALLOWED_PORTS, port
+            ALLOWED_HTTPS_PORTS, port
+        )));
+    }
+    Ok(())
+}
+
+/// Normalizes a `--dns-name` override: lowercase ASCII (punycode for unicode names), no
+/// trailing dot, and a domain rather than an IP address or `host:port`
+pub fn normalize_dns_name(name: &str) -> Result<String> {
+    let trimmed = name.trim().trim_end_matches('.');
+    match url::Host::parse(trimmed) {
+        Ok(url::Host::Domain(domain)) if domain.contains('.') && !domain.contains(':') => {
+            Ok(domain)
+        }
+        _ => Err(FunnelError::InvalidArgument(format!(
+            "'{}' is not a fully qualified DNS name",
+            name
+        ))),
+    }
+}
+
+/// Parses `--public-url-base`: an `http` or `https` URL with a host and no credentials,
+/// query or fragment. Its path, if any, is kept as a prefix.
+pub fn parse_public_url_base(value: &str) -> Result<url::Url> {
+    let invalid = |reason: &str| {
+        FunnelError::InvalidArgument(format!("Invalid public URL base '{}': {}", value, reason))
+    };
+    let url = url::Url::parse(value).map_err(|err| invalid(&err.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid("scheme must be http or https"));
+    }
+    if url.host_str().is_none() {
+        return Err(invalid("missing host"));
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err(invalid("credentials are not allowed"));
+    }
+    if url.query().is_some() || url.fragment().is_some() {
+        return Err(invalid("query and fragment are not allowed"));
+    }
+    Ok(url)
+}
+
+/// Validates a tunnel name: 1-63 characters of `a-z`, `0-9`, `-`, `_` or `.`,
+/// starting with a letter or digit
+pub fn validate_name(name: &str) -> Result<()> {
+    const MAX_NAME_LEN: usize = 63;
+
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(FunnelError::InvalidArgument(format!(
+            "name must be 1-{} characters, got {}",
+            MAX_NAME_LEN,
+            name.len()
+        )));
+    }
+    if !name.starts_with(|c: char| c.is_ascii_lowercase() || c.is_ascii_digit()) {
+        return Err(FunnelError::InvalidArgument(format!(
+            "name '{}' must start with a lowercase letter or digit",
+            name
+        )));
+    }
+    if let Some(bad) = name
+        .chars()
+        .find(|&c| !(c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_' | '.')))
+    {
+        return Err(FunnelError::InvalidArgument(format!(
+            "name '{}' contains '{}'; use a-z, 0-9, '-', '_' or '.'",
+            name, bad
         )));
     }
     Ok(())
 }
 
+/// Longest `--expose-for` value; it is a note, not a document
+pub const MAX_EXPOSE_FOR_LEN: usize = 200;
+
+/// Checks an `--expose-for` note: a tailnet user, a device, or free text. Surrounding
+/// whitespace is dropped; the rest must be non-empty, printable, and short.
+pub fn parse_expose_for(raw: &str) -> Result<String> {
+    let value = raw.trim();
+    if value.is_empty() || value.chars().any(|c| c.is_control()) {
+        return Err(FunnelError::InvalidArgument(
+            "needs a non-empty printable value, e.g. alice@example.com or ci-runner".to_string(),
+        ));
+    }
+    if value.chars().count() > MAX_EXPOSE_FOR_LEN {
+        return Err(FunnelError::InvalidArgument(format!(
+            "must be at most {} characters",
+            MAX_EXPOSE_FOR_LEN
+        )));
+    }
+    Ok(value.to_string())
+}
+
+/// Parses a `key=value` tag. Keys follow the name rules; values are non-empty and printable.
+pub fn parse_tag(raw: &str) -> Result<(String, String)> {
+    let Some((key, value)) = raw.split_once('=') else {
+        return Err(FunnelError::InvalidArgument(format!(
+            "tag '{}' must look like key=value",
+            raw
+        )));
+    };
+    validate_name(key).map_err(|_| {
+        FunnelError::InvalidArgument(format!(
+            "tag key '{}' must use a-z, 0-9, '-', '_' or '.' and start with a letter or digit",
+            key
+        ))
+    })?;
+    if value.is_empty() || value.chars().any(|c| c.is_control()) {
+        return Err(FunnelError::InvalidArgument(format!(
+            "tag '{}' needs a non-empty printable value",
+            raw
+        )));
+    }
+    Ok((key.to_string(), value.to_string()))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -291,4 +716,19 @@ mod tests {
             _ => panic!("Expected InvalidArgument error"),
         }
     }
+
+    #[test]
+    fn test_parse_expose_for() {
+        assert_eq!(
+            parse_expose_for("  alice@example.com ").unwrap(),
+            "alice@example.com"
+        );
+        assert_eq!(
+            parse_expose_for("Stripe webhook tests").unwrap(),
+            "Stripe webhook tests"
+        );
+        assert!(parse_expose_for("  ").is_err());
+        assert!(parse_expose_for("a\tb").is_err());
+        assert!(parse_expose_for(&"x".repeat(MAX_EXPOSE_FOR_LEN + 1)).is_err());
+    }
 }
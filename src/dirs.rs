@@ -1,7 +1,10 @@
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 use crate::error::{FunnelError, Result};
 
+static STATE_DIR_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
 pub fn runtime_dir() -> Result<PathBuf> {
     if let Some(path) = std::env::var_os("XDG_RUNTIME_DIR") {
         let path = PathBuf::from(path).join("funnelctl");
@@ -15,19 +18,28 @@ pub fn runtime_dir() -> Result<PathBuf> {
 }
 
 pub fn state_dir() -> Result<PathBuf> {
-    if let Some(path) = std::env::var_os("XDG_STATE_HOME") {
-        let path = PathBuf::from(path).join("funnelctl");
-        ensure_dir(&path)?;
-        return Ok(path);
+    if let Some(path) = STATE_DIR_OVERRIDE.get() {
+        ensure_dir(path)?;
+        return Ok(path.clone());
     }
 
-    if cfg!(target_os = "macos") {
-        let path = home_dir()?.join("Library/Application Support/funnelctl");
+    if let Some(path) = std::env::var_os("XDG_STATE_HOME") {
+        let path = PathBuf::from(path).join("funnelctl");
         ensure_dir(&path)?;
         return Ok(path);
     }
 
-    let path = home_dir()?.join(".local/state/funnelctl");
+    let path = if cfg!(windows) {
+        local_app_data().map(|dir| dir.join("funnelctl").join("state"))
+    } else if cfg!(target_os = "macos") {
+        home_dir().map(|home| home.join("Library/Application Support/funnelctl"))
+    } else {
+        home_dir().map(|home| home.join(".local/state/funnelctl"))
+    };
+    let path = match path {
+        Ok(path) => path,
+        Err(_) => fallback_dir()?,
+    };
     ensure_dir(&path)?;
     Ok(path)
 }
@@ -39,13 +51,17 @@ pub fn config_dir() -> Result<PathBuf> {
         return Ok(path);
     }
 
-    if cfg!(target_os = "macos") {
-        let path = home_dir()?.join("Library/Application Support/funnelctl");
-        ensure_dir(&path)?;
-        return Ok(path);
-    }
-
-    let path = home_dir()?.join(".config/funnelctl");
+    let path = if cfg!(windows) {
+        windows_folder("APPDATA", "AppData\\Roaming").map(|dir| dir.join("funnelctl"))
+    } else if cfg!(target_os = "macos") {
+        home_dir().map(|home| home.join("Library/Application Support/funnelctl"))
+    } else {
+        home_dir().map(|home| home.join(".config/funnelctl"))
+    };
+    let path = match path {
+        Ok(path) => path,
+        Err(_) => fallback_dir()?.join("config"),
+    };
     ensure_dir(&path)?;
     Ok(path)
 }
@@ -57,24 +73,134 @@ pub fn cache_dir() -> Result<PathBuf> {
         return Ok(path);
     }
 
-    if cfg!(target_os = "macos") {
-        let path = home_dir()?.join("Library/Caches/funnelctl");
-        ensure_dir(&path)?;
-        return Ok(path);
-    }
-
-    let path = home_dir()?.join(".cache/funnelctl");
+    let path = if cfg!(windows) {
+        local_app_data().map(|dir| dir.join("funnelctl").join("cache"))
+    } else if cfg!(target_os = "macos") {
+        home_dir().map(|home| home.join("Library/Caches/funnelctl"))
+    } else {
+        home_dir().map(|home| home.join(".cache/funnelctl"))
+    };
+    let path = match path {
+        Ok(path) => path,
+        Err(_) => fallback_dir()?.join("cache"),
+    };
     ensure_dir(&path)?;
     Ok(path)
 }
 
+/// Uses `path` as the state directory for the rest of the process (`--state-dir`).
+/// Relative paths are resolved against the current directory.
+pub fn set_state_dir(path: PathBuf) -> Result<()> {
+    let path = if path.is_absolute() {
+        path
+    } else {
+        std::env::current_dir()
+            .map_err(|err| {
+                FunnelError::Other(format!("Failed to resolve current directory: {}", err))
+            })?
+            .join(path)
+    };
+    let _ = STATE_DIR_OVERRIDE.set(path);
+    Ok(())
+}
+
+/// Base directory when HOME is unset, as in systemd units and containers:
+/// `/run/user/<uid>/funnelctl` if the per-user runtime dir exists, else a private
+/// `$TMPDIR/funnelctl-<uid>`
+fn fallback_dir() -> Result<PathBuf> {
+    #[cfg(unix)]
+    let path = {
+        let uid = unsafe { libc::getuid() };
+        let run_user = PathBuf::from(format!("/run/user/{}", uid));
+        if run_user.is_dir() {
+            run_user.join("funnelctl")
+        } else {
+            std::env::temp_dir().join(format!("funnelctl-{}", uid))
+        }
+    };
+    #[cfg(not(unix))]
+    let path = std::env::temp_dir().join("funnelctl");
+
+    tracing::debug!(path = %path.display(), "HOME is not set; using fallback directory");
+    ensure_private_dir(&path)?;
+    Ok(path)
+}
+
+/// Maps an identifier to a safe file name stem (anything outside `[A-Za-z0-9_-]` becomes `_`)
+pub fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|ch| {
+            if ch.is_ascii_alphanumeric() || ch == '-' || ch == '_' {
+                ch
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Writes a file atomically with owner-only permissions (0600 on Unix). The contents go
+/// to a fresh hidden file next to `path` first, named per process and call, so two
+/// writers never share a temp file and a planted one is never opened.
+pub fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tmp = path.with_file_name(format!(
+        ".{}.{}.{:08x}.tmp",
+        name,
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    let mut options = std::fs::OpenOptions::new();
+    options.create_new(true).write(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let write = |options: &std::fs::OpenOptions| -> std::io::Result<()> {
+        use std::io::Write;
+        let mut file = options.open(&tmp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path)
+    };
+    write(&options).map_err(|err| {
+        // Never remove a file someone else created under our name
+        if err.kind() != std::io::ErrorKind::AlreadyExists {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        FunnelError::Other(format!("Failed to write {}: {}", path.display(), err))
+    })
+}
+
 fn home_dir() -> Result<PathBuf> {
-    std::env::var_os("HOME")
-        .map(PathBuf::from)
+    let home = std::env::var_os("HOME").or_else(|| {
+        if cfg!(windows) {
+            std::env::var_os("USERPROFILE")
+        } else {
+            None
+        }
+    });
+    home.map(PathBuf::from)
         .ok_or_else(|| FunnelError::Other("Unable to resolve HOME directory".to_string()))
 }
 
-fn ensure_dir(path: &Path) -> Result<()> {
+fn local_app_data() -> Result<PathBuf> {
+    windows_folder("LOCALAPPDATA", "AppData\\Local")
+}
+
+/// A Windows known folder from its environment variable, or its default under the profile
+fn windows_folder(var: &str, default: &str) -> Result<PathBuf> {
+    match std::env::var_os(var) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => Ok(home_dir()?.join(default)),
+    }
+}
+
+pub(crate) fn ensure_dir(path: &Path) -> Result<()> {
     if path.exists() {
         return Ok(());
     }
@@ -85,6 +211,41 @@ fn ensure_dir(path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Like `ensure_dir`, but in a shared location such as `/tmp`: refuses a directory
+/// (or symlink) that someone else created first. The check comes before the chmod,
+/// which would otherwise follow a planted symlink.
+fn ensure_private_dir(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+        match std::fs::DirBuilder::new().mode(0o700).create(path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+            Err(err) => {
+                return Err(FunnelError::Other(format!(
+                    "Failed to create {}: {}",
+                    path.display(),
+                    err
+                )))
+            }
+        }
+        let metadata = std::fs::symlink_metadata(path).map_err(|err| {
+            FunnelError::Other(format!("Failed to inspect {}: {}", path.display(), err))
+        })?;
+        let uid = unsafe { libc::getuid() };
+        if !metadata.is_dir() || metadata.uid() != uid {
+            return Err(FunnelError::Other(format!(
+                "Refusing to use {}: not a directory owned by the current user; pass --state-dir",
+                path.display()
+            )));
+        }
+        set_permissions(path)?;
+    }
+    #[cfg(not(unix))]
+    ensure_dir(path)?;
+    Ok(())
+}
+
 #[cfg(unix)]
 fn set_permissions(path: &Path) -> Result<()> {
     use std::os::unix::fs::PermissionsExt;
@@ -102,3 +263,56 @@ fn set_permissions(path: &Path) -> Result<()> {
 fn set_permissions(_path: &Path) -> Result<()> {
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_private_dir_refuses_symlink_without_chmod() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("elsewhere");
+        std::fs::create_dir(&target).unwrap();
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let link = dir.path().join("funnelctl-link");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        assert!(ensure_private_dir(&link).is_err());
+        let mode = std::fs::metadata(&target).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+
+        let fresh = dir.path().join("funnelctl-fresh");
+        ensure_private_dir(&fresh).unwrap();
+        let mode = std::fs::metadata(&fresh).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[test]
+    fn test_write_private_leaves_no_temp_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lease.json");
+        // Where the old fixed temp name would have been; must be left alone
+        let old_tmp = dir.path().join("lease.tmp");
+        std::fs::write(&old_tmp, b"someone else's").unwrap();
+
+        write_private(&path, b"first").unwrap();
+        write_private(&path, b"second").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"second");
+        assert_eq!(std::fs::read(&old_tmp).unwrap(), b"someone else's");
+        let mut names: Vec<String> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["lease.json", "lease.tmp"]);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
@@ -1,5 +1,169 @@
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+/// Every exit code funnelctl uses. The numbers and names are stable across releases;
+/// `funnelctl errors` lists them for scripts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Other = 1,
+    InvalidArgument = 2,
+    Unreachable = 10,
+    Permission = 11,
+    Prerequisites = 12,
+    Conflict = 13,
+    ApplyFailed = 14,
+    TargetPortInaccessible = 15,
+    VersionTooOld = 16,
+    PolicyDenied = 17,
+    Locked = 18,
+    PolicyViolation = 19,
+    TargetDown = 20,
+    ApplyTimeout = 21,
+    Interrupted = 130,
+}
+
+impl ErrorCode {
+    pub const ALL: &'static [ErrorCode] = &[
+        ErrorCode::Other,
+        ErrorCode::InvalidArgument,
+        ErrorCode::Unreachable,
+        ErrorCode::Permission,
+        ErrorCode::Prerequisites,
+        ErrorCode::Conflict,
+        ErrorCode::ApplyFailed,
+        ErrorCode::TargetPortInaccessible,
+        ErrorCode::VersionTooOld,
+        ErrorCode::PolicyDenied,
+        ErrorCode::Locked,
+        ErrorCode::PolicyViolation,
+        ErrorCode::TargetDown,
+        ErrorCode::ApplyTimeout,
+        ErrorCode::Interrupted,
+    ];
+
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorCode::Other => "other",
+            ErrorCode::InvalidArgument => "invalid_argument",
+            ErrorCode::Unreachable => "unreachable",
+            ErrorCode::Permission => "permission",
+            ErrorCode::Prerequisites => "prerequisites",
+            ErrorCode::Conflict => "conflict",
+            ErrorCode::ApplyFailed => "apply_failed",
+            ErrorCode::TargetPortInaccessible => "target_inaccessible",
+            ErrorCode::VersionTooOld => "version_too_old",
+            ErrorCode::PolicyDenied => "policy_denied",
+            ErrorCode::Locked => "locked",
+            ErrorCode::PolicyViolation => "policy_violation",
+            ErrorCode::TargetDown => "target_down",
+            ErrorCode::ApplyTimeout => "apply_timeout",
+            ErrorCode::Interrupted => "interrupted",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            ErrorCode::Other => "Generic failure",
+            ErrorCode::InvalidArgument => "Invalid CLI usage or argument",
+            ErrorCode::Unreachable => "LocalAPI unreachable / tailscaled not running",
+            ErrorCode::Permission => "Permission denied (socket access / auth)",
+            ErrorCode::Prerequisites => "Funnel/serve prerequisites unmet",
+            ErrorCode::Conflict => "Conflicting existing config",
+            ErrorCode::ApplyFailed => "Apply/remove failed (tailscaled error)",
+            ErrorCode::TargetPortInaccessible => "Target port not accessible",
+            ErrorCode::VersionTooOld => "Tailscaled version too old",
+            ErrorCode::PolicyDenied => "Tunnel vetoed by a policy plugin",
+            ErrorCode::Locked => "Another funnelctl instance holds the lock",
+            ErrorCode::PolicyViolation => "Refused by a policy in config.toml",
+            ErrorCode::TargetDown => "Tunnel torn down because its target stopped",
+            ErrorCode::ApplyTimeout => "tailscaled did not finish applying in time",
+            ErrorCode::Interrupted => {
+                "Ctrl+C while waiting to open, or a second one during teardown"
+            }
+        }
+    }
+
+    /// Whether running the same command again may succeed with the same input: tailscaled
+    /// coming back, a lock being released, a concurrent serve edit settling, the target
+    /// starting. Usage, permission, version and policy errors need someone to act first.
+    pub fn retryable(self) -> bool {
+        self.retry_after().is_some()
+    }
+
+    /// Seconds to back off before retrying, for retryable codes
+    pub fn retry_after(self) -> Option<u64> {
+        match self {
+            ErrorCode::Unreachable | ErrorCode::TargetPortInaccessible | ErrorCode::TargetDown => {
+                Some(5)
+            }
+            ErrorCode::ApplyFailed => Some(2),
+            ErrorCode::ApplyTimeout => Some(5),
+            ErrorCode::Locked => Some(1),
+            ErrorCode::Other
+            | ErrorCode::InvalidArgument
+            | ErrorCode::Permission
+            | ErrorCode::Prerequisites
+            | ErrorCode::Conflict
+            | ErrorCode::VersionTooOld
+            | ErrorCode::PolicyDenied
+            | ErrorCode::PolicyViolation
+            | ErrorCode::Interrupted => None,
+        }
+    }
+}
+
+/// Which input an exit-2 error is about: a flag such as `--ttl`, or a config key such as
+/// `open.ttl`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldError {
+    pub name: String,
+    pub value: String,
+    pub constraint: String,
+}
+
+/// One clash between a route being written and a handler already in the serve config
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConflictEntry {
+    /// `exact_path`, `captured_by_prefix`, `captures_existing` or `in_use`
+    pub kind: String,
+    pub host_port: String,
+    /// The path being written
+    pub path: String,
+    pub existing_path: String,
+    pub existing_target: String,
+    /// The foreground session owning the existing handler; absent for background routes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session: Option<String>,
+    pub message: String,
+}
+
+/// One row of `funnelctl errors --json`
+#[derive(Debug, Serialize)]
+pub struct ErrorCodeEntry {
+    pub code: i32,
+    pub name: &'static str,
+    pub description: &'static str,
+    pub retryable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl From<ErrorCode> for ErrorCodeEntry {
+    fn from(code: ErrorCode) -> Self {
+        Self {
+            code: code.code(),
+            name: code.name(),
+            description: code.description(),
+            retryable: code.retryable(),
+            retry_after: code.retry_after(),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum FunnelError {
     #[error("LocalAPI unreachable")]
@@ -30,6 +194,14 @@ pub enum FunnelError {
         context: String,
     },
 
+    /// Every route conflict found by one check (see `Conflict::entry`), and a nearby
+    /// path that is free on every port when one was found
+    #[error("Configuration conflict")]
+    Conflicts {
+        conflicts: Vec<ConflictEntry>,
+        suggested_path: Option<String>,
+    },
+
     #[error("Apply operation failed")]
     ApplyFailed {
         #[source]
@@ -37,6 +209,15 @@ pub enum FunnelError {
         context: String,
     },
 
+    /// The apply deadline passed with tailscaled still busy; `context` says whether the
+    /// write took effect and was undone
+    #[error("Apply timed out")]
+    ApplyTimeout {
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+        context: String,
+    },
+
     #[error("Target port inaccessible")]
     TargetPortInaccessible {
         #[source]
@@ -51,25 +232,131 @@ pub enum FunnelError {
         context: String,
     },
 
+    #[error("Denied by policy")]
+    PolicyDenied {
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+        context: String,
+    },
+
+    #[error("Another funnelctl instance is running")]
+    Locked {
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+        context: String,
+    },
+
+    #[error("Refused by config policy")]
+    PolicyViolation {
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+        context: String,
+    },
+
+    #[error("Target stopped")]
+    TargetDown {
+        #[source]
+        source: Option<Box<dyn std::error::Error + Send + Sync>>,
+        context: String,
+    },
+
     #[error("Invalid argument: {0}")]
     InvalidArgument(String),
 
+    /// An invalid argument whose flag or config key is known (see `for_field`)
+    #[error("Invalid argument: {constraint}")]
+    InvalidField {
+        field: String,
+        value: String,
+        constraint: String,
+    },
+
+    /// Ctrl-C before anything was applied, e.g. while `open` waits for its port or start
+    /// time
+    #[error("{0}")]
+    Interrupted(String),
+
     #[error("{0}")]
     Other(String),
 }
 
 impl FunnelError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            FunnelError::Unreachable { .. } => ErrorCode::Unreachable,
+            FunnelError::Permission { .. } => ErrorCode::Permission,
+            FunnelError::Prerequisites { .. } => ErrorCode::Prerequisites,
+            FunnelError::Conflict { .. } | FunnelError::Conflicts { .. } => ErrorCode::Conflict,
+            FunnelError::ApplyFailed { .. } => ErrorCode::ApplyFailed,
+            FunnelError::ApplyTimeout { .. } => ErrorCode::ApplyTimeout,
+            FunnelError::TargetPortInaccessible { .. } => ErrorCode::TargetPortInaccessible,
+            FunnelError::VersionTooOld { .. } => ErrorCode::VersionTooOld,
+            FunnelError::PolicyDenied { .. } => ErrorCode::PolicyDenied,
+            FunnelError::Locked { .. } => ErrorCode::Locked,
+            FunnelError::PolicyViolation { .. } => ErrorCode::PolicyViolation,
+            FunnelError::TargetDown { .. } => ErrorCode::TargetDown,
+            FunnelError::InvalidArgument(_) | FunnelError::InvalidField { .. } => {
+                ErrorCode::InvalidArgument
+            }
+            FunnelError::Interrupted(_) => ErrorCode::Interrupted,
+            FunnelError::Other(_) => ErrorCode::Other,
+        }
+    }
+
     pub fn exit_code(&self) -> i32 {
+        self.code().code()
+    }
+
+    pub fn retryable(&self) -> bool {
+        self.code().retryable()
+    }
+
+    pub fn retry_after(&self) -> Option<u64> {
+        self.code().retry_after()
+    }
+
+    /// Pins an `InvalidArgument` to the flag or config key and value it came from, so
+    /// JSON consumers can point at the input; other errors are returned unchanged
+    pub fn for_field(self, field: &str, value: impl Into<String>) -> Self {
+        match self {
+            FunnelError::InvalidArgument(constraint) => FunnelError::InvalidField {
+                field: field.to_string(),
+                value: value.into(),
+                constraint,
+            },
+            other => other,
+        }
+    }
+
+    /// The `field` object of the JSON error event
+    pub fn field_error(&self) -> Option<FieldError> {
+        match self {
+            FunnelError::InvalidField {
+                field,
+                value,
+                constraint,
+            } => Some(FieldError {
+                name: field.clone(),
+                value: value.clone(),
+                constraint: constraint.clone(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// The `conflicts` array of the JSON error event
+    pub fn conflict_entries(&self) -> &[ConflictEntry] {
+        match self {
+            FunnelError::Conflicts { conflicts, .. } => conflicts,
+            _ => &[],
+        }
+    }
+
+    /// A free path to retry a conflicting route on
+    pub fn suggested_path(&self) -> Option<&str> {
         match self {
-            FunnelError::Unreachable { .. } => 10,
-            FunnelError::Permission { .. } => 11,
-            FunnelError::Prerequisites { .. } => 12,
-            FunnelError::Conflict { .. } => 13,
-            FunnelError::ApplyFailed { .. } => 14,
-            FunnelError::TargetPortInaccessible { .. } => 15,
-            FunnelError::VersionTooOld { .. } => 16,
-            FunnelError::InvalidArgument(_) => 2,
-            FunnelError::Other(_) => 1,
+            FunnelError::Conflicts { suggested_path, .. } => suggested_path.as_deref(),
+            _ => None,
         }
     }
 
@@ -112,7 +399,7 @@ impl FunnelError {
             ),
             FunnelError::Permission { context, .. } => (
                 Some(context.clone()),
-                Some("Run with sudo or add your user to the operator group".to_string()),
+                Some(crate::backend::operator::permission_fix()),
             ),
             FunnelError::Prerequisites { context, .. } => (
                 Some(context.clone()),
@@ -120,12 +407,38 @@ impl FunnelError {
             ),
             FunnelError::Conflict { context, .. } => (
                 Some(context.clone()),
-                Some("Use a different --path or add --force to override".to_string()),
+                Some(
+                    "Use a different --path or --name, or add --force to override route conflicts"
+                        .to_string(),
+                ),
+            ),
+            FunnelError::Conflicts {
+                conflicts,
+                suggested_path,
+            } => (
+                Some(describe_conflicts(conflicts)),
+                Some(match suggested_path {
+                    Some(path) => format!(
+                        "{} is free: use --path {} (or `open --auto-resolve` to take it), or add --force to override route conflicts",
+                        path, path
+                    ),
+                    None => {
+                        "Use a different --path or --name, or add --force to override route conflicts"
+                            .to_string()
+                    }
+                }),
             ),
             FunnelError::ApplyFailed { context, .. } => (
                 Some(context.clone()),
                 Some(
-                    "Check tailscaled logs for more details. Route may still exist; run `tailscale serve off` to clean up."
+                    "Check tailscaled logs for more details. The serve config may be half-written; run `funnelctl rollback` to restore the one saved before the write."
+                        .to_string(),
+                ),
+            ),
+            FunnelError::ApplyTimeout { context, .. } => (
+                Some(context.clone()),
+                Some(
+                    "Check that tailscaled responds (`tailscale status`), then retry; raise `[localapi] apply_timeout` or pass --apply-timeout for a slow daemon"
                         .to_string(),
                 ),
             ),
@@ -137,10 +450,141 @@ impl FunnelError {
                 Some(context.clone()),
                 Some("Upgrade tailscaled. See https://tailscale.com/download".to_string()),
             ),
+            FunnelError::PolicyDenied { context, .. } => (
+                Some(context.clone()),
+                Some(
+                    "Ask whoever maintains the plugin, or remove it from [[open.plugins]] in config.toml"
+                        .to_string(),
+                ),
+            ),
+            FunnelError::Locked { context, .. } => (
+                Some(context.clone()),
+                Some(
+                    "Wait for it to finish, or run `funnelctl status` to see what is running"
+                        .to_string(),
+                ),
+            ),
+            FunnelError::PolicyViolation { context, .. } => (
+                Some(context.clone()),
+                Some("Adjust the flags to satisfy the policy, or change config.toml".to_string()),
+            ),
+            FunnelError::TargetDown { context, .. } => (
+                Some(context.clone()),
+                Some(
+                    "Restart your service; drop --stop-on-target-down to keep the tunnel up"
+                        .to_string(),
+                ),
+            ),
             FunnelError::InvalidArgument(msg) => (Some(msg.clone()), None),
+            FunnelError::InvalidField { constraint, .. } => (Some(constraint.clone()), None),
+            FunnelError::Interrupted(_) => (None, None),
             FunnelError::Other(msg) => (Some(msg.clone()), None),
         }
     }
 }
 
+/// A single conflict as its message; several as a numbered list, one per line
+fn describe_conflicts(conflicts: &[ConflictEntry]) -> String {
+    match conflicts {
+        [conflict] => conflict.message.clone(),
+        _ => {
+            let mut text = format!("{} conflicting routes:", conflicts.len());
+            for (index, conflict) in conflicts.iter().enumerate() {
+                text.push_str(&format!(
+                    "\n  {}. {}: {}",
+                    index + 1,
+                    conflict.host_port,
+                    conflict.message
+                ));
+            }
+            text
+        }
+    }
+}
+
 pub type Result<T> = std::result::Result<T, FunnelError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes_are_unique_and_named() {
+        for (index, code) in ErrorCode::ALL.iter().enumerate() {
+            assert!(ErrorCode::ALL[index + 1..]
+                .iter()
+                .all(|other| other.code() != code.code() && other.name() != code.name()));
+        }
+        let err = FunnelError::Locked {
+            source: None,
+            context: "held".to_string(),
+        };
+        assert_eq!(err.exit_code(), 18);
+        assert!(err.retryable());
+        assert!(!FunnelError::InvalidArgument("bad".to_string()).retryable());
+        assert_eq!(
+            FunnelError::VersionTooOld {
+                source: None,
+                context: "1.48.0".to_string(),
+            }
+            .retry_after(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_for_field_only_wraps_invalid_arguments() {
+        let err = FunnelError::InvalidArgument("TTL must be at least 30 seconds".to_string())
+            .for_field("--ttl", "10s");
+        assert_eq!(err.exit_code(), 2);
+        assert_eq!(
+            err.to_string(),
+            "Invalid argument: TTL must be at least 30 seconds"
+        );
+        assert_eq!(
+            err.field_error(),
+            Some(FieldError {
+                name: "--ttl".to_string(),
+                value: "10s".to_string(),
+                constraint: "TTL must be at least 30 seconds".to_string(),
+            })
+        );
+
+        let err = FunnelError::Other("boom".to_string()).for_field("--ttl", "10s");
+        assert!(err.field_error().is_none());
+    }
+
+    #[test]
+    fn test_conflicts_are_numbered() {
+        let entry = |path: &str| ConflictEntry {
+            kind: "exact_path".to_string(),
+            host_port: "node.ts.net:443".to_string(),
+            path: path.to_string(),
+            existing_path: path.to_string(),
+            existing_target: "http://127.0.0.1:8080".to_string(),
+            session: None,
+            message: format!("path '{}' already maps elsewhere", path),
+        };
+        let one = FunnelError::Conflicts {
+            conflicts: vec![entry("/a")],
+            suggested_path: Some("/a-2".to_string()),
+        };
+        assert_eq!(one.exit_code(), 13);
+        assert!(one
+            .format_detailed(false)
+            .contains("Cause: path '/a' already maps elsewhere\n"));
+        assert!(one
+            .get_fix()
+            .unwrap()
+            .starts_with("/a-2 is free: use --path /a-2"));
+
+        let two = FunnelError::Conflicts {
+            conflicts: vec![entry("/a"), entry("/b")],
+            suggested_path: None,
+        };
+        let text = two.format_detailed(false);
+        assert!(text.contains("Cause: 2 conflicting routes:\n  1. node.ts.net:443: path '/a'"));
+        assert!(text.contains("\n  2. node.ts.net:443: path '/b'"));
+        assert_eq!(two.conflict_entries().len(), 2);
+    }
+}
@@ -0,0 +1,393 @@
+//! Status file a foreground `open` rewrites while its tunnel is up, at
+//! `<runtime_dir>/status/<lease_id>.json`.
+//!
+//! External watchdogs read it instead of parsing logs: a file whose `updated_at` stops
+//! moving belongs to a wedged or killed `open`, and `last_healthy` tells how long the
+//! tunnel has been unusable.
+
+use std::path::{Path, PathBuf};
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http_body_util::Empty;
+use humantime::format_duration;
+use hyper::Uri;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::time::{sleep, timeout, Duration, Instant};
+
+use crate::core::{LocalTarget, RouteState};
+use crate::dirs;
+use crate::error::{FunnelError, Result};
+
+/// How often the status file is rewritten
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// How long the target gets to accept a TCP connection before it counts as down
+const TARGET_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+/// First and longest wait between probes while a down target is retried
+const RETRY_BACKOFF_START: Duration = Duration::from_millis(500);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(5);
+/// Wait between `--health-path` requests, and the longest one request may take
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const HEALTH_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TargetHealth {
+    /// The local target accepted a TCP connection
+    Up,
+    /// The connection was refused or timed out
+    Down,
+}
+
+/// `version` of the status file format
+pub const STATUS_FILE_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TunnelStatus {
+    pub version: u32,
+    pub lease_id: String,
+    pub name: Option<String>,
+    pub url: String,
+    pub pid: u32,
+    pub started_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// When this file was last written
+    pub updated_at: DateTime<Utc>,
+    /// Last time the route was intact and the target up; `None` until that first happens
+    pub last_healthy: Option<DateTime<Utc>>,
+    pub route: RouteState,
+    pub target: TargetHealth,
+    /// Requests in flight through the local proxy at `updated_at`; absent without it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub active_requests: Option<u64>,
+}
+
+impl TunnelStatus {
+    pub fn new(
+        lease_id: String,
+        name: Option<String>,
+        url: String,
+        started_at: DateTime<Utc>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        Self {
+            version: STATUS_FILE_VERSION,
+            lease_id,
+            name,
+            url,
+            pid: std::process::id(),
+            started_at,
+            expires_at,
+            updated_at: started_at,
+            last_healthy: None,
+            route: RouteState::Intact,
+            target: TargetHealth::Down,
+            active_requests: None,
+        }
+    }
+
+    /// The status file of the tunnel holding `lease_id`, if it writes one and it parses
+    pub fn read(lease_id: &str) -> Option<Self> {
+        let path = dirs::runtime_dir()
+            .ok()?
+            .join("status")
+            .join(format!("{}.json", dirs::sanitize_file_name(lease_id)));
+        let contents = std::fs::read(path).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+}
+
+/// The status file of one running tunnel; `remove` deletes it on a clean stop
+pub struct StatusFile {
+    path: PathBuf,
+    status: TunnelStatus,
+}
+
+impl StatusFile {
+    pub fn create(status: TunnelStatus) -> Result<Self> {
+        let dir = dirs::runtime_dir()?.join("status");
+        dirs::ensure_dir(&dir)?;
+        Self::at(&dir, status)
+    }
+
+    pub fn at(dir: &Path, status: TunnelStatus) -> Result<Self> {
+        let path = dir.join(format!(
+            "{}.json",
+            dirs::sanitize_file_name(&status.lease_id)
+        ));
+        let file = Self { path, status };
+        file.write()?;
+        Ok(file)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records the latest checks and rewrites the file
+    pub fn beat(
+        &mut self,
+        route: RouteState,
+        target: TargetHealth,
+        expires_at: Option<DateTime<Utc>>,
+        active_requests: Option<u64>,
+        now: DateTime<Utc>,
+    ) -> Result<()> {
+        self.status.route = route;
+        self.status.target = target;
+        self.status.expires_at = expires_at;
+        self.status.active_requests = active_requests;
+        self.status.updated_at = now;
+        if route == RouteState::Intact && target == TargetHealth::Up {
+            self.status.last_healthy = Some(now);
+        }
+        self.write()
+    }
+
+    pub fn remove(self) {
+        if let Err(err) = std::fs::remove_file(&self.path) {
+            tracing::warn!(path = %self.path.display(), error = %err, "failed to remove status file");
+        }
+    }
+
+    fn write(&self) -> Result<()> {
+        let contents = serde_json::to_vec_pretty(&self.status).map_err(|err| {
+            FunnelError::Other(format!("Failed to serialize tunnel status: {}", err))
+        })?;
+        dirs::write_private(&self.path, &contents)
+    }
+}
+
+/// Checks that the local target accepts TCP connections
+pub async fn probe_target(target: &LocalTarget) -> TargetHealth {
+    let connect = TcpStream::connect((target.bind.as_str(), target.port));
+    match timeout(TARGET_PROBE_TIMEOUT, connect).await {
+        Ok(Ok(_)) => TargetHealth::Up,
+        _ => TargetHealth::Down,
+    }
+}
+
+/// `open --health-path`: polls `path` on the target until it answers 2xx. An app can
+/// accept connections well before it serves traffic, which `probe_target` cannot tell.
+/// Fails with the last answer once `within` has passed.
+pub async fn wait_until_healthy(target: &LocalTarget, path: &str, within: Duration) -> Result<()> {
+    let uri: Uri =
+        format!("{}{}", target, path)
+            .parse()
+            .map_err(|err| FunnelError::InvalidField {
+                field: "--health-path".to_string(),
+                value: path.to_string(),
+                constraint: format!("not a valid URL path: {}", err),
+            })?;
+    let client: Client<HttpConnector, Empty<Bytes>> =
+        Client::builder(TokioExecutor::new()).build(HttpConnector::new());
+    let deadline = Instant::now() + within;
+    loop {
+        let wait = HEALTH_REQUEST_TIMEOUT.min(deadline.saturating_duration_since(Instant::now()));
+        let last = match timeout(wait, client.get(uri.clone())).await {
+            Ok(Ok(response)) if response.status().is_success() => return Ok(()),
+            Ok(Ok(response)) => format!("answered {}", response.status()),
+            Ok(Err(err)) if err.is_connect() => "could not connect".to_string(),
+            Ok(Err(err)) => format!("failed: {}", err),
+            Err(_) => "timed out".to_string(),
+        };
+        tracing::debug!(%uri, last = %last, "target not healthy yet");
+        if Instant::now() + HEALTH_POLL_INTERVAL >= deadline {
+            return Err(FunnelError::TargetPortInaccessible {
+                source: None,
+                context: format!(
+                    "{} did not answer 2xx within {} (last request {})",
+                    uri,
+                    format_duration(within),
+                    last
+                ),
+            });
+        }
+        sleep(HEALTH_POLL_INTERVAL).await;
+    }
+}
+
+/// Turns raw probe results into reported target changes. With a retry window, a target
+/// that goes down is probed again with backoff and only reported down once it stayed
+/// down for the whole window, so a dev server restarting on save goes unnoticed.
+pub struct TargetWatch {
+    reported: TargetHealth,
+    retry: Duration,
+    down_since: Option<Instant>,
+    backoff: Duration,
+}
+
+impl TargetWatch {
+    /// Starts from `up`: `apply` checked the target before writing the route
+    pub fn new(retry: Option<Duration>) -> Self {
+        Self {
+            reported: TargetHealth::Up,
+            retry: retry.unwrap_or_default(),
+            down_since: None,
+            backoff: RETRY_BACKOFF_START,
+        }
+    }
+
+    /// Checks if the last probe found the target up; while it is down, or being retried,
+    /// the heartbeat already probes on its own schedule
+    pub fn is_up(&self) -> bool {
+        self.reported == TargetHealth::Up && self.down_since.is_none()
+    }
+
+    /// Records a probe. Returns the change to report, if any, and when to probe next.
+    pub fn observe(
+        &mut self,
+        probe: TargetHealth,
+        now: Instant,
+    ) -> (Option<TargetHealth>, Duration) {
+        if probe == TargetHealth::Up {
+            self.down_since = None;
+            self.backoff = RETRY_BACKOFF_START;
+            let change = (self.reported == TargetHealth::Down).then_some(TargetHealth::Up);
+            self.reported = TargetHealth::Up;
+            return (change, HEARTBEAT_INTERVAL);
+        }
+        if self.reported == TargetHealth::Down {
+            return (None, HEARTBEAT_INTERVAL);
+        }
+
+        let since = *self.down_since.get_or_insert(now);
+        let waited = now.saturating_duration_since(since);
+        if waited >= self.retry {
+            self.reported = TargetHealth::Down;
+            self.backoff = RETRY_BACKOFF_START;
+            return (Some(TargetHealth::Down), HEARTBEAT_INTERVAL);
+        }
+        let next = self.backoff.min(self.retry - waited);
+        self.backoff = (self.backoff * 2).min(RETRY_BACKOFF_MAX);
+        (None, next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(file: &StatusFile) -> TunnelStatus {
+        serde_json::from_slice(&std::fs::read(file.path()).unwrap()).unwrap()
+    }
+
+    /// Answers 503 to the first `failures` requests, then 200
+    async fn warming_target(failures: usize) -> LocalTarget {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            for served in 0.. {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let status = if served < failures {
+                    "503 Service Unavailable"
+                } else {
+                    "200 OK"
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        LocalTarget::new("127.0.0.1".to_string(), port)
+    }
+
+    #[tokio::test]
+    async fn test_wait_until_healthy() {
+        let target = warming_target(2).await;
+        wait_until_healthy(&target, "/healthz", Duration::from_secs(10))
+            .await
+            .unwrap();
+
+        let target = warming_target(usize::MAX).await;
+        let err = wait_until_healthy(&target, "/healthz", Duration::from_millis(600))
+            .await
+            .unwrap_err();
+        let FunnelError::TargetPortInaccessible { context, .. } = err else {
+            panic!("expected target_inaccessible, got {:?}", err);
+        };
+        assert!(context.contains("answered 503"), "{}", context);
+    }
+
+    #[tokio::test]
+    async fn test_beat_tracks_last_healthy() {
+        let dir = tempfile::tempdir().unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let target = LocalTarget::new("127.0.0.1".to_string(), port);
+
+        let started = Utc::now();
+        let status = TunnelStatus::new(
+            "lease-1".to_string(),
+            Some("api".to_string()),
+            "https://node.ts.net/x".to_string(),
+            started,
+            None,
+        );
+        let mut file = StatusFile::at(dir.path(), status).unwrap();
+        assert_eq!(read(&file).last_healthy, None);
+
+        let healthy_at = started + chrono::Duration::seconds(10);
+        let health = probe_target(&target).await;
+        assert_eq!(health, TargetHealth::Up);
+        file.beat(RouteState::Intact, health, None, Some(2), healthy_at)
+            .unwrap();
+        assert_eq!(read(&file).active_requests, Some(2));
+
+        drop(listener);
+        let later = healthy_at + chrono::Duration::seconds(10);
+        file.beat(RouteState::Removed, TargetHealth::Up, None, None, later)
+            .unwrap();
+        let status = read(&file);
+        assert_eq!(status.updated_at, later);
+        assert_eq!(status.last_healthy, Some(healthy_at));
+        assert_eq!(status.route, RouteState::Removed);
+        assert_eq!(status.active_requests, None);
+
+        let path = file.path().to_path_buf();
+        file.remove();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_target_watch_absorbs_flaps_within_retry_window() {
+        let start = Instant::now();
+        let mut watch = TargetWatch::new(Some(Duration::from_secs(3)));
+        let (change, next) = watch.observe(TargetHealth::Down, start);
+        assert_eq!((change, next), (None, RETRY_BACKOFF_START));
+        let (change, next) = watch.observe(TargetHealth::Down, start + next);
+        assert_eq!((change, next), (None, Duration::from_secs(1)));
+        assert_eq!(
+            watch.observe(TargetHealth::Up, start + Duration::from_millis(1500)),
+            (None, HEARTBEAT_INTERVAL)
+        );
+
+        let down = start + Duration::from_secs(20);
+        watch.observe(TargetHealth::Down, down);
+        let (change, next) = watch.observe(TargetHealth::Down, down + Duration::from_millis(2500));
+        assert_eq!((change, next), (None, Duration::from_millis(500)));
+        let (change, _) = watch.observe(TargetHealth::Down, down + Duration::from_secs(3));
+        assert_eq!(change, Some(TargetHealth::Down));
+        let (change, _) = watch.observe(TargetHealth::Up, down + Duration::from_secs(13));
+        assert_eq!(change, Some(TargetHealth::Up));
+    }
+
+    #[test]
+    fn test_target_watch_without_retry_reports_at_once() {
+        let mut watch = TargetWatch::new(None);
+        let (change, next) = watch.observe(TargetHealth::Down, Instant::now());
+        assert_eq!(
+            (change, next),
+            (Some(TargetHealth::Down), HEARTBEAT_INTERVAL)
+        );
+    }
+}
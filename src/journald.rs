@@ -0,0 +1,332 @@
+//! Logging straight to journald when funnelctl runs under systemd.
+//!
+//! Records go over the native protocol (one datagram per record on
+//! `/run/systemd/journal/socket`) with a real `PRIORITY` and every tracing field as a
+//! journal field, so `journalctl -u funnelctl@hook -p warning` and
+//! `journalctl LEASE_ID=...` work. `open` adds `LEASE_ID` and `URL` to everything it logs
+//! once the tunnel is up (see `set_context`).
+//!
+//! A record too big for one datagram is handed over in a sealed memfd, as `sd_journal_send`
+//! does. One that cannot be delivered at all goes to stderr instead of being lost.
+
+use std::fmt::{self, Write as _};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use tracing::field::{Field, Visit};
+use tracing::Level;
+#[cfg(unix)]
+use tracing::{Event, Subscriber};
+#[cfg(unix)]
+use tracing_subscriber::layer::{Context, Layer};
+
+const SOCKET: &str = "/run/systemd/journal/socket";
+const IDENTIFIER: &str = "funnelctl";
+/// Fields journald or `journalctl` give a meaning, plus our own `TARGET`. Tracing fields
+/// and context with these names are renamed (`priority` becomes `F_PRIORITY`), so they
+/// cannot change a record's priority, message or identifier.
+const RESERVED_FIELDS: &[&str] = &[
+    "MESSAGE",
+    "MESSAGE_ID",
+    "PRIORITY",
+    "CODE_FILE",
+    "CODE_LINE",
+    "CODE_FUNC",
+    "ERRNO",
+    "INVOCATION_ID",
+    "USER_INVOCATION_ID",
+    "SYSLOG_FACILITY",
+    "SYSLOG_IDENTIFIER",
+    "SYSLOG_PID",
+    "SYSLOG_TIMESTAMP",
+    "SYSLOG_RAW",
+    "DOCUMENTATION",
+    "TID",
+    "UNIT",
+    "USER_UNIT",
+    "TARGET",
+];
+
+/// Fields added to every record from now on, e.g. the lease of the running tunnel
+static CONTEXT: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Whether systemd started us with a journal to write to: `JOURNAL_STREAM` is set when
+/// stderr goes to the journal, `INVOCATION_ID` for any unit
+pub fn detected() -> bool {
+    let under_systemd = ["JOURNAL_STREAM", "INVOCATION_ID"]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some_and(|value| !value.is_empty()));
+    under_systemd && Path::new(SOCKET).exists()
+}
+
+/// Whether logs are going to journald rather than stderr
+pub fn active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Sets (or replaces) a field attached to every later journald record
+pub fn set_context(name: &str, value: impl Into<String>) {
+    let name = field_name(name);
+    let value = value.into();
+    let mut context = CONTEXT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    match context.iter_mut().find(|(existing, _)| *existing == name) {
+        Some(entry) => entry.1 = value,
+        None => context.push((name, value)),
+    }
+}
+
+#[cfg(unix)]
+pub struct JournaldLayer {
+    socket: UnixDatagram,
+}
+
+#[cfg(unix)]
+impl JournaldLayer {
+    pub fn connect() -> std::io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SOCKET)?;
+        ACTIVE.store(true, Ordering::Relaxed);
+        Ok(Self { socket })
+    }
+}
+
+#[cfg(unix)]
+impl<S: Subscriber> Layer<S> for JournaldLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        let mut fields = FieldCollector::default();
+        event.record(&mut fields);
+
+        let mut payload = Vec::new();
+        put(&mut payload, "PRIORITY", priority(metadata.level()));
+        put(&mut payload, "SYSLOG_IDENTIFIER", IDENTIFIER);
+        put(&mut payload, "MESSAGE", &fields.message);
+        put(&mut payload, "TARGET", metadata.target());
+        for (name, value) in &fields.fields {
+            put(&mut payload, name, value);
+        }
+        for (name, value) in CONTEXT
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+        {
+            put(&mut payload, name, value);
+        }
+        if let Err(err) = send(&self.socket, &payload) {
+            // tracing would come straight back here; stderr is usually the journal's
+            // stream connection, so the line still lands next to the other records
+            eprintln!(
+                "funnelctl: journald refused a {} byte record ({}): {}",
+                payload.len(),
+                err,
+                fields.message
+            );
+        }
+    }
+}
+
+/// One datagram, or a sealed memfd when the record is larger than a datagram may be
+#[cfg(unix)]
+fn send(socket: &UnixDatagram, payload: &[u8]) -> std::io::Result<()> {
+    match socket.send(payload) {
+        Ok(_) => Ok(()),
+        #[cfg(target_os = "linux")]
+        Err(err) if matches!(err.raw_os_error(), Some(libc::EMSGSIZE | libc::ENOBUFS)) => {
+            send_memfd(socket, payload)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// journald reads a record from a memfd passed with `SCM_RIGHTS`, once it is sealed
+/// against changes
+#[cfg(target_os = "linux")]
+fn send_memfd(socket: &UnixDatagram, payload: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::mem::size_of;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    let fd = unsafe {
+        libc::memfd_create(
+            b"funnelctl-journal\0".as_ptr().cast(),
+            libc::MFD_ALLOW_SEALING | libc::MFD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let mut file = std::fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    file.write_all(payload)?;
+    let seals = libc::F_SEAL_SHRINK | libc::F_SEAL_GROW | libc::F_SEAL_WRITE | libc::F_SEAL_SEAL;
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let fd = file.as_raw_fd();
+    // u64 keeps the control buffer aligned for cmsghdr
+    let space = unsafe { libc::CMSG_SPACE(size_of::<libc::c_int>() as u32) } as usize;
+    let mut control = vec![0u64; space.div_ceil(size_of::<u64>())];
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = space as _;
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = libc::CMSG_LEN(size_of::<libc::c_int>() as u32) as _;
+        std::ptr::copy_nonoverlapping(
+            (&fd as *const libc::c_int).cast::<u8>(),
+            libc::CMSG_DATA(header),
+            size_of::<libc::c_int>(),
+        );
+    }
+    if unsafe { libc::sendmsg(socket.as_raw_fd(), &message, 0) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct FieldCollector {
+    message: String,
+    fields: Vec<(String, String)>,
+}
+
+impl Visit for FieldCollector {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, format!("{:?}", value));
+    }
+}
+
+impl FieldCollector {
+    fn push(&mut self, field: &Field, value: String) {
+        if field.name() == "message" {
+            self.message = value;
+            return;
+        }
+        // The fmt layer shows fields after the message; keep that for plain `journalctl`
+        let _ = write!(self.message, " {}={}", field.name(), value);
+        self.fields.push((field_name(field.name()), value));
+    }
+}
+
+fn priority(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "3",
+        Level::WARN => "4",
+        Level::INFO => "6",
+        _ => "7",
+    }
+}
+
+/// Journal field names are `A-Z`, `0-9` and `_`, and may not start with `_` (trusted
+/// fields) or a digit. Names in `RESERVED_FIELDS` get the same `F` prefix.
+fn field_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.starts_with(|c: char| c == '_' || c.is_ascii_digit()) {
+        format!("F{}", name)
+    } else if RESERVED_FIELDS.contains(&name.as_str()) {
+        format!("F_{}", name)
+    } else {
+        name
+    }
+}
+
+/// Appends one field; values with a newline use the length-prefixed binary form
+fn put(payload: &mut Vec<u8>, name: &str, value: &str) {
+    payload.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        payload.push(b'\n');
+        payload.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        payload.push(b'=');
+    }
+    payload.extend_from_slice(value.as_bytes());
+    payload.push(b'\n');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_field_encoding() {
+        assert_eq!(field_name("lease_id"), "LEASE_ID");
+        assert_eq!(field_name("http.path"), "HTTP_PATH");
+        assert_eq!(field_name("_hidden"), "F_HIDDEN");
+        assert_eq!(field_name("priority"), "F_PRIORITY");
+        assert_eq!(field_name("MESSAGE"), "F_MESSAGE");
+        assert_eq!(field_name("syslog.identifier"), "F_SYSLOG_IDENTIFIER");
+        assert_eq!(field_name("message_text"), "MESSAGE_TEXT");
+
+        let mut payload = Vec::new();
+        put(&mut payload, "URL", "https://node.ts.net/hook");
+        put(&mut payload, "MESSAGE", "a\nb");
+        let mut expected = b"URL=https://node.ts.net/hook\nMESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(payload, expected);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_oversized_record_goes_through_memfd() {
+        use std::io::{Read, Seek, SeekFrom};
+        use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+        let (sender, receiver) = UnixDatagram::pair().unwrap();
+        let mut payload = Vec::new();
+        put(&mut payload, "MESSAGE", &"x".repeat(1 << 20));
+        // Far beyond any datagram limit, so the plain send fails and the memfd takes over
+        send(&sender, &payload).unwrap();
+
+        let mut data = [0u8; 16];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr().cast(),
+            iov_len: data.len(),
+        };
+        let mut control = [0u64; 8];
+        let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+        message.msg_iov = &mut iov;
+        message.msg_iovlen = 1;
+        message.msg_control = control.as_mut_ptr().cast();
+        message.msg_controllen = std::mem::size_of_val(&control) as _;
+        let received = unsafe { libc::recvmsg(receiver.as_raw_fd(), &mut message, 0) };
+        assert_eq!(
+            received, 0,
+            "the record travels in the fd, not the datagram"
+        );
+        let fd = unsafe {
+            let header = libc::CMSG_FIRSTHDR(&message);
+            assert_eq!((*header).cmsg_type, libc::SCM_RIGHTS);
+            std::ptr::read_unaligned(libc::CMSG_DATA(header).cast::<libc::c_int>())
+        };
+        let mut file = std::fs::File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+        let seals = unsafe { libc::fcntl(fd, libc::F_GET_SEALS) };
+        assert_ne!(seals & libc::F_SEAL_WRITE, 0);
+        // The fd shares the sender's offset, at the end; journald maps the file from 0
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).unwrap();
+        assert!(contents == payload, "memfd holds {} bytes", contents.len());
+    }
+}
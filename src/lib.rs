@@ -1,9 +1,19 @@
 pub mod backend;
 pub mod cli;
 pub mod cmd;
+pub mod config;
+pub mod control;
 pub mod core;
 pub mod dirs;
 pub mod error;
+pub mod heartbeat;
+pub mod journald;
 pub mod lock;
 pub mod net;
 pub mod output;
+pub mod plugin;
+pub mod progress;
+pub mod proxy;
+pub mod teardown;
+pub mod template;
+pub mod version;
@@ -1,8 +1,10 @@
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use fs4::FileExt;
+use serde::{Deserialize, Serialize};
 
 use crate::dirs;
 use crate::error::{FunnelError, Result};
@@ -28,62 +30,321 @@ impl LockGuard {
                 ))
             })?;
 
-        if file.try_lock_exclusive().is_ok() {
-            write_pid(&mut file)?;
-            return Ok(Self { _file: file });
-        }
-
-        let pid = read_pid(&mut file).ok();
-        if let Some(pid) = pid {
-            if !pid_is_alive(pid) && file.try_lock_exclusive().is_ok() {
-                write_pid(&mut file)?;
+        let owner = match file.try_lock_exclusive() {
+            Ok(()) => {
+                write_owner(&mut file)?;
                 return Ok(Self { _file: file });
             }
-            return Err(FunnelError::Conflict {
-                source: None,
-                context: format!("Another funnelctl instance is running (PID {})", pid),
-            });
-        }
+            Err(err) if is_contended(&err) => {
+                let owner = read_owner(&mut file).ok();
+                // The holder may have exited between our attempt and reading its record
+                let stale = owner.as_ref().is_some_and(|owner| !owner.is_live());
+                if stale && file.try_lock_exclusive().is_ok() {
+                    write_owner(&mut file)?;
+                    return Ok(Self { _file: file });
+                }
+                owner
+            }
+            Err(err) => {
+                // No advisory locks here (some network filesystems), so the record is all
+                // there is: a live owner keeps it, anything else is taken over.
+                tracing::debug!(error = %err, "advisory lock unavailable; using owner record");
+                match read_owner(&mut file) {
+                    Ok(owner) if owner.is_live() => Some(owner),
+                    _ => {
+                        write_owner(&mut file)?;
+                        return Ok(Self { _file: file });
+                    }
+                }
+            }
+        };
 
-        Err(FunnelError::Conflict {
+        let context = match owner {
+            Some(owner) => format!("Another funnelctl instance is running ({})", owner),
+            None => "Another funnelctl instance is running".to_string(),
+        };
+        Err(FunnelError::Locked {
             source: None,
-            context: "Another funnelctl instance is running".to_string(),
+            context,
         })
     }
 }
 
+/// Host-wide mutex around one ServeConfig read-modify-write. Unlike [`LockGuard`] it
+/// waits instead of failing, and it lives in the system temp dir, so writers of every
+/// user (and lock-free ones such as teardown and self-heal) queue up instead of
+/// racing each other into `412` retries. Only an optimization: If-Match still guards
+/// the write, so when the mutex cannot be had the write goes ahead without it.
+pub struct ApplyMutex {
+    _file: File,
+}
+
+impl ApplyMutex {
+    /// How long a writer waits for the mutex before writing without it
+    pub const WAIT: Duration = Duration::from_secs(10);
+    const POLL: Duration = Duration::from_millis(25);
+
+    pub async fn acquire(wait: Duration) -> Option<Self> {
+        Self::acquire_at(&apply_mutex_path(), wait).await
+    }
+
+    /// `acquire` on an explicit mutex file
+    async fn acquire_at(path: &Path, wait: Duration) -> Option<Self> {
+        let file = match open_shared(path) {
+            Ok(file) => file,
+            Err(err) => {
+                tracing::debug!(path = %path.display(), error = %err, "apply mutex unavailable");
+                return None;
+            }
+        };
+        let deadline = tokio::time::Instant::now() + wait;
+        loop {
+            match file.try_lock_exclusive() {
+                Ok(()) => return Some(Self { _file: file }),
+                Err(err) if is_contended(&err) => {
+                    if tokio::time::Instant::now() >= deadline {
+                        tracing::debug!("apply mutex still held; writing without it");
+                        return None;
+                    }
+                    tokio::time::sleep(Self::POLL).await;
+                }
+                Err(err) => {
+                    tracing::debug!(error = %err, "advisory lock unavailable for apply mutex");
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+fn apply_mutex_path() -> PathBuf {
+    std::env::temp_dir().join("funnelctl-apply.lock")
+}
+
+/// Opens the mutex file, creating it readable and writable by every user. An existing
+/// file is opened without `O_CREAT`, which sticky temp dirs refuse for files of other
+/// users (`fs.protected_regular`). Any user can plant something at the path, so neither
+/// open follows a symlink, an existing file must be a regular one (`O_NONBLOCK` keeps a
+/// planted FIFO from hanging the open), and only a file created here is chmodded.
+fn open_shared(path: &Path) -> std::io::Result<File> {
+    match no_follow(OpenOptions::new().read(true)).open(path) {
+        Ok(file) => return require_regular(file),
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        Err(_) => {}
+    }
+    let created = no_follow(OpenOptions::new().read(true).write(true).create_new(true)).open(path);
+    let file = match created {
+        Ok(file) => file,
+        // Created by someone else since the first attempt
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            return require_regular(no_follow(OpenOptions::new().read(true)).open(path)?);
+        }
+        Err(err) => return Err(err),
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        // Best effort: another user's umask must not lock everyone else out
+        let _ = file.set_permissions(std::fs::Permissions::from_mode(0o666));
+    }
+    Ok(file)
+}
+
+fn no_follow(options: &mut OpenOptions) -> &mut OpenOptions {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK);
+    }
+    options
+}
+
+/// `fstat` of the opened file, so the check and the lock see the same inode
+fn require_regular(file: File) -> std::io::Result<File> {
+    if file.metadata()?.is_file() {
+        Ok(file)
+    } else {
+        Err(std::io::Error::other(
+            "apply mutex path is not a regular file",
+        ))
+    }
+}
+
+/// Who holds the lock. PID alone is ambiguous once the PID is reused, so the process
+/// start time and host are recorded too.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct LockOwner {
+    pid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    start_time: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hostname: Option<String>,
+}
+
+impl LockOwner {
+    fn current() -> Self {
+        let pid = std::process::id();
+        Self {
+            pid,
+            start_time: process_start_time(pid),
+            hostname: hostname(),
+        }
+    }
+
+    /// Parses the JSON record, or the bare PID written by older versions
+    fn parse(contents: &str) -> Option<Self> {
+        let contents = contents.trim();
+        if let Ok(pid) = contents.parse::<u32>() {
+            return Some(Self {
+                pid,
+                start_time: None,
+                hostname: None,
+            });
+        }
+        serde_json::from_str(contents).ok()
+    }
+
+    /// Whether the recorded process still exists. A different start time means the PID
+    /// was reused. Owners on another host cannot be checked and count as live.
+    fn is_live(&self) -> bool {
+        if let (Some(recorded), Some(ours)) = (&self.hostname, hostname()) {
+            if *recorded != ours {
+                return true;
+            }
+        }
+        if !pid_is_alive(self.pid) {
+            return false;
+        }
+        match (self.start_time, process_start_time(self.pid)) {
+            (Some(recorded), Some(actual)) => recorded == actual,
+            _ => true,
+        }
+    }
+}
+
+impl std::fmt::Display for LockOwner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PID {}", self.pid)?;
+        match (&self.hostname, hostname()) {
+            (Some(recorded), Some(ours)) if *recorded != ours => write!(f, " on {}", recorded),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn is_contended(err: &std::io::Error) -> bool {
+    err.kind() == std::io::ErrorKind::WouldBlock
+        || err.raw_os_error() == fs4::lock_contended_error().raw_os_error()
+}
+
 fn lock_path() -> Result<PathBuf> {
     let dir = dirs::runtime_dir()?;
     Ok(dir.join("funnelctl.lock"))
 }
 
-fn write_pid(file: &mut File) -> Result<()> {
-    let pid = std::process::id();
+fn write_owner(file: &mut File) -> Result<()> {
+    let owner = serde_json::to_string(&LockOwner::current())
+        .map_err(|err| FunnelError::Other(format!("Failed to encode lock owner: {}", err)))?;
     file.set_len(0)
         .map_err(|err| FunnelError::Other(format!("Failed to truncate lock file: {}", err)))?;
     file.seek(SeekFrom::Start(0))
         .map_err(|err| FunnelError::Other(format!("Failed to seek lock file: {}", err)))?;
-    write!(file, "{}", pid)
+    write!(file, "{}", owner)
         .map_err(|err| FunnelError::Other(format!("Failed to write lock file: {}", err)))?;
     file.flush()
         .map_err(|err| FunnelError::Other(format!("Failed to flush lock file: {}", err)))?;
     Ok(())
 }
 
-fn read_pid(file: &mut File) -> Result<u32> {
+fn read_owner(file: &mut File) -> Result<LockOwner> {
     let mut contents = String::new();
     file.seek(SeekFrom::Start(0))
         .map_err(|err| FunnelError::Other(format!("Failed to seek lock file: {}", err)))?;
     file.read_to_string(&mut contents)
         .map_err(|err| FunnelError::Other(format!("Failed to read lock file: {}", err)))?;
-    let pid = contents
-        .trim()
-        .parse::<u32>()
-        .map_err(|err| FunnelError::Other(format!("Failed to parse lock PID: {}", err)))?;
-    Ok(pid)
+    LockOwner::parse(&contents)
+        .ok_or_else(|| FunnelError::Other("Failed to parse lock owner".to_string()))
+}
+
+/// Process start time in platform units (clock ticks since boot on Linux, microseconds
+/// since the epoch on macOS, FILETIME on Windows). Only compared for equality.
+pub(crate) fn process_start_time(pid: u32) -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        // The command name may contain spaces and parentheses; fields resume after the last ')'
+        let rest = &stat[stat.rfind(')')? + 1..];
+        // `rest` starts at field 3 (state); starttime is field 22
+        rest.split_whitespace().nth(19)?.parse().ok()
+    }
+
+    #[cfg(target_os = "macos")]
+    unsafe {
+        let mut info: libc::proc_bsdinfo = std::mem::zeroed();
+        let size = std::mem::size_of::<libc::proc_bsdinfo>() as libc::c_int;
+        let written = libc::proc_pidinfo(
+            pid as libc::c_int,
+            libc::PROC_PIDTBSDINFO,
+            0,
+            &mut info as *mut libc::proc_bsdinfo as *mut libc::c_void,
+            size,
+        );
+        if written != size {
+            return None;
+        }
+        Some(info.pbi_start_tvsec * 1_000_000 + info.pbi_start_tvusec)
+    }
+
+    #[cfg(windows)]
+    unsafe {
+        use windows_sys::Win32::Foundation::{CloseHandle, FILETIME};
+        use windows_sys::Win32::System::Threading::{
+            GetProcessTimes, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+        };
+
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            return None;
+        }
+        let empty = FILETIME {
+            dwLowDateTime: 0,
+            dwHighDateTime: 0,
+        };
+        let (mut created, mut exited, mut kernel, mut user) = (empty, empty, empty, empty);
+        let queried = GetProcessTimes(handle, &mut created, &mut exited, &mut kernel, &mut user);
+        CloseHandle(handle);
+        if queried == 0 {
+            return None;
+        }
+        Some((u64::from(created.dwHighDateTime) << 32) | u64::from(created.dwLowDateTime))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+    {
+        let _ = pid;
+        None
+    }
+}
+
+fn hostname() -> Option<String> {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        let result = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+        if result != 0 {
+            return None;
+        }
+        let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        String::from_utf8(buf[..len].to_vec()).ok()
+    }
+
+    #[cfg(not(unix))]
+    {
+        std::env::var("COMPUTERNAME").ok()
+    }
 }
 
-fn pid_is_alive(pid: u32) -> bool {
+pub(crate) fn pid_is_alive(pid: u32) -> bool {
     #[cfg(unix)]
     unsafe {
         let result = libc::kill(pid as i32, 0);
@@ -94,9 +355,126 @@ fn pid_is_alive(pid: u32) -> bool {
         matches!(err.raw_os_error(), Some(libc::EPERM))
     }
 
-    #[cfg(not(unix))]
+    #[cfg(windows)]
+    unsafe {
+        use windows_sys::Win32::Foundation::{
+            CloseHandle, GetLastError, ERROR_ACCESS_DENIED, STILL_ACTIVE,
+        };
+        use windows_sys::Win32::System::Threading::{
+            GetExitCodeProcess, OpenProcess, PROCESS_QUERY_LIMITED_INFORMATION,
+        };
+
+        let handle = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, pid);
+        if handle.is_null() {
+            // Access denied means the process exists but belongs to another user
+            return GetLastError() == ERROR_ACCESS_DENIED;
+        }
+        let mut exit_code = 0u32;
+        let queried = GetExitCodeProcess(handle, &mut exit_code);
+        CloseHandle(handle);
+        queried != 0 && exit_code == STILL_ACTIVE as u32
+    }
+
+    #[cfg(not(any(unix, windows)))]
     {
         let _ = pid;
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_legacy_pid_record() {
+        let owner = LockOwner::parse("4242\n").unwrap();
+        assert_eq!(owner.pid, 4242);
+        assert!(owner.start_time.is_none());
+        assert!(LockOwner::parse("not a record").is_none());
+    }
+
+    #[test]
+    fn test_owner_record_roundtrip() {
+        let owner = LockOwner::current();
+        let json = serde_json::to_string(&owner).unwrap();
+        assert_eq!(LockOwner::parse(&json), Some(owner));
+    }
+
+    #[test]
+    fn test_current_owner_is_live() {
+        assert!(LockOwner::current().is_live());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn test_reused_pid_is_not_live() {
+        let mut owner = LockOwner::current();
+        assert!(owner.start_time.is_some());
+        owner.start_time = owner.start_time.map(|time| time + 1);
+        assert!(!owner.is_live());
+    }
+
+    #[tokio::test]
+    async fn test_apply_mutex_waits_for_holder() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("funnelctl-apply.lock");
+        let held = ApplyMutex::acquire_at(&path, ApplyMutex::WAIT)
+            .await
+            .unwrap();
+        assert!(ApplyMutex::acquire_at(&path, Duration::from_millis(60))
+            .await
+            .is_none());
+        drop(held);
+        assert!(ApplyMutex::acquire_at(&path, ApplyMutex::WAIT)
+            .await
+            .is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_open_shared_refuses_planted_paths() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let victim = dir.path().join("victim");
+        std::fs::write(&victim, "secret").unwrap();
+        std::fs::set_permissions(&victim, std::fs::Permissions::from_mode(0o600)).unwrap();
+        let link = dir.path().join("link.lock");
+        std::os::unix::fs::symlink(&victim, &link).unwrap();
+        assert!(open_shared(&link).is_err());
+        let mode = std::fs::metadata(&victim).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let fifo = dir.path().join("fifo.lock");
+        let c_path = std::ffi::CString::new(fifo.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        assert!(open_shared(&fifo).is_err());
+
+        let existing = dir.path().join("existing.lock");
+        std::fs::write(&existing, "").unwrap();
+        std::fs::set_permissions(&existing, std::fs::Permissions::from_mode(0o600)).unwrap();
+        open_shared(&existing).unwrap();
+        let mode = std::fs::metadata(&existing).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let fresh = dir.path().join("fresh.lock");
+        open_shared(&fresh).unwrap();
+        let mode = std::fs::metadata(&fresh).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o666);
+    }
+
+    #[test]
+    fn test_owner_on_other_host_is_live() {
+        let owner = LockOwner {
+            pid: u32::MAX,
+            start_time: None,
+            hostname: Some("some-other-host.invalid".to_string()),
+        };
+        assert!(owner.is_live());
+        assert_eq!(
+            owner.to_string(),
+            "PID 4294967295 on some-other-host.invalid"
+        );
+    }
+}
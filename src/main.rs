@@ -1,25 +1,45 @@
+use chrono::Utc;
 use clap::Parser;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
-use funnelctl::backend::{localapi::LocalApiBackend, UnreachableBackend};
-use funnelctl::cli::{Cli, Commands};
-use funnelctl::cmd::{CloseCommand, CompletionsCommand, DoctorCommand, OpenCommand, StatusCommand};
+use funnelctl::backend::localapi::{
+    LocalApiBackend, DEFAULT_APPLY_TIMEOUT, DEFAULT_STATUS_CACHE_TTL,
+};
+use funnelctl::backend::{MockBackend, UnreachableBackend};
+use funnelctl::cli::{Cli, Commands, LocalApiArgs};
+use funnelctl::cmd::history;
+use funnelctl::cmd::open::open_event_stream;
+use funnelctl::cmd::reaper::reap_expired;
+use funnelctl::cmd::{
+    ApplyCommand, AuditCommand, CheckCommand, CleanCommand, CloseCommand, CompletionsCommand,
+    DoctorCommand, DoctorOptions, DownCommand, ErrorsCommand, ExportCommand, ExtendCommand,
+    FunnelPolicyCommand, HealthCommand, HistoryCommand, ImportCommand, MigrateCommand, OpenCommand,
+    PauseCommand, PromptCommand, ReaperCommand, RelayCommand, RollbackCommand, SelftestCommand,
+    SelftestOptions, StatusCommand, UpCommand, WatchRequestsCommand,
+};
+use funnelctl::config::{Config, LocalApiConfig};
+use funnelctl::core::LeaseStore;
+use funnelctl::dirs;
 use funnelctl::error::FunnelError;
-use funnelctl::output::{self, Event};
+use funnelctl::journald;
+use funnelctl::net::http_dump;
+use funnelctl::output::{self, Event, Theme};
+use funnelctl::plugin;
+use funnelctl::version;
 
 #[tokio::main]
 async fn main() {
     let exit_code = match run().await {
-        Ok(()) => 0,
+        Ok(code) => code,
         Err((err, json_mode)) => {
+            // stderr reaches the journal as plain lines; this one is searchable by priority
+            if journald::active() {
+                tracing::error!(code = err.exit_code(), "{}", err);
+            }
             if json_mode {
-                let event = Event::Error {
-                    version: 1,
-                    code: err.exit_code(),
-                    message: err.to_string(),
-                    suggestion: err.get_fix(),
-                };
-                let _ = event.emit_json();
+                let _ = Event::from_error(&err).emit_json();
             } else {
                 let use_color = output::use_color();
                 eprintln!("{}", err.format_detailed(use_color));
@@ -31,62 +51,485 @@ async fn main() {
     std::process::exit(exit_code);
 }
 
-async fn run() -> Result<(), (FunnelError, bool)> {
-    let cli = match Cli::try_parse() {
+/// Returns the process exit code; only `doctor` and `selftest` report failures without an error
+async fn run() -> Result<i32, (FunnelError, bool)> {
+    let argv: Vec<String> = std::env::args().skip(1).collect();
+    if version::wants_json(&argv) {
+        let info = serde_json::to_string(&version::VersionInfo::current())
+            .map_err(|err| (FunnelError::Other(err.to_string()), false))?;
+        println!("{}", info);
+        return Ok(0);
+    }
+
+    let mut cli = match Cli::try_parse() {
         Ok(cli) => cli,
-        Err(err) => return Err((map_parse_error(err), false)),
+        Err(err) => return Err((map_parse_error(err), asks_for_json(&argv))),
     };
 
-    if let Err(err) = init_tracing(cli.verbose) {
-        return Err((err, false));
+    if cli.debug_http {
+        http_dump::enable();
+    }
+    if let Err(err) = init_tracing(cli.verbose, cli.debug_http) {
+        return Err((err, cli.command.json()));
+    }
+
+    if let Some(dir) = cli.state_dir.clone() {
+        dirs::set_state_dir(dir).map_err(|err| (err, cli.command.json()))?;
+    }
+
+    // Neither touches state or config, so both work on a broken install
+    let stateless = matches!(cli.command, Commands::Completions(_) | Commands::Errors(_));
+    if !stateless {
+        // An unusable state dir fails the commands that need it, in their own output
+        // mode; doctor reports it as a check
+        match dirs::state_dir() {
+            Ok(dir) => {
+                tracing::info!(path = %dir.display(), "using state directory");
+                collect_stale_leases();
+            }
+            Err(err) => tracing::info!(error = %err, "state directory unavailable"),
+        }
+    }
+
+    if let Commands::Open(args) = &mut cli.command {
+        let given = history::open_arguments(&argv);
+        match args.from_history.as_deref() {
+            Some(query) => match history::replay_open(query, &given, args.yes) {
+                Ok(Some(replayed)) => **args = replayed,
+                Ok(None) => return Ok(0),
+                Err(err) => return Err((err, args.json)),
+            },
+            None => args.raw_args = given,
+        }
     }
 
-    let json_mode = matches!(cli.command, Commands::Open(ref args) if args.json);
+    let json_mode = cli.command.json();
+
+    let config = if stateless {
+        Config::default()
+    } else {
+        Config::load().map_err(|err| (err, json_mode))?
+    };
+    let theme = Theme::resolve(&config.theme, cli.plain);
 
-    match cli.command {
+    let outcome = match cli.command {
         Commands::Open(args) => {
-            let transport = LocalApiBackend::build_transport(
-                args.socket.clone(),
-                args.localapi_port,
-                args.localapi_password_file.clone(),
+            open_event_stream(&args).map_err(|err| (err, json_mode))?;
+            let backend = match args.remote_node.as_deref() {
+                Some(node) => {
+                    let remote = RemoteNode {
+                        port: args.remote_port,
+                        token_file: args.remote_token_file.clone(),
+                        force: args.force,
+                        skip_checks: args.skip_checks,
+                    };
+                    build_remote_backend(node, &remote, &args.localapi, &config.localapi).await
+                }
+                None => build_backend(
+                    &args.localapi,
+                    args.force,
+                    args.skip_checks,
+                    &config.localapi,
+                ),
+            };
+            let backend = backend.map_err(|err| {
+                plugin::publish(&Event::from_error(&err));
+                (err, json_mode)
+            })?;
+            if args.remote_node.is_none() {
+                reap_detached(backend.as_ref()).await;
+            }
+            let cmd = OpenCommand::new(*args, config.open, theme);
+            cmd.run(backend, json_mode)
+                .await
+                .map_err(|err| (err, json_mode))
+        }
+        Commands::Apply(args) if !args.nodes.is_empty() => {
+            let remote = RemoteNode {
+                port: args.remote_port,
+                token_file: args.remote_token_file.clone(),
+                force: args.force,
+                skip_checks: args.skip_checks,
+            };
+            let mut nodes = Vec::with_capacity(args.nodes.len());
+            for node in &args.nodes {
+                let backend =
+                    build_remote_backend(node, &remote, &args.localapi, &config.localapi).await;
+                nodes.push((
+                    node.clone(),
+                    backend.map(|backend| backend as Arc<dyn funnelctl::backend::Backend>),
+                ));
+            }
+            ApplyCommand::new(args, config.open, theme)
+                .run_nodes(nodes)
+                .await
+                .map_err(|err| (err, json_mode))
+        }
+        Commands::Apply(args) => {
+            let backend = build_backend(
+                &args.localapi,
+                args.force,
+                args.skip_checks,
+                &config.localapi,
             )
             .map_err(|err| (err, json_mode))?;
-            let backend = Arc::new(LocalApiBackend::new(transport, args.force));
-            let cmd = OpenCommand::new(args);
-            cmd.run(backend, json_mode)
+            reap_detached(backend.as_ref()).await;
+            let result = ApplyCommand::new(args, config.open, theme)
+                .run(backend.clone())
+                .await;
+            report_warnings(backend.as_ref());
+            result.map_err(|err| (err, json_mode))
+        }
+        Commands::Up(args) => {
+            let backend = build_backend(
+                &args.localapi,
+                args.force,
+                args.skip_checks,
+                &config.localapi,
+            )
+            .map_err(|err| (err, json_mode))?;
+            reap_detached(backend.as_ref()).await;
+            UpCommand::new(args, config.open)
+                .run(backend)
                 .await
                 .map_err(|err| (err, json_mode))
         }
+        Commands::Down(args) => {
+            let backend = build_backend(&args.localapi, false, false, &config.localapi)
+                .map_err(|err| (err, json_mode))?;
+            reap_detached(backend.as_ref()).await;
+            let result = DownCommand::new(args).run(backend.clone()).await;
+            report_warnings(backend.as_ref());
+            result.map_err(|err| (err, json_mode))
+        }
         Commands::Doctor(args) => {
-            let tcp_mode = args.localapi_port.is_some();
+            let tcp_mode = args.localapi.localapi_port.is_some();
             let backend: Arc<dyn funnelctl::backend::Backend> =
                 match LocalApiBackend::build_transport(
-                    args.socket.clone(),
-                    args.localapi_port,
-                    args.localapi_password_file.clone(),
+                    args.localapi.socket.clone(),
+                    args.localapi.localapi_port,
+                    args.localapi.localapi_password_file.clone(),
                 ) {
-                    Ok(transport) => Arc::new(LocalApiBackend::new(transport, false)),
+                    Ok(transport) => Arc::new(
+                        LocalApiBackend::new(transport, false)
+                            .with_etag_fallback(args.localapi.no_etag)
+                            .with_status_cache(cache_ttl(&args.localapi, &config.localapi))
+                            .with_apply_timeout(
+                                apply_timeout(&args.localapi, &config.localapi)
+                                    .map_err(|err| (err, json_mode))?,
+                            ),
+                    ),
                     Err(err) if !tcp_mode => match err {
                         FunnelError::Unreachable { context, .. } => {
                             Arc::new(UnreachableBackend::new(context))
                         }
-                        other => return Err((other, false)),
+                        other => return Err((other, json_mode)),
                     },
-                    Err(err) => return Err((err, false)),
+                    Err(err) => return Err((err, json_mode)),
                 };
-            DoctorCommand::run(backend, tcp_mode)
+            let options = DoctorOptions {
+                tcp_mode,
+                external: args.external,
+                dump_raw: cli.verbose > 0,
+                theme,
+                only: args.only,
+                skip: args.skip,
+                daemon_sockets: Some(LocalApiBackend::socket_candidates(
+                    args.localapi.socket.as_deref(),
+                )),
+                socket: if tcp_mode {
+                    None
+                } else {
+                    args.localapi
+                        .socket
+                        .clone()
+                        .or_else(LocalApiBackend::default_socket)
+                },
+                https_port: Some(args.https_port),
+                fix: args.fix,
+                json: args.json,
+            };
+            let report = DoctorCommand::run(backend.clone(), options).await;
+            report_warnings(backend.as_ref());
+            return Ok(report.map_err(|err| (err, json_mode))?.exit_code);
+        }
+        Commands::Close(args) => {
+            let backend = build_backend(&args.localapi, false, false, &config.localapi)
+                .map_err(|err| (err, json_mode))?;
+            reap_detached(backend.as_ref()).await;
+            let result = CloseCommand::new(args).run(backend.clone()).await;
+            report_warnings(backend.as_ref());
+            result.map_err(|err| (err, json_mode))
+        }
+        Commands::Clean(args) => {
+            let backend = build_backend(&args.localapi, false, false, &config.localapi)
+                .map_err(|err| (err, json_mode))?;
+            reap_detached(backend.as_ref()).await;
+            let result = CleanCommand::new(args).run(backend.clone()).await;
+            report_warnings(backend.as_ref());
+            result.map_err(|err| (err, json_mode))
+        }
+        Commands::Export(args) => {
+            let backend = build_backend(&args.localapi, false, false, &config.localapi)
+                .map_err(|err| (err, json_mode))?;
+            ExportCommand::new(args)
+                .run(backend)
+                .await
+                .map_err(|err| (err, json_mode))
+        }
+        Commands::Audit(args) => {
+            let backend = build_backend(&args.localapi, false, false, &config.localapi)
+                .map_err(|err| (err, json_mode))?;
+            AuditCommand::new(args)
+                .run(backend)
+                .await
+                .map_err(|err| (err, json_mode))
+        }
+        Commands::FunnelPolicy(args) => {
+            let backend = build_backend(&args.localapi, false, false, &config.localapi)
+                .map_err(|err| (err, json_mode))?;
+            FunnelPolicyCommand::new(args)
+                .run(backend)
+                .await
+                .map_err(|err| (err, json_mode))
+        }
+        Commands::Import(args) => {
+            let backend = build_backend(&args.localapi, args.force, false, &config.localapi)
+                .map_err(|err| (err, json_mode))?;
+            reap_detached(backend.as_ref()).await;
+            let result = ImportCommand::new(args).run(backend.clone()).await;
+            report_warnings(backend.as_ref());
+            result.map_err(|err| (err, json_mode))
+        }
+        Commands::Rollback(args) => {
+            let backend = build_backend(&args.localapi, false, false, &config.localapi)
+                .map_err(|err| (err, json_mode))?;
+            let result = RollbackCommand::new(args, theme).run(backend.clone()).await;
+            report_warnings(backend.as_ref());
+            result.map_err(|err| (err, json_mode))
+        }
+        Commands::Reaper(args) => {
+            let backend = build_backend(&args.localapi, false, false, &config.localapi)
+                .map_err(|err| (err, json_mode))?;
+            let result = ReaperCommand::run(backend.clone()).await;
+            report_warnings(backend.as_ref());
+            result.map_err(|err| (err, json_mode))
+        }
+        Commands::Relay(args) => {
+            let transport = LocalApiBackend::build_transport(
+                args.localapi.socket.clone(),
+                args.localapi.localapi_port,
+                args.localapi.localapi_password_file.clone(),
+            )
+            .map_err(|err| (err, json_mode))?;
+            RelayCommand::new(args)
+                .run(transport)
+                .await
+                .map_err(|err| (err, json_mode))
+        }
+        Commands::Migrate(args) => MigrateCommand::new(args)
+            .run()
+            .map_err(|err| (err, json_mode)),
+        Commands::Selftest(args) => {
+            let backend: Arc<dyn funnelctl::backend::Backend> = if args.offline {
+                Arc::new(MockBackend::new())
+            } else {
+                build_backend(&args.localapi, false, false, &config.localapi)
+                    .map_err(|err| (err, json_mode))?
+            };
+            let options = SelftestOptions {
+                offline: args.offline,
+                theme,
+            };
+            let report = SelftestCommand::run(backend.clone(), options).await;
+            report_warnings(backend.as_ref());
+            return Ok(report.exit_code);
+        }
+        Commands::Check(args) => {
+            let backend = local_backend(&args.localapi, false, false, &config.localapi)
+                .map_err(|err| (err, json_mode))?
+                .with_target_check(args.check_targets);
+            return CheckCommand::new(args, config.open, theme)
+                .run(Arc::new(backend))
+                .await
+                .map_err(|err| (err, json_mode));
+        }
+        Commands::Health(args) => {
+            let backend = build_backend(&args.localapi, false, false, &config.localapi)
+                .map_err(|err| (err, json_mode))?;
+            return HealthCommand::new(args)
+                .run(backend)
+                .await
+                .map_err(|err| (err, json_mode));
+        }
+        Commands::Extend(args) => ExtendCommand::new(args)
+            .run()
+            .await
+            .map_err(|err| (err, json_mode)),
+        Commands::Pause(args) => PauseCommand::pause(args)
+            .run()
+            .await
+            .map_err(|err| (err, json_mode)),
+        Commands::Resume(args) => PauseCommand::resume(args)
+            .run()
+            .await
+            .map_err(|err| (err, json_mode)),
+        Commands::Status(args) => StatusCommand::new(args)
+            .run()
+            .await
+            .map_err(|err| (err, json_mode)),
+        Commands::Prompt(args) => {
+            let backend = build_backend(&args.localapi, false, false, &config.localapi);
+            PromptCommand::new(args)
+                .run(backend)
                 .await
-                .map_err(|err| (err, false))
+                .map_err(|err| (err, json_mode))
         }
-        Commands::Close => CloseCommand::run().await.map_err(|err| (err, false)),
-        Commands::Status => StatusCommand::run().await.map_err(|err| (err, false)),
+        Commands::WatchRequests(args) => WatchRequestsCommand::new(args)
+            .run()
+            .await
+            .map_err(|err| (err, json_mode)),
+        Commands::History(args) => HistoryCommand::new(args)
+            .run()
+            .map_err(|err| (err, json_mode)),
+        Commands::Errors(args) => ErrorsCommand::new(args)
+            .run()
+            .map_err(|err| (err, json_mode)),
         Commands::Completions(args) => {
             let cmd = CompletionsCommand { shell: args.shell };
-            cmd.run().map_err(|err| (err, false))
+            cmd.run().map_err(|err| (err, json_mode))
         }
+    };
+    outcome.map(|()| 0)
+}
+
+/// Marks dead or expired leases stale. Best effort: a broken state dir must not block commands.
+fn collect_stale_leases() {
+    if let Err(err) = LeaseStore::open().and_then(|store| store.collect_garbage(Utc::now())) {
+        tracing::warn!(error = %err, "lease garbage collection failed");
+    }
+}
+
+/// Tears down expired detached tunnels before the command runs. Best effort, like
+/// `collect_stale_leases`; `funnelctl reaper` reports failures instead.
+async fn reap_detached(backend: &LocalApiBackend) {
+    if let Err(err) = reap_expired(backend).await {
+        tracing::warn!(error = %err, "failed to reap expired detached tunnels");
+    }
+}
+
+/// Prints the warnings the backend raised while the command ran; `open` and `up` print
+/// their own as they go
+fn report_warnings(backend: &dyn funnelctl::backend::Backend) {
+    for warning in backend.take_warnings() {
+        output::advise(format_args!("Warning: {}", warning));
+    }
+}
+
+fn build_backend(
+    args: &LocalApiArgs,
+    force: bool,
+    skip_checks: bool,
+    config: &LocalApiConfig,
+) -> Result<Arc<LocalApiBackend>, FunnelError> {
+    local_backend(args, force, skip_checks, config).map(Arc::new)
+}
+
+/// `build_backend` before it is shared, for commands that adjust it further
+fn local_backend(
+    args: &LocalApiArgs,
+    force: bool,
+    skip_checks: bool,
+    config: &LocalApiConfig,
+) -> Result<LocalApiBackend, FunnelError> {
+    let transport = LocalApiBackend::build_transport(
+        args.socket.clone(),
+        args.localapi_port,
+        args.localapi_password_file.clone(),
+    )?;
+    Ok(LocalApiBackend::new(transport, force)
+        .with_etag_fallback(args.no_etag)
+        .with_skip_checks(skip_checks)
+        .with_status_cache(cache_ttl(args, config))
+        .with_apply_timeout(apply_timeout(args, config)?))
+}
+
+/// `--no-cache` turns the status cache off for one run
+fn cache_ttl(args: &LocalApiArgs, config: &LocalApiConfig) -> Duration {
+    if args.no_cache {
+        Duration::ZERO
+    } else {
+        config.status_cache.unwrap_or(DEFAULT_STATUS_CACHE_TTL)
+    }
+}
+
+/// `--apply-timeout`, else `localapi.apply_timeout`, else the default
+fn apply_timeout(args: &LocalApiArgs, config: &LocalApiConfig) -> Result<Duration, FunnelError> {
+    let Some(raw) = &args.apply_timeout else {
+        return Ok(config.apply_timeout.unwrap_or(DEFAULT_APPLY_TIMEOUT));
+    };
+    match humantime::parse_duration(raw) {
+        Ok(timeout) if !timeout.is_zero() => Ok(timeout),
+        Ok(_) => Err(FunnelError::InvalidField {
+            field: "--apply-timeout".to_string(),
+            value: raw.clone(),
+            constraint: "--apply-timeout must be greater than zero".to_string(),
+        }),
+        Err(err) => Err(FunnelError::InvalidField {
+            field: "--apply-timeout".to_string(),
+            value: raw.clone(),
+            constraint: format!("Invalid --apply-timeout '{}': {}", raw, err),
+        }),
     }
 }
 
+/// How to reach other nodes' relays (`open --remote-node`, `apply --node`)
+struct RemoteNode {
+    port: u16,
+    token_file: Option<PathBuf>,
+    force: bool,
+    skip_checks: bool,
+}
+
+/// A node's relay. The local daemon, if there is one, supplies the tailnet suffix for a
+/// bare node name.
+async fn build_remote_backend(
+    node: &str,
+    remote: &RemoteNode,
+    args: &LocalApiArgs,
+    config: &LocalApiConfig,
+) -> Result<Arc<LocalApiBackend>, FunnelError> {
+    let token_file = remote.token_file.clone().ok_or_else(|| {
+        FunnelError::InvalidArgument("--remote-node requires --remote-token-file".to_string())
+    })?;
+    let local = build_backend(args, false, false, config).ok();
+    let transport = LocalApiBackend::build_remote_transport(
+        node,
+        remote.port,
+        token_file,
+        local
+            .as_deref()
+            .map(|backend| backend as &dyn funnelctl::backend::Backend),
+    )
+    .await?;
+    Ok(Arc::new(
+        LocalApiBackend::new(transport, remote.force)
+            .with_etag_fallback(args.no_etag)
+            .with_status_cache(cache_ttl(args, config))
+            .with_apply_timeout(apply_timeout(args, config)?)
+            .with_skip_checks(remote.skip_checks)
+            .for_remote_node(),
+    ))
+}
+
+/// Whether `--json` appears among the options, for errors clap raises before there is a
+/// parsed command to ask
+fn asks_for_json(argv: &[String]) -> bool {
+    argv.iter()
+        .take_while(|arg| *arg != "--")
+        .any(|arg| arg == "--json")
+}
+
 fn map_parse_error(err: clap::Error) -> FunnelError {
     use clap::error::ErrorKind;
     if matches!(
@@ -99,14 +542,18 @@ fn map_parse_error(err: clap::Error) -> FunnelError {
     FunnelError::InvalidArgument(err.to_string())
 }
 
-fn init_tracing(verbose: u8) -> Result<(), FunnelError> {
+fn init_tracing(verbose: u8, debug_http: bool) -> Result<(), FunnelError> {
+    use tracing_subscriber::prelude::*;
     use tracing_subscriber::EnvFilter;
 
+    let journal = journal_layer();
     let filter = match std::env::var("RUST_LOG") {
         Ok(value) if !value.trim().is_empty() => EnvFilter::try_new(value)
             .map_err(|err| FunnelError::Other(format!("Invalid RUST_LOG value: {}", err)))?,
         _ => {
             let level = match verbose {
+                // The journal filters by priority itself, so a unit logs lifecycle events
+                0 if journal.is_some() => "info",
                 0 => "error",
                 1 => "info",
                 2 => "debug",
@@ -115,12 +562,42 @@ fn init_tracing(verbose: u8) -> Result<(), FunnelError> {
             EnvFilter::new(level)
         }
     };
+    // --debug-http shows the exchanges whatever -v says
+    let filter = if debug_http {
+        let directive = format!("{}=debug", http_dump::TARGET)
+            .parse()
+            .map_err(|err| FunnelError::Other(format!("Invalid log directive: {}", err)))?;
+        filter.add_directive(directive)
+    } else {
+        filter
+    };
 
-    tracing_subscriber::fmt()
-        .with_env_filter(filter)
-        .with_writer(std::io::stderr)
-        .with_target(false)
-        .without_time()
+    let stderr = journal.is_none().then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_writer(std::io::stderr)
+            .with_target(false)
+            .without_time()
+    });
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(journal)
+        .with(stderr)
         .try_init()
         .map_err(|err| FunnelError::Other(format!("Failed to initialize logging: {}", err)))
 }
+
+/// Native journald logging when systemd runs us; stderr lines otherwise
+#[cfg(unix)]
+fn journal_layer() -> Option<journald::JournaldLayer> {
+    if !journald::detected() {
+        return None;
+    }
+    journald::JournaldLayer::connect()
+        .map_err(|err| eprintln!("Warning: cannot log to journald ({}); using stderr.", err))
+        .ok()
+}
+
+#[cfg(not(unix))]
+fn journal_layer() -> Option<tracing_subscriber::layer::Identity> {
+    None
+}
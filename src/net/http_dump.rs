@@ -0,0 +1,142 @@
+//! `--debug-http`: every LocalAPI exchange in the log, for bug reports.
+//!
+//! Credentials in headers are masked, bodies are redacted by the caller (it knows which
+//! document it holds) or left out, and cut at `MAX_BODY_BYTES`. Everything goes to the
+//! `funnelctl::http` tracing target at debug level.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use hyper::header::HeaderMap;
+use hyper::{Method, Request, Response};
+
+pub const TARGET: &str = "funnelctl::http";
+const MAX_BODY_BYTES: usize = 4096;
+const REDACTED: &str = "[redacted]";
+const SECRET_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// The request line and headers as sent, including those the transport adds
+pub fn request<B>(request: &Request<B>, body_len: usize) {
+    if !enabled() {
+        return;
+    }
+    tracing::debug!(
+        target: TARGET,
+        "> {} {}\n{}> ({} byte body)",
+        request.method(),
+        request.uri(),
+        format_headers(request.headers(), "> "),
+        body_len
+    );
+}
+
+pub fn response<B>(method: &Method, path: &str, response: &Response<B>) {
+    if !enabled() {
+        return;
+    }
+    tracing::debug!(
+        target: TARGET,
+        "< {} for {} {}\n{}",
+        response.status(),
+        method,
+        path,
+        format_headers(response.headers(), "< ")
+    );
+}
+
+/// A body, or one line of a streamed body; `text` must already be redacted
+pub fn body(direction: char, path: &str, text: &str) {
+    if !enabled() {
+        return;
+    }
+    tracing::debug!(
+        target: TARGET,
+        "{} body of {}: {}",
+        direction,
+        path,
+        truncate(text)
+    );
+}
+
+/// A body that cannot be redacted, logged as its size and content type only
+pub fn omitted_body(direction: char, path: &str, content_type: Option<&str>, len: usize) {
+    body(direction, path, &omitted(content_type, len));
+}
+
+fn omitted(content_type: Option<&str>, len: usize) -> String {
+    format!(
+        "[{} bytes of {} omitted]",
+        len,
+        content_type.unwrap_or("untyped content")
+    )
+}
+
+fn format_headers(headers: &HeaderMap, prefix: &str) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if SECRET_HEADERS.contains(&name.as_str()) {
+                REDACTED.into()
+            } else {
+                String::from_utf8_lossy(value.as_bytes())
+            };
+            format!("{}{}: {}\n", prefix, name, value)
+        })
+        .collect()
+}
+
+fn truncate(text: &str) -> String {
+    if text.len() <= MAX_BODY_BYTES {
+        return text.to_string();
+    }
+    let mut end = MAX_BODY_BYTES;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}... ({} more bytes)", &text[..end], text.len() - end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
+
+    #[test]
+    fn test_headers_masked_and_bodies_truncated() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_static("Basic OnNlY3JldA=="),
+        );
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let formatted = format_headers(&headers, "> ");
+        assert!(formatted.contains("> authorization: [redacted]\n"));
+        assert!(formatted.contains("> content-type: application/json\n"));
+        assert!(!formatted.contains("OnNlY3JldA"));
+
+        let long = "é".repeat(MAX_BODY_BYTES);
+        let cut = truncate(&long);
+        assert!(cut.ends_with(&format!("... ({} more bytes)", MAX_BODY_BYTES)));
+        assert_eq!(truncate("{}"), "{}");
+
+        assert_eq!(
+            omitted(Some("text/plain; charset=utf-8"), 42),
+            "[42 bytes of text/plain; charset=utf-8 omitted]"
+        );
+        assert_eq!(omitted(None, 7), "[7 bytes of untyped content omitted]");
+    }
+}
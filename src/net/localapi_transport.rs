@@ -4,14 +4,21 @@ use std::sync::{Arc, Mutex};
 use base64::engine::general_purpose::STANDARD as base64_engine;
 use base64::Engine;
 use bytes::Bytes;
+use hyper::client::conn::http1;
 use hyper::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, HOST};
 use hyper::http::uri::InvalidUri;
 use hyper::{Method, Request, Response, StatusCode, Uri};
 use hyper_util::client::legacy::connect::HttpConnector;
 use hyper_util::client::legacy::Client;
-use hyper_util::rt::TokioExecutor;
+use hyper_util::rt::{TokioExecutor, TokioIo};
 use hyperlocal::{UnixConnector, Uri as UnixUri};
 use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+use super::http_dump;
 
 const LOCAL_API_HOST: &str = "local-tailscaled.sock";
 const SEC_TAILSCALE_HEADER: &str = "sec-tailscale";
@@ -51,6 +58,10 @@ pub enum LocalApiError {
     },
     #[error("watch-ipn-bus did not provide a session id")]
     MissingSessionId,
+    #[error("remote token file {path}: {reason}")]
+    TokenFile { path: PathBuf, reason: String },
+    #[error("TLS setup failed: {0}")]
+    Tls(String),
 }
 
 pub struct TransportRequest {
@@ -79,6 +90,10 @@ impl TransportRequest {
         self
     }
 
+    fn body_len(&self) -> usize {
+        self.body.as_ref().map_or(0, Vec::len)
+    }
+
     fn build_request(
         &self,
         uri: Uri,
@@ -105,6 +120,7 @@ impl TransportRequest {
 pub enum LocalApiTransport {
     UnixSocket(UnixSocketTransport),
     TcpAuth(TcpAuthTransport),
+    Remote(RemoteTransport),
 }
 
 impl LocalApiTransport {
@@ -124,14 +140,47 @@ impl LocalApiTransport {
         )?))
     }
 
+    /// Another node's LocalAPI behind its `funnelctl relay`, over HTTPS with the node's
+    /// Tailscale certificate
+    pub fn remote(
+        host: impl Into<String>,
+        port: u16,
+        token_file: impl Into<PathBuf>,
+    ) -> Result<Self, LocalApiError> {
+        Ok(Self::Remote(RemoteTransport::new(
+            host.into(),
+            port,
+            token_file.into(),
+        )?))
+    }
+
+    /// Identifies the daemon behind this transport, for caches shared between runs
+    pub fn cache_key(&self) -> String {
+        match self {
+            LocalApiTransport::UnixSocket(transport) => {
+                format!("unix:{}", transport.socket_path.display())
+            }
+            LocalApiTransport::TcpAuth(transport) => {
+                format!("tcp:{}:{}", transport.host, transport.port)
+            }
+            LocalApiTransport::Remote(transport) => {
+                format!("remote:{}:{}", transport.host, transport.port)
+            }
+        }
+    }
+
     pub async fn send(
         &self,
         request: TransportRequest,
     ) -> Result<Response<hyper::body::Incoming>, LocalApiError> {
-        match self {
+        let (method, path) = (request.method.clone(), request.path.clone());
+        let response = match self {
             LocalApiTransport::UnixSocket(transport) => transport.send(request).await,
             LocalApiTransport::TcpAuth(transport) => transport.send(request).await,
-        }
+            LocalApiTransport::Remote(transport) => transport.send(request).await,
+        }?;
+        http_dump::response(&method, &path, &response);
+        Ok(response)
     }
 }
 
@@ -158,6 +207,7 @@ impl UnixSocketTransport {
         let mut extra_headers = HeaderMap::new();
         extra_headers.insert(HOST, HeaderValue::from_static(LOCAL_API_HOST));
         let req = request.build_request(uri, extra_headers)?;
+        http_dump::request(&req, request.body_len());
         tracing::debug!(method = %req.method(), path = %request.path, "LocalAPI request (unix)");
         let response = self.client.request(req).await?;
         Ok(response)
@@ -225,6 +275,7 @@ impl TcpAuthTransport {
             HeaderValue::from_static("localapi"),
         );
         let req = request.build_request(uri, extra_headers)?;
+        http_dump::request(&req, request.body_len());
         tracing::debug!(method = %req.method(), path = %request.path, "LocalAPI request (tcp)");
         let response = self.client.request(req).await?;
         Ok(response)
@@ -237,6 +288,97 @@ impl TcpAuthTransport {
     }
 }
 
+#[derive(Clone)]
+pub struct RemoteTransport {
+    host: String,
+    port: u16,
+    token: String,
+    tls: TlsConnector,
+}
+
+impl RemoteTransport {
+    pub fn new(host: String, port: u16, token_file: PathBuf) -> Result<Self, LocalApiError> {
+        let token = read_token_file(&token_file)?;
+        Ok(Self {
+            host,
+            port,
+            token,
+            tls: tls_connector()?,
+        })
+    }
+
+    /// One connection per request: watch-ipn-bus holds its connection for the whole
+    /// session, and the rest are a handful of calls per command
+    async fn send(
+        &self,
+        request: TransportRequest,
+    ) -> Result<Response<hyper::body::Incoming>, LocalApiError> {
+        let server_name = ServerName::try_from(self.host.clone())
+            .map_err(|err| LocalApiError::Tls(format!("invalid node name: {}", err)))?;
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let tls = self.tls.connect(server_name, stream).await?;
+        let (mut sender, conn) = http1::handshake(TokioIo::new(tls)).await?;
+        tokio::spawn(async move {
+            if let Err(err) = conn.await {
+                tracing::debug!(error = %err, "remote LocalAPI connection ended with error");
+            }
+        });
+
+        let uri: Uri = request.path.parse()?;
+        let mut extra_headers = HeaderMap::new();
+        extra_headers.insert(
+            HOST,
+            HeaderValue::from_str(&self.host)
+                .map_err(|_| LocalApiError::InvalidHeaderValue { name: "host" })?,
+        );
+        extra_headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", self.token)).map_err(|_| {
+                LocalApiError::InvalidHeaderValue {
+                    name: "authorization",
+                }
+            })?,
+        );
+        let req = request.build_request(uri, extra_headers)?;
+        http_dump::request(&req, request.body_len());
+        tracing::debug!(method = %req.method(), path = %request.path, host = %self.host, "LocalAPI request (remote)");
+        Ok(sender.send_request(req).await?)
+    }
+}
+
+fn tls_connector() -> Result<TlsConnector, LocalApiError> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let mut config = ClientConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|err| LocalApiError::Tls(err.to_string()))?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Reads a relay token with the same rules as the LocalAPI password file
+pub fn read_token_file(path: &Path) -> Result<String, LocalApiError> {
+    read_password_file(path).map_err(|err| {
+        let reason = match err {
+            LocalApiError::PasswordPermissions { mode, .. } => {
+                format!("must have 0600 permissions (got {:03o})", mode)
+            }
+            LocalApiError::PasswordRead { source, .. } => format!("could not be read: {}", source),
+            LocalApiError::EmptyPasswordFile { .. } => "is empty".to_string(),
+            other => other.to_string(),
+        };
+        LocalApiError::TokenFile {
+            path: path.to_path_buf(),
+            reason,
+        }
+    })
+}
+
 fn build_basic_auth(password: &str) -> Result<HeaderValue, LocalApiError> {
     let creds = format!(":{password}");
     let encoded = base64_engine.encode(creds.as_bytes());
@@ -1,3 +1,5 @@
+pub mod http_dump;
 pub mod localapi_transport;
+pub mod probe;
 
 pub use localapi_transport::{LocalApiError, LocalApiTransport, TransportRequest};
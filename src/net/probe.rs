@@ -0,0 +1,297 @@
+//! Reachability probing from outside the tailnet, used by `doctor --external`,
+//! `selftest` and the public DNS check of `open`.
+//!
+//! MagicDNS answers `*.ts.net` names with tailnet addresses, so the probe resolves the
+//! node's public Funnel ingress addresses over DNS-over-HTTPS and connects to them
+//! directly, presenting the node name via SNI.
+
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::Incoming;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+const DOH_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+const DOH_HOST: &str = "cloudflare-dns.com";
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum ProbeError {
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("HTTP error: {0}")]
+    Http(#[from] hyper::Error),
+
+    #[error("{0}")]
+    Protocol(String),
+
+    #[error("timed out after {0:?}")]
+    Timeout(Duration),
+}
+
+/// Loopback HTTP server answering every request with a fixed token
+pub struct ProbeResponder {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl ProbeResponder {
+    pub async fn start(token: String) -> Result<Self, ProbeError> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        let token = Bytes::from(token);
+
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let token = token.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |_req: Request<Incoming>| {
+                        let token = token.clone();
+                        async move { Ok::<_, Infallible>(Response::new(Full::new(token))) }
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        Ok(Self { addr, task })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+}
+
+impl Drop for ProbeResponder {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DohResponse {
+    #[serde(rename = "Answer", default)]
+    answer: Vec<DohAnswer>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DohAnswer {
+    #[serde(rename = "type")]
+    record_type: u16,
+    data: String,
+}
+
+/// Resolves `host` to its public IPv4 addresses, bypassing the system (MagicDNS) resolver
+pub async fn public_addresses(host: &str, timeout: Duration) -> Result<Vec<IpAddr>, ProbeError> {
+    let addrs = lookup_a(host, timeout).await?;
+    if addrs.is_empty() {
+        return Err(ProbeError::Protocol(format!(
+            "{} has no public A record",
+            host
+        )));
+    }
+    Ok(addrs)
+}
+
+/// The A records public DNS has for `host`; empty when the name does not exist there
+pub async fn lookup_a(host: &str, timeout: Duration) -> Result<Vec<IpAddr>, ProbeError> {
+    const RECORD_A: u16 = 1;
+
+    let path = format!("/dns-query?name={}&type=A", host);
+    let (status, body) = https_get(DOH_ADDR, 443, DOH_HOST, &path, timeout).await?;
+    if status != StatusCode::OK {
+        return Err(ProbeError::Protocol(format!(
+            "DNS-over-HTTPS lookup returned {}",
+            status
+        )));
+    }
+
+    let response: DohResponse = serde_json::from_slice(&body)
+        .map_err(|err| ProbeError::Protocol(format!("invalid DNS-over-HTTPS answer: {}", err)))?;
+    Ok(response
+        .answer
+        .iter()
+        .filter(|answer| answer.record_type == RECORD_A)
+        .filter_map(|answer| answer.data.parse().ok())
+        .collect())
+}
+
+/// Whether the Internet can reach `addr`: not a tailnet (100.64.0.0/10), private,
+/// loopback, link-local or unspecified address. Funnel ingress is always public.
+pub fn is_public_address(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => {
+            let [a, b, ..] = v4.octets();
+            let tailnet = a == 100 && (b & 0xc0) == 64;
+            !(tailnet
+                || v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified())
+        }
+        IpAddr::V6(v6) => {
+            // fd7a:115c:a1e0::/48 is the tailnet ULA range
+            let unique_local = (v6.segments()[0] & 0xfe00) == 0xfc00;
+            !(unique_local || v6.is_loopback() || v6.is_unspecified())
+        }
+    }
+}
+
+/// Issues `GET https://{host}{path}` against `addr`, returning the status and body
+pub async fn https_get(
+    addr: IpAddr,
+    port: u16,
+    host: &str,
+    path: &str,
+    timeout: Duration,
+) -> Result<(StatusCode, Bytes), ProbeError> {
+    let exchange = async {
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|err| ProbeError::Protocol(format!("invalid server name: {}", err)))?;
+        let stream = TcpStream::connect((addr, port)).await?;
+        let tls = tls_connector()?.connect(server_name, stream).await?;
+        send_get(tls, host, path).await
+    };
+
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| ProbeError::Timeout(timeout))?
+}
+
+/// Issues a plain-HTTP `GET {path}` against `addr`, for loopback targets
+pub async fn http_get(
+    addr: SocketAddr,
+    path: &str,
+    timeout: Duration,
+) -> Result<(StatusCode, Bytes), ProbeError> {
+    let exchange = async {
+        let stream = TcpStream::connect(addr).await?;
+        send_get(stream, &addr.to_string(), path).await
+    };
+
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| ProbeError::Timeout(timeout))?
+}
+
+async fn send_get<S>(stream: S, host: &str, path: &str) -> Result<(StatusCode, Bytes), ProbeError>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + Unpin + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        let _ = conn.await;
+    });
+
+    let request = Request::get(path)
+        .header(hyper::header::HOST, host)
+        .header(hyper::header::ACCEPT, "application/dns-json, */*")
+        .header(
+            hyper::header::USER_AGENT,
+            concat!("funnelctl/", env!("CARGO_PKG_VERSION")),
+        )
+        .body(Full::new(Bytes::new()))
+        .map_err(|err| ProbeError::Protocol(err.to_string()))?;
+    let response = sender.send_request(request).await?;
+    let status = response.status();
+    let body = Limited::new(response.into_body(), MAX_BODY_BYTES)
+        .collect()
+        .await
+        .map_err(|err| ProbeError::Protocol(format!("failed to read body: {}", err)))?
+        .to_bytes();
+    Ok((status, body))
+}
+
+fn tls_connector() -> Result<TlsConnector, ProbeError> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let mut config = ClientConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(|err| ProbeError::Protocol(format!("TLS setup failed: {}", err)))?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_probe_responder_serves_token() {
+        let responder = ProbeResponder::start("token-123".to_string())
+            .await
+            .unwrap();
+
+        let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, responder.port()))
+            .await
+            .unwrap();
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+
+        let request = Request::get("/anything")
+            .header(hyper::header::HOST, "localhost")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, Bytes::from("token-123"));
+    }
+
+    #[test]
+    fn test_doh_answer_parsing() {
+        let json = r#"{"Status":0,"Answer":[
+            {"name":"node.ts.net","type":5,"TTL":60,"data":"alias.ts.net."},
+            {"name":"alias.ts.net","type":1,"TTL":60,"data":"203.0.113.7"}
+        ]}"#;
+        let response: DohResponse = serde_json::from_str(json).unwrap();
+        let a: Vec<&str> = response
+            .answer
+            .iter()
+            .filter(|answer| answer.record_type == 1)
+            .map(|answer| answer.data.as_str())
+            .collect();
+        assert_eq!(a, vec!["203.0.113.7"]);
+    }
+
+    #[test]
+    fn test_public_addresses_exclude_tailnet_and_private() {
+        for addr in ["203.0.113.7", "208.111.34.1", "2001:db8::1"] {
+            assert!(is_public_address(addr.parse().unwrap()), "{}", addr);
+        }
+        for addr in [
+            "100.101.102.103",
+            "100.64.0.1",
+            "192.168.1.20",
+            "10.0.0.1",
+            "127.0.0.1",
+            "fd7a:115c:a1e0::1",
+        ] {
+            assert!(!is_public_address(addr.parse().unwrap()), "{}", addr);
+        }
+        assert!(is_public_address("100.128.0.1".parse().unwrap()));
+    }
+}
@@ -1,34 +1,301 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Local, SecondsFormat, Utc};
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
 use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::config::ThemeConfig;
+use crate::core::{RouteState, ServeChange, ServeDiff, Usage};
+use crate::error::{ConflictEntry, FieldError, FunnelError};
+use crate::proxy::ProxySummary;
+
+/// `open --json-fd` / `--json-file`: a copy of the event stream next to human output
+static EVENT_STREAM: Mutex<Option<File>> = Mutex::new(None);
 
 pub fn use_color() -> bool {
     std::io::stdout().is_terminal() && supports_color::on(supports_color::Stream::Stdout).is_some()
 }
 
+/// Color used for labels in human output; `bold` is the uncolored default
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Accent {
+    #[default]
+    Bold,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+}
+
+impl Accent {
+    fn sgr(self) -> &'static str {
+        match self {
+            Accent::Bold => "1",
+            Accent::Red => "1;31",
+            Accent::Green => "1;32",
+            Accent::Yellow => "1;33",
+            Accent::Blue => "1;34",
+            Accent::Magenta => "1;35",
+            Accent::Cyan => "1;36",
+        }
+    }
+}
+
+/// How human output is drawn: colors, tree characters, and marks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub color: bool,
+    pub accent: Accent,
+    /// ASCII-only tree characters, marks, and punctuation
+    pub ascii: bool,
+    pub emoji: bool,
+    /// `--plain`: ASCII, no color, and one line per record
+    pub plain: bool,
+}
+
+impl Theme {
+    pub fn resolve(config: &ThemeConfig, plain: bool) -> Self {
+        let ascii = plain || config.ascii;
+        Self {
+            color: !plain && use_color(),
+            accent: config.accent,
+            ascii,
+            emoji: config.emoji && !ascii,
+            plain,
+        }
+    }
+
+    pub fn label(&self, text: &str) -> String {
+        self.paint(self.accent.sgr(), text)
+    }
+
+    fn dim(&self, text: &str) -> String {
+        self.paint("2", text)
+    }
+
+    fn paint(&self, sgr: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", sgr, text)
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn branch(&self) -> String {
+        self.dim(if self.ascii { "|-" } else { "├─" })
+    }
+
+    fn last_branch(&self) -> String {
+        self.dim(if self.ascii { "`-" } else { "└─" })
+    }
+
+    /// Pass and fail marks for check lists
+    pub fn marks(&self) -> (String, String) {
+        if self.emoji {
+            ("✅".to_string(), "❌".to_string())
+        } else if self.ascii {
+            (self.paint("1;32", "[ok]"), self.paint("1;31", "[FAIL]"))
+        } else {
+            (self.paint("1;32", "✓"), self.paint("1;31", "✗"))
+        }
+    }
+
+    /// Replaces non-ASCII punctuation in ASCII mode
+    pub fn text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.ascii && !text.is_ascii() {
+            Cow::Owned(to_ascii(text))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::resolve(&ThemeConfig::default(), false)
+    }
+}
+
+fn to_ascii(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            c if c.is_ascii() => out.push(c),
+            '—' | '–' | '─' => out.push('-'),
+            '…' => out.push_str("..."),
+            '‘' | '’' => out.push('\''),
+            '“' | '”' => out.push('"'),
+            '→' => out.push_str("->"),
+            '←' => out.push_str("<-"),
+            _ => out.push('?'),
+        }
+    }
+    out
+}
+
+/// `version` carried by every event, JSON error, and plugin message
+pub const EVENT_VERSION: u32 = 1;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "event", rename_all = "snake_case")]
 pub enum Event {
     Started {
         version: u32,
         url: String,
+        /// The same route on the other HTTPS ports (`--all-https-ports`)
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        mirror_urls: Vec<String>,
         local_target: String,
         path: String,
         https_port: u16,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        /// Who or what the tunnel is for (`--expose-for`)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expose_for: Option<String>,
         started_at: DateTime<Utc>,
         expires_at: Option<DateTime<Utc>>,
+        /// Opened with `--skip-checks`
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        unchecked: bool,
+        /// Public DNS does not send the URL's host to Funnel (see `open --no-dns-check`)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dns_warning: Option<String>,
+        /// Raised by the backend while applying, e.g. an unguarded write under `--no-etag`
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        warnings: Vec<String>,
+    },
+    Extended {
+        version: u32,
+        expires_at: Option<DateTime<Utc>>,
     },
     Stopped {
         version: u32,
         reason: StopReason,
         stopped_at: DateTime<Utc>,
         duration_seconds: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        proxy: Option<ProxySummary>,
+    },
+    /// `open --dry-run`: what opening would write, without writing it
+    DryRun {
+        version: u32,
+        url: String,
+        changes: Vec<ServeChange>,
+        /// RFC 6902 operations from the current serve config to the new one
+        patch: Vec<serde_json::Value>,
+    },
+    RouteChanged {
+        version: u32,
+        state: RouteState,
+        restored: bool,
+    },
+    /// The local target stopped accepting connections
+    TargetDown {
+        version: u32,
+        at: DateTime<Utc>,
+        local_target: String,
+    },
+    /// The local target accepts connections again
+    TargetUp {
+        version: u32,
+        at: DateTime<Utc>,
+        local_target: String,
+    },
+    /// A route check could not reach tailscaled; sent once per outage
+    BackendDisconnected {
+        version: u32,
+        at: DateTime<Utc>,
+        error: String,
+    },
+    /// A route check reached tailscaled again after `backend_disconnected`
+    BackendReconnected {
+        version: u32,
+        at: DateTime<Utc>,
+        downtime_seconds: u64,
+    },
+    /// `--self-heal` wrote the route back; `after` is the state that triggered it
+    Reapplied {
+        version: u32,
+        at: DateTime<Utc>,
+        path: String,
+        after: RouteState,
+    },
+    /// `open --at` is waiting to apply the route
+    Scheduled {
+        version: u32,
+        starts_at: DateTime<Utc>,
+    },
+    /// `funnelctl pause` stopped public traffic; the session and URL stay
+    Paused {
+        version: u32,
+        at: DateTime<Utc>,
+        /// True when the local proxy answers with the holding page; false when Funnel
+        /// was turned off for the route instead
+        holding_page: bool,
+    },
+    /// `funnelctl resume` let public traffic through again
+    Resumed {
+        version: u32,
+        at: DateTime<Utc>,
+        paused_seconds: u64,
+    },
+    /// The local proxy saw its first request from outside the tailnet
+    FirstRequest {
+        version: u32,
+        at: DateTime<Utc>,
+        method: String,
+        path: String,
+    },
+    /// Heartbeat of a tunnel behind the local proxy: the number of requests in flight
+    /// changed since the last heartbeat
+    Activity {
+        version: u32,
+        at: DateTime<Utc>,
+        active_requests: u64,
+    },
+    /// Sent to plugins only, before the route is applied; policy plugins answer it
+    Exposing {
+        version: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        local_target: String,
+        path: String,
+        https_port: u16,
+        funnel: bool,
+    },
+    /// A plugin's `annotate` directive
+    Annotation {
+        version: u32,
+        plugin: String,
+        message: String,
     },
     Error {
         version: u32,
         code: i32,
         message: String,
         suggestion: Option<String>,
+        /// Exit 2 errors that know which flag or config key was wrong
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        field: Option<FieldError>,
+        /// Exit 13 errors list every conflicting route
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        conflicts: Vec<ConflictEntry>,
+        /// A nearby path free of those conflicts
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        suggested_path: Option<String>,
+        /// Whether the same command may succeed later (see `ErrorCode::retryable`)
+        #[serde(default)]
+        retryable: bool,
+        /// Seconds to wait before retrying
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
     },
 }
 
@@ -37,10 +304,45 @@ pub enum Event {
 pub enum StopReason {
     UserInterrupt,
     TtlExpired,
+    Terminated,
+    Closed,
+    /// `--stop-on-target-down` saw the target stop accepting connections
+    TargetDown,
+    /// Another tunnel took the route over with `--force`
+    Superseded,
     Error,
 }
 
+impl StopReason {
+    /// The reason as spelled in the `stopped` event
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StopReason::UserInterrupt => "user_interrupt",
+            StopReason::TtlExpired => "ttl_expired",
+            StopReason::Terminated => "terminated",
+            StopReason::Closed => "closed",
+            StopReason::TargetDown => "target_down",
+            StopReason::Superseded => "superseded",
+            StopReason::Error => "error",
+        }
+    }
+}
+
 impl Event {
+    pub fn from_error(err: &FunnelError) -> Self {
+        Event::Error {
+            version: 1,
+            code: err.exit_code(),
+            message: err.to_string(),
+            suggestion: err.get_fix(),
+            field: err.field_error(),
+            conflicts: err.conflict_entries().to_vec(),
+            suggested_path: err.suggested_path().map(str::to_string),
+            retryable: err.retryable(),
+            retry_after: err.retry_after(),
+        }
+    }
+
     pub fn emit_json(&self) -> io::Result<()> {
         let mut stdout = io::stdout();
         serde_json::to_writer(&mut stdout, self).map_err(|e| {
@@ -54,68 +356,273 @@ impl Event {
     }
 }
 
+/// Sends every later `stream_event` to `path` (a `/dev/fd/N` entry or a named pipe) as
+/// NDJSON. Appends, so a file the shell opened with `3>>` keeps its contents.
+pub fn open_event_stream(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().append(true).create(true).open(path)?;
+    *lock_event_stream() = Some(file);
+    Ok(())
+}
+
+/// Writes `event` to the event stream, if one is open. A reader that went away closes
+/// the stream rather than failing the tunnel.
+pub fn stream_event(event: &Event) {
+    let mut stream = lock_event_stream();
+    let Some(file) = stream.as_mut() else {
+        return;
+    };
+    let result = serde_json::to_vec(event)
+        .map_err(io::Error::other)
+        .and_then(|mut line| {
+            line.push(b'\n');
+            file.write_all(&line)?;
+            file.flush()
+        });
+    if let Err(err) = result {
+        tracing::warn!(error = %err, "event stream closed");
+        *stream = None;
+    }
+}
+
+fn lock_event_stream() -> std::sync::MutexGuard<'static, Option<File>> {
+    EVENT_STREAM
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// `open --quiet-warnings`: advisory lines are dropped, genuine diagnostics still print
+static QUIET_WARNINGS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet_warnings(quiet: bool) {
+    QUIET_WARNINGS.store(quiet, Ordering::Relaxed);
+}
+
+/// Prints an advisory `Warning:` or `Note:` line to stderr, unless `--quiet-warnings`
+/// is set. Lines about a running tunnel going wrong use `eprintln!` and always print.
+pub fn advise(line: std::fmt::Arguments) {
+    if !QUIET_WARNINGS.load(Ordering::Relaxed) {
+        eprintln!("{}", line);
+    }
+}
+
+/// What `open` prints once the tunnel is up
+#[derive(Debug, Clone, Copy)]
+pub struct Started<'a> {
+    /// The primary URL first; the rest are its `--all-https-ports` mirrors
+    pub urls: &'a [String],
+    pub name: Option<&'a str>,
+    pub local_target: &'a str,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub detached: bool,
+    pub curl_example: Option<&'a str>,
+}
+
+/// Below this many columns the started banner is drawn on one line
+const COMPACT_BELOW_COLUMNS: usize = 60;
+
+/// Whether `open` should print the one-line started form without `--compact`: stdout is
+/// not a terminal, or the terminal is too narrow for the banner
+pub fn prefers_compact() -> bool {
+    !io::stdout().is_terminal()
+        || terminal_width().is_some_and(|width| width < COMPACT_BELOW_COLUMNS)
+}
+
+/// Columns of the terminal on stderr, where the banner goes; `COLUMNS` wins when set
+fn terminal_width() -> Option<usize> {
+    if let Some(columns) = std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+    {
+        return Some(columns);
+    }
+    #[cfg(unix)]
+    {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        let result = unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) };
+        if result == 0 && size.ws_col > 0 {
+            return Some(usize::from(size.ws_col));
+        }
+    }
+    None
+}
+
 pub struct HumanOutput {
-    use_color: bool,
+    theme: Theme,
+    banner: bool,
+    compact: bool,
 }
 
 impl HumanOutput {
-    pub fn new() -> Self {
+    pub fn new(theme: Theme) -> Self {
         Self {
-            use_color: use_color(),
+            theme,
+            banner: true,
+            compact: false,
         }
     }
 
-    pub fn print_started(
+    /// `open --no-banner`: only the URLs (and curl example) are printed
+    pub fn banner(mut self, banner: bool) -> Self {
+        self.banner = banner;
+        self
+    }
+
+    /// `open --compact`: `URL ← http://127.0.0.1:8081, expires 18:00` instead of the tree
+    pub fn compact(mut self, compact: bool) -> Self {
+        self.compact = compact;
+        self
+    }
+
+    /// URLs and the curl example go to stdout, so `url=$(funnelctl open ...)` captures
+    /// only them; the Name/Local/Expires banner goes to stderr
+    pub fn print_started(&self, started: &Started) -> io::Result<()> {
+        self.write_started(
+            started,
+            io::stdout().is_terminal(),
+            &mut io::stdout().lock(),
+            &mut io::stderr().lock(),
+        )
+    }
+
+    /// A compact banner shares the URL's line when stdout is a terminal; otherwise stdout
+    /// keeps the bare URL and the rest goes to stderr as `<name> ← ...`
+    fn write_started(
         &self,
-        url: &str,
-        local_target: &str,
-        expires_at: Option<DateTime<Utc>>,
+        started: &Started,
+        stdout_is_terminal: bool,
+        stdout: &mut impl Write,
+        stderr: &mut impl Write,
     ) -> io::Result<()> {
-        let mut stdout = io::stdout();
+        let Started {
+            urls,
+            name,
+            local_target,
+            expires_at,
+            detached,
+            curl_example,
+        } = *started;
+        let (url, mirrors) = urls
+            .split_first()
+            .map_or(("", &[][..]), |(url, mirrors)| (url.as_str(), mirrors));
 
-        writeln!(stdout, "{}", url)?;
+        if self.theme.plain {
+            let mut line = url.to_string();
+            for mirror in mirrors {
+                line.push_str(&format!(" also={}", mirror));
+            }
+            if self.banner {
+                if let Some(name) = name {
+                    line.push_str(&format!(" name={}", name));
+                }
+                line.push_str(&format!(" local={}", local_target));
+                match expires_at {
+                    Some(exp) => line.push_str(&format!(
+                        " expires={}",
+                        exp.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+                    )),
+                    None => line.push_str(" expires=never"),
+                }
+                if detached {
+                    line.push_str(" detached=yes");
+                }
+            }
+            writeln!(stdout, "{}", self.theme.text(&line))?;
+            return stdout.flush();
+        }
 
-        let (branch, local_label, expires_label, ctrl_label) = if self.use_color {
-            (
-                "\x1b[2m├─\x1b[0m",
-                "\x1b[1mLocal:\x1b[0m",
-                "\x1b[1mExpires:\x1b[0m",
-                "\x1b[1mPress Ctrl-C to stop\x1b[0m",
+        let summary =
+            (self.compact && self.banner).then(|| self.compact_summary(started, Local::now()));
+        match &summary {
+            Some(summary) if stdout_is_terminal => writeln!(stdout, "{} {}", url, summary)?,
+            _ => writeln!(stdout, "{}", url)?,
+        }
+        for mirror in mirrors {
+            writeln!(stdout, "{}", mirror)?;
+        }
+        if let Some(command) = curl_example {
+            writeln!(stdout, "{}", command)?;
+        }
+        stdout.flush()?;
+        if let Some(summary) = summary.filter(|_| !stdout_is_terminal) {
+            writeln!(stderr, "{} {}", name.unwrap_or("tunnel"), summary)?;
+            return stderr.flush();
+        }
+        if !self.banner || self.compact {
+            return Ok(());
+        }
+
+        let stop_text = if detached {
+            format!(
+                "Detached. Stop with: funnelctl close {}",
+                name.unwrap_or("<lease>")
             )
         } else {
-            ("├─", "Local:", "Expires:", "Press Ctrl-C to stop")
+            "Press Ctrl-C to stop".to_string()
         };
+        let branch = self.theme.branch();
+        let (name_label, local_label, expires_label, ctrl_label) = (
+            self.theme.label("Name:"),
+            self.theme.label("Local:"),
+            self.theme.label("Expires:"),
+            self.theme.label(&stop_text),
+        );
 
-        writeln!(stdout, "{} {} {}", branch, local_label, local_target)?;
+        if let Some(name) = name {
+            writeln!(stderr, "{} {}    {}", branch, name_label, name)?;
+        }
+        writeln!(stderr, "{} {}   {}", branch, local_label, local_target)?;
 
         let expiry_text = if let Some(exp) = expires_at {
             format!("{}", exp.format("%Y-%m-%d %H:%M:%S UTC"))
+        } else if detached {
+            "never (until closed)".to_string()
         } else {
             "never (Ctrl-C to stop)".to_string()
         };
 
-        let last_branch = if self.use_color {
-            "\x1b[2m└─\x1b[0m"
-        } else {
-            "└─"
-        };
+        writeln!(stderr, "{} {} {}", branch, expires_label, expiry_text)?;
+        writeln!(stderr, "{} {}", self.theme.last_branch(), ctrl_label)?;
 
-        writeln!(stdout, "{} {} {}", branch, expires_label, expiry_text)?;
-        writeln!(stdout, "{} {}", last_branch, ctrl_label)?;
+        stderr.flush()
+    }
 
-        stdout.flush()
+    /// `← http://127.0.0.1:8081, expires 18:00`; the date is added when the tunnel
+    /// outlives today, and detached tunnels name what to close
+    fn compact_summary(&self, started: &Started, now: DateTime<Local>) -> String {
+        let mut summary = format!("← {}", started.local_target);
+        if let Some(expires_at) = started.expires_at {
+            let expires_at = expires_at.with_timezone(&Local);
+            let format = if expires_at.date_naive() == now.date_naive() {
+                "%H:%M"
+            } else {
+                "%Y-%m-%d %H:%M"
+            };
+            summary.push_str(&format!(", expires {}", expires_at.format(format)));
+        }
+        if started.detached {
+            summary.push_str(&format!(
+                ", detached (funnelctl close {})",
+                started.name.unwrap_or("<lease>")
+            ));
+        }
+        self.theme.text(&summary).into_owned()
     }
 
     pub fn print_stopped(
         &self,
         reason: StopReason,
         duration_seconds: Option<u64>,
+        proxy: Option<&ProxySummary>,
     ) -> io::Result<()> {
         let mut stderr = io::stderr();
 
         let reason_text = match reason {
             StopReason::UserInterrupt => "Stopped by user",
             StopReason::TtlExpired => "TTL expired",
+            StopReason::Terminated => "Terminated",
+            StopReason::Closed => "Closed by `funnelctl close`",
+            StopReason::TargetDown => "Target stopped accepting connections",
+            StopReason::Superseded => "Superseded by a forced tunnel on the same route",
             StopReason::Error => "Stopped due to error",
         };
 
@@ -125,36 +632,276 @@ impl HumanOutput {
             String::new()
         };
 
-        writeln!(stderr, "{}{}", reason_text, duration_text)?;
+        let summary_lines = proxy.map(proxy_summary_lines).unwrap_or_default();
+        if self.theme.plain {
+            let mut line = format!("{}{}", reason_text, duration_text);
+            for summary in &summary_lines {
+                line.push_str("; ");
+                line.push_str(summary);
+            }
+            writeln!(stderr, "{}", line)?;
+        } else {
+            writeln!(stderr, "{}{}", reason_text, duration_text)?;
+            for summary in &summary_lines {
+                writeln!(stderr, "{}", summary)?;
+            }
+        }
+
         stderr.flush()
     }
 }
 
+/// The proxy part of the stop summary: counters, then traffic once anything was forwarded
+fn proxy_summary_lines(summary: &ProxySummary) -> Vec<String> {
+    let limit_text = match summary.max_concurrency {
+        Some(limit) => format!("/{}", limit),
+        None => String::new(),
+    };
+    let mut lines = vec![format!(
+        "Requests: {} (peak concurrency {}{}, {} rejected)",
+        summary.requests, summary.peak_concurrency, limit_text, summary.rejected
+    )];
+    if let (Some(p50), Some(p95)) = (summary.latency_p50_ms, summary.latency_p95_ms) {
+        lines.push(format!(
+            "Latency: p50 {} ms, p95 {} ms; {} in, {} out",
+            p50,
+            p95,
+            format_bytes(summary.bytes_in),
+            format_bytes(summary.bytes_out)
+        ));
+    }
+    if !summary.top_paths.is_empty() {
+        let paths: Vec<String> = summary
+            .top_paths
+            .iter()
+            .map(|hits| format!("{} ({})", hits.path, hits.requests))
+            .collect();
+        lines.push(format!("Top paths: {}", paths.join(", ")));
+    }
+    lines
+}
+
+/// `12 req, 3.4 KB in, 1.0 MB out` for table cells; `-` when nothing was counted
+pub fn format_usage(usage: Option<&Usage>) -> String {
+    match usage {
+        Some(usage) => format!(
+            "{} req, {} in, {} out",
+            usage.requests,
+            format_bytes(usage.bytes_in),
+            format_bytes(usage.bytes_out)
+        ),
+        None => "-".to_string(),
+    }
+}
+
+/// Bytes in B, KB, MB, or GB (powers of 1000), one decimal above bytes
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KB", "MB", "GB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
 impl Default for HumanOutput {
     fn default() -> Self {
-        Self::new()
+        Self::new(Theme::default())
+    }
+}
+
+/// `url`'s path under `base`, for `--public-url-base`; the host and port of `url` are
+/// dropped, since the serve config keeps using the tailnet name
+pub fn public_url(base: &url::Url, url: &url::Url) -> url::Url {
+    let mut public = base.clone();
+    public.set_path(&format!(
+        "{}{}",
+        base.path().trim_end_matches('/'),
+        url.path()
+    ));
+    public
+}
+
+/// Builds `curl -fsSL <url>[/<example_path>]`, quoting the URL if the shell would mangle it
+pub fn curl_example(url: &str, example_path: Option<&str>) -> String {
+    let mut target = url.trim_end_matches('/').to_string();
+    if let Some(path) = example_path.map(|path| path.trim_start_matches('/')) {
+        if !path.is_empty() {
+            target.push('/');
+            target.push_str(path);
+        }
     }
+
+    let shell_safe = target
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_./:%+=,@~".contains(c));
+    if shell_safe {
+        format!("curl -fsSL {}", target)
+    } else {
+        format!("curl -fsSL '{}'", target.replace('\'', r"'\''"))
+    }
+}
+
+/// One line per handler or AllowFunnel change: `+` added, `-` removed, `~` changed
+pub fn render_serve_diff(diff: &ServeDiff, theme: &Theme) -> String {
+    if diff.is_empty() {
+        return "No changes to the serve config".to_string();
+    }
+    let arrow = theme.text("→");
+    let session = |session: &Option<String>| match session {
+        Some(session) => format!(" (session {})", session),
+        None => String::new(),
+    };
+    let lines: Vec<String> = diff
+        .changes
+        .iter()
+        .map(|change| match change {
+            ServeChange::HandlerAdded {
+                host_port,
+                path,
+                target,
+                session: id,
+            } => theme.paint(
+                "32",
+                &format!(
+                    "+ {}{} {} {}{}",
+                    host_port,
+                    path,
+                    arrow,
+                    target,
+                    session(id)
+                ),
+            ),
+            ServeChange::HandlerRemoved {
+                host_port,
+                path,
+                target,
+                session: id,
+            } => theme.paint(
+                "31",
+                &format!(
+                    "- {}{} {} {}{}",
+                    host_port,
+                    path,
+                    arrow,
+                    target,
+                    session(id)
+                ),
+            ),
+            ServeChange::TargetChanged {
+                host_port,
+                path,
+                from,
+                to,
+                session: id,
+            } => theme.paint(
+                "33",
+                &format!(
+                    "~ {}{}: {} {} {}{}",
+                    host_port,
+                    path,
+                    from,
+                    arrow,
+                    to,
+                    session(id)
+                ),
+            ),
+            ServeChange::FunnelChanged {
+                host_port,
+                enabled: true,
+                session: id,
+            } => theme.paint("32", &format!("+ AllowFunnel {}{}", host_port, session(id))),
+            ServeChange::FunnelChanged {
+                host_port,
+                enabled: false,
+                session: id,
+            } => theme.paint("31", &format!("- AllowFunnel {}{}", host_port, session(id))),
+        })
+        .collect();
+    if lines.is_empty() {
+        return format!(
+            "No handler or AllowFunnel changes; {} other serve config field(s) change",
+            diff.patch.len()
+        );
+    }
+    lines.join("\n")
+}
+
+/// Renders rows as left-aligned columns separated by two spaces
+pub fn render_table(headers: &[&str], rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = headers.iter().map(|header| header.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let render_row = |cells: Vec<&str>| {
+        let line: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        line.join("  ").trim_end().to_string()
+    };
+
+    let mut lines = vec![render_row(headers.to_vec())];
+    for row in rows {
+        lines.push(render_row(row.iter().map(String::as_str).collect()));
+    }
+    lines.join("\n")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::proxy::PathHits;
+
+    #[test]
+    fn test_event_stream_appends_ndjson() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.ndjson");
+        std::fs::write(&path, "earlier\n").unwrap();
+        open_event_stream(&path).unwrap();
+        stream_event(&Event::Extended {
+            version: 1,
+            expires_at: None,
+        });
+        *lock_event_stream() = None;
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with("earlier\n"));
+        assert!(written.contains("{\"event\":\"extended\",\"version\":1,\"expires_at\":null}\n"));
+    }
 
     #[test]
     fn test_event_serialization() {
         let event = Event::Started {
             version: 1,
             url: "https://node.tailnet.ts.net/funnelctl/abc123".to_string(),
+            mirror_urls: Vec::new(),
             local_target: "http://127.0.0.1:8081".to_string(),
             path: "/funnelctl/abc123".to_string(),
             https_port: 443,
+            name: None,
+            expose_for: None,
             started_at: Utc::now(),
             expires_at: None,
+            unchecked: false,
+            dns_warning: None,
+            warnings: Vec::new(),
         };
 
         let json = serde_json::to_string(&event).expect("Failed to serialize");
         assert!(json.contains("\"event\":\"started\""));
         assert!(json.contains("\"version\":1"));
+        assert!(!json.contains("unchecked"));
+        assert!(!json.contains("mirror_urls"));
     }
 
     #[test]
@@ -164,11 +911,129 @@ mod tests {
             reason: StopReason::UserInterrupt,
             stopped_at: Utc::now(),
             duration_seconds: Some(1800),
+            proxy: None,
         };
 
         let json = serde_json::to_string(&event).expect("Failed to serialize");
         assert!(json.contains("\"event\":\"stopped\""));
         assert!(json.contains("\"reason\":\"user_interrupt\""));
+        assert!(!json.contains("\"proxy\""));
+    }
+
+    #[test]
+    fn test_stopped_event_with_proxy_summary() {
+        let event = Event::Stopped {
+            version: 1,
+            reason: StopReason::TtlExpired,
+            stopped_at: Utc::now(),
+            duration_seconds: Some(60),
+            proxy: Some(ProxySummary {
+                requests: 12,
+                rejected: 2,
+                peak_concurrency: 4,
+                max_concurrency: Some(4),
+                latency_p50_ms: Some(3),
+                latency_p95_ms: Some(40),
+                bytes_in: 1_500,
+                bytes_out: 2_400_000,
+                top_paths: vec![PathHits {
+                    path: "/hook".to_string(),
+                    requests: 10,
+                }],
+            }),
+        };
+
+        let json = serde_json::to_string(&event).expect("Failed to serialize");
+        assert!(json.contains("\"peak_concurrency\":4"));
+        assert!(json.contains("\"rejected\":2"));
+        assert!(json.contains("\"latency_p95_ms\":40"));
+        assert!(json.contains("\"top_paths\":[{\"path\":\"/hook\",\"requests\":10}]"));
+    }
+
+    #[test]
+    fn test_proxy_summary_lines() {
+        let Event::Stopped {
+            proxy: Some(summary),
+            ..
+        } = serde_json::from_str::<Event>(
+            r#"{"event":"stopped","version":1,"reason":"closed","stopped_at":"2026-01-08T12:00:00Z","duration_seconds":5,
+               "proxy":{"requests":3,"rejected":0,"peak_concurrency":1,"latency_p50_ms":2,"latency_p95_ms":9,
+                        "bytes_in":0,"bytes_out":1234567,"top_paths":[{"path":"/a","requests":2},{"path":"/b","requests":1}]}}"#,
+        )
+        .unwrap()
+        else {
+            panic!("expected a stopped event with a proxy summary");
+        };
+        assert_eq!(
+            proxy_summary_lines(&summary),
+            vec![
+                "Requests: 3 (peak concurrency 1, 0 rejected)",
+                "Latency: p50 2 ms, p95 9 ms; 0 B in, 1.2 MB out",
+                "Top paths: /a (2), /b (1)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_transition_event_names() {
+        let at = Utc::now();
+        let events = [
+            (
+                Event::TargetDown {
+                    version: 1,
+                    at,
+                    local_target: "http://127.0.0.1:3000".to_string(),
+                },
+                "target_down",
+            ),
+            (
+                Event::BackendReconnected {
+                    version: 1,
+                    at,
+                    downtime_seconds: 4,
+                },
+                "backend_reconnected",
+            ),
+            (
+                Event::FirstRequest {
+                    version: 1,
+                    at,
+                    method: "POST".to_string(),
+                    path: "/funnelctl/abc123".to_string(),
+                },
+                "first_request",
+            ),
+            (
+                Event::Activity {
+                    version: 1,
+                    at,
+                    active_requests: 3,
+                },
+                "activity",
+            ),
+            (
+                Event::Reapplied {
+                    version: 1,
+                    at,
+                    path: "/hook".to_string(),
+                    after: RouteState::Removed,
+                },
+                "reapplied",
+            ),
+            (
+                Event::Resumed {
+                    version: 1,
+                    at,
+                    paused_seconds: 90,
+                },
+                "resumed",
+            ),
+        ];
+        for (event, name) in events {
+            let json = serde_json::to_value(&event).unwrap();
+            assert_eq!(json["event"], name);
+            assert!(json["at"].is_string());
+        }
     }
 
     #[test]
@@ -178,10 +1043,248 @@ mod tests {
             code: 10,
             message: "LocalAPI unreachable".to_string(),
             suggestion: Some("Is tailscaled running?".to_string()),
+            field: None,
+            conflicts: Vec::new(),
+            suggested_path: None,
+            retryable: true,
+            retry_after: Some(5),
         };
 
         let json = serde_json::to_string(&event).expect("Failed to serialize");
         assert!(json.contains("\"event\":\"error\""));
         assert!(json.contains("\"code\":10"));
+        assert!(json.contains("\"retryable\":true,\"retry_after\":5"));
+        assert!(!json.contains("conflicts"));
+    }
+
+    #[test]
+    fn test_stop_reason_as_str_matches_serde() {
+        for reason in [
+            StopReason::UserInterrupt,
+            StopReason::TtlExpired,
+            StopReason::Terminated,
+            StopReason::Closed,
+            StopReason::Error,
+        ] {
+            let json = serde_json::to_string(&reason).unwrap();
+            assert_eq!(json, format!("\"{}\"", reason.as_str()));
+        }
+    }
+
+    #[test]
+    fn test_curl_example_joins_path() {
+        let url = "https://node.ts.net/funnelctl/abc123";
+        assert_eq!(
+            curl_example(url, None),
+            "curl -fsSL https://node.ts.net/funnelctl/abc123"
+        );
+        assert_eq!(
+            curl_example(url, Some("/health")),
+            "curl -fsSL https://node.ts.net/funnelctl/abc123/health"
+        );
+        assert_eq!(
+            curl_example("https://node.ts.net/", Some("health")),
+            "curl -fsSL https://node.ts.net/health"
+        );
+    }
+
+    #[test]
+    fn test_public_url_rebases_path() {
+        let url = url::Url::parse("https://node.ts.net:8443/funnelctl/a%20b").unwrap();
+        let base = url::Url::parse("https://hooks.example.com").unwrap();
+        assert_eq!(
+            public_url(&base, &url).as_str(),
+            "https://hooks.example.com/funnelctl/a%20b"
+        );
+        let base = url::Url::parse("https://cdn.example.com:8080/edge/").unwrap();
+        assert_eq!(
+            public_url(&base, &url).as_str(),
+            "https://cdn.example.com:8080/edge/funnelctl/a%20b"
+        );
+    }
+
+    #[test]
+    fn test_curl_example_quotes_shell_characters() {
+        assert_eq!(
+            curl_example("https://node.ts.net/hook", Some("/a?b=1&c='x'")),
+            r"curl -fsSL 'https://node.ts.net/hook/a?b=1&c='\''x'\'''"
+        );
+    }
+
+    #[test]
+    fn test_theme_ascii_text_and_marks() {
+        let config = ThemeConfig {
+            ascii: true,
+            ..ThemeConfig::default()
+        };
+        let theme = Theme {
+            color: false,
+            ..Theme::resolve(&config, false)
+        };
+        assert_eq!(
+            theme.text("Permission denied — need root…"),
+            "Permission denied - need root..."
+        );
+        assert_eq!(theme.last_branch(), "`-");
+
+        let plain = Theme::resolve(&ThemeConfig::default(), true);
+        assert!(plain.ascii && !plain.color);
+        assert_eq!(plain.marks(), ("[ok]".to_string(), "[FAIL]".to_string()));
+    }
+
+    #[test]
+    fn test_theme_emoji_disabled_by_ascii() {
+        let config = ThemeConfig {
+            emoji: true,
+            ..ThemeConfig::default()
+        };
+        assert!(Theme::resolve(&config, false).emoji);
+        assert!(!Theme::resolve(&config, true).emoji);
+    }
+
+    #[test]
+    fn test_render_table_aligns_columns() {
+        let rows = vec![
+            vec!["api".to_string(), "https://a".to_string()],
+            vec!["preview-long".to_string(), "https://b".to_string()],
+        ];
+        let table = render_table(&["NAME", "URL"], &rows);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "NAME          URL");
+        assert_eq!(lines[1], "api           https://a");
+        assert_eq!(lines[2], "preview-long  https://b");
+    }
+
+    #[test]
+    fn test_render_serve_diff_plain() {
+        let theme = Theme::resolve(&ThemeConfig::default(), true);
+        let diff = ServeDiff {
+            changes: vec![
+                ServeChange::TargetChanged {
+                    host_port: "node:443".to_string(),
+                    path: "/a".to_string(),
+                    from: "http://127.0.0.1:1".to_string(),
+                    to: "http://127.0.0.1:2".to_string(),
+                    session: None,
+                },
+                ServeChange::FunnelChanged {
+                    host_port: "node:443".to_string(),
+                    enabled: false,
+                    session: Some("s1".to_string()),
+                },
+            ],
+            patch: vec![serde_json::Value::Null],
+        };
+        assert_eq!(
+            render_serve_diff(&diff, &theme),
+            "~ node:443/a: http://127.0.0.1:1 -> http://127.0.0.1:2\n- AllowFunnel node:443 (session s1)"
+        );
+        assert_eq!(
+            render_serve_diff(&ServeDiff::default(), &theme),
+            "No changes to the serve config"
+        );
+    }
+
+    #[test]
+    fn test_started_streams() {
+        let urls = vec!["https://node.ts.net/hook".to_string()];
+        let started = Started {
+            urls: &urls,
+            name: Some("hook"),
+            local_target: "http://127.0.0.1:3000",
+            expires_at: None,
+            detached: false,
+            curl_example: None,
+        };
+        let theme = Theme::resolve(&ThemeConfig::default(), false);
+        let write = |output: HumanOutput| {
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            output
+                .write_started(&started, false, &mut out, &mut err)
+                .unwrap();
+            (
+                String::from_utf8(out).unwrap(),
+                String::from_utf8(err).unwrap(),
+            )
+        };
+
+        let (out, err) = write(HumanOutput::new(theme));
+        assert_eq!(out, "https://node.ts.net/hook\n");
+        assert!(err.contains("Name:") && err.contains("Press Ctrl-C to stop"));
+
+        let (out, err) = write(HumanOutput::new(theme).banner(false));
+        assert_eq!(out, "https://node.ts.net/hook\n");
+        assert_eq!(err, "");
+
+        let plain = Theme::resolve(&ThemeConfig::default(), true);
+        let (out, err) = write(HumanOutput::new(plain).banner(false));
+        assert_eq!(out, "https://node.ts.net/hook\n");
+        assert_eq!(err, "");
+    }
+
+    #[test]
+    fn test_compact_started() {
+        let urls = vec!["https://node.ts.net/hook".to_string()];
+        let now = Local::now();
+        let started = Started {
+            urls: &urls,
+            name: Some("hook"),
+            local_target: "http://127.0.0.1:8081",
+            expires_at: Some(now.with_timezone(&Utc) + chrono::Duration::seconds(60)),
+            detached: false,
+            curl_example: None,
+        };
+        let theme = Theme::resolve(&ThemeConfig::default(), false);
+        let output = HumanOutput::new(theme).compact(true);
+        let expected = format!(
+            "← http://127.0.0.1:8081, expires {}",
+            (now + chrono::Duration::seconds(60)).format("%H:%M")
+        );
+        // Around midnight the expiry falls on the next day and carries the date
+        if (now + chrono::Duration::seconds(60)).date_naive() == now.date_naive() {
+            assert_eq!(output.compact_summary(&started, now), expected);
+        }
+        let yesterday = now - chrono::Duration::days(1);
+        assert!(output.compact_summary(&started, yesterday).contains(
+            &(now + chrono::Duration::seconds(60))
+                .format("%Y-%m-%d ")
+                .to_string()
+        ));
+
+        let write = |stdout_is_terminal: bool| {
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            output
+                .write_started(&started, stdout_is_terminal, &mut out, &mut err)
+                .unwrap();
+            (
+                String::from_utf8(out).unwrap(),
+                String::from_utf8(err).unwrap(),
+            )
+        };
+        let (out, err) = write(true);
+        assert!(out.starts_with("https://node.ts.net/hook ← http://127.0.0.1:8081"));
+        assert_eq!(out.lines().count(), 1);
+        assert_eq!(err, "");
+        let (out, err) = write(false);
+        assert_eq!(out, "https://node.ts.net/hook\n");
+        assert!(err.starts_with("hook ← http://127.0.0.1:8081"));
+        assert_eq!(err.lines().count(), 1);
+
+        let ascii = Theme::resolve(
+            &ThemeConfig {
+                ascii: true,
+                ..ThemeConfig::default()
+            },
+            false,
+        );
+        let detached = Started {
+            expires_at: None,
+            detached: true,
+            ..started
+        };
+        assert_eq!(
+            HumanOutput::new(ascii).compact_summary(&detached, now),
+            "<- http://127.0.0.1:8081, detached (funnelctl close hook)"
+        );
     }
 }
@@ -0,0 +1,327 @@
+//! External plugins for `open`: executables from `[[open.plugins]]` in config.toml.
+//!
+//! Each plugin runs for the whole `open` and gets every event of the run as NDJSON on
+//! stdin, the same objects `--json` prints plus `exposing` before the route is applied.
+//! It may print directives on stdout, one JSON object per line:
+//!
+//! - `{"directive":"annotate","message":"..."}` adds a line to funnelctl's output
+//! - `{"directive":"allow"}` / `{"directive":"veto","reason":"..."}` answer `exposing`;
+//!   only plugins with `policy = true` are asked, and `open` waits for each of them
+
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, ChildStdout, Command};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::error::{FunnelError, Result};
+use crate::output::{self, Event};
+
+/// How long a policy plugin gets to answer `exposing`
+const VERDICT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long plugins get to handle the last events and exit once `open` is done
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+/// Event queues of the running plugins; `publish` can be called from anywhere in `open`
+static SINKS: Mutex<Vec<mpsc::UnboundedSender<Arc<str>>>> = Mutex::new(Vec::new());
+
+/// One `[[open.plugins]]` entry
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginConfig {
+    pub command: PathBuf,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Ask this plugin before exposing anything; no answer counts as a veto
+    #[serde(default)]
+    pub policy: bool,
+}
+
+impl PluginConfig {
+    fn name(&self) -> String {
+        self.command
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| self.command.display().to_string())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "directive", rename_all = "snake_case")]
+enum Directive {
+    Allow,
+    Veto {
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    Annotate {
+        message: String,
+    },
+}
+
+enum Verdict {
+    Allow,
+    Veto(Option<String>),
+}
+
+/// Sends `event` to the `--json-fd` stream and every running plugin. Never blocks on a
+/// plugin: one that stops reading only delays its own queue.
+pub fn publish(event: &Event) {
+    output::stream_event(event);
+    let sinks = lock_sinks();
+    if sinks.is_empty() {
+        return;
+    }
+    let line: Arc<str> = match serde_json::to_string(event) {
+        Ok(line) => line.into(),
+        Err(err) => {
+            tracing::warn!(error = %err, "failed to serialize event for plugins");
+            return;
+        }
+    };
+    for sink in sinks.iter() {
+        let _ = sink.send(Arc::clone(&line));
+    }
+}
+
+fn lock_sinks() -> std::sync::MutexGuard<'static, Vec<mpsc::UnboundedSender<Arc<str>>>> {
+    SINKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+struct Running {
+    name: String,
+    policy: bool,
+    child: Child,
+    verdicts: mpsc::UnboundedReceiver<Verdict>,
+    writer: JoinHandle<()>,
+}
+
+/// The plugins of one `open` run
+pub struct Plugins {
+    running: Vec<Running>,
+}
+
+impl Plugins {
+    pub fn none() -> Self {
+        Self {
+            running: Vec::new(),
+        }
+    }
+
+    /// Starts every configured plugin; a plugin that cannot be started fails the run
+    pub fn start(configs: &[PluginConfig], json: bool) -> Result<Self> {
+        let mut plugins = Self::none();
+        for config in configs {
+            let name = config.name();
+            let mut child = Command::new(&config.command)
+                .args(&config.args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::inherit())
+                .kill_on_drop(true)
+                .spawn()
+                .map_err(|err| {
+                    FunnelError::Other(format!(
+                        "Failed to start plugin {}: {}",
+                        config.command.display(),
+                        err
+                    ))
+                })?;
+            let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+                return Err(FunnelError::Other(format!(
+                    "Plugin {} has no stdin or stdout",
+                    name
+                )));
+            };
+
+            let (events, queue) = mpsc::unbounded_channel();
+            let writer = tokio::spawn(write_events(stdin, queue));
+            let (verdict_tx, verdicts) = mpsc::unbounded_channel();
+            tokio::spawn(read_directives(name.clone(), stdout, verdict_tx, json));
+            lock_sinks().push(events);
+
+            plugins.running.push(Running {
+                name,
+                policy: config.policy,
+                child,
+                verdicts,
+                writer,
+            });
+        }
+        Ok(plugins)
+    }
+
+    /// Publishes `exposing` and waits for every policy plugin to allow it
+    pub async fn check_exposure(&mut self, exposing: &Event) -> Result<()> {
+        publish(exposing);
+        for plugin in self.running.iter_mut().filter(|plugin| plugin.policy) {
+            let problem = match timeout(VERDICT_TIMEOUT, plugin.verdicts.recv()).await {
+                Ok(Some(Verdict::Allow)) => continue,
+                Ok(Some(Verdict::Veto(Some(reason)))) => format!("vetoed the tunnel: {}", reason),
+                Ok(Some(Verdict::Veto(None))) => "vetoed the tunnel".to_string(),
+                Ok(None) => "exited without answering".to_string(),
+                Err(_) => format!("did not answer within {}s", VERDICT_TIMEOUT.as_secs()),
+            };
+            return Err(FunnelError::PolicyDenied {
+                source: None,
+                context: format!("Plugin {} {}", plugin.name, problem),
+            });
+        }
+        Ok(())
+    }
+
+    /// Closes every plugin's stdin once its queue is drained, then waits briefly for it
+    /// to exit before killing it
+    pub async fn shutdown(self) {
+        lock_sinks().clear();
+        for mut plugin in self.running {
+            let _ = timeout(SHUTDOWN_GRACE, &mut plugin.writer).await;
+            if timeout(SHUTDOWN_GRACE, plugin.child.wait()).await.is_err() {
+                tracing::warn!(plugin = %plugin.name, "plugin did not exit; killing it");
+                let _ = plugin.child.kill().await;
+            }
+        }
+    }
+}
+
+async fn write_events(mut stdin: ChildStdin, mut queue: mpsc::UnboundedReceiver<Arc<str>>) {
+    while let Some(line) = queue.recv().await {
+        let written = async {
+            stdin.write_all(line.as_bytes()).await?;
+            stdin.write_all(b"\n").await?;
+            stdin.flush().await
+        };
+        if let Err(err) = written.await {
+            tracing::debug!(error = %err, "plugin stopped reading events");
+            return;
+        }
+    }
+}
+
+async fn read_directives(
+    name: String,
+    stdout: ChildStdout,
+    verdicts: mpsc::UnboundedSender<Verdict>,
+    json: bool,
+) {
+    let mut lines = BufReader::new(stdout).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<Directive>(&line) {
+            Ok(Directive::Allow) => {
+                let _ = verdicts.send(Verdict::Allow);
+            }
+            Ok(Directive::Veto { reason }) => {
+                let _ = verdicts.send(Verdict::Veto(reason));
+            }
+            Ok(Directive::Annotate { message }) => annotate(&name, message, json),
+            Err(err) => {
+                tracing::warn!(plugin = %name, error = %err, "ignoring invalid plugin directive")
+            }
+        }
+    }
+}
+
+/// Annotations go to the user only; plugins do not see each other's
+fn annotate(plugin: &str, message: String, json: bool) {
+    if !json {
+        eprintln!("[{}] {}", plugin, message);
+        return;
+    }
+    let event = Event::Annotation {
+        version: 1,
+        plugin: plugin.to_string(),
+        message,
+    };
+    if let Err(err) = event.emit_json() {
+        tracing::warn!(error = %err, "failed to print plugin annotation");
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    fn plugin(dir: &Path, name: &str, body: &str, policy: bool) -> PluginConfig {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        PluginConfig {
+            command: path,
+            args: Vec::new(),
+            policy,
+        }
+    }
+
+    fn exposing() -> Event {
+        Event::Exposing {
+            version: 1,
+            name: None,
+            local_target: "http://127.0.0.1:3000".to_string(),
+            path: "/hook".to_string(),
+            https_port: 443,
+            funnel: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plugins_receive_events_and_can_veto() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("events.log");
+        let recorder = plugin(
+            dir.path(),
+            "recorder",
+            &format!("cat > {}", log.display()),
+            false,
+        );
+        let allow = plugin(
+            dir.path(),
+            "allow",
+            r#"read line; echo '{"directive":"allow"}'; cat > /dev/null"#,
+            true,
+        );
+
+        let mut plugins = Plugins::start(&[recorder, allow], true).unwrap();
+        plugins.check_exposure(&exposing()).await.unwrap();
+        publish(&Event::Extended {
+            version: 1,
+            expires_at: None,
+        });
+        plugins.shutdown().await;
+
+        // Other tests may publish at the same time, so only look for ours
+        let names: Vec<String> = std::fs::read_to_string(&log)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["event"].to_string()
+            })
+            .collect();
+        assert!(names.contains(&"\"exposing\"".to_string()));
+        assert!(names.contains(&"\"extended\"".to_string()));
+
+        let veto = plugin(
+            dir.path(),
+            "veto",
+            r#"read line; echo '{"directive":"veto","reason":"no funnel on fridays"}'"#,
+            true,
+        );
+        let mut plugins = Plugins::start(&[veto], true).unwrap();
+        let err = plugins.check_exposure(&exposing()).await.unwrap_err();
+        assert_eq!(err.exit_code(), 17);
+        assert!(format!("{:?}", err).contains("no funnel on fridays"));
+        plugins.shutdown().await;
+    }
+}
@@ -0,0 +1,143 @@
+//! Phase reporting for slow operations, rendered as a stderr spinner in human mode.
+
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+use crate::output::Theme;
+
+const FRAMES: &[&str] = &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+const ASCII_FRAMES: &[&str] = &["|", "/", "-", "\\"];
+const FRAME_INTERVAL: Duration = Duration::from_millis(80);
+
+/// A step of `Backend::apply`, in the order they usually happen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// `open --health-path`, before anything is applied
+    WaitingForHealth,
+    ConnectingWatchBus,
+    CheckingTarget,
+    CheckingPrerequisites,
+    ApplyingServeConfig {
+        attempt: u8,
+    },
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::WaitingForHealth => write!(f, "waiting for the target's health check…"),
+            Phase::ConnectingWatchBus => write!(f, "connecting to tailscaled…"),
+            Phase::CheckingTarget => write!(f, "checking local target…"),
+            Phase::CheckingPrerequisites => write!(f, "checking prerequisites…"),
+            Phase::ApplyingServeConfig { attempt: 1 } => write!(f, "applying serve config…"),
+            Phase::ApplyingServeConfig { attempt } => {
+                write!(f, "applying serve config (attempt {})…", attempt)
+            }
+        }
+    }
+}
+
+/// Receives phase changes from a backend
+pub trait Progress: Send + Sync {
+    fn phase(&self, phase: Phase);
+}
+
+/// Discards phase changes; used in JSON mode and by callers without a terminal
+pub struct NoProgress;
+
+impl Progress for NoProgress {
+    fn phase(&self, _phase: Phase) {}
+}
+
+/// Animated single-line spinner on stderr. The line is cleared by `finish` or on drop,
+/// so nothing is left behind for the output that follows.
+pub struct Spinner {
+    message: Arc<Mutex<String>>,
+    task: JoinHandle<()>,
+    theme: Theme,
+}
+
+impl Spinner {
+    /// Starts a spinner when stderr is a terminal; `--plain` never animates
+    pub fn start(theme: &Theme) -> Option<Self> {
+        if theme.plain || !io::stderr().is_terminal() {
+            return None;
+        }
+        let frames = if theme.ascii { ASCII_FRAMES } else { FRAMES };
+
+        let message = Arc::new(Mutex::new(String::new()));
+        let shared = Arc::clone(&message);
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(FRAME_INTERVAL);
+            for frame in frames.iter().cycle() {
+                interval.tick().await;
+                // Held while drawing so `Drop` cannot clear the line between our check and write
+                let Ok(text) = shared.lock() else { return };
+                if text.is_empty() {
+                    continue;
+                }
+                let mut stderr = io::stderr().lock();
+                let _ = write!(stderr, "\r\x1b[2K{} {}", frame, text);
+                let _ = stderr.flush();
+            }
+        });
+
+        Some(Self {
+            message,
+            task,
+            theme: *theme,
+        })
+    }
+
+    /// Stops the animation and clears the line
+    pub fn finish(self) {
+        drop(self);
+    }
+}
+
+impl Progress for Spinner {
+    fn phase(&self, phase: Phase) {
+        if let Ok(mut text) = self.message.lock() {
+            *text = self.theme.text(&phase.to_string()).into_owned();
+        }
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.task.abort();
+        let mut text = match self.message.lock() {
+            Ok(text) => text,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        text.clear();
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[2K");
+        let _ = stderr.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_messages() {
+        assert_eq!(
+            Phase::CheckingPrerequisites.to_string(),
+            "checking prerequisites…"
+        );
+        assert_eq!(
+            Phase::ApplyingServeConfig { attempt: 1 }.to_string(),
+            "applying serve config…"
+        );
+        assert_eq!(
+            Phase::ApplyingServeConfig { attempt: 2 }.to_string(),
+            "applying serve config (attempt 2)…"
+        );
+    }
+}
@@ -0,0 +1,943 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Full};
+use hyper::body::{Body, Frame, Incoming, SizeHint};
+use hyper::header::{HeaderName, HeaderValue, CACHE_CONTROL, CONTENT_TYPE, REFRESH, RETRY_AFTER};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode, Uri};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, oneshot, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::core::{LocalTarget, Usage};
+use crate::error::{FunnelError, Result};
+
+/// Seconds clients are asked to wait after being rejected for exceeding the
+/// concurrency limit.
+const RETRY_AFTER_SECS: u64 = 1;
+/// tailscaled marks requests that arrived through Funnel with this header
+const FUNNEL_REQUEST_HEADER: &str = "tailscale-funnel-request";
+/// Tailnet identity header; a request carrying it came from inside the tailnet
+const USER_LOGIN_HEADER: &str = "tailscale-user-login";
+/// Latency samples kept for the percentiles; past this, the oldest are overwritten
+const MAX_LATENCY_SAMPLES: usize = 10_000;
+/// Distinct paths counted for `top_paths`; paths first seen after this are not counted
+const MAX_TRACKED_PATHS: usize = 1_000;
+/// Paths listed in the summary
+const TOP_PATHS: usize = 5;
+/// Seconds the holding page asks browsers to wait before reloading
+const HOLDING_REFRESH_SECS: u64 = 5;
+/// `open --holding-page` without a file of its own
+pub const DEFAULT_HOLDING_PAGE: &str = r#"<!doctype html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta http-equiv="refresh" content="5">
+<title>Restarting</title>
+<style>body{font-family:system-ui,sans-serif;max-width:32rem;margin:20vh auto;padding:0 1rem;color:#333}</style>
+</head>
+<body>
+<h1>Service restarting</h1>
+<p>This service is restarting. This page retries automatically in a few seconds.</p>
+</body>
+</html>
+"#;
+/// Served to Funnel requests while the tunnel is paused, unless `--holding-page-file`
+/// gave a page of its own
+pub const PAUSED_PAGE: &str = r#"<!doctype html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<meta http-equiv="refresh" content="5">
+<title>Down for maintenance</title>
+<style>body{font-family:system-ui,sans-serif;max-width:32rem;margin:20vh auto;padding:0 1rem;color:#333}</style>
+</head>
+<body>
+<h1>Down for maintenance</h1>
+<p>This service is paused for maintenance. This page retries automatically in a few seconds.</p>
+</body>
+</html>
+"#;
+/// Added to every response under `--noindex`; hyper has no constant for it
+const X_ROBOTS_TAG: HeaderName = HeaderName::from_static("x-robots-tag");
+/// Request log entries a slow `watch-requests` may fall behind by before it misses some
+const REQUEST_LOG_CAPACITY: usize = 256;
+
+type ProxyBody = BoxBody<Bytes, hyper::Error>;
+
+/// Options controlling the local proxy that sits between tailscaled and the
+/// user's target.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyOptions {
+    /// Maximum number of in-flight requests; excess requests receive 503.
+    pub max_concurrency: Option<usize>,
+    /// Run the proxy for its request log alone (`open --inspect`)
+    pub inspect: bool,
+    /// Keep crawlers out (`open --noindex`): the proxy answers `robots.txt` itself and
+    /// marks every response `X-Robots-Tag: noindex`
+    pub noindex: bool,
+    /// HTML served with a 503 while the target is down (`open --holding-page`);
+    /// `None` keeps the plain 502
+    pub holding_page: Option<Bytes>,
+}
+
+impl ProxyOptions {
+    /// Returns true if any option requires the local proxy to run
+    pub fn is_enabled(&self) -> bool {
+        self.max_concurrency.is_some()
+            || self.inspect
+            || self.noindex
+            || self.holding_page.is_some()
+    }
+}
+
+/// Counters collected by the proxy, reported in the run summary
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxySummary {
+    pub requests: u64,
+    pub rejected: u64,
+    pub peak_concurrency: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrency: Option<usize>,
+    /// Time until the target's response headers; `None` when nothing was forwarded
+    #[serde(default)]
+    pub latency_p50_ms: Option<u64>,
+    #[serde(default)]
+    pub latency_p95_ms: Option<u64>,
+    /// Request body bytes sent to the target
+    #[serde(default)]
+    pub bytes_in: u64,
+    /// Response body bytes the target sent back
+    #[serde(default)]
+    pub bytes_out: u64,
+    /// Most requested paths, busiest first
+    #[serde(default)]
+    pub top_paths: Vec<PathHits>,
+}
+
+impl From<&ProxySummary> for Usage {
+    fn from(summary: &ProxySummary) -> Self {
+        Self {
+            requests: summary.requests,
+            bytes_in: summary.bytes_in,
+            bytes_out: summary.bytes_out,
+        }
+    }
+}
+
+/// The first request that reached the proxy from outside the tailnet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FirstRequest {
+    pub at: DateTime<Utc>,
+    pub method: String,
+    pub path: String,
+}
+
+/// One answered request, as `watch-requests` shows it. The query string is left out;
+/// it often carries tokens.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestLog {
+    pub at: DateTime<Utc>,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    /// Time until the response headers
+    pub duration_ms: u64,
+    /// Arrived through Funnel rather than from inside the tailnet
+    pub external: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathHits {
+    pub path: String,
+    pub requests: u64,
+}
+
+#[derive(Debug, Default)]
+struct ProxyStats {
+    in_flight: AtomicUsize,
+    peak: AtomicUsize,
+    requests: AtomicU64,
+    rejected: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    traffic: Mutex<Traffic>,
+}
+
+#[derive(Debug, Default)]
+struct Traffic {
+    latencies_ms: Vec<u64>,
+    next_sample: usize,
+    paths: HashMap<String, u64>,
+}
+
+impl ProxyStats {
+    /// A panicking connection task must not take the counters with it
+    fn traffic(&self) -> std::sync::MutexGuard<'_, Traffic> {
+        self.traffic
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn record_path(&self, path: &str) {
+        let mut traffic = self.traffic();
+        if let Some(hits) = traffic.paths.get_mut(path) {
+            *hits += 1;
+        } else if traffic.paths.len() < MAX_TRACKED_PATHS {
+            traffic.paths.insert(path.to_string(), 1);
+        }
+    }
+
+    fn record_latency(&self, started: Instant) {
+        let latency = started.elapsed().as_millis() as u64;
+        let mut traffic = self.traffic();
+        if traffic.latencies_ms.len() < MAX_LATENCY_SAMPLES {
+            traffic.latencies_ms.push(latency);
+        } else {
+            let slot = traffic.next_sample;
+            traffic.latencies_ms[slot] = latency;
+            traffic.next_sample = (slot + 1) % MAX_LATENCY_SAMPLES;
+        }
+    }
+}
+
+struct ProxyState {
+    upstream: LocalTarget,
+    client: Client<HttpConnector, CountingBody>,
+    limit: Option<Arc<Semaphore>>,
+    stats: Arc<ProxyStats>,
+    /// Taken by the first external request
+    first_request: Mutex<Option<oneshot::Sender<FirstRequest>>>,
+    request_log: broadcast::Sender<RequestLog>,
+    noindex: bool,
+    holding_page: Option<Bytes>,
+    /// Woken on every failed upstream request, so `open` probes the target at once
+    upstream_failed: Arc<Notify>,
+    /// Set by `funnelctl pause`: Funnel requests get the holding page instead
+    paused: Arc<AtomicBool>,
+}
+
+/// A loopback HTTP reverse proxy forwarding to the user's target.
+///
+/// tailscaled is pointed at the proxy instead of the target so funnelctl can
+/// enforce limits and observe traffic without touching the service itself.
+pub struct LocalProxy {
+    addr: SocketAddr,
+    options: ProxyOptions,
+    stats: Arc<ProxyStats>,
+    first_request: Option<oneshot::Receiver<FirstRequest>>,
+    request_log: broadcast::Sender<RequestLog>,
+    upstream_failed: Arc<Notify>,
+    paused: Arc<AtomicBool>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl LocalProxy {
+    pub async fn start(upstream: &LocalTarget, options: ProxyOptions) -> Result<Self> {
+        let listen_ip = match upstream.bind.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            _ => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        };
+        let listener = TcpListener::bind((listen_ip, 0)).await.map_err(|err| {
+            FunnelError::Other(format!(
+                "Failed to start local proxy on {}: {}",
+                listen_ip, err
+            ))
+        })?;
+        let addr = listener.local_addr().map_err(|err| {
+            FunnelError::Other(format!("Failed to read local proxy address: {}", err))
+        })?;
+
+        let stats = Arc::new(ProxyStats::default());
+        let (first_tx, first_rx) = oneshot::channel();
+        let (request_log, _) = broadcast::channel(REQUEST_LOG_CAPACITY);
+        let upstream_failed = Arc::new(Notify::new());
+        let paused = Arc::new(AtomicBool::new(false));
+        let state = Arc::new(ProxyState {
+            upstream: upstream.clone(),
+            client: Client::builder(TokioExecutor::new()).build(HttpConnector::new()),
+            limit: options
+                .max_concurrency
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            stats: Arc::clone(&stats),
+            first_request: Mutex::new(Some(first_tx)),
+            request_log: request_log.clone(),
+            noindex: options.noindex,
+            holding_page: options.holding_page.clone(),
+            upstream_failed: Arc::clone(&upstream_failed),
+            paused: Arc::clone(&paused),
+        });
+
+        let task = tokio::spawn(accept_loop(listener, state));
+        tracing::debug!(%addr, upstream = %upstream, "local proxy listening");
+
+        Ok(Self {
+            addr,
+            options,
+            stats,
+            first_request: Some(first_rx),
+            request_log,
+            upstream_failed,
+            paused,
+            task: Some(task),
+        })
+    }
+
+    /// The address tailscaled should forward to
+    pub fn local_target(&self) -> LocalTarget {
+        LocalTarget::new(self.addr.ip().to_string(), self.addr.port())
+    }
+
+    /// Resolves once, when the first request from outside the tailnet arrives. `None`
+    /// after the first call.
+    pub fn first_request(&mut self) -> Option<oneshot::Receiver<FirstRequest>> {
+        self.first_request.take()
+    }
+
+    /// Every answered request is sent here; subscribe to tail them
+    pub fn request_log(&self) -> broadcast::Sender<RequestLog> {
+        self.request_log.clone()
+    }
+
+    /// Notified when a request could not reach the target
+    pub fn upstream_failed(&self) -> Arc<Notify> {
+        Arc::clone(&self.upstream_failed)
+    }
+
+    /// While paused, requests from outside the tailnet get the holding page and never
+    /// reach the target; tailnet requests are still forwarded
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Requests being served right now, including ones still streaming a response body
+    pub fn in_flight(&self) -> usize {
+        self.stats.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// The cumulative counters alone, cheap enough for every heartbeat
+    pub fn usage(&self) -> Usage {
+        Usage {
+            requests: self.stats.requests.load(Ordering::Relaxed),
+            bytes_in: self.stats.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.stats.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn summary(&self) -> ProxySummary {
+        let (latencies, top_paths) = {
+            let traffic = self.stats.traffic();
+            let mut latencies = traffic.latencies_ms.clone();
+            latencies.sort_unstable();
+            let mut paths: Vec<PathHits> = traffic
+                .paths
+                .iter()
+                .map(|(path, requests)| PathHits {
+                    path: path.clone(),
+                    requests: *requests,
+                })
+                .collect();
+            paths.sort_by(|a, b| b.requests.cmp(&a.requests).then(a.path.cmp(&b.path)));
+            paths.truncate(TOP_PATHS);
+            (latencies, paths)
+        };
+        ProxySummary {
+            requests: self.stats.requests.load(Ordering::Relaxed),
+            rejected: self.stats.rejected.load(Ordering::Relaxed),
+            peak_concurrency: self.stats.peak.load(Ordering::Relaxed),
+            max_concurrency: self.options.max_concurrency,
+            latency_p50_ms: percentile(&latencies, 50),
+            latency_p95_ms: percentile(&latencies, 95),
+            bytes_in: self.stats.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.stats.bytes_out.load(Ordering::Relaxed),
+            top_paths,
+        }
+    }
+
+    /// Stops accepting connections, drops in-flight ones, and returns the final counters
+    pub fn shutdown(mut self) -> ProxySummary {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        self.summary()
+    }
+}
+
+impl Drop for LocalProxy {
+    fn drop(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[u64], percent: usize) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted.get(rank - 1).copied()
+}
+
+async fn accept_loop(listener: TcpListener, state: Arc<ProxyState>) {
+    // Dropping the set when this task is aborted also aborts every connection.
+    let mut connections = JoinSet::new();
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                tracing::debug!(error = %err, "local proxy accept failed");
+                continue;
+            }
+        };
+        while connections.try_join_next().is_some() {}
+
+        let state = Arc::clone(&state);
+        connections.spawn(async move {
+            let service = service_fn(move |request| handle(Arc::clone(&state), request));
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!(error = %err, %peer, "local proxy connection ended with error");
+            }
+        });
+    }
+}
+
+async fn handle(
+    state: Arc<ProxyState>,
+    request: Request<Incoming>,
+) -> std::result::Result<Response<ProxyBody>, Infallible> {
+    let noindex = state.noindex;
+    let mut response = respond(state, request).await?;
+    if noindex {
+        response
+            .headers_mut()
+            .insert(X_ROBOTS_TAG, HeaderValue::from_static("noindex"));
+    }
+    Ok(response)
+}
+
+async fn respond(
+    state: Arc<ProxyState>,
+    request: Request<Incoming>,
+) -> std::result::Result<Response<ProxyBody>, Infallible> {
+    state.stats.requests.fetch_add(1, Ordering::Relaxed);
+    state.stats.record_path(request.uri().path());
+    let started = Instant::now();
+    let entry = RequestLog {
+        at: Utc::now(),
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        status: 0,
+        duration_ms: 0,
+        external: is_external(&request),
+    };
+    if entry.external {
+        let sender = state
+            .first_request
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take();
+        if let Some(sender) = sender {
+            let _ = sender.send(FirstRequest {
+                at: entry.at,
+                method: entry.method.clone(),
+                path: entry.path.clone(),
+            });
+        }
+    }
+
+    if state.noindex {
+        if let Some(body) = robots_txt(&request) {
+            let response = plain_response(StatusCode::OK, body);
+            log_request(&state, entry, response.status(), started);
+            return Ok(response);
+        }
+    }
+
+    if entry.external && state.paused.load(Ordering::Relaxed) {
+        let page = state
+            .holding_page
+            .clone()
+            .unwrap_or_else(|| Bytes::from_static(PAUSED_PAGE.as_bytes()));
+        let response = holding_page(page);
+        log_request(&state, entry, response.status(), started);
+        return Ok(response);
+    }
+
+    let permit = match &state.limit {
+        Some(limit) => match Arc::clone(limit).try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                state.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                let response = too_many_requests();
+                log_request(&state, entry, response.status(), started);
+                return Ok(response);
+            }
+        },
+        None => None,
+    };
+    let guard = InFlightGuard::enter(Arc::clone(&state.stats), permit);
+
+    match forward(&state, request).await {
+        Ok(response) => {
+            state.stats.record_latency(started);
+            log_request(&state, entry, response.status(), started);
+            Ok(response.map(|body| TrackedBody::new(body, guard).boxed()))
+        }
+        Err(err) => {
+            tracing::debug!(error = %err, upstream = %state.upstream, "local proxy upstream request failed");
+            state.upstream_failed.notify_one();
+            let response = match &state.holding_page {
+                Some(page) => holding_page(page.clone()),
+                None => plain_response(
+                    StatusCode::BAD_GATEWAY,
+                    "funnelctl: local target unavailable\n",
+                ),
+            };
+            log_request(&state, entry, response.status(), started);
+            Ok(response)
+        }
+    }
+}
+
+/// Publishes `entry` to `watch-requests` subscribers; costs nothing when there are none
+fn log_request(state: &ProxyState, mut entry: RequestLog, status: StatusCode, started: Instant) {
+    if state.request_log.receiver_count() == 0 {
+        return;
+    }
+    entry.status = status.as_u16();
+    entry.duration_ms = started.elapsed().as_millis() as u64;
+    let _ = state.request_log.send(entry);
+}
+
+/// The `robots.txt` answering a request for one: it disallows the directory the file
+/// was asked for in, which is the tunnel's path when tailscaled passes the mount path
+/// through, and `/` for a tunnel mounted at the root. Crawlers only read it at the root.
+fn robots_txt<B>(request: &Request<B>) -> Option<String> {
+    if request.method() != hyper::Method::GET && request.method() != hyper::Method::HEAD {
+        return None;
+    }
+    let dir = request.uri().path().strip_suffix("robots.txt")?;
+    if !dir.ends_with('/') {
+        return None;
+    }
+    Some(format!("User-agent: *\nDisallow: {}\n", dir))
+}
+
+/// Funnel requests are marked by tailscaled; without the mark (older tailscaled), a
+/// request without a tailnet identity is taken to be from outside
+fn is_external<B>(request: &Request<B>) -> bool {
+    let headers = request.headers();
+    headers.contains_key(FUNNEL_REQUEST_HEADER) || !headers.contains_key(USER_LOGIN_HEADER)
+}
+
+async fn forward(
+    state: &ProxyState,
+    request: Request<Incoming>,
+) -> std::result::Result<Response<Incoming>, FunnelError> {
+    let (mut parts, body) = request.into_parts();
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|value| value.as_str())
+        .unwrap_or("/");
+    let uri: Uri = format!("{}{}", state.upstream, path_and_query)
+        .parse()
+        .map_err(|err| FunnelError::Other(format!("Invalid upstream URI: {}", err)))?;
+    parts.uri = uri;
+
+    let body = CountingBody {
+        inner: body,
+        stats: Arc::clone(&state.stats),
+    };
+    state
+        .client
+        .request(Request::from_parts(parts, body))
+        .await
+        .map_err(|err| FunnelError::Other(format!("Upstream request failed: {}", err)))
+}
+
+fn too_many_requests() -> Response<ProxyBody> {
+    let mut response = plain_response(
+        StatusCode::SERVICE_UNAVAILABLE,
+        "funnelctl: too many concurrent requests, retry shortly\n",
+    );
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+    response
+}
+
+/// 503 with the holding page; `Refresh` reloads custom pages that lack a meta refresh
+fn holding_page(page: Bytes) -> Response<ProxyBody> {
+    let mut response = plain_response(StatusCode::SERVICE_UNAVAILABLE, page);
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/html; charset=utf-8"),
+    );
+    headers.insert(RETRY_AFTER, HeaderValue::from(HOLDING_REFRESH_SECS));
+    headers.insert(REFRESH, HeaderValue::from(HOLDING_REFRESH_SECS));
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response
+}
+
+fn plain_response(status: StatusCode, body: impl Into<Bytes>) -> Response<ProxyBody> {
+    let mut response = Response::new(
+        Full::new(body.into())
+            .map_err(|never| match never {})
+            .boxed(),
+    );
+    *response.status_mut() = status;
+    response.headers_mut().insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    response
+}
+
+/// Tracks one in-flight request; released once the response body is finished
+struct InFlightGuard {
+    stats: Arc<ProxyStats>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl InFlightGuard {
+    fn enter(stats: Arc<ProxyStats>, permit: Option<OwnedSemaphorePermit>) -> Self {
+        let current = stats.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        stats.peak.fetch_max(current, Ordering::Relaxed);
+        Self {
+            stats,
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct TrackedBody {
+    inner: Incoming,
+    guard: Option<InFlightGuard>,
+}
+
+impl TrackedBody {
+    fn new(inner: Incoming, guard: InFlightGuard) -> Self {
+        Self {
+            inner,
+            guard: Some(guard),
+        }
+    }
+}
+
+impl Body for TrackedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let (Some(data), Some(guard)) = (frame.data_ref(), &self.guard) {
+                    guard
+                        .stats
+                        .bytes_out
+                        .fetch_add(data.len() as u64, Ordering::Relaxed);
+                }
+            }
+            Poll::Ready(None) | Poll::Ready(Some(Err(_))) => {
+                self.guard.take();
+            }
+            Poll::Pending => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// The request body on its way to the target, counting `bytes_in`
+struct CountingBody {
+    inner: Incoming,
+    stats: Arc<ProxyStats>,
+}
+
+impl Body for CountingBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.stats
+                    .bytes_in
+                    .fetch_add(data.len() as u64, Ordering::Relaxed);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Empty;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::time::{sleep, Duration};
+
+    async fn slow_upstream(delay: Duration) -> LocalTarget {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    let _ = stream.read(&mut buf).await;
+                    sleep(delay).await;
+                    let _ = stream
+                        .write_all(
+                            b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nok",
+                        )
+                        .await;
+                });
+            }
+        });
+        LocalTarget::new("127.0.0.1".to_string(), port)
+    }
+
+    async fn get(target: &LocalTarget) -> Response<Incoming> {
+        get_path(target, "/hook").await
+    }
+
+    async fn get_path(target: &LocalTarget, path: &str) -> Response<Incoming> {
+        let client: Client<HttpConnector, Empty<Bytes>> =
+            Client::builder(TokioExecutor::new()).build(HttpConnector::new());
+        let uri: Uri = format!("{}{}", target, path).parse().unwrap();
+        client.get(uri).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_proxy_forwards_requests() {
+        let upstream = slow_upstream(Duration::from_millis(0)).await;
+        let mut proxy = LocalProxy::start(&upstream, ProxyOptions::default())
+            .await
+            .unwrap();
+        let first_request = proxy.first_request().unwrap();
+        assert!(proxy.first_request().is_none());
+        let mut request_log = proxy.request_log().subscribe();
+
+        let response = get(&proxy.local_target()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let _ = response.into_body().collect().await.unwrap();
+        let first = first_request.await.unwrap();
+        assert_eq!(
+            (first.method.as_str(), first.path.as_str()),
+            ("GET", "/hook")
+        );
+        let entry = request_log.recv().await.unwrap();
+        assert_eq!((entry.path.as_str(), entry.status), ("/hook", 200));
+        assert!(entry.external);
+
+        let summary = proxy.shutdown();
+        assert_eq!(summary.requests, 1);
+        assert_eq!(summary.rejected, 0);
+        assert_eq!(summary.peak_concurrency, 1);
+        assert_eq!(summary.bytes_out, 2);
+        assert!(summary.latency_p50_ms.is_some());
+        assert_eq!(
+            summary.top_paths,
+            vec![PathHits {
+                path: "/hook".to_string(),
+                requests: 1
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_noindex_answers_robots_txt() {
+        let upstream = slow_upstream(Duration::from_millis(0)).await;
+        let proxy = LocalProxy::start(
+            &upstream,
+            ProxyOptions {
+                noindex: true,
+                ..ProxyOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        let target = proxy.local_target();
+
+        let response = get(&target).await;
+        assert_eq!(response.headers().get(X_ROBOTS_TAG).unwrap(), "noindex");
+        let robots = get_path(&target, "/robots.txt").await;
+        assert_eq!(robots.headers().get(X_ROBOTS_TAG).unwrap(), "noindex");
+        let body = robots.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"User-agent: *\nDisallow: /\n");
+        // Answered by the proxy, so the target saw only the first request
+        assert_eq!(proxy.shutdown().bytes_out, 2);
+
+        let request = |path: &str| Request::get(path).body(()).unwrap();
+        assert_eq!(
+            robots_txt(&request("/funnelctl/abc/robots.txt")).as_deref(),
+            Some("User-agent: *\nDisallow: /funnelctl/abc/\n")
+        );
+        assert_eq!(robots_txt(&request("/myrobots.txt")), None);
+        assert_eq!(robots_txt(&request("/hook")), None);
+    }
+
+    #[tokio::test]
+    async fn test_holding_page_while_target_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let upstream = LocalTarget::new("127.0.0.1".to_string(), port);
+        let proxy = LocalProxy::start(
+            &upstream,
+            ProxyOptions {
+                holding_page: Some(Bytes::from_static(b"<p>back soon</p>")),
+                ..ProxyOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        let upstream_failed = proxy.upstream_failed();
+
+        let response = get(&proxy.local_target()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers().get(CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        assert_eq!(response.headers().get(REFRESH).unwrap(), "5");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"<p>back soon</p>");
+        tokio::time::timeout(Duration::from_secs(1), upstream_failed.notified())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_paused_proxy_holds_funnel_requests() {
+        let upstream = slow_upstream(Duration::from_millis(0)).await;
+        let proxy = LocalProxy::start(&upstream, ProxyOptions::default())
+            .await
+            .unwrap();
+
+        proxy.set_paused(true);
+        let response = get(&proxy.local_target()).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], PAUSED_PAGE.as_bytes());
+
+        proxy.set_paused(false);
+        let response = get(&proxy.local_target()).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn test_tailnet_requests_are_not_external() {
+        let request = |headers: &[(&str, &str)]| {
+            let mut builder = Request::get("/hook");
+            for (name, value) in headers {
+                builder = builder.header(*name, *value);
+            }
+            builder.body(()).unwrap()
+        };
+        assert!(is_external(&request(&[])));
+        assert!(is_external(&request(&[("Tailscale-Funnel-Request", "?1")])));
+        assert!(!is_external(&request(&[(
+            "Tailscale-User-Login",
+            "alice@example.com"
+        )])));
+    }
+
+    #[test]
+    fn test_percentile_nearest_rank() {
+        let samples: Vec<u64> = (1..=20).collect();
+        assert_eq!(percentile(&samples, 50), Some(10));
+        assert_eq!(percentile(&samples, 95), Some(19));
+        assert_eq!(percentile(&[7], 95), Some(7));
+        assert_eq!(percentile(&[], 50), None);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_rejects_over_limit() {
+        let upstream = slow_upstream(Duration::from_millis(300)).await;
+        let proxy = LocalProxy::start(
+            &upstream,
+            ProxyOptions {
+                max_concurrency: Some(1),
+                ..ProxyOptions::default()
+            },
+        )
+        .await
+        .unwrap();
+        let target = proxy.local_target();
+
+        let first = tokio::spawn({
+            let target = target.clone();
+            async move { get(&target).await.status() }
+        });
+        sleep(Duration::from_millis(100)).await;
+        let second = get(&target).await;
+
+        assert_eq!(second.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            second.headers().get(RETRY_AFTER),
+            Some(&HeaderValue::from(RETRY_AFTER_SECS))
+        );
+        assert_eq!(first.await.unwrap(), StatusCode::OK);
+
+        let summary = proxy.shutdown();
+        assert_eq!(summary.rejected, 1);
+        assert_eq!(summary.peak_concurrency, 1);
+        assert_eq!(summary.max_concurrency, Some(1));
+    }
+}
@@ -0,0 +1,205 @@
+//! Last-resort teardown of an applied tunnel.
+//!
+//! `open` removes its route itself on every normal stop. `TeardownGuard` covers the
+//! paths that skip that: an early `?`, a cancelled future, or a panic. Release builds
+//! abort on panic, so no destructor runs there; a panic hook does the cleanup instead.
+
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, Once, Weak};
+use std::time::Duration;
+
+use crate::backend::Backend;
+use crate::core::{Lease, LeaseStore, Route};
+use crate::error::{FunnelError, Result};
+
+/// Longest a guard or the panic hook waits for the cleanup to finish
+const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+type Slot = Mutex<Option<Teardown>>;
+
+/// Guards the panic hook can reach; dead entries are pruned on every arm
+static ARMED: Mutex<Vec<Weak<Slot>>> = Mutex::new(Vec::new());
+static HOOK: Once = Once::new();
+
+struct Teardown {
+    backend: Arc<dyn Backend>,
+    lease: Lease,
+}
+
+/// Removes the tunnel described by `lease` when dropped, unless disarmed first
+pub struct TeardownGuard {
+    slot: Arc<Slot>,
+}
+
+impl TeardownGuard {
+    /// Arms a guard for a route that was just applied. `lease` needs the applied spec and
+    /// lease id; it does not have to be saved.
+    pub fn arm(backend: Arc<dyn Backend>, lease: Lease) -> Self {
+        HOOK.call_once(install_panic_hook);
+        let slot = Arc::new(Mutex::new(Some(Teardown { backend, lease })));
+        let mut armed = lock(&ARMED);
+        armed.retain(|slot| slot.strong_count() > 0);
+        armed.push(Arc::downgrade(&slot));
+        Self { slot }
+    }
+
+    /// Hands teardown back to the caller
+    pub fn disarm(self) {
+        lock(&self.slot).take();
+    }
+}
+
+impl Drop for TeardownGuard {
+    fn drop(&mut self) {
+        if let Some(teardown) = lock(&self.slot).take() {
+            tracing::warn!(lease = %teardown.lease.lease_id, "open exited early; tearing down tunnel");
+            run_blocking(teardown);
+        }
+    }
+}
+
+fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        let slots: Vec<Arc<Slot>> = lock(&ARMED).iter().filter_map(Weak::upgrade).collect();
+        for slot in slots {
+            if let Some(teardown) = lock(&slot).take() {
+                eprintln!(
+                    "funnelctl panicked; removing tunnel {}",
+                    teardown.lease.tunnel_spec.path
+                );
+                run_blocking(teardown);
+            }
+        }
+    }));
+}
+
+/// A panic elsewhere must not stop the cleanup, so poisoning is ignored
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Runs the cleanup on its own thread and runtime, so it works from `Drop`, from a panic
+/// hook, and while the caller's runtime is shutting down. Gives up after
+/// `TEARDOWN_TIMEOUT`, e.g. when the panicking thread holds a lock the backend needs.
+fn run_blocking(teardown: Teardown) {
+    let (done, wait) = mpsc::channel();
+    let spawned = std::thread::Builder::new()
+        .name("funnelctl-teardown".to_string())
+        .spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|err| FunnelError::Other(err.to_string()));
+            let result = runtime.and_then(|runtime| {
+                runtime.block_on(remove(teardown.backend.as_ref(), &teardown.lease))
+            });
+            let _ = done.send(result);
+        });
+    if let Err(err) = spawned {
+        tracing::warn!(error = %err, "cannot start teardown thread");
+        return;
+    }
+    match wait.recv_timeout(TEARDOWN_TIMEOUT) {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => tracing::warn!(error = %err, "best-effort teardown failed"),
+        Err(_) => tracing::warn!("best-effort teardown timed out"),
+    }
+}
+
+/// Takes no lock: the process being torn down may hold it
+async fn remove(backend: &dyn Backend, lease: &Lease) -> Result<()> {
+    if lease.tunnel_spec.detached {
+        let routes: Vec<Route> = backend
+            .routes()
+            .await?
+            .into_iter()
+            .filter(|route| lease.owns(route))
+            .collect();
+        backend.remove_routes(&routes).await?;
+    } else {
+        backend.remove(&lease.lease_id).await?;
+    }
+    LeaseStore::open()?.remove(&lease.lease_id)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MockBackend;
+    use crate::core::{LocalTarget, TunnelSpec};
+    use crate::progress::NoProgress;
+    use std::path::PathBuf;
+    use std::sync::OnceLock;
+
+    /// A panic in one test runs the hook for every armed guard, so these run one at a time
+    static SERIAL: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    fn isolate_state_dir() {
+        static DIR: OnceLock<PathBuf> = OnceLock::new();
+        let dir = DIR.get_or_init(|| tempfile::tempdir().unwrap().keep());
+        crate::dirs::set_state_dir(dir.clone()).unwrap();
+    }
+
+    async fn applied(detached: bool) -> (Arc<MockBackend>, Lease) {
+        isolate_state_dir();
+        let backend = Arc::new(MockBackend::new());
+        let mut spec = TunnelSpec::new(
+            LocalTarget::new("127.0.0.1".to_string(), 3000),
+            443,
+            "/funnelctl/guard".to_string(),
+            true,
+        );
+        if detached {
+            spec = spec.detached();
+        }
+        let result = backend.apply(&spec, &NoProgress).await.unwrap();
+        let lease = Lease::new(result.lease_id, spec, None);
+        LeaseStore::open().unwrap().save(&lease).unwrap();
+        (backend, lease)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_panic_after_apply_removes_route() {
+        let _serial = SERIAL.lock().await;
+        for detached in [false, true] {
+            let (backend, lease) = applied(detached).await;
+            let guard = TeardownGuard::arm(backend.clone(), lease.clone());
+            let task = tokio::spawn(async move {
+                let _guard = guard;
+                panic!("simulated panic mid-run");
+            });
+            assert!(task.await.unwrap_err().is_panic());
+            assert!(backend.routes().await.unwrap().is_empty(), "{}", detached);
+            let leases = LeaseStore::open().unwrap().list().unwrap();
+            assert!(leases.iter().all(|other| other.lease_id != lease.lease_id));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cancelled_run_removes_route() {
+        let _serial = SERIAL.lock().await;
+        let (backend, lease) = applied(false).await;
+        let guard = TeardownGuard::arm(backend.clone(), lease);
+        let task = tokio::spawn(async move {
+            let _guard = guard;
+            std::future::pending::<()>().await;
+        });
+        task.abort();
+        assert!(task.await.unwrap_err().is_cancelled());
+        assert!(backend.routes().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_disarmed_guard_leaves_route() {
+        let _serial = SERIAL.lock().await;
+        let (backend, lease) = applied(true).await;
+        TeardownGuard::arm(backend.clone(), lease.clone()).disarm();
+        assert_eq!(backend.routes().await.unwrap().len(), 1);
+        backend.remove(&lease.lease_id).await.unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+//! `open --format` templates: literal text with `{field}` placeholders over the
+//! `started` event. `{{` and `}}` produce literal braces.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use crate::error::{FunnelError, Result};
+use crate::output::Event;
+
+const FIELDS: &[(&str, Field)] = &[
+    ("url", Field::Url),
+    ("local_target", Field::LocalTarget),
+    ("path", Field::Path),
+    ("https_port", Field::HttpsPort),
+    ("name", Field::Name),
+    ("started_at", Field::StartedAt),
+    ("expires_at", Field::ExpiresAt),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Url,
+    LocalTarget,
+    Path,
+    HttpsPort,
+    Name,
+    StartedAt,
+    ExpiresAt,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// A parsed `--format` string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parses `source`, rejecting unknown placeholders and unbalanced braces up front so
+    /// a typo fails before any tunnel is created
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = source.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => {
+                                return Err(invalid(format!("unclosed placeholder '{{{}'", name)))
+                            }
+                        }
+                    }
+                    let field = lookup(name.trim())?;
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '}' => return Err(invalid("unmatched '}' (use '}}' for a literal brace)")),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self { parts })
+    }
+
+    /// Renders the template; fields without a value (e.g. `expires_at` with no TTL) are empty
+    pub fn render(&self, event: &Event) -> String {
+        let mut out = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => out.push_str(text),
+                Part::Field(field) => out.push_str(&field_value(*field, event)),
+            }
+        }
+        out
+    }
+}
+
+fn lookup(name: &str) -> Result<Field> {
+    FIELDS
+        .iter()
+        .find(|(field_name, _)| *field_name == name)
+        .map(|(_, field)| *field)
+        .ok_or_else(|| {
+            let available: Vec<&str> = FIELDS.iter().map(|(name, _)| *name).collect();
+            invalid(format!(
+                "unknown placeholder '{{{}}}'; available: {}",
+                name,
+                available.join(", ")
+            ))
+        })
+}
+
+fn field_value(field: Field, event: &Event) -> String {
+    let Event::Started {
+        url,
+        local_target,
+        path,
+        https_port,
+        name,
+        started_at,
+        expires_at,
+        ..
+    } = event
+    else {
+        return String::new();
+    };
+
+    match field {
+        Field::Url => url.clone(),
+        Field::LocalTarget => local_target.clone(),
+        Field::Path => path.clone(),
+        Field::HttpsPort => https_port.to_string(),
+        Field::Name => name.clone().unwrap_or_default(),
+        Field::StartedAt => timestamp(started_at),
+        Field::ExpiresAt => expires_at.as_ref().map(timestamp).unwrap_or_default(),
+    }
+}
+
+/// Same RFC 3339 spelling as the JSON events
+fn timestamp(time: &DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+fn invalid(message: impl Into<String>) -> FunnelError {
+    FunnelError::InvalidArgument(message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn started(expires_at: Option<DateTime<Utc>>) -> Event {
+        Event::Started {
+            version: 1,
+            url: "https://node.ts.net/funnelctl/abc".to_string(),
+            mirror_urls: Vec::new(),
+            local_target: "http://127.0.0.1:8081".to_string(),
+            path: "/funnelctl/abc".to_string(),
+            https_port: 443,
+            name: Some("api".to_string()),
+            expose_for: None,
+            started_at: Utc.with_ymd_and_hms(2026, 1, 8, 12, 0, 0).unwrap(),
+            expires_at,
+            unchecked: false,
+            dns_warning: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_render_fields() {
+        let template = Template::parse("{url} (expires {expires_at})").unwrap();
+        let expires = Utc.with_ymd_and_hms(2026, 1, 8, 12, 30, 0).unwrap();
+        assert_eq!(
+            template.render(&started(Some(expires))),
+            "https://node.ts.net/funnelctl/abc (expires 2026-01-08T12:30:00Z)"
+        );
+        assert_eq!(
+            template.render(&started(None)),
+            "https://node.ts.net/funnelctl/abc (expires )"
+        );
+    }
+
+    #[test]
+    fn test_escaped_braces_and_spaces() {
+        let template = Template::parse("{{{ name }:{https_port}}}").unwrap();
+        assert_eq!(template.render(&started(None)), "{api:443}");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = Template::parse("{uri}").unwrap_err();
+        assert!(err.to_string().contains("unknown placeholder '{uri}'"));
+        assert!(Template::parse("{url").is_err());
+        assert!(Template::parse("url}").is_err());
+    }
+}
@@ -0,0 +1,89 @@
+//! `funnelctl --version --json`: what was built, and which formats it speaks
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::backend::localapi::MIN_SUPPORTED_VERSION;
+use crate::core::manifest::MANIFEST_VERSION;
+use crate::core::snapshot::SNAPSHOT_VERSION;
+use crate::heartbeat::STATUS_FILE_VERSION;
+use crate::output::EVENT_VERSION;
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    /// Short commit hash, when built from a git checkout
+    pub git_commit: Option<&'static str>,
+    pub build_date: Option<DateTime<Utc>>,
+    pub features: Vec<&'static str>,
+    pub target: String,
+    /// Oldest tailscaled whose LocalAPI funnelctl supports
+    pub min_localapi_version: String,
+    pub schemas: Schemas,
+}
+
+/// The `version` each machine-readable format carries
+#[derive(Debug, Serialize)]
+pub struct Schemas {
+    /// `--json` events, JSON errors, and plugin messages
+    pub events: u32,
+    /// `funnelctl export` documents
+    pub export: u32,
+    /// `funnelctl.toml` project manifests
+    pub manifest: u32,
+    /// Per-tunnel status files read by prompts and `health`
+    pub status_file: u32,
+}
+
+impl VersionInfo {
+    pub fn current() -> Self {
+        let (major, minor, patch) = MIN_SUPPORTED_VERSION;
+        Self {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: option_env!("FUNNELCTL_GIT_COMMIT"),
+            build_date: option_env!("FUNNELCTL_BUILD_EPOCH")
+                .and_then(|epoch| epoch.parse().ok())
+                .and_then(|epoch| DateTime::from_timestamp(epoch, 0)),
+            features: option_env!("FUNNELCTL_FEATURES")
+                .unwrap_or_default()
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .collect(),
+            target: format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS),
+            min_localapi_version: format!("{}.{}.{}", major, minor, patch),
+            schemas: Schemas {
+                events: EVENT_VERSION,
+                export: SNAPSHOT_VERSION,
+                manifest: MANIFEST_VERSION,
+                status_file: STATUS_FILE_VERSION,
+            },
+        }
+    }
+}
+
+/// `--version --json` (in either order, and nothing else) asks for the JSON document;
+/// clap would stop at `--version` or reject `--json` as unknown
+pub fn wants_json(args: &[String]) -> bool {
+    let is_version = |arg: &String| arg == "--version" || arg == "-V";
+    args.len() == 2 && args.iter().any(is_version) && args.iter().any(|arg| arg == "--json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_json() {
+        let args = |list: &[&str]| list.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert!(wants_json(&args(&["--version", "--json"])));
+        assert!(wants_json(&args(&["--json", "-V"])));
+        assert!(!wants_json(&args(&["--version"])));
+        assert!(!wants_json(&args(&["open", "--json"])));
+
+        let info = serde_json::to_value(VersionInfo::current()).unwrap();
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(info["min_localapi_version"], "1.50.0");
+        assert_eq!(info["schemas"]["events"], 1);
+        assert!(info["features"].is_array());
+    }
+}
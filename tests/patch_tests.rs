@@ -1,6 +1,6 @@
 use funnelctl::core::{
-    apply_patch, detect_conflicts, remove_patch, Conflict, HttpHandler, ServeConfig,
-    WebServerConfig,
+    apply_background_patch, apply_patch, detect_conflicts, foreground_route_state, remove_patch,
+    Conflict, HttpHandler, RouteState, ServeConfig, WebServerConfig,
 };
 use serde_json::Value;
 use std::collections::HashMap;
@@ -82,7 +82,7 @@ fn test_detect_conflicts_exact_path_different_target() {
         false,
     );
     assert!(result.is_err());
-    match result.unwrap_err() {
+    match result.unwrap_err().remove(0) {
         Conflict::ExactPathDifferentTarget {
             path,
             existing_target,
@@ -146,6 +146,7 @@ fn test_detect_conflicts_non_proxy_handler() {
             proxy: None,
             path: None,
             text: Some("ok".to_string()),
+            accept_app_caps: None,
             unknown_fields: HashMap::new(),
         },
     );
@@ -179,7 +180,7 @@ fn test_detect_conflicts_prefix_captures_new_path() {
         false,
     );
     assert!(result.is_err());
-    match result.unwrap_err() {
+    match result.unwrap_err().remove(0) {
         Conflict::CapturedByExistingPrefix {
             new_path,
             existing_prefix,
@@ -209,7 +210,7 @@ fn test_detect_conflicts_new_prefix_captures_existing() {
         false,
     );
     assert!(result.is_err());
-    match result.unwrap_err() {
+    match result.unwrap_err().remove(0) {
         Conflict::NewPrefixCapturesExisting {
             new_prefix,
             captured_path,
@@ -252,6 +253,46 @@ fn test_detect_conflicts_multiple_prefix_levels() {
     assert!(result2.is_err());
 }
 
+#[test]
+fn test_detect_conflicts_reports_every_conflict() {
+    let config = create_config_with_handlers(
+        "example.com:443",
+        vec![
+            ("/api/", "http://127.0.0.1:8080"),
+            ("/api/v1/", "http://127.0.0.1:8081"),
+            ("/api/v1/users", "http://127.0.0.1:8082"),
+            ("/api/v1/users/me", "http://127.0.0.1:8083"),
+            ("/api/v2", "http://127.0.0.1:8084"),
+        ],
+        false,
+    );
+
+    let conflicts = detect_conflicts(
+        &config,
+        "example.com:443",
+        "/api/v1/",
+        "http://127.0.0.1:9000",
+        false,
+    )
+    .unwrap_err();
+    let kinds: Vec<_> = conflicts.iter().map(Conflict::kind).collect();
+    assert_eq!(
+        kinds,
+        [
+            "exact_path",
+            "captured_by_prefix",
+            "captures_existing",
+            "captures_existing"
+        ]
+    );
+
+    let entry = conflicts[3].entry("example.com:443", Some("session-1"));
+    assert_eq!(entry.path, "/api/v1/");
+    assert_eq!(entry.existing_path, "/api/v1/users/me");
+    assert!(entry.existing_target.contains("8083"));
+    assert!(entry.message.ends_with("(session session-1)"));
+}
+
 #[test]
 fn test_detect_conflicts_no_prefix_without_trailing_slash() {
     let config = create_config_with_handlers(
@@ -436,6 +477,34 @@ fn test_apply_patch_without_funnel() {
     );
 }
 
+#[test]
+fn test_apply_background_patch_keeps_existing_handlers() {
+    let mut config = create_config_with_handlers(
+        "example.com:443",
+        vec![("/", "http://127.0.0.1:3000")],
+        false,
+    );
+    apply_background_patch(
+        &mut config,
+        "example.com:443",
+        "/funnelctl/abc",
+        "http://127.0.0.1:8080",
+        true,
+    );
+
+    assert!(config.foreground.is_none());
+    let handlers = config.web.as_ref().unwrap()["example.com:443"]
+        .handlers
+        .as_ref()
+        .unwrap();
+    assert_eq!(handlers.len(), 2);
+    assert_eq!(
+        handlers["/funnelctl/abc"].get_proxy_target(),
+        Some("http://127.0.0.1:8080")
+    );
+    assert!(config.is_funnel_enabled("example.com:443"));
+}
+
 #[test]
 fn test_apply_patch_preserves_unknown_fields() {
     let mut config = ServeConfig::new();
@@ -732,3 +801,60 @@ fn test_path_prefix_edge_cases() {
     assert!(result2.is_ok());
     assert_eq!(result2.unwrap(), None);
 }
+
+#[test]
+fn test_foreground_route_state_intact_and_removed() {
+    let host_port = "node.ts.net:443";
+    let target = "http://127.0.0.1:8081";
+    let mut config = ServeConfig::new();
+    apply_patch(&mut config, "session-1", host_port, "/hook", target, true).unwrap();
+
+    let state =
+        foreground_route_state(&config, "session-1", host_port, "/hook", target, true).unwrap();
+    assert_eq!(state, RouteState::Intact);
+
+    remove_patch(&mut config, "session-1", host_port, "/hook").unwrap();
+    let state =
+        foreground_route_state(&config, "session-1", host_port, "/hook", target, true).unwrap();
+    assert_eq!(state, RouteState::Removed);
+
+    // `tailscale serve off` drops the whole foreground map
+    config.foreground = None;
+    let state =
+        foreground_route_state(&config, "session-1", host_port, "/hook", target, true).unwrap();
+    assert_eq!(state, RouteState::Removed);
+}
+
+#[test]
+fn test_foreground_route_state_replaced() {
+    let host_port = "node.ts.net:443";
+    let target = "http://127.0.0.1:8081";
+
+    let mut config = ServeConfig::new();
+    apply_patch(
+        &mut config,
+        "session-1",
+        host_port,
+        "/hook",
+        "http://127.0.0.1:9000",
+        true,
+    )
+    .unwrap();
+    let state =
+        foreground_route_state(&config, "session-1", host_port, "/hook", target, true).unwrap();
+    assert_eq!(state, RouteState::Replaced);
+
+    // A background handler on the same path takes over
+    let mut config = create_config_with_handlers(host_port, vec![("/hook", "text")], true);
+    apply_patch(&mut config, "session-1", host_port, "/hook", target, true).unwrap();
+    let state =
+        foreground_route_state(&config, "session-1", host_port, "/hook", target, true).unwrap();
+    assert_eq!(state, RouteState::Replaced);
+
+    // Funnel switched off for the session
+    let mut config = ServeConfig::new();
+    apply_patch(&mut config, "session-1", host_port, "/hook", target, false).unwrap();
+    let state =
+        foreground_route_state(&config, "session-1", host_port, "/hook", target, true).unwrap();
+    assert_eq!(state, RouteState::Replaced);
+}
@@ -0,0 +1,350 @@
+use funnelctl::core::{
+    apply_background_patch, apply_patch, list_routes, remove_patch, remove_routes,
+    restore_snapshot, RestoreOutcome, Route, ServeConfig,
+};
+use serde_json::Value;
+use std::path::PathBuf;
+
+const SESSION: &str = "funnelctl-golden";
+const HOST_PORT: &str = "golden.tail1234.ts.net:8443";
+const PATH: &str = "/funnelctl/golden";
+const TARGET: &str = "http://127.0.0.1:9999";
+
+fn fixture_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/serve-config")
+}
+
+/// Parses a fixture by its path under the fixture dir. A `null` document (no serve
+/// config) reads as an empty one, as the backend treats it.
+fn fixture(name: &str) -> (ServeConfig, Value) {
+    let text = std::fs::read_to_string(fixture_dir().join(name)).unwrap();
+    let text = if text.trim() == "null" { "{}" } else { &text };
+    let config: ServeConfig = serde_json::from_str(text).unwrap();
+    let value: Value = serde_json::from_str(text).unwrap();
+    (config, value)
+}
+
+/// `*.json` files directly in `dir`, as paths relative to the fixture dir
+fn json_files(dir: &str) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(fixture_dir().join(dir)) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| name.ends_with(".json"))
+        .map(|name| format!("{}/{}", dir, name))
+        .collect();
+    names.sort();
+    names
+}
+
+/// Version directories under `captured/`, e.g. `v1.50.1`
+fn capture_dirs() -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(fixture_dir().join("captured")) else {
+        return Vec::new();
+    };
+    let mut dirs: Vec<String> = entries
+        .map(|entry| entry.unwrap())
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| format!("captured/{}", entry.file_name().to_string_lossy()))
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+/// Every synthetic and captured fixture, by path under the fixture dir
+fn all_fixtures() -> Vec<(String, ServeConfig, Value)> {
+    let mut names = json_files("synthetic");
+    for dir in capture_dirs() {
+        names.extend(json_files(&dir));
+    }
+    assert!(!names.is_empty());
+    names
+        .into_iter()
+        .map(|name| {
+            let (config, value) = fixture(&name);
+            (name, config, value)
+        })
+        .collect()
+}
+
+/// Drops our session from `Foreground`, and the map itself if the fixture had none
+fn without_session(mut value: Value, original: &Value) -> Value {
+    if let Some(foreground) = value.get_mut("Foreground").and_then(Value::as_object_mut) {
+        foreground.remove(SESSION);
+        if foreground.is_empty() && original.get("Foreground").is_none() {
+            value.as_object_mut().unwrap().remove("Foreground");
+        }
+    }
+    value
+}
+
+/// Captures must sit in a directory named after the tailscaled release they came
+/// from, and funnelctl supports 1.50.0 and later
+#[test]
+fn test_captures_are_labeled_with_version() {
+    for dir in capture_dirs() {
+        let version = dir.trim_start_matches("captured/v");
+        let parts: Vec<u32> = version
+            .split('.')
+            .map(|part| {
+                part.parse()
+                    .unwrap_or_else(|_| panic!("{} is not v<x.y.z>", dir))
+            })
+            .collect();
+        assert_eq!(parts.len(), 3, "{} is not v<x.y.z>", dir);
+        assert!(
+            (parts[0], parts[1]) >= (1, 50),
+            "{} is older than the oldest supported tailscaled",
+            dir
+        );
+        assert!(!json_files(&dir).is_empty(), "{} has no captures", dir);
+    }
+}
+
+/// Keys a capture carries that look like a typed field under another spelling. If a
+/// daemon spells `TCPForward` differently, it lands in `unknown_fields` and funnelctl
+/// silently stops seeing it.
+fn misspelled_typed_keys(config: &ServeConfig) -> Vec<String> {
+    const TYPED: &[&str] = &[
+        "TCP",
+        "Web",
+        "Services",
+        "AllowFunnel",
+        "Foreground",
+        "HTTPS",
+        "HTTP",
+        "TCPForward",
+        "TerminateTLS",
+        "Handlers",
+        "Proxy",
+        "Path",
+        "Text",
+        "AcceptAppCaps",
+        "Tun",
+    ];
+    let mut keys: Vec<&String> = config.unknown_fields.keys().collect();
+    let tcp = config.tcp.iter().flatten().map(|(_, handler)| handler);
+    let services = config.services.iter().flatten().map(|(_, service)| service);
+    let service_tcp = services
+        .clone()
+        .flat_map(|service| service.tcp.iter().flatten().map(|(_, handler)| handler));
+    for handler in tcp.chain(service_tcp) {
+        keys.extend(handler.unknown_fields.keys());
+    }
+    let web = config.web.iter().flatten().chain(
+        services
+            .clone()
+            .flat_map(|service| service.web.iter().flatten()),
+    );
+    for (_, web_config) in web {
+        keys.extend(web_config.unknown_fields.keys());
+        for (_, handler) in web_config.handlers.iter().flatten() {
+            keys.extend(handler.unknown_fields.keys());
+        }
+    }
+    for service in services {
+        keys.extend(service.unknown_fields.keys());
+    }
+    let mut found: Vec<String> = keys
+        .into_iter()
+        .filter(|key| TYPED.iter().any(|typed| typed.eq_ignore_ascii_case(key)))
+        .cloned()
+        .collect();
+    for session in config
+        .foreground
+        .iter()
+        .flat_map(|sessions| sessions.values())
+    {
+        let session: ServeConfig = serde_json::from_value(session.clone()).unwrap();
+        found.extend(misspelled_typed_keys(&session));
+    }
+    found
+}
+
+/// Real daemon output must fill the typed fields, and so must the synthetic documents
+#[test]
+fn test_captures_fill_typed_fields() {
+    for dir in capture_dirs() {
+        for name in json_files(&dir) {
+            let (config, _) = fixture(&name);
+            assert_eq!(
+                misspelled_typed_keys(&config),
+                Vec::<String>::new(),
+                "{} has fields funnelctl would not see",
+                name
+            );
+        }
+    }
+    for name in json_files("synthetic") {
+        let (config, _) = fixture(&name);
+        assert!(misspelled_typed_keys(&config).is_empty(), "{}", name);
+    }
+    let mut odd: ServeConfig =
+        serde_json::from_str(r#"{"TCP":{"22":{"TcpForward":"127.0.0.1:22"}}}"#).unwrap();
+    assert_eq!(misspelled_typed_keys(&odd), ["TcpForward"]);
+    odd.tcp = None;
+    assert!(misspelled_typed_keys(&odd).is_empty());
+}
+
+#[test]
+fn test_every_fixture_round_trips() {
+    for (name, config, original) in all_fixtures() {
+        let text = serde_json::to_string(&config).unwrap();
+        let reparsed: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(reparsed, original, "{} changed on round trip", name);
+
+        let again: ServeConfig = serde_json::from_str(&text).unwrap();
+        assert_eq!(again, config, "{} is not stable", name);
+    }
+}
+
+#[test]
+fn test_foreground_patch_keeps_unrelated_fields() {
+    for (name, mut config, original) in all_fixtures() {
+        apply_patch(&mut config, SESSION, HOST_PORT, PATH, TARGET, true).unwrap();
+        let patched = serde_json::to_value(&config).unwrap();
+        assert!(
+            patched
+                .pointer(&format!("/Foreground/{}", SESSION))
+                .is_some(),
+            "{}",
+            name
+        );
+        assert_eq!(
+            without_session(patched, &original),
+            original,
+            "apply_patch touched more than its session in {}",
+            name
+        );
+
+        assert!(remove_patch(&mut config, SESSION, HOST_PORT, PATH).unwrap());
+        let removed = serde_json::to_value(&config).unwrap();
+        assert_eq!(
+            without_session(removed, &original),
+            original,
+            "remove_patch touched more than its session in {}",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_background_patch_and_removal_restore_fixture() {
+    for (name, mut config, original) in all_fixtures() {
+        apply_background_patch(&mut config, HOST_PORT, PATH, TARGET, true);
+        assert!(config.is_funnel_enabled(HOST_PORT), "{}", name);
+
+        let ours: Vec<Route> = list_routes(&config)
+            .unwrap()
+            .into_iter()
+            .filter(|route| route.host_port == HOST_PORT && route.session.is_none())
+            .collect();
+        assert_eq!(remove_routes(&mut config, &ours).unwrap(), 1, "{}", name);
+        assert_eq!(
+            serde_json::to_value(&config).unwrap(),
+            original,
+            "background patch left traces in {}",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_patch_into_existing_session_is_reversible() {
+    let (mut config, original) = fixture("synthetic/multi-session.json");
+    let session = "5e0d2b7a91c4f368";
+    let host_port = "node.tail1234.ts.net:8443";
+
+    apply_patch(&mut config, session, host_port, PATH, TARGET, true).unwrap();
+    let routes = list_routes(&config).unwrap();
+    assert_eq!(
+        routes
+            .iter()
+            .filter(|route| route.session.as_deref() == Some(session))
+            .count(),
+        2
+    );
+
+    assert!(remove_patch(&mut config, session, host_port, PATH).unwrap());
+    assert_eq!(serde_json::to_value(&config).unwrap(), original);
+}
+
+#[test]
+fn test_every_fixture_restores_its_background_routes() {
+    for (name, config, _) in all_fixtures() {
+        let background: Vec<Route> = list_routes(&config)
+            .unwrap()
+            .into_iter()
+            .filter(|route| route.session.is_none())
+            .collect();
+
+        let mut restored = ServeConfig::new();
+        let outcomes = restore_snapshot(&mut restored, &config, false).unwrap();
+        assert_eq!(outcomes.len(), background.len(), "{}", name);
+        assert_eq!(list_routes(&restored).unwrap(), background, "{}", name);
+
+        let again = restore_snapshot(&mut restored, &config, false).unwrap();
+        assert!(
+            again
+                .iter()
+                .all(|outcome| matches!(outcome, RestoreOutcome::Present(_))),
+            "{} is not idempotent",
+            name
+        );
+    }
+}
+
+#[test]
+fn test_foreground_session_fixture() {
+    let (config, value) = fixture("synthetic/foreground-session.json");
+    assert_eq!(serde_json::to_value(&config).unwrap(), value);
+
+    let routes = list_routes(&config).unwrap();
+    assert_eq!(routes.len(), 1);
+    assert_eq!(routes[0].session.as_deref(), Some("a3f1c9e27b6d4e08"));
+    assert_eq!(routes[0].target, "http://127.0.0.1:3000");
+    assert!(routes[0].funnel);
+}
+
+#[test]
+fn test_background_mixed_fixture() {
+    let (config, value) = fixture("synthetic/background-mixed.json");
+    assert_eq!(serde_json::to_value(&config).unwrap(), value);
+
+    let tcp = config.tcp.as_ref().unwrap();
+    assert!(tcp[&80].http);
+    assert!(tcp[&443].https);
+    assert_eq!(tcp[&2222].tcp_forward.as_deref(), Some("127.0.0.1:22"));
+    assert_eq!(
+        tcp[&5432].terminate_tls.as_deref(),
+        Some("node.tail1234.ts.net")
+    );
+    assert!(tcp[&5432].unknown_fields.contains_key("ProxyProtocol"));
+
+    let targets: Vec<String> = list_routes(&config)
+        .unwrap()
+        .into_iter()
+        .map(|route| route.target)
+        .collect();
+    assert!(targets.contains(&"path handler /srv/docs".to_string()));
+    assert!(targets.contains(&"text handler".to_string()));
+}
+
+#[test]
+fn test_services_fixture() {
+    let (config, value) = fixture("synthetic/services.json");
+    assert_eq!(serde_json::to_value(&config).unwrap(), value);
+
+    let services = config.services.as_ref().unwrap();
+    assert_eq!(services.len(), 2);
+    assert!(services["svc:subnet"].tun);
+    assert!(services["svc:grafana"].tcp.as_ref().unwrap()[&443].https);
+
+    let handler = &config.get_handlers("node.tail1234.ts.net:443").unwrap()["/"];
+    assert_eq!(
+        handler.accept_app_caps.as_deref(),
+        Some(&["example.com/cap/admin".to_string()][..])
+    );
+    assert!(config.unknown_fields.is_empty());
+}
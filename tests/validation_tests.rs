@@ -1,5 +1,7 @@
 use funnelctl::core::{
-    validate_https_port, validate_path, validate_port, validate_ttl, ValidationWarning,
+    normalize_dns_name, parse_port, parse_public_url_base, parse_start_time, parse_tag, parse_ttl,
+    validate_https_port, validate_name, validate_path, validate_path_with, validate_port,
+    validate_ttl, validate_ttl_with_warning, PathPolicy, ValidationWarning,
 };
 use std::time::Duration;
 
@@ -167,6 +169,19 @@ fn test_ttl_validation_exactly_5min_no_warning() {
     assert!(result.warnings.is_empty());
 }
 
+#[test]
+fn test_ttl_validation_custom_warning_threshold() {
+    let result =
+        validate_ttl_with_warning(Duration::from_secs(90), Duration::from_secs(60)).unwrap();
+    assert!(result.warnings.is_empty());
+    let result =
+        validate_ttl_with_warning(Duration::from_secs(90), Duration::from_secs(120)).unwrap();
+    assert_eq!(result.warnings.len(), 1);
+    let result = validate_ttl_with_warning(Duration::from_secs(30), Duration::ZERO).unwrap();
+    assert!(result.warnings.is_empty());
+    assert!(validate_ttl_with_warning(Duration::from_secs(29), Duration::ZERO).is_err());
+}
+
 #[test]
 fn test_ttl_validation_one_hour() {
     let result = validate_ttl(Duration::from_secs(3600)).unwrap();
@@ -331,3 +346,243 @@ fn test_validation_warning_equality() {
     };
     assert_eq!(w3, w4);
 }
+
+#[test]
+fn test_name_validation() {
+    assert!(validate_name("deploy-preview").is_ok());
+    assert!(validate_name("pr.123_web").is_ok());
+    assert!(validate_name("7up").is_ok());
+
+    assert!(validate_name("").is_err());
+    assert!(validate_name("-leading").is_err());
+    assert!(validate_name("Upper").is_err());
+    assert!(validate_name("has space").is_err());
+    assert!(validate_name(&"a".repeat(64)).is_err());
+}
+
+#[test]
+fn test_tag_parsing() {
+    assert_eq!(
+        parse_tag("env=preview").unwrap(),
+        ("env".to_string(), "preview".to_string())
+    );
+    assert_eq!(
+        parse_tag("url=https://x/?a=b").unwrap(),
+        ("url".to_string(), "https://x/?a=b".to_string())
+    );
+
+    assert!(parse_tag("env").is_err());
+    assert!(parse_tag("env=").is_err());
+    assert!(parse_tag("=preview").is_err());
+    assert!(parse_tag("Env=preview").is_err());
+}
+
+#[test]
+fn test_ttl_parsing() {
+    use chrono::{Local, TimeZone};
+
+    let now = Local.with_ymd_and_hms(2026, 3, 10, 12, 0, 0).unwrap();
+    assert_eq!(parse_ttl("30m", now).unwrap(), Duration::from_secs(1800));
+    assert_eq!(
+        parse_ttl("until 18:00", now).unwrap(),
+        Duration::from_secs(6 * 3600)
+    );
+    assert_eq!(
+        parse_ttl("until 12:00:45", now).unwrap(),
+        Duration::from_secs(45)
+    );
+
+    let deadline = (now + chrono::Duration::minutes(90)).to_rfc3339();
+    assert_eq!(
+        parse_ttl(&deadline, now).unwrap(),
+        Duration::from_secs(5400)
+    );
+    assert_eq!(
+        parse_ttl(&format!("until {}", deadline), now).unwrap(),
+        Duration::from_secs(5400)
+    );
+
+    assert!(parse_ttl("until 09:00", now).is_err());
+    assert!(parse_ttl("until 12:00", now).is_err());
+    assert!(parse_ttl(&(now - chrono::Duration::minutes(1)).to_rfc3339(), now).is_err());
+    assert!(parse_ttl("until 25:00", now).is_err());
+    assert!(parse_ttl("soon", now).is_err());
+}
+
+#[test]
+fn test_start_time_parsing() {
+    use chrono::{Local, TimeZone};
+
+    let now = Local.with_ymd_and_hms(2026, 3, 10, 12, 0, 0).unwrap();
+    assert_eq!(
+        parse_start_time("14:00", now).unwrap(),
+        Local.with_ymd_and_hms(2026, 3, 10, 14, 0, 0).unwrap()
+    );
+    assert_eq!(
+        parse_start_time("12:00:30", now).unwrap(),
+        now + chrono::Duration::seconds(30)
+    );
+    let later = now + chrono::Duration::days(1);
+    assert_eq!(parse_start_time(&later.to_rfc3339(), now).unwrap(), later);
+
+    assert!(parse_start_time("09:00", now).is_err());
+    assert!(parse_start_time("12:00", now).is_err());
+    assert!(parse_start_time("until 14:00", now).is_err());
+    assert!(parse_start_time("2pm", now).is_err());
+}
+
+#[test]
+fn test_reserved_paths_refused_by_default() {
+    let policy = PathPolicy::default();
+    for path in [
+        "/.well-known",
+        "/.well-known/acme-challenge/token",
+        "/metrics",
+        "/metrics/",
+        "//debug/pprof",
+    ] {
+        assert!(validate_path_with(path, &policy).is_err(), "{}", path);
+    }
+    for path in ["/metrics-dashboard", "/app/metrics", "/webhook/debug"] {
+        assert!(validate_path_with(path, &policy).is_ok(), "{}", path);
+    }
+}
+
+#[test]
+fn test_reserved_path_allowed_with_warning() {
+    let policy = PathPolicy {
+        allow_reserved: true,
+        ..PathPolicy::default()
+    };
+    let result = validate_path_with("/metrics/node", &policy).unwrap();
+    assert!(result.warnings.contains(&ValidationWarning::ReservedPath {
+        path: "/metrics/node".to_string(),
+        reserved: "/metrics".to_string(),
+    }));
+}
+
+#[test]
+fn test_custom_reserved_paths() {
+    let policy = PathPolicy {
+        reserved: vec!["/admin/".to_string()],
+        ..PathPolicy::default()
+    };
+    assert!(validate_path_with("/admin/users", &policy).is_err());
+    assert!(validate_path_with("/metrics", &policy).is_ok());
+
+    let policy = PathPolicy {
+        reserved: Vec::new(),
+        ..PathPolicy::default()
+    };
+    assert!(validate_path_with("/.well-known/x", &policy).is_ok());
+}
+
+#[test]
+fn test_strict_path_rejects_unsafe_characters() {
+    let policy = PathPolicy::default();
+    assert!(validate_path_with("/api/v1/user-profile_123.json~", &policy).is_ok());
+    for path in [
+        "/my hook",
+        "/api/用户",
+        "/100%",
+        "/a?b",
+        "/a#b",
+        "/a\\b",
+        "/a+b",
+    ] {
+        assert!(validate_path_with(path, &policy).is_err(), "{}", path);
+    }
+
+    let lax = PathPolicy {
+        strict: false,
+        ..PathPolicy::default()
+    };
+    let result = validate_path_with("/my hook/données", &lax).unwrap();
+    assert_eq!(result.normalized_path, "/my hook/données");
+}
+
+#[test]
+fn test_path_length_and_segment_limits() {
+    let policy = PathPolicy::default();
+    let result = validate_path_with(&format!("/{}", "a".repeat(200)), &policy).unwrap();
+    assert!(result.warnings.is_empty());
+
+    let result = validate_path_with(&format!("/{}", "a".repeat(300)), &policy).unwrap();
+    assert_eq!(
+        result.warnings,
+        vec![ValidationWarning::PathTooLong {
+            length: 301,
+            segments: 1
+        }]
+    );
+    assert!(validate_path_with(&format!("/{}", "a".repeat(1024)), &policy).is_err());
+
+    let deep = "/a".repeat(20);
+    let result = validate_path_with(&deep, &policy).unwrap();
+    assert_eq!(
+        result.warnings,
+        vec![ValidationWarning::PathTooLong {
+            length: 40,
+            segments: 20
+        }]
+    );
+    assert!(validate_path_with(&"/a".repeat(33), &policy).is_err());
+
+    let tight = PathPolicy {
+        max_length: 16,
+        max_segments: 2,
+        ..PathPolicy::default()
+    };
+    assert!(validate_path_with("/webhook/github", &tight).is_ok());
+    assert!(validate_path_with("/webhook/github/push", &tight).is_err());
+    assert!(validate_path_with("/webhook-receiver", &tight).is_err());
+}
+
+#[test]
+fn test_dns_name_normalization() {
+    assert_eq!(
+        normalize_dns_name("Hooks.Example.COM.").unwrap(),
+        "hooks.example.com"
+    );
+    assert_eq!(
+        normalize_dns_name("bücher.example.com").unwrap(),
+        "xn--bcher-kva.example.com"
+    );
+    for name in [
+        "",
+        "localhost",
+        "100.64.0.1",
+        "[::1]",
+        "host.example.com:443",
+        "a b.com",
+    ] {
+        assert!(normalize_dns_name(name).is_err(), "{}", name);
+    }
+}
+
+#[test]
+fn test_public_url_base_parsing() {
+    let base = parse_public_url_base("https://hooks.example.com/edge").unwrap();
+    assert_eq!(base.host_str(), Some("hooks.example.com"));
+    assert_eq!(base.path(), "/edge");
+    assert!(parse_public_url_base("http://localhost:8080").is_ok());
+
+    for value in [
+        "hooks.example.com",
+        "ftp://hooks.example.com",
+        "https://user:pw@hooks.example.com",
+        "https://hooks.example.com/?a=1",
+        "https://hooks.example.com/#top",
+    ] {
+        assert!(parse_public_url_base(value).is_err(), "{}", value);
+    }
+}
+
+#[test]
+fn test_port_parsing() {
+    assert_eq!(parse_port("3000", "$PORT").unwrap(), 3000);
+    assert_eq!(parse_port(" 5173\n", "Port file .port").unwrap(), 5173);
+    for value in ["", "0", "65536", "3000abc", "localhost:3000"] {
+        assert!(parse_port(value, "$PORT").is_err(), "{}", value);
+    }
+}